@@ -0,0 +1,8 @@
+use std::{env, process};
+
+use redis_starter_rust::redis::aof;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    process::exit(aof::check::main(&args));
+}
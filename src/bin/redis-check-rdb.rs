@@ -0,0 +1,8 @@
+use std::{env, process};
+
+use redis_starter_rust::redis::rdb;
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    process::exit(rdb::check::main(&args));
+}
@@ -1,34 +1,64 @@
-mod redis;
-
+use std::fs::OpenOptions;
 use std::io::Read;
 use std::net::{TcpListener, TcpStream};
-use std::sync::{Arc, Condvar, Mutex, RwLock};
-use std::{env, thread};
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use std::{env, process, thread};
+
+use redis_starter_rust::redis;
 
-use redis::handler::{handle_request,State, StateInner};
+use redis::aof::{self, Aof};
+use redis::blocking::BlockingKeys;
+use redis::client::Client;
+use redis::cluster::Slots;
+use redis::config::Config;
+use redis::handler::{
+    active_expire_cycle, append_only_cycle, close_client, handle_request, open_client,
+    replay_append_only, save_points_cycle, State, StateInner,
+};
+use redis::migrate::MigrateSockets;
 use redis::persistence::lib::PersistenceInner;
+use redis::pubsub::PubSub;
+use redis::rdb::{self, SaveState};
 use redis::server::Info;
+use redis::tracking::Tracking;
+use redis::watch::WatchedKeys;
+
+use redis::parse::{Resp, RespData};
+
+fn handle_connection(persistence: &State, stream: TcpStream) {
+    let mut client = Client::new(stream);
+    open_client(persistence, &client);
 
-use crate::redis::parse::{Resp, RespData};
+    // A command can take more than one read, MIGRATE sends large ones.
+    let mut pending = vec![];
 
-fn handle_connection(persistence: &State, mut stream: TcpStream) {
     loop {
         let mut buf = [0; 1024];
 
-        match stream.read(&mut buf) {
+        let size = match client.stream.read(&mut buf) {
+            Ok(0) => break,
             Ok(size) => {
-                if size <= 0 {
-                    continue;
-                }
                 println!("Received bytes: {}", size);
+                size
             }
             Err(e) => {
                 println!("error: {}", e);
+                break;
             }
         };
 
-        let received = String::from_utf8_lossy(&buf);
-        let req = Resp::parse(received.to_string()).expect("Could not parse request");
+        pending.extend_from_slice(&buf[..size]);
+
+        let complete = complete_commands(&pending);
+
+        if complete == 0 {
+            continue;
+        }
+
+        let req = Resp::parse(&pending[..complete]).expect("Could not parse request");
+        pending.drain(..complete);
 
         match req.data {
             RespData::RequestArray(array) => {
@@ -37,59 +67,105 @@ fn handle_connection(persistence: &State, mut stream: TcpStream) {
                         data_type: redis::parse::RespType::Array,
                         data: req,
                     };
-                    handle_request(persistence, &mut stream, &resp);
+                    handle_request(persistence, &mut client, &resp);
                 }
             }
             _ => {
                 println!("Received: {:?}", req);
-                handle_request(persistence, &mut stream, &req);
+                handle_request(persistence, &mut client, &req);
+            }
+        }
+    }
+
+    close_client(persistence, &mut client);
+}
+
+/// Applies commands the master sent, in order: the replica must see the
+/// master's writes in the sequence they happened.
+fn apply_from_master(persistence: &State, client: &mut Client, received: &[u8]) {
+    println!("Received: {:?}", String::from_utf8_lossy(received));
+
+    let req = Resp::parse(received).unwrap();
+
+    match req.data {
+        RespData::RequestArray(array) => {
+            for req in array {
+                let resp = Resp {
+                    data_type: redis::parse::RespType::Array,
+                    data: req,
+                };
+
+                println!("Handling request: {:?}", resp);
+                handle_request(persistence, client, &resp);
+            }
+        }
+        _ => {
+            println!("Received: {:?}", req);
+        }
+    }
+}
+
+/// How much of `buf` is made of whole commands. The master streams its
+/// writes, so a read can stop in the middle of one.
+fn complete_commands(buf: &[u8]) -> usize {
+    let line = |from: usize| {
+        let end = buf.get(from..)?.windows(2).position(|w| w == b"\r\n")? + from;
+        let n = String::from_utf8_lossy(buf.get(from + 1..end)?).parse::<usize>().ok()?;
+        Some((n, end + 2))
+    };
+
+    let mut done = 0;
+
+    while done < buf.len() {
+        if buf[done] != b'*' {
+            return buf.len();
+        }
+
+        let command = line(done).and_then(|(count, mut pos)| {
+            for _ in 0..count {
+                let (len, start) = line(pos).filter(|_| buf[pos] == b'$')?;
+                pos = start + len + 2;
             }
+
+            (pos <= buf.len()).then_some(pos)
+        });
+
+        match command {
+            Some(end) => done = end,
+            None => break,
         }
     }
+
+    done
 }
 
-fn handle_connection_slave(persistence: &State, stream: Arc<Mutex<TcpStream>>) {
+/// `rest` is what the master sent right after the snapshot.
+fn handle_connection_slave(persistence: &State, stream: Arc<Mutex<TcpStream>>, rest: Vec<u8>) {
     println!("SLAVE HANDLING CONNECTIONS!");
 
+    let mut pending = rest;
+
     loop {
         let mut conn = stream.lock().unwrap();
+        let mut client = Client::master_link(conn.try_clone().unwrap());
 
         loop {
+            let complete = complete_commands(&pending);
+
+            if complete > 0 {
+                apply_from_master(persistence, &mut client, &pending[..complete]);
+                pending.drain(..complete);
+            }
+
             let mut buf = [0; 1024];
             match conn.read(&mut buf) {
                 Ok(size) => {
-                    if size <= 0 {
+                    if size == 0 {
                         continue;
                     }
 
                     println!("Handling request");
-                    let received = &String::from_utf8_lossy(&buf[..size]);
-                    println!("Received: {:?}", received);
-
-                    let req = Resp::parse(received.to_string()).unwrap();
-
-                    match req.data {
-                        RespData::RequestArray(array) => {
-                            for req in array {
-                                let resp = Resp {
-                                    data_type: redis::parse::RespType::Array,
-                                    data: req,
-                                };
-
-                                let send_stream = Arc::clone(persistence);
-                                let mut stream = conn.try_clone().unwrap();
-
-                                println!("Handling request: {:?}", resp);
-
-                                thread::spawn(move || {
-                                    handle_request(&send_stream, &mut stream, &resp);
-                                });
-                            }
-                        }
-                        _ => {
-                            println!("Received: {:?}", req);
-                        }
-                    }
+                    pending.extend_from_slice(&buf[..size]);
                 }
                 Err(e) => {
                     println!("error: {}", e);
@@ -100,10 +176,109 @@ fn handle_connection_slave(persistence: &State, stream: Arc<Mutex<TcpStream>>) {
     }
 }
 
+/// Loads the AOF the manifest in `appenddirname` lists, or a single file
+/// one from before, and goes on appending to it. Without any, a rewrite
+/// starts one from the empty keyspace. A last command cut short gets cut
+/// off the file when `aof-load-truncated` allows it.
+fn load_append_only(persistence: &State) {
+    let config = persistence.config.read().unwrap().clone();
+    let dir = config.aof_dir();
+    let filename = &config.appendfilename;
+
+    let manifest = match aof::manifest::read(&dir, filename) {
+        Ok(None) => {
+            let upgraded = aof::load::upgrade_legacy(Path::new(&config.dir), &dir, filename);
+
+            if let Ok(Some(_)) = upgraded {
+                println!(
+                    "Successfully migrated an old-style AOF into the AOF directory {}",
+                    dir.display()
+                );
+            }
+
+            upgraded
+        }
+        read => read,
+    };
+
+    let loaded = manifest.and_then(|manifest| match manifest {
+        Some(manifest) => aof::load::read_all(&dir, &manifest).map(|all| Some((manifest, all))),
+        None => Ok(None),
+    });
+
+    let (manifest, contents) = match loaded {
+        Ok(Some(loaded)) => loaded,
+        Ok(None) => {
+            persistence.aof.request_start();
+            return;
+        }
+        Err(e) => {
+            eprintln!(
+                "Error loading the AOF in {}: {}. Make a backup of your AOF files, then use ./redis-check-aof --fix <filename.manifest>",
+                dir.display(),
+                e
+            );
+            process::exit(1);
+        }
+    };
+
+    let path = &contents.path;
+
+    if contents.truncated() {
+        if !config.aof_load_truncated {
+            eprintln!(
+                "Unexpected end of file reading the append only file {}. You can: 1) Make a backup of your AOF file, then use ./redis-check-aof --fix <filename.manifest>. 2) Alternatively you can set the 'aof-load-truncated' configuration option to yes and restart the server.",
+                path.display()
+            );
+            process::exit(1);
+        }
+
+        println!(
+            "!!! Warning: short read while loading the AOF file {}!!!",
+            path.display()
+        );
+
+        let truncated = OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_len(contents.valid as u64));
+
+        if let Err(e) = truncated {
+            eprintln!("Error truncating the AOF file {}: {}", path.display(), e);
+            process::exit(1);
+        }
+
+        println!(
+            "AOF {} loaded anyway because aof-load-truncated is enabled",
+            path.display()
+        );
+    }
+
+    if let Some(preamble) = contents.preamble {
+        match rdb::load::restore(&persistence.persisted, preamble) {
+            Ok(keys) => println!("RDB preamble of the AOF loaded: {} keys", keys),
+            Err(e) => {
+                eprintln!("Error loading the RDB preamble of {}: {}", path.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+
+    let commands = replay_append_only(persistence, contents.commands);
+    println!("DB loaded from append only file: {} commands", commands);
+
+    if let Err(e) = persistence.aof.open(&dir, filename, manifest) {
+        eprintln!("Can't open the append-only file in {}: {}", dir.display(), e);
+        process::exit(1);
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
 
     let mut server: Info = redis::server::Info::default();
+    let mut config = Config::default();
+    let mut master_sync = None;
 
     for (i, arg) in args.iter().enumerate() {
         match arg.as_str() {
@@ -113,34 +288,123 @@ fn main() {
             "--replicaof" => {
                 let master_host = args.get(i + 1).unwrap();
                 let master_port = args.get(i + 2).unwrap().parse::<u16>().unwrap();
-                server.slave(master_host.to_string(), master_port);
+                master_sync = Some(server.slave(master_host.to_string(), master_port));
+            }
+            name if name.starts_with("--") => {
+                let value = args.get(i + 1).map(String::as_str).unwrap_or_default();
+
+                if let Err(e) = config.set(&name[2..], value) {
+                    eprintln!(
+                        "Bad directive or wrong value for '{}': {}",
+                        name,
+                        e.unwrap_or_default()
+                    );
+                    process::exit(1);
+                }
             }
             _ => {}
         }
     }
 
+    let persisted = PersistenceInner::default();
+    let rdb_path = config.rdb_path();
+    let appendonly = config.appendonly;
+    let replica = master_sync.is_some();
+
+    // With the AOF on, it is what gets loaded instead.
+    let loaded = match appendonly {
+        true => Ok(None),
+        false => rdb::load::read_file(&rdb_path),
+    };
+
+    let loaded = loaded.and_then(|loaded| match loaded {
+        Some(loaded) => {
+            let version = loaded.version;
+            rdb::load::restore(&persisted, loaded).map(|keys| Some((version, keys)))
+        }
+        None => Ok(None),
+    });
+
+    match loaded {
+        Ok(Some((version, keys))) => {
+            println!("DB loaded from disk (RDB version {}): {} keys", version, keys);
+        }
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Error loading {}: {}", rdb_path.display(), e);
+            process::exit(1);
+        }
+    }
+
+    // A replica starts over from its master's snapshot.
+    let rest = match master_sync {
+        Some(sync) => {
+            persisted.flush();
+
+            let loaded = rdb::load::parse(&sync.rdb)
+                .and_then(|loaded| rdb::load::restore(&persisted, loaded));
+
+            match loaded {
+                Ok(keys) => println!("MASTER <-> REPLICA sync: loaded {} keys", keys),
+                Err(e) => {
+                    eprintln!("Error loading the master's snapshot: {}", e);
+                    process::exit(1);
+                }
+            }
+
+            sync.rest
+        }
+        None => vec![],
+    };
+
     let persist: State = Arc::new(StateInner {
-        persisted: PersistenceInner::default(),
+        persisted,
         info: RwLock::new(server),
-        cond: Condvar::new()
+        blocking: BlockingKeys::default(),
+        exec: RwLock::new(()),
+        transaction: Mutex::new(None),
+        watched: WatchedKeys::default(),
+        pubsub: PubSub::default(),
+        slots: Slots::default(),
+        config: RwLock::new(config),
+        tracking: Tracking::default(),
+        saving: SaveState::default(),
+        aof: Aof::default(),
+        migrate: MigrateSockets::default(),
     });
 
+    // A replica's keyspace comes from its master, its AOF starts over
+    // from there.
+    if appendonly && replica {
+        persist.aof.request_start();
+    } else if appendonly {
+        load_append_only(&persist);
+    }
+
     let port = Arc::clone(&persist).info.read().unwrap().port;
 
     let listener = TcpListener::bind(format!("127.0.0.1:{}", port)).unwrap();
 
     {
-        match persist.info.read().unwrap().as_slave() {
-            Some(slave) => {
-                let persist = Arc::clone(&persist);
-                thread::spawn(move || {
-                    handle_connection_slave(&persist, slave.stream);
-                });
-            }
-            None => {}
+        if let Some(slave) = persist.info.read().unwrap().as_slave() {
+            let persist = Arc::clone(&persist);
+            thread::spawn(move || {
+                handle_connection_slave(&persist, slave.stream, rest);
+            });
         }
     }
 
+    {
+        let persist = Arc::clone(&persist);
+        thread::spawn(move || loop {
+            thread::sleep(Duration::from_millis(100));
+            active_expire_cycle(&persist);
+            save_points_cycle(&persist);
+            append_only_cycle(&persist);
+            persist.migrate.close_idle();
+        });
+    }
+
     for stream in listener.incoming() {
         match stream {
             Ok(stream) => {
@@ -155,3 +419,38 @@ fn main() {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn complete_commands_stops_at_a_partial_command() {
+        let set = b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n";
+        let ping = b"*1\r\n$4\r\nPING\r\n";
+        let buf = [&set[..], &ping[..]].concat();
+
+        assert_eq!(complete_commands(&buf), buf.len());
+
+        for cut in set.len() + 1..buf.len() {
+            assert_eq!(complete_commands(&buf[..cut]), set.len(), "cut at {}", cut);
+        }
+
+        assert_eq!(complete_commands(b""), 0);
+        assert_eq!(complete_commands(b"*"), 0);
+    }
+
+    #[test]
+    fn complete_commands_reads_bulk_strings_by_length() {
+        let buf = b"*2\r\n$4\r\nE\r\nO\r\n$0\r\n\r\n";
+
+        assert_eq!(complete_commands(buf), buf.len());
+        assert_eq!(complete_commands(&buf[..buf.len() - 1]), 0);
+    }
+
+    #[test]
+    fn complete_commands_takes_anything_else_whole() {
+        assert_eq!(complete_commands(b"+OK\r\n"), 5);
+        assert_eq!(complete_commands(b"*1\r\n$4\r\nPING\r\n+OK"), 17);
+    }
+}
@@ -0,0 +1,387 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+use super::{load::read_command, manifest::Manifest};
+use crate::redis::rdb;
+
+/// How much of a file of commands is good, and what's wrong past that.
+struct Analysis {
+    /// Bytes up to the end of the last whole command outside a MULTI.
+    valid: usize,
+    /// Where the problem is, and what it is.
+    error: Option<(usize, String)>,
+}
+
+/// Reads the commands in `data` from `pos` on, the way loading would.
+fn analyze(data: &[u8], mut pos: usize) -> Analysis {
+    let mut valid = pos;
+    let mut multi = false;
+
+    let error = loop {
+        if pos >= data.len() {
+            break multi.then_some("Reached EOF before reading EXEC for MULTI".to_string());
+        }
+
+        let (args, end) = match read_command(data, pos) {
+            Ok(Some(command)) => command,
+            Ok(None) => break Some("Unexpected EOF reading the append only file".to_string()),
+            Err(e) => break Some(e.to_string()),
+        };
+
+        match args.first().map(|name| name.to_ascii_lowercase()).as_deref() {
+            Some(b"multi") if multi => break Some("Unexpected MULTI".to_string()),
+            Some(b"multi") => multi = true,
+            Some(b"exec") if !multi => break Some("Unexpected EXEC".to_string()),
+            Some(b"exec") => multi = false,
+            _ => {}
+        }
+
+        pos = end;
+
+        if !multi {
+            valid = pos;
+        }
+    };
+
+    Analysis {
+        valid,
+        error: error.map(|error| (pos, error)),
+    }
+}
+
+/// Asks before anything is cut off.
+fn confirm(prompt: &str) -> bool {
+    print!("{}\nContinue? [y/N]: ", prompt);
+    io::stdout().flush().ok();
+
+    let mut answer = String::new();
+
+    io::stdin().read_line(&mut answer).is_ok() && answer.trim().to_lowercase().starts_with('y')
+}
+
+/// Checks one file, the RDB preamble too if it has one. Only the `last`
+/// file can be fixed: cutting any other one short would leave out
+/// commands the files after it build on.
+fn check_file(path: &Path, data: &[u8], last: bool, fix: bool) -> bool {
+    let name = path
+        .file_name()
+        .map_or_else(|| path.to_string_lossy(), |name| name.to_string_lossy());
+    let mut pos = 0;
+
+    if data.starts_with(b"REDIS") {
+        println!("The AOF appears to start with an RDB preamble.");
+        println!("Checking the RDB preamble to start:");
+
+        match rdb::check::check(data, &name, false) {
+            Some(end) => {
+                println!("RDB preamble is OK, proceeding with AOF tail...");
+                pos = end;
+            }
+            None => {
+                println!("RDB preamble of AOF file is not sane, aborting.");
+                return false;
+            }
+        }
+    }
+
+    let analysis = analyze(data, pos);
+
+    if let Some((at, error)) = &analysis.error {
+        println!("0x{:016x}: {}", at, error);
+    }
+
+    let diff = data.len() - analysis.valid;
+    let lines = data[..analysis.valid]
+        .iter()
+        .filter(|&&byte| byte == b'\n')
+        .count()
+        + 1;
+
+    println!(
+        "AOF analyzed: filename={}, size={}, ok_up_to={}, ok_up_to_line={}, diff={}",
+        name,
+        data.len(),
+        analysis.valid,
+        lines,
+        diff
+    );
+
+    if diff == 0 {
+        println!("AOF {} is valid", name);
+        return true;
+    }
+
+    if !fix {
+        println!(
+            "AOF {} is not valid. Use the --fix option to try fixing it.",
+            name
+        );
+        return false;
+    }
+
+    if !last {
+        println!(
+            "Failed to truncate AOF {} because it is not the last file",
+            name
+        );
+        return false;
+    }
+
+    let prompt = format!(
+        "This will shrink the AOF {} from {} bytes, with {} bytes, to {} bytes",
+        name,
+        data.len(),
+        diff,
+        analysis.valid
+    );
+
+    if !confirm(&prompt) {
+        println!("Aborting...");
+        return false;
+    }
+
+    let truncated = OpenOptions::new().write(true).open(path).and_then(|file| {
+        file.set_len(analysis.valid as u64)?;
+        file.sync_all()
+    });
+
+    match truncated {
+        Ok(()) => {
+            println!("Successfully truncated AOF {}", name);
+            true
+        }
+        Err(e) => {
+            println!("Failed to truncate AOF {}: {}", name, e);
+            false
+        }
+    }
+}
+
+fn read(path: &Path) -> Option<Vec<u8>> {
+    match fs::read(path) {
+        Ok(data) => Some(data),
+        Err(e) => {
+            println!("Cannot open file {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Whether `data` reads like a manifest rather than commands or an RDB.
+fn is_manifest(data: &[u8]) -> bool {
+    String::from_utf8_lossy(data)
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty() && !line.starts_with('#'))
+        .is_some_and(|line| line.starts_with("file "))
+}
+
+/// Checks every file the manifest at `path` lists, base first.
+fn check_manifest(path: &Path, data: &[u8], fix: bool) -> bool {
+    println!("Start checking Multi Part AOF");
+
+    let manifest = match Manifest::parse(&String::from_utf8_lossy(data)) {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("Invalid AOF manifest file format: {}", e);
+            return false;
+        }
+    };
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let files = manifest.files().count();
+    let first_incr = manifest.base.is_some() as usize;
+
+    if files == 0 {
+        println!("Invalid AOF manifest: it lists no files");
+        return false;
+    }
+
+    for (i, entry) in manifest.files().enumerate() {
+        let path = dir.join(&entry.name);
+
+        let data = match read(&path) {
+            Some(data) => data,
+            None => return false,
+        };
+
+        if i < first_incr {
+            let format = if data.starts_with(b"REDIS") {
+                "RDB"
+            } else {
+                "RESP"
+            };
+
+            println!("Start to check BASE AOF ({} format).", format);
+        } else if i == first_incr {
+            println!("Start to check INCR files.");
+        }
+
+        if !check_file(&path, &data, i + 1 == files, fix) {
+            return false;
+        }
+    }
+
+    println!("All AOF files and manifest are valid");
+    true
+}
+
+/// `redis-check-aof [--fix] <file.manifest|file.aof>`, returns the exit
+/// code.
+pub fn main(args: &[String]) -> i32 {
+    let fix = args[1..].iter().any(|arg| arg == "--fix");
+    let files: Vec<&String> = args[1..].iter().filter(|arg| *arg != "--fix").collect();
+
+    let path = match files.as_slice() {
+        [path] => Path::new(path.as_str()),
+        _ => {
+            eprintln!("Usage: {} [--fix] <file.manifest|file.aof>", args[0]);
+            return 1;
+        }
+    };
+
+    let data = match read(path) {
+        Some(data) => data,
+        None => return 1,
+    };
+
+    let ok = match is_manifest(&data) {
+        true => check_manifest(path, &data, fix),
+        false => {
+            println!("Start checking Old-Style AOF");
+            check_file(path, &data, true, fix)
+        }
+    };
+
+    match ok {
+        true => 0,
+        false => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{path::PathBuf, process};
+
+    use super::*;
+
+    const PING: &[u8] = b"*1\r\n$4\r\nPING\r\n";
+    const MULTI: &[u8] = b"*1\r\n$5\r\nMULTI\r\n";
+    const EXEC: &[u8] = b"*1\r\n$4\r\nEXEC\r\n";
+
+    /// An empty directory of its own for `name`.
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aof-check-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn error(data: &[u8]) -> Option<(usize, String)> {
+        analyze(data, 0).error
+    }
+
+    #[test]
+    fn analyze_finds_the_last_whole_command() {
+        let data = [PING, MULTI, PING, EXEC].concat();
+        let analysis = analyze(&data, 0);
+
+        assert_eq!(analysis.valid, data.len());
+        assert!(analysis.error.is_none());
+
+        let cut = [PING, &PING[..5]].concat();
+
+        assert_eq!(analyze(&cut, 0).valid, PING.len());
+        assert_eq!(
+            error(&cut),
+            Some((
+                PING.len(),
+                "Unexpected EOF reading the append only file".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn analyze_checks_transactions() {
+        let open = [PING, MULTI, PING].concat();
+
+        assert_eq!(analyze(&open, 0).valid, PING.len());
+        assert_eq!(
+            error(&open),
+            Some((
+                open.len(),
+                "Reached EOF before reading EXEC for MULTI".to_string()
+            ))
+        );
+        assert_eq!(
+            error(&[MULTI, MULTI].concat()),
+            Some((MULTI.len(), "Unexpected MULTI".to_string()))
+        );
+        assert_eq!(error(EXEC), Some((0, "Unexpected EXEC".to_string())));
+    }
+
+    #[test]
+    fn analyze_reports_garbage() {
+        let data = [PING, b"garbage"].concat();
+        let analysis = analyze(&data, 0);
+
+        assert_eq!(analysis.valid, PING.len());
+        assert_eq!(analysis.error.unwrap().0, PING.len());
+    }
+
+    #[test]
+    fn manifests_are_told_apart() {
+        assert!(is_manifest(b"# comment\nfile a.aof seq 1 type b\n"));
+        assert!(!is_manifest(PING));
+        assert!(!is_manifest(b"REDIS0011"));
+        assert!(!is_manifest(b""));
+    }
+
+    #[test]
+    fn only_the_last_file_is_fixed() {
+        let dir = dir("fix");
+        let path = dir.join("appendonly.aof");
+        let data = [PING, MULTI].concat();
+
+        fs::write(&path, &data).unwrap();
+
+        assert!(check_file(&path, PING, false, false));
+        assert!(!check_file(&path, &data, true, false));
+        assert!(!check_file(&path, &data, false, true));
+        assert_eq!(fs::read(&path).unwrap(), data);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn manifests_are_checked_file_by_file() {
+        let dir = dir("manifest");
+        let manifest = "file a.aof.1.base.rdb seq 1 type b\nfile a.aof.2.incr.aof seq 2 type i\n";
+        let path = dir.join("a.aof.manifest");
+        let args = vec![
+            "redis-check-aof".to_string(),
+            path.to_string_lossy().into_owned(),
+        ];
+
+        fs::write(&path, manifest).unwrap();
+        fs::write(dir.join("a.aof.1.base.rdb"), rdb::save::serialize(&[])).unwrap();
+
+        // The INCR file isn't there yet.
+        assert_eq!(main(&args), 1);
+
+        fs::write(dir.join("a.aof.2.incr.aof"), PING).unwrap();
+
+        assert_eq!(main(&args), 0);
+
+        fs::write(dir.join("a.aof.1.base.rdb"), b"REDIS0011garbage").unwrap();
+
+        assert_eq!(main(&args), 1);
+        assert!(!check_manifest(&path, b"file x seq 1", false));
+        assert!(!check_manifest(&path, b"# nothing\n", false));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,393 @@
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use super::{
+    manifest::{self, FileType, Manifest, ManifestEntry},
+    AofError,
+};
+use crate::redis::{
+    parse::RespData,
+    rdb::{self, Rdb},
+};
+
+/// What an append only file holds.
+#[derive(Debug, Default)]
+pub struct AofContents {
+    /// The keyspace the file starts from, when it starts with an RDB.
+    pub preamble: Option<Rdb>,
+    pub commands: Vec<Vec<RespData>>,
+    /// Bytes up to the end of the last whole command. A command cut short,
+    /// or a MULTI that never got its EXEC, is left out of `commands`.
+    pub valid: usize,
+    pub len: usize,
+    /// The file `valid` and `len` are about, the last one read.
+    pub path: PathBuf,
+}
+
+impl AofContents {
+    pub fn truncated(&self) -> bool {
+        self.valid < self.len
+    }
+}
+
+/// A command's arguments as they were logged, not all of them are text.
+pub type Args = Vec<Vec<u8>>;
+
+/// The command starting at `pos`, with where it ends. `None` when the data
+/// stops before its end.
+pub fn read_command(data: &[u8], pos: usize) -> Result<Option<(Args, usize)>, AofError> {
+    // A `<prefix><number>\r\n` line, with where the next one starts.
+    let line = |from: usize, prefix: u8| -> Result<Option<(usize, usize)>, AofError> {
+        if data[from] != prefix {
+            return Err(AofError::BadFormat(from));
+        }
+
+        let end = match data[from + 1..].windows(2).position(|w| w == b"\r\n") {
+            Some(end) => from + 1 + end,
+            None => return Ok(None),
+        };
+
+        std::str::from_utf8(&data[from + 1..end])
+            .ok()
+            .and_then(|n| n.parse::<usize>().ok())
+            .map(|n| Some((n, end + 2)))
+            .ok_or(AofError::BadFormat(from))
+    };
+
+    let (argc, mut pos) = match line(pos, b'*')? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+
+    let mut args = Vec::with_capacity(argc);
+
+    for _ in 0..argc {
+        if pos >= data.len() {
+            return Ok(None);
+        }
+
+        let (len, start) = match line(pos, b'$')? {
+            Some(line) => line,
+            None => return Ok(None),
+        };
+
+        let end = start + len;
+
+        if end + 2 > data.len() {
+            return Ok(None);
+        }
+
+        if &data[end..end + 2] != b"\r\n" {
+            return Err(AofError::BadFormat(end));
+        }
+
+        args.push(data[start..end].to_vec());
+        pos = end + 2;
+    }
+
+    Ok(Some((args, pos)))
+}
+
+/// Splits an append only file into its RDB preamble, if any, and commands.
+pub fn parse(data: &[u8]) -> Result<AofContents, AofError> {
+    let mut contents = AofContents {
+        len: data.len(),
+        ..AofContents::default()
+    };
+
+    let mut pos = 0;
+
+    if data.starts_with(b"REDIS") {
+        let (preamble, end) = rdb::load::parse_prefix(data).map_err(AofError::Rdb)?;
+        contents.preamble = Some(preamble);
+        pos = end;
+    }
+
+    contents.valid = pos;
+
+    // Commands of a MULTI only count once its EXEC is there too.
+    let mut multi: Option<Vec<Vec<RespData>>> = None;
+
+    while pos < data.len() {
+        let (args, end) = match read_command(data, pos)? {
+            Some(command) => command,
+            None => break,
+        };
+
+        pos = end;
+
+        let name = args
+            .first()
+            .map(|name| String::from_utf8_lossy(name).to_lowercase())
+            .unwrap_or_default();
+        let command: Vec<RespData> = args.into_iter().map(RespData::BulkString).collect();
+
+        match (name.as_str(), multi.as_mut()) {
+            ("multi", _) => multi = Some(vec![command]),
+            ("exec", Some(queued)) => {
+                queued.push(command);
+                contents.commands.append(queued);
+                contents.valid = pos;
+                multi = None;
+            }
+            (_, Some(queued)) => queued.push(command),
+            (_, None) => {
+                contents.commands.push(command);
+                contents.valid = pos;
+            }
+        }
+    }
+
+    Ok(contents)
+}
+
+/// Reads every file `manifest` lists in `dir`, base first. Only the last
+/// one may end in the middle of a command, see `AofContents::truncated`.
+pub fn read_all(dir: &Path, manifest: &Manifest) -> Result<AofContents, AofError> {
+    let files: Vec<&ManifestEntry> = manifest.files().collect();
+    let mut all = AofContents::default();
+
+    for (i, entry) in files.iter().enumerate() {
+        let path = dir.join(&entry.name);
+
+        let data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(AofError::MissingFile(entry.name.clone()));
+            }
+            Err(e) => return Err(e.into()),
+        };
+
+        let contents = parse(&data)?;
+
+        // Only the base may start with an RDB.
+        if contents.preamble.is_some() && entry.file_type != FileType::Base {
+            return Err(AofError::BadFormat(0));
+        }
+
+        if contents.truncated() && i + 1 < files.len() {
+            return Err(AofError::Truncated(entry.name.clone()));
+        }
+
+        if contents.preamble.is_some() {
+            all.preamble = contents.preamble;
+        }
+
+        all.commands.extend(contents.commands);
+        all.valid = contents.valid;
+        all.len = contents.len;
+        all.path = path;
+    }
+
+    Ok(all)
+}
+
+/// Moves an AOF from before there were several files, `filename` right in
+/// `dir`, into `aof_dir` as the base. `None` when there is no such file.
+pub fn upgrade_legacy(
+    dir: &Path,
+    aof_dir: &Path,
+    filename: &str,
+) -> Result<Option<Manifest>, AofError> {
+    let legacy = dir.join(filename);
+
+    if !legacy.is_file() {
+        return Ok(None);
+    }
+
+    let manifest = Manifest {
+        base: Some(ManifestEntry {
+            name: filename.to_string(),
+            seq: 1,
+            file_type: FileType::Base,
+        }),
+        ..Manifest::default()
+    };
+
+    // The manifest goes first: should the move fail, the next start
+    // complains about the missing file instead of starting out empty.
+    fs::create_dir_all(aof_dir)?;
+    manifest::write(aof_dir, filename, &manifest)?;
+    fs::rename(&legacy, aof_dir.join(filename))?;
+
+    Ok(Some(manifest))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+    use crate::redis::rdb::save;
+
+    fn names(contents: &AofContents) -> Vec<&str> {
+        contents
+            .commands
+            .iter()
+            .map(|command| command[0].inside_value().unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn commands_are_read_whole() {
+        let data = b"*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n*1\r\n$4\r\nPING\r\n";
+        let contents = parse(data).unwrap();
+
+        assert_eq!(names(&contents), ["DEL", "PING"]);
+        assert!(!contents.truncated());
+        assert!(contents.preamble.is_none());
+    }
+
+    #[test]
+    fn a_command_cut_short_is_left_out() {
+        let data = b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nDEL\r\n$1\r";
+        let contents = parse(data).unwrap();
+
+        assert_eq!(names(&contents), ["PING"]);
+        assert!(contents.truncated());
+        assert_eq!(contents.valid, 14);
+    }
+
+    #[test]
+    fn a_multi_without_exec_is_left_out() {
+        let data = [
+            &b"*1\r\n$5\r\nMULTI\r\n*1\r\n$4\r\nINCR\r\n*1\r\n$4\r\nEXEC\r\n"[..],
+            b"*1\r\n$5\r\nMULTI\r\n*1\r\n$4\r\nINCR\r\n",
+        ]
+        .concat();
+        let contents = parse(&data).unwrap();
+
+        assert_eq!(names(&contents), ["MULTI", "INCR", "EXEC"]);
+        assert!(contents.truncated());
+        assert_eq!(contents.valid, 43);
+    }
+
+    #[test]
+    fn garbage_is_an_error() {
+        let data = b"*1\r\n$4\r\nPING\r\nhello";
+
+        assert!(matches!(parse(data), Err(AofError::BadFormat(14))));
+        assert!(matches!(
+            parse(b"*1\r\n$4\r\nPINGxx"),
+            Err(AofError::BadFormat(_))
+        ));
+    }
+
+    /// `files` written to an empty directory, with a manifest listing them
+    /// in order, base first.
+    fn files(name: &str, files: &[(&str, &[u8])]) -> (PathBuf, Manifest) {
+        let dir = std::env::temp_dir().join(format!("aof-load-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut manifest = Manifest::default();
+
+        for (i, (name, data)) in files.iter().enumerate() {
+            fs::write(dir.join(name), data).unwrap();
+
+            let entry = ManifestEntry {
+                name: name.to_string(),
+                seq: i as u64 + 1,
+                file_type: if i == 0 {
+                    FileType::Base
+                } else {
+                    FileType::Incr
+                },
+            };
+
+            match entry.file_type {
+                FileType::Base => manifest.base = Some(entry),
+                _ => manifest.incrs.push(entry),
+            }
+        }
+
+        (dir, manifest)
+    }
+
+    #[test]
+    fn read_all_goes_through_every_file() {
+        let base = save::serialize(&[]);
+        let (dir, manifest) = files(
+            "all",
+            &[
+                ("base.rdb", &base),
+                ("1.incr.aof", b"*1\r\n$4\r\nPING\r\n"),
+                ("2.incr.aof", b"*1\r\n$4\r\nECHO\r\n*1\r\n$3"),
+            ],
+        );
+        let contents = read_all(&dir, &manifest).unwrap();
+
+        assert!(contents.preamble.is_some());
+        assert_eq!(names(&contents), ["PING", "ECHO"]);
+        assert!(contents.truncated());
+        assert_eq!(contents.path, dir.join("2.incr.aof"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn read_all_only_lets_the_last_file_be_cut_short() {
+        let (dir, manifest) = files(
+            "cut",
+            &[
+                ("base.aof", b"*1\r\n$4\r\nPING\r\n*1"),
+                ("1.incr.aof", b"*1\r\n$4\r\nPING\r\n"),
+            ],
+        );
+
+        assert!(matches!(
+            read_all(&dir, &manifest),
+            Err(AofError::Truncated(name)) if name == "base.aof"
+        ));
+
+        fs::remove_file(dir.join("base.aof")).unwrap();
+
+        assert!(matches!(
+            read_all(&dir, &manifest),
+            Err(AofError::MissingFile(name)) if name == "base.aof"
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn only_the_base_may_be_an_rdb() {
+        let rdb = save::serialize(&[]);
+        let (dir, manifest) = files("rdb", &[("base.aof", b""), ("1.incr.aof", &rdb)]);
+
+        assert!(matches!(
+            read_all(&dir, &manifest),
+            Err(AofError::BadFormat(0))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn legacy_files_become_the_base() {
+        let (dir, _) = files("legacy", &[("appendonly.aof", b"*1\r\n$4\r\nPING\r\n")]);
+        let aof_dir = dir.join("appendonlydir");
+
+        assert!(upgrade_legacy(&dir, &aof_dir, "other.aof")
+            .unwrap()
+            .is_none());
+
+        let manifest = upgrade_legacy(&dir, &aof_dir, "appendonly.aof")
+            .unwrap()
+            .unwrap();
+
+        assert!(!dir.join("appendonly.aof").exists());
+        assert_eq!(
+            manifest::read(&aof_dir, "appendonly.aof")
+                .unwrap()
+                .unwrap()
+                .base,
+            manifest.base
+        );
+        assert_eq!(names(&read_all(&aof_dir, &manifest).unwrap()), ["PING"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,270 @@
+use std::{fs, io, path::Path};
+
+use super::AofError;
+use crate::redis::rdb;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FileType {
+    /// The keyspace as it was when the last rewrite started.
+    Base,
+    /// Writes since then.
+    Incr,
+    /// Left over from before a rewrite, waiting to be deleted.
+    History,
+}
+
+impl FileType {
+    fn parse(value: &str) -> Option<FileType> {
+        match value {
+            "b" => Some(FileType::Base),
+            "i" => Some(FileType::Incr),
+            "h" => Some(FileType::History),
+            _ => None,
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            FileType::Base => "b",
+            FileType::Incr => "i",
+            FileType::History => "h",
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+    pub name: String,
+    pub seq: u64,
+    pub file_type: FileType,
+}
+
+/// Which files make up the AOF, the way Redis 7 lists them: a line per
+/// file, `file <name> seq <seq> type <b|i|h>`.
+#[derive(Clone, Debug, Default)]
+pub struct Manifest {
+    pub base: Option<ManifestEntry>,
+    /// Loaded after the base, in this order.
+    pub incrs: Vec<ManifestEntry>,
+    pub history: Vec<ManifestEntry>,
+}
+
+pub fn manifest_name(filename: &str) -> String {
+    format!("{}.manifest", filename)
+}
+
+impl Manifest {
+    pub fn parse(text: &str) -> Result<Manifest, AofError> {
+        let invalid =
+            |line: &str| AofError::Manifest(format!("Invalid AOF manifest line: {}", line));
+        let mut manifest = Manifest::default();
+
+        for line in text.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+
+            if !words.len().is_multiple_of(2) {
+                return Err(invalid(line));
+            }
+
+            let (mut name, mut seq, mut file_type) = (None, None, None);
+
+            for pair in words.chunks(2) {
+                match pair[0] {
+                    "file" => name = Some(pair[1].to_string()),
+                    "seq" => seq = pair[1].parse::<u64>().ok(),
+                    "type" => file_type = FileType::parse(pair[1]),
+                    // Keys newer versions may add.
+                    _ => {}
+                }
+            }
+
+            let entry = match (name, seq, file_type) {
+                (Some(name), Some(seq), Some(file_type)) => ManifestEntry {
+                    name,
+                    seq,
+                    file_type,
+                },
+                _ => return Err(invalid(line)),
+            };
+
+            match entry.file_type {
+                FileType::Base if manifest.base.is_some() => {
+                    return Err(AofError::Manifest(
+                        "Found duplicate base file information".to_string(),
+                    ));
+                }
+                FileType::Base => manifest.base = Some(entry),
+                FileType::Incr => manifest.incrs.push(entry),
+                FileType::History => manifest.history.push(entry),
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    pub fn to_text(&self) -> String {
+        self.base
+            .iter()
+            .chain(&self.history)
+            .chain(&self.incrs)
+            .map(|entry| {
+                format!(
+                    "file {} seq {} type {}\n",
+                    entry.name,
+                    entry.seq,
+                    entry.file_type.as_str()
+                )
+            })
+            .collect()
+    }
+
+    /// The files to load, base first.
+    pub fn files(&self) -> impl Iterator<Item = &ManifestEntry> {
+        self.base.iter().chain(&self.incrs)
+    }
+
+    /// Higher than any file listed, so new names never clash with old ones.
+    fn next_seq(&self) -> u64 {
+        self.files()
+            .chain(&self.history)
+            .map(|entry| entry.seq)
+            .max()
+            .unwrap_or(0)
+            + 1
+    }
+
+    pub fn next_base(&self, filename: &str, rdb: bool) -> ManifestEntry {
+        let seq = self.next_seq();
+        let suffix = if rdb { "rdb" } else { "aof" };
+
+        ManifestEntry {
+            name: format!("{}.{}.base.{}", filename, seq, suffix),
+            seq,
+            file_type: FileType::Base,
+        }
+    }
+
+    pub fn next_incr(&self, filename: &str) -> ManifestEntry {
+        let seq = self.next_seq();
+
+        ManifestEntry {
+            name: format!("{}.{}.incr.aof", filename, seq),
+            seq,
+            file_type: FileType::Incr,
+        }
+    }
+
+    /// Total size of the files to load.
+    pub fn size(&self, dir: &Path) -> u64 {
+        self.files()
+            .filter_map(|entry| fs::metadata(dir.join(&entry.name)).ok())
+            .map(|metadata| metadata.len())
+            .sum()
+    }
+}
+
+/// The manifest in `dir`, `None` when there is none.
+pub fn read(dir: &Path, filename: &str) -> Result<Option<Manifest>, AofError> {
+    match fs::read_to_string(dir.join(manifest_name(filename))) {
+        Ok(text) => Manifest::parse(&text).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn write(dir: &Path, filename: &str, manifest: &Manifest) -> io::Result<()> {
+    rdb::save::write_file(
+        &dir.join(manifest_name(filename)),
+        manifest.to_text().as_bytes(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, seq: u64, file_type: FileType) -> ManifestEntry {
+        ManifestEntry {
+            name: name.to_string(),
+            seq,
+            file_type,
+        }
+    }
+
+    #[test]
+    fn manifests_round_trip() {
+        let manifest = Manifest {
+            base: Some(entry("a.aof.2.base.rdb", 2, FileType::Base)),
+            incrs: vec![
+                entry("a.aof.3.incr.aof", 3, FileType::Incr),
+                entry("a.aof.4.incr.aof", 4, FileType::Incr),
+            ],
+            history: vec![entry("a.aof.1.base.aof", 1, FileType::History)],
+        };
+        let text = manifest.to_text();
+
+        assert_eq!(
+            text,
+            "file a.aof.2.base.rdb seq 2 type b\n\
+             file a.aof.1.base.aof seq 1 type h\n\
+             file a.aof.3.incr.aof seq 3 type i\n\
+             file a.aof.4.incr.aof seq 4 type i\n"
+        );
+
+        let parsed = Manifest::parse(&text).unwrap();
+
+        assert_eq!(parsed.base, manifest.base);
+        assert_eq!(parsed.incrs, manifest.incrs);
+        assert_eq!(parsed.history, manifest.history);
+        assert_eq!(parsed.files().map(|e| e.seq).collect::<Vec<_>>(), [2, 3, 4]);
+    }
+
+    #[test]
+    fn parse_skips_comments_and_unknown_keys() {
+        let manifest = Manifest::parse("# comment\n\n  file x seq 1 type i extra yes  \n").unwrap();
+
+        assert_eq!(manifest.incrs, [entry("x", 1, FileType::Incr)]);
+        assert!(manifest.base.is_none());
+    }
+
+    #[test]
+    fn parse_rejects_bad_lines() {
+        for text in [
+            "file x seq 1",
+            "file x seq 1 type",
+            "file x seq one type i",
+            "file x seq 1 type q",
+            "file x seq 1 type b\nfile y seq 2 type b\n",
+        ] {
+            assert!(
+                matches!(Manifest::parse(text), Err(AofError::Manifest(_))),
+                "{:?}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn new_files_get_the_next_seq() {
+        let mut manifest = Manifest::default();
+
+        assert_eq!(
+            manifest.next_incr("a.aof"),
+            entry("a.aof.1.incr.aof", 1, FileType::Incr)
+        );
+
+        manifest
+            .history
+            .push(entry("a.aof.7.base.aof", 7, FileType::History));
+
+        assert_eq!(
+            manifest.next_base("a.aof", true),
+            entry("a.aof.8.base.rdb", 8, FileType::Base)
+        );
+        assert_eq!(manifest.next_base("a.aof", false).name, "a.aof.8.base.aof");
+    }
+}
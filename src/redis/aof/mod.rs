@@ -0,0 +1,622 @@
+pub mod check;
+pub mod load;
+pub mod manifest;
+pub mod rewrite;
+
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use self::manifest::{Manifest, ManifestEntry};
+use super::{parse::RespData, persistence::stream::now_ms, rdb::RdbError};
+
+/// How often `appendfsync everysec` flushes the file to disk.
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a failed rewrite keeps the automatic ones from trying again,
+/// in seconds.
+const REWRITE_RETRY_DELAY: u64 = 5;
+
+/// When writes reach the disk, the `appendfsync` parameter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Fsync {
+    /// After every write, before it is answered.
+    Always,
+    /// Once a second, from the background cycle.
+    EverySec,
+    /// Whenever the OS gets to it.
+    No,
+}
+
+impl Fsync {
+    pub fn parse(value: &str) -> Option<Fsync> {
+        match value.to_lowercase().as_str() {
+            "always" => Some(Fsync::Always),
+            "everysec" => Some(Fsync::EverySec),
+            "no" => Some(Fsync::No),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Fsync::Always => "always",
+            Fsync::EverySec => "everysec",
+            Fsync::No => "no",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AofError {
+    Io(io::Error),
+    Rdb(RdbError),
+    /// Something other than a command at the given offset.
+    BadFormat(usize),
+    Manifest(String),
+    /// A file the manifest lists isn't there.
+    MissingFile(String),
+    /// A file other than the last one ends in the middle of a command.
+    Truncated(String),
+}
+
+impl fmt::Display for AofError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AofError::Io(e) => write!(f, "{}", e),
+            AofError::Rdb(e) => write!(f, "Bad RDB preamble: {}", e),
+            AofError::BadFormat(offset) => write!(
+                f,
+                "Bad file format reading the append only file at offset {}",
+                offset
+            ),
+            AofError::Manifest(reason) => write!(f, "{}", reason),
+            AofError::MissingFile(name) => write!(f, "The AOF file {} doesn't exist", name),
+            AofError::Truncated(name) => write!(
+                f,
+                "Unexpected end of file reading the AOF file {}, which isn't the last one",
+                name
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for AofError {
+    fn from(e: io::Error) -> AofError {
+        AofError::Io(e)
+    }
+}
+
+/// A command as it is written to the file.
+fn encode<T: AsRef<[u8]>>(args: &[T]) -> Vec<u8> {
+    let mut out = format!("*{}\r\n", args.len()).into_bytes();
+
+    for arg in args {
+        let arg = arg.as_ref();
+        out.extend(format!("${}\r\n", arg.len()).into_bytes());
+        out.extend(arg);
+        out.extend(b"\r\n");
+    }
+
+    out
+}
+
+/// Relative expiries would start over on every replay, so they are logged
+/// as the unix time they run out at.
+fn absolute_expiry(args: &mut [Vec<u8>]) {
+    if !args[0].eq_ignore_ascii_case(b"set") {
+        return;
+    }
+
+    for i in 3..args.len().saturating_sub(1) {
+        if args[i].eq_ignore_ascii_case(b"px") {
+            let ms = std::str::from_utf8(&args[i + 1]).ok().and_then(|ms| ms.parse::<u64>().ok());
+
+            if let Some(ms) = ms {
+                args[i] = b"PXAT".to_vec();
+                args[i + 1] = (now_ms() + ms).to_string().into_bytes();
+            }
+        }
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Status {
+    Off,
+    /// Switched on while running, the files get the keyspace as it is now
+    /// from a rewrite before they count.
+    WaitStart,
+    On,
+}
+
+/// A rewrite on its way: what the thread writing the new base needs to
+/// finish it.
+pub struct Rewrite {
+    dir: PathBuf,
+    filename: String,
+    base: ManifestEntry,
+    /// Where writes go since the rewrite started, when the AOF is on.
+    incr: Option<ManifestEntry>,
+    /// The files the new base replaces.
+    previous: Manifest,
+    rdb: bool,
+}
+
+impl Rewrite {
+    pub fn base_path(&self) -> PathBuf {
+        self.dir.join(&self.base.name)
+    }
+
+    /// Whether the base is an RDB rather than commands.
+    pub fn rdb(&self) -> bool {
+        self.rdb
+    }
+}
+
+struct AofState {
+    status: Status,
+    /// Where the files are, and what their names start with.
+    dir: PathBuf,
+    filename: String,
+    manifest: Manifest,
+    /// The INCR file writes go to.
+    file: Option<File>,
+    /// Written to since the last fsync.
+    unsynced: bool,
+    last_fsync: Instant,
+    last_write_ok: bool,
+    /// Size of all the files, and what it was after the last rewrite.
+    current_size: u64,
+    base_size: u64,
+    rewriting: bool,
+    last_rewrite_ok: bool,
+    /// Unix time in seconds the last rewrite started.
+    last_rewrite_try: u64,
+}
+
+impl AofState {
+    /// Undoes what `Aof::begin_rewrite` did that the AOF can't keep.
+    fn rewrite_failed(&mut self, rewrite: &Rewrite) {
+        self.last_rewrite_ok = false;
+
+        // An INCR file only the new manifest would have listed.
+        if let (Some(incr), false) = (&rewrite.incr, self.status == Status::On) {
+            self.file = None;
+            let _ = fs::remove_file(rewrite.dir.join(&incr.name));
+        }
+    }
+}
+
+/// The append only file writes get logged to, in Redis 7's multi-part
+/// layout: a base with the keyspace as of the last rewrite, INCR files with
+/// the writes since, and a manifest listing them.
+pub struct Aof {
+    inner: Mutex<AofState>,
+}
+
+impl Default for Aof {
+    fn default() -> Aof {
+        Aof {
+            inner: Mutex::new(AofState {
+                status: Status::Off,
+                dir: PathBuf::new(),
+                filename: String::new(),
+                manifest: Manifest::default(),
+                file: None,
+                unsynced: false,
+                last_fsync: Instant::now(),
+                last_write_ok: true,
+                current_size: 0,
+                base_size: 0,
+                rewriting: false,
+                last_rewrite_ok: true,
+                last_rewrite_try: 0,
+            }),
+        }
+    }
+}
+
+impl Aof {
+    /// Starts appending to the last INCR file of what was loaded at
+    /// startup, to a new one if there is none.
+    pub fn open(&self, dir: &Path, filename: &str, mut manifest: Manifest) -> io::Result<()> {
+        let incr = match manifest.incrs.last() {
+            Some(incr) => incr.clone(),
+            None => {
+                let incr = manifest.next_incr(filename);
+                manifest.incrs.push(incr.clone());
+                manifest::write(dir, filename, &manifest)?;
+                incr
+            }
+        };
+
+        let file = open_append(&dir.join(&incr.name))?;
+        let size = manifest.size(dir);
+        let mut inner = self.inner.lock().unwrap();
+
+        inner.status = Status::On;
+        inner.dir = dir.to_path_buf();
+        inner.filename = filename.to_string();
+        inner.manifest = manifest;
+        inner.file = Some(file);
+        inner.current_size = size;
+        inner.base_size = size;
+        Ok(())
+    }
+
+    /// Switches the AOF on once a rewrite gave it the keyspace.
+    pub fn request_start(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.status == Status::Off {
+            inner.status = Status::WaitStart;
+        }
+    }
+
+    /// Stops logging, whatever was written gets flushed first.
+    pub fn close(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(file) = inner.file.take() {
+            if let Err(e) = file.sync_data() {
+                println!("Error fsyncing the AOF file: {}", e);
+            }
+        }
+
+        inner.status = Status::Off;
+        inner.unsynced = false;
+    }
+
+    /// Whether the background cycle should start a rewrite: to switch the
+    /// AOF on, or because it grew `percentage` percent since the last one
+    /// and is over `min_size` bytes.
+    pub fn rewrite_due(&self, percentage: u64, min_size: u64) -> bool {
+        let inner = self.inner.lock().unwrap();
+
+        let retry =
+            inner.last_rewrite_ok || now_ms() / 1000 - inner.last_rewrite_try > REWRITE_RETRY_DELAY;
+
+        if inner.rewriting || !retry {
+            return false;
+        }
+
+        match inner.status {
+            Status::Off => false,
+            Status::WaitStart => true,
+            Status::On => {
+                let base = inner.base_size.max(1);
+
+                percentage > 0
+                    && inner.current_size > min_size
+                    && inner.current_size.saturating_sub(base) * 100 / base >= percentage
+            }
+        }
+    }
+
+    /// Starts a rewrite, the caller holding every command off. From here
+    /// on writes go to a new INCR file, so the base only needs the keyspace
+    /// as it is now. `dir` and `filename` are for an AOF that isn't on yet,
+    /// `None` when a rewrite is already running.
+    pub fn begin_rewrite(
+        &self,
+        dir: &Path,
+        filename: &str,
+        rdb: bool,
+    ) -> io::Result<Option<Rewrite>> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.rewriting {
+            return Ok(None);
+        }
+
+        inner.last_rewrite_try = now_ms() / 1000;
+
+        let (dir, filename, mut manifest) = match inner.status {
+            Status::On => (
+                inner.dir.clone(),
+                inner.filename.clone(),
+                inner.manifest.clone(),
+            ),
+            // Whatever is on disk gets replaced.
+            _ => (
+                dir.to_path_buf(),
+                filename.to_string(),
+                manifest::read(dir, filename)
+                    .ok()
+                    .flatten()
+                    .unwrap_or_default(),
+            ),
+        };
+
+        let started = fs::create_dir_all(&dir).and_then(|_| match inner.status {
+            Status::Off => Ok(None),
+            status => {
+                let incr = manifest.next_incr(&filename);
+                let file = open_append(&dir.join(&incr.name))?;
+
+                // Listed right away, a crash before the base is done still
+                // loads the old base and every write.
+                if status == Status::On {
+                    manifest.incrs.push(incr.clone());
+
+                    if let Err(e) = manifest::write(&dir, &filename, &manifest) {
+                        let _ = fs::remove_file(dir.join(&incr.name));
+                        return Err(e);
+                    }
+                }
+
+                Ok(Some((incr, file)))
+            }
+        });
+
+        let incr = match started {
+            Ok(Some((incr, file))) => {
+                if let Some(old) = inner.file.replace(file) {
+                    let _ = old.sync_data();
+                }
+
+                Some(incr)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                inner.last_rewrite_ok = false;
+                return Err(e);
+            }
+        };
+
+        if inner.status == Status::On {
+            inner.manifest = manifest.clone();
+        }
+
+        inner.rewriting = true;
+
+        Ok(Some(Rewrite {
+            base: manifest.next_base(&filename, rdb),
+            dir,
+            filename,
+            incr,
+            previous: manifest,
+            rdb,
+        }))
+    }
+
+    /// Puts the base the background thread wrote, `written` bytes of it,
+    /// in the manifest and deletes the files it replaces.
+    pub fn finish_rewrite(&self, rewrite: Rewrite, written: io::Result<u64>) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        inner.rewriting = false;
+
+        let size = match written {
+            Ok(size) => size,
+            Err(e) => {
+                println!("Background AOF rewrite failed: {}", e);
+                inner.rewrite_failed(&rewrite);
+                return false;
+            }
+        };
+
+        // Switched off meanwhile, the INCR file stopped getting writes.
+        let incr = rewrite.incr.clone().filter(|_| inner.file.is_some());
+
+        let manifest = Manifest {
+            base: Some(rewrite.base.clone()),
+            incrs: incr.into_iter().collect(),
+            history: vec![],
+        };
+
+        if let Err(e) = manifest::write(&rewrite.dir, &rewrite.filename, &manifest) {
+            println!("Error writing the AOF manifest: {}", e);
+            let _ = fs::remove_file(rewrite.base_path());
+            inner.rewrite_failed(&rewrite);
+            return false;
+        }
+
+        let listed: Vec<&str> = manifest.files().map(|entry| entry.name.as_str()).collect();
+
+        for old in rewrite.previous.files().chain(&rewrite.previous.history) {
+            if !listed.contains(&old.name.as_str()) {
+                let _ = fs::remove_file(rewrite.dir.join(&old.name));
+            }
+        }
+
+        if inner.status == Status::WaitStart && inner.file.is_some() {
+            inner.status = Status::On;
+        }
+
+        if inner.status == Status::On {
+            let incr_size = manifest.incrs.iter().map(|incr| {
+                fs::metadata(rewrite.dir.join(&incr.name)).map_or(0, |metadata| metadata.len())
+            });
+
+            inner.current_size = size + incr_size.sum::<u64>();
+            inner.base_size = inner.current_size;
+            inner.dir = rewrite.dir;
+            inner.filename = rewrite.filename;
+            inner.manifest = manifest;
+        }
+
+        inner.last_rewrite_ok = true;
+        true
+    }
+
+    /// Logs a write as it was propagated.
+    pub fn feed(&self, vals: &[RespData], fsync: Fsync) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let file = match inner.file.as_mut() {
+            Some(file) => file,
+            None => return,
+        };
+
+        let mut args: Vec<Vec<u8>> = vals
+            .iter()
+            .map(|val| val.inside_bytes().unwrap_or_default().to_vec())
+            .collect();
+
+        // Messages aren't part of the keyspace.
+        if args[0].eq_ignore_ascii_case(b"publish") || args[0].eq_ignore_ascii_case(b"spublish") {
+            return;
+        }
+
+        absolute_expiry(&mut args);
+
+        let command = encode(&args);
+
+        let written = file.write_all(&command).and_then(|_| match fsync {
+            Fsync::Always => file.sync_data(),
+            _ => Ok(()),
+        });
+
+        if let Err(e) = &written {
+            println!("Error writing to the AOF file: {}", e);
+        }
+
+        inner.last_write_ok = written.is_ok();
+        inner.unsynced = fsync != Fsync::Always;
+        inner.current_size += command.len() as u64;
+    }
+
+    /// The `appendfsync everysec` flush, called from the background cycle.
+    pub fn fsync_cycle(&self, fsync: Fsync) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if fsync != Fsync::EverySec
+            || !inner.unsynced
+            || inner.last_fsync.elapsed() < FSYNC_INTERVAL
+        {
+            return;
+        }
+
+        if let Some(file) = &inner.file {
+            if let Err(e) = file.sync_data() {
+                println!("Error fsyncing the AOF file: {}", e);
+            }
+        }
+
+        inner.unsynced = false;
+        inner.last_fsync = Instant::now();
+    }
+
+    /// Lines for the INFO persistence section.
+    pub fn info(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let status = |ok: bool| if ok { "ok" } else { "err" };
+
+        let mut lines = vec![
+            format!("aof_enabled:{}", (inner.status != Status::Off) as u8),
+            format!("aof_rewrite_in_progress:{}", inner.rewriting as u8),
+            format!(
+                "aof_last_bgrewrite_status:{}",
+                status(inner.last_rewrite_ok)
+            ),
+            format!("aof_last_write_status:{}", status(inner.last_write_ok)),
+        ];
+
+        if inner.status == Status::On {
+            lines.push(format!("aof_current_size:{}", inner.current_size));
+            lines.push(format!("aof_base_size:{}", inner.base_size));
+        }
+
+        lines.join("\r\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    fn args(command: &[&str]) -> Vec<Vec<u8>> {
+        command.iter().map(|arg| arg.as_bytes().to_vec()).collect()
+    }
+
+    fn bulks(command: &[&str]) -> Vec<RespData> {
+        command.iter().map(|arg| RespData::new_bulk(arg)).collect()
+    }
+
+    /// An empty directory of its own for `name`.
+    fn dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("aof-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn fsync_policies() {
+        for fsync in [Fsync::Always, Fsync::EverySec, Fsync::No] {
+            assert_eq!(Fsync::parse(fsync.as_str()), Some(fsync));
+        }
+
+        assert_eq!(Fsync::parse("EVERYSEC"), Some(Fsync::EverySec));
+        assert_eq!(Fsync::parse("sometimes"), None);
+    }
+
+    #[test]
+    fn commands_are_encoded_as_bulk_arrays() {
+        assert_eq!(
+            encode(&args(&["SET", "k", ""])),
+            b"*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$0\r\n\r\n"
+        );
+        assert_eq!(encode(&[b"a\r\nb".to_vec()]), b"*1\r\n$4\r\na\r\nb\r\n");
+    }
+
+    #[test]
+    fn relative_expiries_become_absolute() {
+        let before = now_ms();
+        let mut set = args(&["set", "k", "v", "px", "1000"]);
+
+        absolute_expiry(&mut set);
+
+        assert_eq!(set[3], b"PXAT");
+
+        let at: u64 = String::from_utf8(set[4].clone()).unwrap().parse().unwrap();
+
+        assert!(at >= before + 1000 && at <= now_ms() + 1000);
+
+        // A value that happens to be "px" isn't an option.
+        let mut value = args(&["SET", "k", "px", "NX"]);
+        absolute_expiry(&mut value);
+
+        assert_eq!(value, args(&["SET", "k", "px", "NX"]));
+    }
+
+    #[test]
+    fn feed_appends_writes_but_not_messages() {
+        let dir = dir("feed");
+        let aof = Aof::default();
+
+        aof.feed(&bulks(&["SET", "k", "v"]), Fsync::Always);
+        aof.open(&dir, "appendonly.aof", Manifest::default())
+            .unwrap();
+        aof.feed(&bulks(&["SET", "k", "v"]), Fsync::Always);
+        aof.feed(&bulks(&["PUBLISH", "ch", "hi"]), Fsync::Always);
+        aof.feed(&bulks(&["DEL", "k"]), Fsync::EverySec);
+        aof.close();
+
+        let manifest = manifest::read(&dir, "appendonly.aof").unwrap().unwrap();
+        let data = fs::read(dir.join(&manifest.incrs[0].name)).unwrap();
+
+        assert_eq!(
+            data,
+            [
+                encode(&args(&["SET", "k", "v"])),
+                encode(&args(&["DEL", "k"]))
+            ]
+            .concat()
+        );
+        assert!(aof.info().contains("aof_last_write_status:ok"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,133 @@
+use crate::redis::{
+    persistence::{
+        sorted_set::format_double,
+        stream::{Stream, StreamId},
+    },
+    rdb::{RdbEntry, RdbValue},
+};
+
+use super::encode;
+
+/// Members per SADD or ZADD, so huge keys don't become huge commands.
+const ITEMS_PER_COMMAND: usize = 64;
+
+/// The keyspace in `entries` as the commands that build it again, the base
+/// of the AOF when `aof-use-rdb-preamble` is off.
+pub fn commands(entries: &[RdbEntry]) -> Vec<u8> {
+    let mut out = vec![];
+    let mut emit = |args: Vec<String>| out.extend(encode(&args));
+
+    for entry in entries {
+        let key = entry.key.clone();
+
+        match &entry.value {
+            RdbValue::String(value) => {
+                let mut args = vec!["SET".to_string(), key, value.clone()];
+
+                if let Some(at) = entry.expire_ms {
+                    args.extend(["PXAT".to_string(), at.to_string()]);
+                }
+
+                emit(args);
+            }
+            RdbValue::Set(set) => {
+                for members in set.members().chunks(ITEMS_PER_COMMAND) {
+                    let mut args = vec!["SADD".to_string(), key.clone()];
+                    args.extend(members.iter().map(|member| member.to_string()));
+                    emit(args);
+                }
+            }
+            RdbValue::SortedSet(zset) => {
+                for pairs in zset.entries().chunks(ITEMS_PER_COMMAND) {
+                    let mut args = vec!["ZADD".to_string(), key.clone()];
+
+                    for (member, score) in pairs {
+                        args.extend([format_double(*score), member.to_string()]);
+                    }
+
+                    emit(args);
+                }
+            }
+            RdbValue::Stream(stream) => stream_commands(&key, stream, &mut emit),
+        }
+    }
+
+    out
+}
+
+/// Entries go in as XADDs with their IDs, then XSETID restores what
+/// deletions and trimming left behind, then the groups with their
+/// consumers and pending entries.
+fn stream_commands(key: &str, stream: &Stream, emit: &mut impl FnMut(Vec<String>)) {
+    let args = |words: &[&str]| -> Vec<String> { words.iter().map(|w| w.to_string()).collect() };
+
+    for entry in stream.range(StreamId::MIN, StreamId::MAX) {
+        let mut command = args(&["XADD", key, &entry.id.to_string()]);
+
+        for (field, value) in &entry.pairs {
+            command.extend([field.clone(), value.clone()]);
+        }
+
+        emit(command);
+    }
+
+    // An empty stream still has to exist, so an entry goes in and right
+    // back out.
+    if stream.is_empty() {
+        let id = stream.last_id().max(StreamId { ms: 0, seq: 1 });
+        emit(args(&[
+            "XADD",
+            key,
+            "MAXLEN",
+            "0",
+            &id.to_string(),
+            "x",
+            "y",
+        ]));
+    }
+
+    emit(args(&[
+        "XSETID",
+        key,
+        &stream.last_id().to_string(),
+        "ENTRIESADDED",
+        &stream.entries_added().to_string(),
+        "MAXDELETEDID",
+        &stream.max_deleted_id().to_string(),
+    ]));
+
+    for (name, group) in &stream.groups {
+        let entries_read = group.entries_read.map_or(-1, |read| read as i64);
+
+        emit(args(&[
+            "XGROUP",
+            "CREATE",
+            key,
+            name,
+            &group.last_id.to_string(),
+            "ENTRIESREAD",
+            &entries_read.to_string(),
+        ]));
+
+        for consumer in group.consumers.keys() {
+            emit(args(&["XGROUP", "CREATECONSUMER", key, name, consumer]));
+        }
+
+        for (id, entry) in &group.pending {
+            emit(args(&[
+                "XCLAIM",
+                key,
+                name,
+                &entry.consumer,
+                "0",
+                &id.to_string(),
+                "TIME",
+                &entry.delivery_time.to_string(),
+                "RETRYCOUNT",
+                &entry.delivery_count.to_string(),
+                "FORCE",
+                "JUSTID",
+            ]));
+        }
+    }
+}
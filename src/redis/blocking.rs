@@ -0,0 +1,183 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+// How often a blocked client checks whether it is still connected.
+const BLOCK_SLICE: Duration = Duration::from_millis(500);
+
+#[derive(Default)]
+struct Waiter {
+    ready: Mutex<bool>,
+    cond: Condvar,
+}
+
+/// Clients blocked on keys, woken whenever one of those keys may have
+/// something for them.
+#[derive(Default)]
+pub struct BlockingKeys {
+    waiters: Mutex<HashMap<String, Vec<Arc<Waiter>>>>,
+}
+
+impl BlockingKeys {
+    /// Wakes every client blocked on `key`, they retry and block again if
+    /// someone else got served first.
+    pub fn signal(&self, key: &str) {
+        if let Some(waiters) = self.waiters.lock().unwrap().get(key) {
+            for waiter in waiters {
+                *waiter.ready.lock().unwrap() = true;
+                waiter.cond.notify_all();
+            }
+        }
+    }
+
+    fn register(&self, keys: &[String], waiter: &Arc<Waiter>) {
+        let mut waiters = self.waiters.lock().unwrap();
+
+        for key in keys {
+            waiters
+                .entry(key.to_string())
+                .or_default()
+                .push(Arc::clone(waiter));
+        }
+    }
+
+    fn unregister(&self, keys: &[String], waiter: &Arc<Waiter>) {
+        let mut waiters = self.waiters.lock().unwrap();
+
+        for key in keys {
+            if let Some(list) = waiters.get_mut(key) {
+                list.retain(|w| !Arc::ptr_eq(w, waiter));
+
+                if list.is_empty() {
+                    waiters.remove(key);
+                }
+            }
+        }
+    }
+
+    /// Retries `attempt` every time one of `keys` is signalled until it
+    /// yields a value. Gives up after `timeout` (zero blocks forever) or
+    /// once `is_closed` reports the client went away.
+    pub fn block_on<T>(
+        &self,
+        keys: &[String],
+        timeout: Duration,
+        is_closed: impl Fn() -> bool,
+        mut attempt: impl FnMut() -> Option<T>,
+    ) -> Option<T> {
+        let waiter = Arc::new(Waiter::default());
+
+        // Registering before the first attempt means a signal racing with it
+        // leaves `ready` set instead of getting lost.
+        self.register(keys, &waiter);
+
+        let deadline = match timeout.is_zero() {
+            true => None,
+            false => Some(Instant::now() + timeout),
+        };
+
+        let result = 'outer: loop {
+            if let Some(val) = attempt() {
+                break Some(val);
+            }
+
+            let mut ready = waiter.ready.lock().unwrap();
+
+            while !*ready {
+                let mut slice = BLOCK_SLICE;
+
+                if let Some(deadline) = deadline {
+                    let now = Instant::now();
+
+                    if now >= deadline {
+                        break 'outer None;
+                    }
+
+                    slice = slice.min(deadline - now);
+                }
+
+                ready = waiter.cond.wait_timeout(ready, slice).unwrap().0;
+
+                if !*ready && is_closed() {
+                    break 'outer None;
+                }
+            }
+
+            *ready = false;
+        };
+
+        self.unregister(keys, &waiter);
+
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    use super::*;
+
+    #[test]
+    fn served_without_blocking_when_ready() {
+        let blocking = BlockingKeys::default();
+        let keys = ["k".to_string()];
+
+        assert_eq!(
+            blocking.block_on(&keys, Duration::ZERO, || false, || Some(1)),
+            Some(1)
+        );
+        assert!(blocking.waiters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn times_out() {
+        let blocking = BlockingKeys::default();
+        let keys = ["k".to_string()];
+        let start = Instant::now();
+
+        let result: Option<()> =
+            blocking.block_on(&keys, Duration::from_millis(50), || false, || None);
+
+        assert!(result.is_none());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        assert!(blocking.waiters.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn signals_wake_the_waiter() {
+        let blocking = Arc::new(BlockingKeys::default());
+        let pushed = Arc::new(AtomicBool::new(false));
+
+        let waiter = {
+            let blocking = Arc::clone(&blocking);
+            let pushed = Arc::clone(&pushed);
+
+            thread::spawn(move || {
+                let keys = ["a".to_string(), "b".to_string()];
+
+                blocking.block_on(
+                    &keys,
+                    Duration::from_secs(5),
+                    || false,
+                    || pushed.load(Ordering::SeqCst).then_some("b"),
+                )
+            })
+        };
+
+        while !blocking.waiters.lock().unwrap().contains_key("b") {
+            thread::yield_now();
+        }
+
+        pushed.store(true, Ordering::SeqCst);
+        blocking.signal("b");
+
+        assert_eq!(waiter.join().unwrap(), Some("b"));
+        assert!(blocking.waiters.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,191 @@
+use std::{
+    cell::Cell,
+    io::{self, Read, Write},
+    net::{Shutdown, SocketAddr, TcpStream},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::{
+    parse::RespData,
+    pubsub::{Subscriber, Writer},
+};
+
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    static CURRENT: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// The client whose command is running on this thread, `None` for work
+/// no client asked for, such as expiring keys in the background.
+pub fn current() -> Option<u64> {
+    CURRENT.with(Cell::get)
+}
+
+/// Keeps a client as `current` until dropped, see `Client::enter`.
+pub struct Running(Option<u64>);
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.set(self.0));
+    }
+}
+
+/// What a client reads requests from and writes replies to.
+#[derive(Debug)]
+pub enum Connection {
+    Socket(TcpStream),
+    /// No peer at all: nothing comes in and replies are dropped, for the
+    /// commands an AOF replays.
+    Detached,
+}
+
+impl Connection {
+    pub fn try_clone(&self) -> io::Result<Connection> {
+        match self {
+            Connection::Socket(stream) => stream.try_clone().map(Connection::Socket),
+            Connection::Detached => Ok(Connection::Detached),
+        }
+    }
+
+    /// The socket underneath, `None` when detached.
+    pub fn socket(&self) -> Option<&TcpStream> {
+        match self {
+            Connection::Socket(stream) => Some(stream),
+            Connection::Detached => None,
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Connection::Socket(stream) => stream.peer_addr(),
+            Connection::Detached => Err(io::ErrorKind::NotConnected.into()),
+        }
+    }
+
+    pub fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        match self {
+            Connection::Socket(stream) => stream.shutdown(how),
+            Connection::Detached => Ok(()),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Connection::Socket(stream) => stream.read(buf),
+            Connection::Detached => Ok(0),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Connection::Socket(stream) => stream.write(buf),
+            Connection::Detached => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Connection::Socket(stream) => stream.flush(),
+            Connection::Detached => Ok(()),
+        }
+    }
+}
+
+/// Per-connection state.
+pub struct Client {
+    pub id: u64,
+    pub stream: Connection,
+    /// Clone of `stream` for anything other connections may also write to
+    /// this one, such as published messages.
+    pub writer: Writer,
+    /// Set on the connection a replica receives its master's writes from,
+    /// nothing gets answered on it.
+    pub master_link: bool,
+    /// Commands queued since MULTI, `None` outside a transaction.
+    pub multi: Option<Vec<Vec<RespData>>>,
+    /// A command was rejected while queueing, EXEC will abort.
+    pub multi_error: bool,
+    /// WATCHed keys, with whether each had already expired back then.
+    pub watched: Vec<(String, bool)>,
+    /// Set once a watched key is touched.
+    pub dirty: Arc<AtomicBool>,
+    pub channels: Vec<String>,
+    pub patterns: Vec<String>,
+    /// Switched by HELLO.
+    pub resp3: Arc<AtomicBool>,
+    /// CLIENT CACHING for the next command, in OPTIN/OPTOUT tracking.
+    pub caching: Option<bool>,
+}
+
+impl Client {
+    pub fn new(stream: TcpStream) -> Client {
+        Client::with_connection(Connection::Socket(stream))
+    }
+
+    /// A client with no peer, see `Connection::Detached`.
+    pub fn detached() -> Client {
+        Client::with_connection(Connection::Detached)
+    }
+
+    fn with_connection(stream: Connection) -> Client {
+        Client {
+            id: NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed),
+            writer: Arc::new(Mutex::new(stream.try_clone().unwrap())),
+            stream,
+            master_link: false,
+            multi: None,
+            multi_error: false,
+            watched: vec![],
+            dirty: Arc::new(AtomicBool::new(false)),
+            channels: vec![],
+            patterns: vec![],
+            resp3: Arc::new(AtomicBool::new(false)),
+            caching: None,
+        }
+    }
+
+    pub fn master_link(stream: TcpStream) -> Client {
+        Client {
+            master_link: true,
+            ..Client::new(stream)
+        }
+    }
+
+    /// Leaves the transaction, dropping whatever was queued.
+    pub fn discard_multi(&mut self) {
+        self.multi = None;
+        self.multi_error = false;
+    }
+
+    /// Makes this the client `current` reports while the guard lives, for
+    /// the command about to run on its behalf.
+    pub fn enter(&self) -> Running {
+        Running(CURRENT.with(|current| current.replace(Some(self.id))))
+    }
+
+    pub fn subscriber(&self) -> Subscriber {
+        Subscriber {
+            id: self.id,
+            writer: Arc::clone(&self.writer),
+            resp3: Arc::clone(&self.resp3),
+        }
+    }
+
+    pub fn resp3(&self) -> bool {
+        self.resp3.load(Ordering::Relaxed)
+    }
+
+    /// Channel and pattern subscriptions, counted together the way the
+    /// (un)subscribe replies report them.
+    pub fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
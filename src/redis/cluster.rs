@@ -0,0 +1,132 @@
+use std::sync::Mutex;
+
+pub const CLUSTER_SLOTS: usize = 16384;
+
+/// CRC16-CCITT (XMODEM), the checksum Redis Cluster derives slots from.
+fn crc16(data: &[u8]) -> u16 {
+    let mut crc: u16 = 0;
+
+    for &byte in data {
+        crc ^= (byte as u16) << 8;
+
+        for _ in 0..8 {
+            crc = match crc & 0x8000 {
+                0 => crc << 1,
+                _ => (crc << 1) ^ 0x1021,
+            };
+        }
+    }
+
+    crc
+}
+
+/// Slot of a key or sharded channel. Only the part inside the first
+/// non-empty `{...}` is hashed when there is one.
+pub fn key_hash_slot(key: &[u8]) -> u16 {
+    let tag = key.iter().position(|&b| b == b'{').and_then(|start| {
+        let len = key[start + 1..].iter().position(|&b| b == b'}')?;
+
+        match len {
+            0 => None,
+            len => Some(&key[start + 1..start + 1 + len]),
+        }
+    });
+
+    crc16(tag.unwrap_or(key)) & (CLUSTER_SLOTS as u16 - 1)
+}
+
+/// Hash slots this node serves. There is no cluster bus: a node starts out
+/// serving every slot and CLUSTER ADDSLOTS/DELSLOTS change that by hand.
+/// Only sharded pub/sub consults it so far.
+pub struct Slots {
+    served: Mutex<Vec<bool>>,
+}
+
+impl Default for Slots {
+    fn default() -> Slots {
+        Slots {
+            served: Mutex::new(vec![true; CLUSTER_SLOTS]),
+        }
+    }
+}
+
+impl Slots {
+    pub fn serves(&self, slot: u16) -> bool {
+        self.served.lock().unwrap()[slot as usize]
+    }
+
+    /// Starts or stops serving `slots`. Nothing changes unless every slot
+    /// is in the opposite state.
+    pub fn set_served(&self, slots: &[u16], serve: bool) -> Result<(), String> {
+        let mut served = self.served.lock().unwrap();
+
+        for (i, &slot) in slots.iter().enumerate() {
+            if slots[..i].contains(&slot) {
+                return Err(format!("ERR Slot {} specified multiple times", slot));
+            }
+
+            if served[slot as usize] == serve {
+                return Err(match serve {
+                    true => format!("ERR Slot {} is already busy", slot),
+                    false => format!("ERR Slot {} is already unassigned", slot),
+                });
+            }
+        }
+
+        for &slot in slots {
+            served[slot as usize] = serve;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc16_matches_xmodem() {
+        assert_eq!(crc16(b"123456789"), 0x31c3);
+        assert_eq!(crc16(b""), 0);
+    }
+
+    #[test]
+    fn key_slots_match_redis() {
+        assert_eq!(key_hash_slot(b"foo"), 12182);
+        assert_eq!(key_hash_slot(b"somekey"), 11058);
+    }
+
+    #[test]
+    fn hash_tags() {
+        let slot = key_hash_slot(b"user1000");
+
+        assert_eq!(key_hash_slot(b"{user1000}.following"), slot);
+        assert_eq!(key_hash_slot(b"x{user1000}{other}"), slot);
+        // An empty tag hashes the whole key.
+        assert_eq!(key_hash_slot(b"foo{}{bar}"), crc16(b"foo{}{bar}") & 16383);
+        assert_eq!(key_hash_slot(b"foo{bar"), crc16(b"foo{bar") & 16383);
+    }
+
+    #[test]
+    fn serving_slots() {
+        let slots = Slots::default();
+
+        assert!(slots.serves(0) && slots.serves(16383));
+        assert_eq!(
+            slots.set_served(&[1, 2], true),
+            Err("ERR Slot 1 is already busy".to_string())
+        );
+        assert_eq!(slots.set_served(&[1, 2], false), Ok(()));
+        assert!(!slots.serves(1) && !slots.serves(2));
+        assert_eq!(
+            slots.set_served(&[3, 2], false),
+            Err("ERR Slot 2 is already unassigned".to_string())
+        );
+        assert!(slots.serves(3));
+        assert_eq!(
+            slots.set_served(&[1, 1], true),
+            Err("ERR Slot 1 specified multiple times".to_string())
+        );
+    }
+}
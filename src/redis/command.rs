@@ -0,0 +1,254 @@
+use super::parse::RespData;
+
+/// The command may block the client waiting for keys.
+pub const BLOCKING: u8 = 1 << 0;
+/// The command is refused inside MULTI.
+pub const NO_MULTI: u8 = 1 << 1;
+/// The command only reads keys, client side caching tracks those.
+pub const READONLY: u8 = 1 << 2;
+/// The command runs with every other client held off, see SAVE.
+pub const EXCLUSIVE: u8 = 1 << 3;
+
+/// What the server knows about a command before running it.
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// Redis style: positive is the exact argument count, command name
+    /// included, negative the minimum.
+    pub arity: i32,
+    pub flags: u8,
+}
+
+impl CommandSpec {
+    const fn new(name: &'static str, arity: i32, flags: u8) -> CommandSpec {
+        CommandSpec { name, arity, flags }
+    }
+
+    pub fn has(&self, flag: u8) -> bool {
+        self.flags & flag != 0
+    }
+
+    pub fn check_arity(&self, argc: usize) -> bool {
+        let argc = argc as i32;
+
+        match self.arity > 0 {
+            true => argc == self.arity,
+            false => argc >= -self.arity,
+        }
+    }
+}
+
+const COMMANDS: &[CommandSpec] = &[
+    CommandSpec::new("ping", -1, 0),
+    CommandSpec::new("echo", 2, 0),
+    CommandSpec::new("set", -3, 0),
+    CommandSpec::new("get", 2, READONLY),
+    CommandSpec::new("type", 2, READONLY),
+    CommandSpec::new("dump", 2, READONLY),
+    CommandSpec::new("restore", -4, 0),
+    CommandSpec::new("migrate", -6, EXCLUSIVE),
+    CommandSpec::new("del", -2, 0),
+    CommandSpec::new("info", -1, 0),
+    CommandSpec::new("replconf", -1, NO_MULTI),
+    CommandSpec::new("psync", -3, NO_MULTI | EXCLUSIVE),
+    CommandSpec::new("multi", 1, NO_MULTI),
+    CommandSpec::new("exec", 1, NO_MULTI),
+    CommandSpec::new("discard", 1, NO_MULTI),
+    CommandSpec::new("watch", -2, 0),
+    CommandSpec::new("unwatch", 1, 0),
+    CommandSpec::new("flushdb", -1, 0),
+    CommandSpec::new("quit", -1, 0),
+    CommandSpec::new("reset", 1, 0),
+    CommandSpec::new("subscribe", -2, NO_MULTI),
+    CommandSpec::new("unsubscribe", -1, NO_MULTI),
+    CommandSpec::new("psubscribe", -2, NO_MULTI),
+    CommandSpec::new("punsubscribe", -1, NO_MULTI),
+    CommandSpec::new("ssubscribe", -2, NO_MULTI),
+    CommandSpec::new("sunsubscribe", -1, NO_MULTI),
+    CommandSpec::new("publish", 3, 0),
+    CommandSpec::new("spublish", 3, 0),
+    CommandSpec::new("pubsub", -2, 0),
+    CommandSpec::new("cluster", -2, 0),
+    CommandSpec::new("config", -2, 0),
+    CommandSpec::new("hello", -1, 0),
+    CommandSpec::new("client", -2, 0),
+    CommandSpec::new("save", 1, NO_MULTI | EXCLUSIVE),
+    CommandSpec::new("bgsave", -1, EXCLUSIVE),
+    CommandSpec::new("lastsave", 1, 0),
+    CommandSpec::new("bgrewriteaof", 1, EXCLUSIVE),
+    CommandSpec::new("xadd", -5, 0),
+    CommandSpec::new("xrange", -4, READONLY),
+    CommandSpec::new("xrevrange", -4, READONLY),
+    CommandSpec::new("xread", -4, BLOCKING | READONLY),
+    CommandSpec::new("xlen", 2, READONLY),
+    CommandSpec::new("xinfo", -2, 0),
+    CommandSpec::new("xsetid", -3, 0),
+    CommandSpec::new("xdel", -3, 0),
+    CommandSpec::new("xtrim", -4, 0),
+    CommandSpec::new("xgroup", -2, 0),
+    CommandSpec::new("xreadgroup", -7, BLOCKING),
+    CommandSpec::new("xack", -4, 0),
+    CommandSpec::new("xpending", -3, READONLY),
+    CommandSpec::new("xclaim", -6, 0),
+    CommandSpec::new("xautoclaim", -6, 0),
+    CommandSpec::new("sadd", -3, 0),
+    CommandSpec::new("srem", -3, 0),
+    CommandSpec::new("smembers", 2, READONLY),
+    CommandSpec::new("sismember", 3, READONLY),
+    CommandSpec::new("smismember", -3, READONLY),
+    CommandSpec::new("scard", 2, READONLY),
+    CommandSpec::new("spop", -2, 0),
+    CommandSpec::new("srandmember", -2, READONLY),
+    CommandSpec::new("smove", 4, 0),
+    CommandSpec::new("sinter", -2, READONLY),
+    CommandSpec::new("sunion", -2, READONLY),
+    CommandSpec::new("sdiff", -2, READONLY),
+    CommandSpec::new("sinterstore", -3, EXCLUSIVE),
+    CommandSpec::new("sunionstore", -3, EXCLUSIVE),
+    CommandSpec::new("sdiffstore", -3, EXCLUSIVE),
+    CommandSpec::new("sintercard", -3, READONLY),
+    CommandSpec::new("zadd", -4, 0),
+    CommandSpec::new("zincrby", 4, 0),
+    CommandSpec::new("zrem", -3, 0),
+    CommandSpec::new("zscore", 3, READONLY),
+    CommandSpec::new("zmscore", -3, READONLY),
+    CommandSpec::new("zcard", 2, READONLY),
+    CommandSpec::new("zrank", -3, READONLY),
+    CommandSpec::new("zrevrank", -3, READONLY),
+    CommandSpec::new("zcount", 4, READONLY),
+    CommandSpec::new("zrange", -4, READONLY),
+    CommandSpec::new("zunionstore", -4, EXCLUSIVE),
+    CommandSpec::new("zinterstore", -4, EXCLUSIVE),
+    CommandSpec::new("zdiffstore", -4, EXCLUSIVE),
+    CommandSpec::new("zrangestore", -5, EXCLUSIVE),
+    CommandSpec::new("zpopmin", -2, 0),
+    CommandSpec::new("zpopmax", -2, 0),
+    CommandSpec::new("bzpopmin", -3, BLOCKING),
+    CommandSpec::new("bzpopmax", -3, BLOCKING),
+    CommandSpec::new("zmpop", -4, 0),
+    CommandSpec::new("bzmpop", -5, BLOCKING),
+    CommandSpec::new("geoadd", -5, 0),
+    CommandSpec::new("geopos", -2, READONLY),
+    CommandSpec::new("geodist", -4, READONLY),
+    CommandSpec::new("geohash", -2, READONLY),
+    CommandSpec::new("geosearch", -7, READONLY),
+    CommandSpec::new("geosearchstore", -8, EXCLUSIVE),
+];
+
+/// Case-insensitive lookup of a command by name.
+pub fn lookup(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|command| command.name.eq_ignore_ascii_case(name))
+}
+
+/// Keys a write modifies, from the command as it is propagated. Writes
+/// take the key first, except for the few listed here.
+pub fn modified_keys(vals: &[RespData]) -> Vec<&str> {
+    let name = vals.first().unwrap().inside_value().unwrap().to_lowercase();
+
+    if name == "del" {
+        return vals[1..].iter().filter_map(RespData::inside_value).collect();
+    }
+
+    let positions: &[usize] = match name.as_str() {
+        "flushdb" | "publish" | "spublish" => &[],
+        "xgroup" => &[2],
+        "smove" => &[1, 2],
+        _ => &[1],
+    };
+
+    positions
+        .iter()
+        .filter_map(|&pos| vals.get(pos)?.inside_value())
+        .collect()
+}
+
+/// Keys a READONLY command reads. Most take one key first, the few listed
+/// here read several.
+pub fn read_keys(vals: &[RespData]) -> Vec<&str> {
+    let name = vals.first().unwrap().inside_value().unwrap().to_lowercase();
+    let args: Vec<&str> = vals.iter().filter_map(RespData::inside_value).collect();
+
+    let keys: &[&str] = match name.as_str() {
+        "sinter" | "sunion" | "sdiff" => &args[1..],
+        "sintercard" => {
+            let numkeys = args[1].parse::<usize>().unwrap_or(0);
+            &args[2..args.len().min(2 + numkeys)]
+        }
+        "xread" => {
+            let streams = args
+                .iter()
+                .position(|arg| arg.eq_ignore_ascii_case("streams"))
+                .map_or(args.len(), |pos| pos + 1);
+            let rest = &args[streams..];
+            &rest[..rest.len() / 2]
+        }
+        _ => &args[1..2],
+    };
+
+    keys.to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command(args: &[&str]) -> Vec<RespData> {
+        args.iter().map(|arg| RespData::new_bulk(arg)).collect()
+    }
+
+    #[test]
+    fn lookup_ignores_case() {
+        assert_eq!(lookup("GeoPos").unwrap().name, "geopos");
+        assert!(lookup("nosuchcommand").is_none());
+    }
+
+    #[test]
+    fn arity() {
+        let exact = CommandSpec::new("exact", 3, 0);
+        let at_least = CommandSpec::new("at-least", -3, 0);
+
+        assert!(exact.check_arity(3));
+        assert!(!exact.check_arity(2) && !exact.check_arity(4));
+        assert!(at_least.check_arity(3) && at_least.check_arity(10));
+        assert!(!at_least.check_arity(2));
+    }
+
+    #[test]
+    fn flags() {
+        assert!(lookup("multi").unwrap().has(NO_MULTI));
+        assert!(lookup("get").unwrap().has(READONLY));
+        assert!(!lookup("set").unwrap().has(READONLY));
+    }
+
+    #[test]
+    fn modified_keys_by_command() {
+        assert_eq!(modified_keys(&command(&["SET", "k", "v"])), ["k"]);
+        assert_eq!(modified_keys(&command(&["del", "a", "b"])), ["a", "b"]);
+        assert_eq!(
+            modified_keys(&command(&["SMOVE", "a", "b", "m"])),
+            ["a", "b"]
+        );
+        assert_eq!(
+            modified_keys(&command(&["XGROUP", "CREATE", "s", "g", "$"])),
+            ["s"]
+        );
+        assert!(modified_keys(&command(&["PUBLISH", "c", "m"])).is_empty());
+    }
+
+    #[test]
+    fn read_keys_by_command() {
+        assert_eq!(read_keys(&command(&["GET", "k"])), ["k"]);
+        assert_eq!(read_keys(&command(&["SINTER", "a", "b"])), ["a", "b"]);
+        assert_eq!(
+            read_keys(&command(&["SINTERCARD", "2", "a", "b", "LIMIT", "1"])),
+            ["a", "b"]
+        );
+        assert_eq!(
+            read_keys(&command(&[
+                "XREAD", "COUNT", "1", "STREAMS", "a", "b", "0", "0"
+            ])),
+            ["a", "b"]
+        );
+    }
+}
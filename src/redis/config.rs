@@ -0,0 +1,304 @@
+use std::path::{Path, PathBuf};
+
+use super::{aof::Fsync, notify};
+
+/// Parameters settable with CONFIG SET or `--<name> <value>` at startup.
+#[derive(Clone)]
+pub struct Config {
+    pub notify_keyspace_events: u32,
+    /// Where the RDB file lives.
+    pub dir: String,
+    pub dbfilename: String,
+    /// Save points as seconds and changes: a BGSAVE starts once that many
+    /// changes are that old.
+    pub save: Vec<(u64, u64)>,
+    /// Full resyncs send the snapshot straight to replicas that can take
+    /// it, instead of saving it to disk first.
+    pub repl_diskless_sync: bool,
+    /// Writes get logged to the append only file, which is what startup
+    /// loads instead of the RDB file.
+    pub appendonly: bool,
+    /// What the AOF files' names start with.
+    pub appendfilename: String,
+    /// The directory in `dir` the AOF files go in.
+    pub appenddirname: String,
+    pub appendfsync: Fsync,
+    /// An append only file whose last command was cut short still loads,
+    /// minus that command.
+    pub aof_load_truncated: bool,
+    /// Rewrites write the base as an RDB rather than as commands.
+    pub aof_use_rdb_preamble: bool,
+    /// A rewrite starts by itself once the AOF grew this many percent
+    /// since the last one, 0 turns that off, and is over the minimum size
+    /// in bytes.
+    pub auto_aof_rewrite_percentage: u64,
+    pub auto_aof_rewrite_min_size: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            notify_keyspace_events: 0,
+            dir: ".".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+            save: vec![(3600, 1), (300, 100), (60, 10000)],
+            repl_diskless_sync: true,
+            appendonly: false,
+            appendfilename: "appendonly.aof".to_string(),
+            appenddirname: "appendonlydir".to_string(),
+            appendfsync: Fsync::EverySec,
+            aof_load_truncated: true,
+            aof_use_rdb_preamble: true,
+            auto_aof_rewrite_percentage: 100,
+            auto_aof_rewrite_min_size: 64 * 1024 * 1024,
+        }
+    }
+}
+
+const PARAMETERS: &[&str] = &[
+    "notify-keyspace-events",
+    "dir",
+    "dbfilename",
+    "save",
+    "repl-diskless-sync",
+    "appendonly",
+    "appendfilename",
+    "appenddirname",
+    "appendfsync",
+    "aof-load-truncated",
+    "aof-use-rdb-preamble",
+    "auto-aof-rewrite-percentage",
+    "auto-aof-rewrite-min-size",
+];
+
+/// Parameters only settable at startup.
+const IMMUTABLE: &[&str] = &["appendfilename", "appenddirname"];
+
+fn parse_bool(value: &str) -> Result<bool, Option<String>> {
+    match value.to_lowercase().as_str() {
+        "yes" => Ok(true),
+        "no" => Ok(false),
+        _ => Err(Some("argument must be 'yes' or 'no'".to_string())),
+    }
+}
+
+fn format_bool(value: bool) -> String {
+    match value {
+        true => "yes".to_string(),
+        false => "no".to_string(),
+    }
+}
+
+/// A size in bytes, with an optional unit: k, m and g are powers of 1000,
+/// kb, mb and gb powers of 1024.
+fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let digits = value
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(value.len());
+
+    let unit = match &value[digits..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+
+    value[..digits].parse::<u64>().ok()?.checked_mul(unit)
+}
+
+/// `<seconds> <changes>` pairs, nothing at all turns saving off.
+fn parse_save(value: &str) -> Option<Vec<(u64, u64)>> {
+    let numbers = value
+        .split_whitespace()
+        .map(|n| n.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+
+    if !numbers.len().is_multiple_of(2) {
+        return None;
+    }
+
+    Some(numbers.chunks(2).map(|pair| (pair[0], pair[1])).collect())
+}
+
+impl Config {
+    pub fn parameters() -> &'static [&'static str] {
+        PARAMETERS
+    }
+
+    pub fn is_mutable(name: &str) -> bool {
+        !IMMUTABLE.contains(&name.to_lowercase().as_str())
+    }
+
+    pub fn get(&self, name: &str) -> Option<String> {
+        match name.to_lowercase().as_str() {
+            "notify-keyspace-events" => {
+                Some(notify::classes_to_string(self.notify_keyspace_events))
+            }
+            "dir" => Some(self.dir.clone()),
+            "dbfilename" => Some(self.dbfilename.clone()),
+            "save" => Some(
+                self.save
+                    .iter()
+                    .map(|(seconds, changes)| format!("{} {}", seconds, changes))
+                    .collect::<Vec<String>>()
+                    .join(" "),
+            ),
+            "repl-diskless-sync" => Some(format_bool(self.repl_diskless_sync)),
+            "appendonly" => Some(format_bool(self.appendonly)),
+            "appendfilename" => Some(self.appendfilename.clone()),
+            "appenddirname" => Some(self.appenddirname.clone()),
+            "appendfsync" => Some(self.appendfsync.as_str().to_string()),
+            "aof-load-truncated" => Some(format_bool(self.aof_load_truncated)),
+            "aof-use-rdb-preamble" => Some(format_bool(self.aof_use_rdb_preamble)),
+            "auto-aof-rewrite-percentage" => Some(self.auto_aof_rewrite_percentage.to_string()),
+            "auto-aof-rewrite-min-size" => Some(self.auto_aof_rewrite_min_size.to_string()),
+            _ => None,
+        }
+    }
+
+    /// Fails with the reason the value was refused, or `None` for a
+    /// parameter that doesn't exist.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<(), Option<String>> {
+        match name.to_lowercase().as_str() {
+            "notify-keyspace-events" => {
+                self.notify_keyspace_events = notify::parse_classes(value).map_err(Some)?;
+            }
+            "dir" => {
+                if !Path::new(value).is_dir() {
+                    return Err(Some("No such file or directory".to_string()));
+                }
+
+                self.dir = value.to_string();
+            }
+            "dbfilename" => {
+                if value.contains('/') {
+                    return Err(Some(
+                        "dbfilename can't be a path, just a filename".to_string(),
+                    ));
+                }
+
+                self.dbfilename = value.to_string();
+            }
+            "save" => {
+                self.save = parse_save(value).ok_or(Some("Invalid save parameters".to_string()))?;
+            }
+            "repl-diskless-sync" => self.repl_diskless_sync = parse_bool(value)?,
+            "appendonly" => self.appendonly = parse_bool(value)?,
+            "appendfilename" => {
+                if value.contains('/') {
+                    return Err(Some(
+                        "appendfilename can't be a path, just a filename".to_string(),
+                    ));
+                }
+
+                self.appendfilename = value.to_string();
+            }
+            "appenddirname" => {
+                if value.is_empty() || value.contains('/') {
+                    return Err(Some(
+                        "appenddirname can't be a path, just a dirname".to_string(),
+                    ));
+                }
+
+                self.appenddirname = value.to_string();
+            }
+            "appendfsync" => {
+                self.appendfsync = Fsync::parse(value).ok_or(Some(
+                    "argument(s) must be one of the following: always, everysec, no".to_string(),
+                ))?;
+            }
+            "aof-load-truncated" => self.aof_load_truncated = parse_bool(value)?,
+            "aof-use-rdb-preamble" => self.aof_use_rdb_preamble = parse_bool(value)?,
+            "auto-aof-rewrite-percentage" => {
+                self.auto_aof_rewrite_percentage = value
+                    .parse::<u64>()
+                    .map_err(|_| Some("argument must be a positive integer".to_string()))?;
+            }
+            "auto-aof-rewrite-min-size" => {
+                self.auto_aof_rewrite_min_size = parse_memory(value)
+                    .ok_or(Some("argument must be a memory value".to_string()))?;
+            }
+            _ => return Err(None),
+        }
+
+        Ok(())
+    }
+
+    pub fn rdb_path(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.dbfilename)
+    }
+
+    pub fn aof_dir(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.appenddirname)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn booleans_are_yes_or_no() {
+        let mut config = Config::default();
+
+        assert_eq!(config.get("repl-diskless-sync").as_deref(), Some("yes"));
+        assert!(config.set("repl-diskless-sync", "NO").is_ok());
+        assert_eq!(config.get("repl-diskless-sync").as_deref(), Some("no"));
+        assert_eq!(
+            config.set("repl-diskless-sync", "1"),
+            Err(Some("argument must be 'yes' or 'no'".to_string()))
+        );
+        assert!(!config.repl_diskless_sync);
+    }
+
+    #[test]
+    fn save_points_round_trip() {
+        let mut config = Config::default();
+
+        assert!(config.set("save", "3600 1  300 100").is_ok());
+        assert_eq!(config.save, [(3600, 1), (300, 100)]);
+        assert_eq!(config.get("save").as_deref(), Some("3600 1 300 100"));
+        assert!(config.set("save", "").is_ok());
+        assert!(config.save.is_empty());
+        assert!(config.set("save", "3600").is_err());
+        assert!(config.set("save", "3600 x").is_err());
+    }
+
+    #[test]
+    fn memory_units() {
+        assert_eq!(parse_memory("64"), Some(64));
+        assert_eq!(parse_memory("64b"), Some(64));
+        assert_eq!(parse_memory("1k"), Some(1000));
+        assert_eq!(parse_memory("1KB"), Some(1024));
+        assert_eq!(parse_memory("64mb"), Some(64 * 1024 * 1024));
+        assert_eq!(parse_memory("2g"), Some(2_000_000_000));
+        assert_eq!(parse_memory("1tb"), None);
+        assert_eq!(parse_memory("mb"), None);
+        assert_eq!(parse_memory("-1"), None);
+        assert_eq!(parse_memory("99999999999gb"), None);
+    }
+
+    #[test]
+    fn aof_parameters() {
+        let mut config = Config::default();
+
+        assert!(config.set("auto-aof-rewrite-min-size", "1mb").is_ok());
+        assert_eq!(
+            config.get("auto-aof-rewrite-min-size").as_deref(),
+            Some("1048576")
+        );
+        assert!(config.set("auto-aof-rewrite-min-size", "lots").is_err());
+        assert!(config.set("auto-aof-rewrite-percentage", "-5").is_err());
+        assert!(config.set("appendfsync", "always").is_ok());
+        assert_eq!(config.appendfsync, Fsync::Always);
+        assert!(config.set("appendfilename", "x/y.aof").is_err());
+        assert!(!Config::is_mutable("appenddirname"));
+        assert!(Config::is_mutable("appendfsync"));
+        assert!(config.aof_dir().ends_with("appendonlydir"));
+    }
+}
@@ -0,0 +1,672 @@
+//! Geohash helpers behind the GEO commands, ported from Redis'
+//! `geohash.c` and `geohash_helper.c` so searches visit the same boxes
+//! and return the same members in the same order.
+
+use super::persistence::sorted_set::{ScoreRange, SortedSet, ZRangeBy, ZRangeSpec};
+
+const GEO_STEP_MAX: u8 = 26;
+const GEO_LAT_MIN: f64 = -85.05112878;
+const GEO_LAT_MAX: f64 = 85.05112878;
+const GEO_LONG_MIN: f64 = -180.0;
+const GEO_LONG_MAX: f64 = 180.0;
+
+const EARTH_RADIUS_IN_METERS: f64 = 6372797.560856;
+const MERCATOR_MAX: f64 = 20037726.37;
+
+const GEO_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Range {
+    min: f64,
+    max: f64,
+}
+
+const LONG_RANGE: Range = Range {
+    min: GEO_LONG_MIN,
+    max: GEO_LONG_MAX,
+};
+
+const LAT_RANGE: Range = Range {
+    min: GEO_LAT_MIN,
+    max: GEO_LAT_MAX,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct HashBits {
+    bits: u64,
+    step: u8,
+}
+
+impl HashBits {
+    fn is_zero(&self) -> bool {
+        self.bits == 0 && self.step == 0
+    }
+
+    /// Sorted set scores covered by this box, as a half open range.
+    fn score_range(&self) -> (f64, f64) {
+        let shift = 52 - self.step as u32 * 2;
+        (
+            (self.bits << shift) as f64,
+            ((self.bits + 1) << shift) as f64,
+        )
+    }
+}
+
+struct Area {
+    longitude: Range,
+    latitude: Range,
+}
+
+fn interleave64(xlo: u32, ylo: u32) -> u64 {
+    const B: [u64; 5] = [
+        0x5555555555555555,
+        0x3333333333333333,
+        0x0F0F0F0F0F0F0F0F,
+        0x00FF00FF00FF00FF,
+        0x0000FFFF0000FFFF,
+    ];
+    const S: [u32; 5] = [1, 2, 4, 8, 16];
+
+    let mut x = xlo as u64;
+    let mut y = ylo as u64;
+
+    for i in (0..5).rev() {
+        x = (x | (x << S[i])) & B[i];
+        y = (y | (y << S[i])) & B[i];
+    }
+
+    x | (y << 1)
+}
+
+fn deinterleave64(interleaved: u64) -> u64 {
+    const B: [u64; 6] = [
+        0x5555555555555555,
+        0x3333333333333333,
+        0x0F0F0F0F0F0F0F0F,
+        0x00FF00FF00FF00FF,
+        0x0000FFFF0000FFFF,
+        0x00000000FFFFFFFF,
+    ];
+    const S: [u32; 6] = [0, 1, 2, 4, 8, 16];
+
+    let mut x = interleaved;
+    let mut y = interleaved >> 1;
+
+    for i in 0..6 {
+        x = (x | (x >> S[i])) & B[i];
+        y = (y | (y >> S[i])) & B[i];
+    }
+
+    x | (y << 32)
+}
+
+fn encode(
+    long_range: Range,
+    lat_range: Range,
+    longitude: f64,
+    latitude: f64,
+    step: u8,
+) -> Option<HashBits> {
+    if !valid_coordinates(longitude, latitude)
+        || latitude < lat_range.min
+        || latitude > lat_range.max
+        || longitude < long_range.min
+        || longitude > long_range.max
+    {
+        return None;
+    }
+
+    let lat_offset = (latitude - lat_range.min) / (lat_range.max - lat_range.min);
+    let long_offset = (longitude - long_range.min) / (long_range.max - long_range.min);
+
+    let scale = (1u64 << step) as f64;
+
+    Some(HashBits {
+        bits: interleave64((lat_offset * scale) as u32, (long_offset * scale) as u32),
+        step,
+    })
+}
+
+fn decode(long_range: Range, lat_range: Range, hash: HashBits) -> Area {
+    let hash_sep = deinterleave64(hash.bits);
+    let lat_scale = lat_range.max - lat_range.min;
+    let long_scale = long_range.max - long_range.min;
+
+    let ilato = hash_sep as u32 as f64;
+    let ilono = (hash_sep >> 32) as u32 as f64;
+    let div = (1u64 << hash.step) as f64;
+
+    Area {
+        latitude: Range {
+            min: lat_range.min + (ilato / div) * lat_scale,
+            max: lat_range.min + ((ilato + 1.0) / div) * lat_scale,
+        },
+        longitude: Range {
+            min: long_range.min + (ilono / div) * long_scale,
+            max: long_range.min + ((ilono + 1.0) / div) * long_scale,
+        },
+    }
+}
+
+fn area_center(area: &Area) -> (f64, f64) {
+    let longitude = (area.longitude.min + area.longitude.max) / 2.0;
+    let latitude = (area.latitude.min + area.latitude.max) / 2.0;
+
+    (
+        longitude.clamp(GEO_LONG_MIN, GEO_LONG_MAX),
+        latitude.clamp(GEO_LAT_MIN, GEO_LAT_MAX),
+    )
+}
+
+fn move_x(hash: &mut HashBits, d: i8) {
+    if d == 0 {
+        return;
+    }
+
+    let mut x = hash.bits & 0xaaaaaaaaaaaaaaaa;
+    let y = hash.bits & 0x5555555555555555;
+    let zz = 0x5555555555555555u64 >> (64 - hash.step as u32 * 2);
+
+    if d > 0 {
+        x = x.wrapping_add(zz + 1);
+    } else {
+        x |= zz;
+        x = x.wrapping_sub(zz + 1);
+    }
+
+    x &= 0xaaaaaaaaaaaaaaaau64 >> (64 - hash.step as u32 * 2);
+    hash.bits = x | y;
+}
+
+fn move_y(hash: &mut HashBits, d: i8) {
+    if d == 0 {
+        return;
+    }
+
+    let x = hash.bits & 0xaaaaaaaaaaaaaaaa;
+    let mut y = hash.bits & 0x5555555555555555;
+    let zz = 0xaaaaaaaaaaaaaaaau64 >> (64 - hash.step as u32 * 2);
+
+    if d > 0 {
+        y = y.wrapping_add(zz + 1);
+    } else {
+        y |= zz;
+        y = y.wrapping_sub(zz + 1);
+    }
+
+    y &= 0x5555555555555555u64 >> (64 - hash.step as u32 * 2);
+    hash.bits = x | y;
+}
+
+/// The box itself followed by north, south, east, west, north east,
+/// north west, south east and south west, the order Redis scans them in.
+fn neighbors(hash: HashBits) -> [HashBits; 9] {
+    let moves: [(i8, i8); 9] = [
+        (0, 0),
+        (0, 1),
+        (0, -1),
+        (1, 0),
+        (-1, 0),
+        (1, 1),
+        (-1, 1),
+        (1, -1),
+        (-1, -1),
+    ];
+
+    moves.map(|(dx, dy)| {
+        let mut neighbor = hash;
+        move_x(&mut neighbor, dx);
+        move_y(&mut neighbor, dy);
+        neighbor
+    })
+}
+
+fn deg_rad(ang: f64) -> f64 {
+    ang * (std::f64::consts::PI / 180.0)
+}
+
+fn rad_deg(ang: f64) -> f64 {
+    ang / (std::f64::consts::PI / 180.0)
+}
+
+fn lat_distance(lat1d: f64, lat2d: f64) -> f64 {
+    EARTH_RADIUS_IN_METERS * (deg_rad(lat2d) - deg_rad(lat1d)).abs()
+}
+
+/// Haversine distance in meters.
+pub fn distance(lon1d: f64, lat1d: f64, lon2d: f64, lat2d: f64) -> f64 {
+    let lon1r = deg_rad(lon1d);
+    let lon2r = deg_rad(lon2d);
+    let v = ((lon2r - lon1r) / 2.0).sin();
+
+    if v == 0.0 {
+        return lat_distance(lat1d, lat2d);
+    }
+
+    let lat1r = deg_rad(lat1d);
+    let lat2r = deg_rad(lat2d);
+    let u = ((lat2r - lat1r) / 2.0).sin();
+    let a = u * u + lat1r.cos() * lat2r.cos() * v * v;
+
+    2.0 * EARTH_RADIUS_IN_METERS * a.sqrt().asin()
+}
+
+pub fn valid_coordinates(longitude: f64, latitude: f64) -> bool {
+    (GEO_LONG_MIN..=GEO_LONG_MAX).contains(&longitude)
+        && (GEO_LAT_MIN..=GEO_LAT_MAX).contains(&latitude)
+}
+
+/// The 52 bit geohash used as the sorted set score of a position.
+pub fn encode_score(longitude: f64, latitude: f64) -> Option<f64> {
+    encode(LONG_RANGE, LAT_RANGE, longitude, latitude, GEO_STEP_MAX).map(|hash| hash.bits as f64)
+}
+
+pub fn decode_score(score: f64) -> (f64, f64) {
+    let hash = HashBits {
+        bits: score as u64,
+        step: GEO_STEP_MAX,
+    };
+
+    area_center(&decode(LONG_RANGE, LAT_RANGE, hash))
+}
+
+/// The standard 11 character geohash. Scores use a -85,85 latitude range,
+/// so the position is re-encoded against -90,90 first.
+pub fn geohash_string(score: f64) -> String {
+    let (longitude, latitude) = decode_score(score);
+
+    let hash = encode(
+        LONG_RANGE,
+        Range {
+            min: -90.0,
+            max: 90.0,
+        },
+        longitude,
+        latitude,
+        GEO_STEP_MAX,
+    )
+    .unwrap_or_default();
+
+    (0..11)
+        .map(|i| {
+            // Only 52 bits exist, the eleventh character is always '0'.
+            let idx = match i {
+                10 => 0,
+                i => (hash.bits >> (52 - (i + 1) * 5)) & 0x1f,
+            };
+
+            GEO_ALPHABET[idx as usize] as char
+        })
+        .collect()
+}
+
+/// Coordinates are replied with 17 decimals minus trailing zeros, like
+/// Redis' human readable long doubles.
+pub fn format_coordinate(val: f64) -> String {
+    let formatted = format!("{:.17}", val);
+    formatted
+        .trim_end_matches('0')
+        .trim_end_matches('.')
+        .to_string()
+}
+
+/// Conversion factor to meters for a GEO unit.
+pub fn unit_to_meters(unit: &str) -> Option<f64> {
+    match unit.to_lowercase().as_str() {
+        "m" => Some(1.0),
+        "km" => Some(1000.0),
+        "ft" => Some(0.3048),
+        "mi" => Some(1609.34),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Shape {
+    Radius(f64),
+    Box { width: f64, height: f64 },
+}
+
+/// A GEOSEARCH area, sizes are in the unit given by the user and
+/// `conversion` turns them into meters.
+#[derive(Clone, Copy, Debug)]
+pub struct GeoSearch {
+    pub longitude: f64,
+    pub latitude: f64,
+    pub shape: Shape,
+    pub conversion: f64,
+}
+
+impl GeoSearch {
+    fn estimate_steps(mut range_meters: f64, latitude: f64) -> u8 {
+        if range_meters == 0.0 {
+            return GEO_STEP_MAX;
+        }
+
+        let mut step: i32 = 1;
+
+        while range_meters < MERCATOR_MAX {
+            range_meters *= 2.0;
+            step += 1;
+        }
+
+        // Make sure the range is included in most of the base cases.
+        step -= 2;
+
+        // Boxes get narrower towards the poles.
+        if !(-66.0..=66.0).contains(&latitude) {
+            step -= 1;
+
+            if !(-80.0..=80.0).contains(&latitude) {
+                step -= 1;
+            }
+        }
+
+        step.clamp(1, GEO_STEP_MAX as i32) as u8
+    }
+
+    /// min longitude, min latitude, max longitude, max latitude.
+    fn bounding_box(&self) -> [f64; 4] {
+        let (height, width) = match self.shape {
+            Shape::Radius(radius) => (radius, radius),
+            Shape::Box { width, height } => (height / 2.0, width / 2.0),
+        };
+
+        let height = height * self.conversion;
+        let width = width * self.conversion;
+
+        let lat_delta = rad_deg(height / EARTH_RADIUS_IN_METERS);
+        let long_delta_top =
+            rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(self.latitude + lat_delta).cos());
+        let long_delta_bottom =
+            rad_deg(width / EARTH_RADIUS_IN_METERS / deg_rad(self.latitude - lat_delta).cos());
+
+        // Hemispheres are mirrored, so the widest edge is on opposite sides.
+        let long_delta = match self.latitude < 0.0 {
+            true => long_delta_bottom,
+            false => long_delta_top,
+        };
+
+        [
+            self.longitude - long_delta,
+            self.latitude - lat_delta,
+            self.longitude + long_delta,
+            self.latitude + lat_delta,
+        ]
+    }
+
+    /// Score ranges of the geohash boxes that may hold matches, in the
+    /// order Redis walks them.
+    pub fn score_ranges(&self) -> Vec<(f64, f64)> {
+        let [min_lon, min_lat, max_lon, max_lat] = self.bounding_box();
+
+        let radius_meters = match self.shape {
+            Shape::Radius(radius) => radius,
+            Shape::Box { width, height } => ((width / 2.0).powi(2) + (height / 2.0).powi(2)).sqrt(),
+        } * self.conversion;
+
+        let mut steps = Self::estimate_steps(radius_meters, self.latitude);
+
+        let mut hash = encode(LONG_RANGE, LAT_RANGE, self.longitude, self.latitude, steps).unwrap();
+        let mut boxes = neighbors(hash);
+
+        // Near the edge of the center box the estimated step may be too
+        // small for the neighbors to cover the whole area.
+        let north = decode(LONG_RANGE, LAT_RANGE, boxes[1]);
+        let south = decode(LONG_RANGE, LAT_RANGE, boxes[2]);
+        let east = decode(LONG_RANGE, LAT_RANGE, boxes[3]);
+        let west = decode(LONG_RANGE, LAT_RANGE, boxes[4]);
+
+        let decrease_step = north.latitude.max < max_lat
+            || south.latitude.min > min_lat
+            || east.longitude.max < max_lon
+            || west.longitude.min > min_lon;
+
+        if steps > 1 && decrease_step {
+            steps -= 1;
+            hash = encode(LONG_RANGE, LAT_RANGE, self.longitude, self.latitude, steps).unwrap();
+            boxes = neighbors(hash);
+        }
+
+        let area = decode(LONG_RANGE, LAT_RANGE, hash);
+
+        // Drop neighbors that cannot intersect the search area.
+        if steps >= 2 {
+            let mut useless: Vec<usize> = vec![];
+
+            if area.latitude.min < min_lat {
+                useless.extend([2, 8, 7]);
+            }
+
+            if area.latitude.max > max_lat {
+                useless.extend([1, 5, 6]);
+            }
+
+            if area.longitude.min < min_lon {
+                useless.extend([4, 8, 6]);
+            }
+
+            if area.longitude.max > max_lon {
+                useless.extend([3, 7, 5]);
+            }
+
+            for i in useless {
+                boxes[i] = HashBits::default();
+            }
+        }
+
+        let mut ranges: Vec<(f64, f64)> = vec![];
+        let mut last: Option<HashBits> = None;
+
+        for hash in boxes {
+            if hash.is_zero() {
+                continue;
+            }
+
+            // With huge radiuses adjacent neighbors can be the same box.
+            if last == Some(hash) {
+                continue;
+            }
+
+            ranges.push(hash.score_range());
+            last = Some(hash);
+        }
+
+        ranges
+    }
+
+    /// Distance from the center in meters, when the point is inside the area.
+    pub fn distance_if_within(&self, longitude: f64, latitude: f64) -> Option<f64> {
+        match self.shape {
+            Shape::Radius(radius) => {
+                let distance = distance(self.longitude, self.latitude, longitude, latitude);
+
+                (distance <= radius * self.conversion).then_some(distance)
+            }
+            Shape::Box { width, height } => {
+                if lat_distance(latitude, self.latitude) > height * self.conversion / 2.0 {
+                    return None;
+                }
+
+                if distance(longitude, latitude, self.longitude, latitude)
+                    > width * self.conversion / 2.0
+                {
+                    return None;
+                }
+
+                Some(distance(self.longitude, self.latitude, longitude, latitude))
+            }
+        }
+    }
+}
+
+/// A member found by a search, `dist` is in meters from the center.
+pub struct GeoPoint {
+    pub member: String,
+    pub score: f64,
+    pub longitude: f64,
+    pub latitude: f64,
+    pub dist: f64,
+}
+
+impl GeoSearch {
+    /// Members of `zset` inside the area, in scan order. A non zero `limit`
+    /// stops the scan once that many were found, for `COUNT n ANY`.
+    pub fn members(&self, zset: &SortedSet, limit: usize) -> Vec<GeoPoint> {
+        let mut points: Vec<GeoPoint> = vec![];
+
+        for (min, max) in self.score_ranges() {
+            if limit > 0 && points.len() >= limit {
+                break;
+            }
+
+            let spec = ZRangeSpec {
+                by: ZRangeBy::Score(ScoreRange {
+                    min,
+                    max,
+                    minex: false,
+                    maxex: true,
+                }),
+                rev: false,
+                offset: 0,
+                limit: -1,
+            };
+
+            for (member, score) in zset.range(&spec) {
+                let (longitude, latitude) = decode_score(score);
+
+                if let Some(dist) = self.distance_if_within(longitude, latitude) {
+                    points.push(GeoPoint {
+                        member,
+                        score,
+                        longitude,
+                        latitude,
+                        dist,
+                    });
+
+                    if limit > 0 && points.len() >= limit {
+                        break;
+                    }
+                }
+            }
+        }
+
+        points
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::persistence::sorted_set::ZAddFlags;
+
+    const PALERMO: (f64, f64) = (13.361389, 38.115556);
+    const CATANIA: (f64, f64) = (15.087269, 37.502669);
+
+    #[test]
+    fn scores_match_redis() {
+        assert_eq!(encode_score(PALERMO.0, PALERMO.1), Some(3479099956230698.0));
+        assert_eq!(encode_score(CATANIA.0, CATANIA.1), Some(3479447370796909.0));
+        assert_eq!(encode_score(0.0, 86.0), None);
+        assert_eq!(encode_score(181.0, 0.0), None);
+    }
+
+    #[test]
+    fn decoding_returns_the_center_of_the_box() {
+        let (longitude, latitude) = decode_score(3479099956230698.0);
+
+        assert_eq!(format_coordinate(longitude), "13.36138933897018433");
+        assert_eq!(format_coordinate(latitude), "38.11555639549629859");
+    }
+
+    #[test]
+    fn interleaving_round_trips() {
+        for (x, y) in [
+            (0, 0),
+            (1, 0),
+            (0, 1),
+            (0x3ffffff, 0x1234567),
+            (u32::MAX, 7),
+        ] {
+            assert_eq!(
+                deinterleave64(interleave64(x, y)),
+                x as u64 | (y as u64) << 32
+            );
+        }
+    }
+
+    #[test]
+    fn geohash_strings_match_redis() {
+        assert_eq!(geohash_string(3479099956230698.0), "sqc8b49rny0");
+        assert_eq!(geohash_string(3479447370796909.0), "sqdtr74hyu0");
+    }
+
+    #[test]
+    fn distances_match_redis() {
+        // GEODIST measures between the stored, decoded positions.
+        let (lon1, lat1) = decode_score(3479099956230698.0);
+        let (lon2, lat2) = decode_score(3479447370796909.0);
+        let dist = distance(lon1, lat1, lon2, lat2);
+
+        assert!((dist - 166274.1516).abs() < 0.001, "{}", dist);
+        assert_eq!(distance(1.0, 2.0, 1.0, 2.0), 0.0);
+    }
+
+    #[test]
+    fn neighbors_surround_the_box() {
+        let hash = encode(LONG_RANGE, LAT_RANGE, PALERMO.0, PALERMO.1, 10).unwrap();
+        let center = decode(LONG_RANGE, LAT_RANGE, hash);
+        let [_, north, south, east, west, ..] =
+            neighbors(hash).map(|neighbor| decode(LONG_RANGE, LAT_RANGE, neighbor));
+
+        assert_eq!(north.latitude.min, center.latitude.max);
+        assert_eq!(south.latitude.max, center.latitude.min);
+        assert_eq!(east.longitude.min, center.longitude.max);
+        assert_eq!(west.longitude.max, center.longitude.min);
+        assert_eq!(north.longitude, center.longitude);
+    }
+
+    #[test]
+    fn searches_find_members_in_the_area() {
+        let mut zset = SortedSet::default();
+
+        for (member, (longitude, latitude)) in [("Palermo", PALERMO), ("Catania", CATANIA)] {
+            let score = encode_score(longitude, latitude).unwrap();
+            zset.add(score, member, ZAddFlags::default());
+        }
+
+        let search = |shape| GeoSearch {
+            longitude: 15.0,
+            latitude: 37.0,
+            shape,
+            conversion: unit_to_meters("km").unwrap(),
+        };
+        let found = |search: GeoSearch| {
+            let mut members: Vec<String> = search
+                .members(&zset, 0)
+                .into_iter()
+                .map(|point| point.member)
+                .collect();
+            members.sort();
+            members
+        };
+
+        assert_eq!(found(search(Shape::Radius(100.0))), ["Catania"]);
+        assert_eq!(found(search(Shape::Radius(200.0))), ["Catania", "Palermo"]);
+        assert_eq!(
+            found(search(Shape::Box {
+                width: 400.0,
+                height: 400.0
+            })),
+            ["Catania", "Palermo"]
+        );
+        assert_eq!(search(Shape::Radius(200.0)).members(&zset, 1).len(), 1);
+    }
+
+    #[test]
+    fn units() {
+        assert_eq!(unit_to_meters("KM"), Some(1000.0));
+        assert_eq!(unit_to_meters("mi"), Some(1609.34));
+        assert_eq!(unit_to_meters("yd"), None);
+    }
+}
@@ -1,31 +1,85 @@
-use core::{panic, time};
+use core::panic;
 use std::{
     borrow::BorrowMut,
-    io::{Read, Write},
-    net::TcpStream,
-    num::ParseIntError,
-    sync::{Arc, Condvar, Mutex, RwLock},
+    collections::BTreeMap,
+    io::{self, Read, Write},
+    net::Shutdown,
+    sync::{atomic::Ordering, Arc, Mutex, RwLock},
+    thread,
+    time::Duration,
 };
 
 use super::{
-    parse::{Resp, RespData},
+    aof::{self, Aof},
+    blocking::BlockingKeys,
+    client::{self, Client, Connection},
+    cluster::{key_hash_slot, Slots, CLUSTER_SLOTS},
+    command::{self, CommandSpec, BLOCKING, EXCLUSIVE, NO_MULTI, READONLY},
+    config::Config,
+    geo::{self, GeoPoint, GeoSearch, Shape},
+    migrate::MigrateSockets,
+    notify,
+    parse::{Resp, RespData, RespType},
     persistence::{
         kv_pair::PersistedValue,
         lib::{PersistedType, PersistenceInner},
-        stream::{StreamError, StreamVal},
+        set::random_index,
+        sorted_set::{
+            format_double, parse_score, zdiff, zinter, zunion, Aggregate, LexRange, ScoreRange,
+            SortedSet, ZAddFlags, ZAddOutcome, ZRangeBy, ZRangeSpec, ZSetSource,
+        },
+        stream::{
+            now_ms, ClaimOptions, ClaimOutcome, ConsumerGroup, IdSpec, Stream, StreamError,
+            StreamId, StreamVal, TrimSpec, TrimStrategy,
+        },
     },
+    pubsub::{glob_match, PubSub},
+    rdb::{self, SaveState},
     server::{Info, Role},
+    tracking::{Tracking, TrackingOptions, INVALIDATE_CHANNEL},
+    watch::WatchedKeys,
 };
 
 pub struct StateInner {
     pub persisted: PersistenceInner,
     pub info: RwLock<Info>,
-    pub cond: Condvar,
+    pub blocking: BlockingKeys,
+    /// Commands run under the read side, EXEC under the write side so a
+    /// transaction never interleaves with other clients.
+    pub exec: RwLock<()>,
+    /// The EXEC in progress, if any.
+    pub transaction: Mutex<Option<Transaction>>,
+    pub watched: WatchedKeys,
+    pub pubsub: PubSub,
+    pub slots: Slots,
+    pub config: RwLock<Config>,
+    pub tracking: Tracking,
+    pub saving: SaveState,
+    pub aof: Aof,
+    pub migrate: MigrateSockets,
 }
 
 pub type State = Arc<StateInner>;
 
-fn write_stream(stream: &mut TcpStream, content: &[u8]) {
+pub struct Transaction {
+    /// The client running the EXEC.
+    client: u64,
+    /// Writes held back to reach replicas as one MULTI/EXEC block.
+    propagated: Vec<Vec<RespData>>,
+}
+
+impl StateInner {
+    /// Whether the running command is part of an EXEC.
+    fn in_transaction(&self) -> bool {
+        self.transaction
+            .lock()
+            .unwrap()
+            .as_ref()
+            .is_some_and(|transaction| client::current() == Some(transaction.client))
+    }
+}
+
+fn write_stream(stream: &mut Connection, content: &[u8]) {
     match stream.write(content) {
         Ok(size) => {
             println!("size: {}", size);
@@ -36,46 +90,316 @@ fn write_stream(stream: &mut TcpStream, content: &[u8]) {
     };
 }
 
-fn handle_ping(stream: &mut TcpStream) {
-    write_stream(stream, b"+PONG\r\n");
+fn handle_ping(persistence: &State, client: &mut Client, vals: &[RespData]) {
+    if vals.len() > 2 {
+        handle_error(
+            &mut client.stream,
+            "ERR wrong number of arguments for 'ping' command",
+        );
+        return;
+    }
+
+    let message = vals.get(1).and_then(RespData::inside_value);
+
+    // RESP2 subscribers get it framed like the messages they receive.
+    if !client.resp3() && in_pubsub(persistence, client) {
+        write_client(
+            client,
+            &RespData::new_bulk_array(&["pong", message.unwrap_or_default()]),
+        );
+        return;
+    }
+
+    match message {
+        Some(message) => write_stream(&mut client.stream, &RespData::new_bulk(message).as_bytes()),
+        None => write_stream(&mut client.stream, b"+PONG\r\n"),
+    }
 }
 
-fn handle_echo(stream: &mut TcpStream, data: &RespData) {
+fn handle_echo(stream: &mut Connection, data: &RespData) {
     write_stream(
         stream,
         &RespData::new_bulk(data.inside_value().unwrap()).as_bytes(),
     );
 }
 
+/// Every write goes through here, replicas applying their master's writes
+/// included, so this is also where modified keys get signalled.
 fn propagate(persistence: &State, vals: &[RespData]) {
-    let info = persistence.info.read().unwrap();
+    for key in command::modified_keys(vals) {
+        signal_modified_key(persistence, key);
+    }
+
+    if let Some(transaction) = persistence.transaction.lock().unwrap().as_mut() {
+        transaction.propagated.push(vals.to_vec());
+        return;
+    }
+
+    feed_append_only(persistence, vals);
+    write_replicas(persistence, vals);
+}
+
+fn feed_append_only(persistence: &State, vals: &[RespData]) {
+    let fsync = persistence.config.read().unwrap().appendfsync;
+    persistence.aof.feed(vals, fsync);
+}
+
+/// Fails the EXEC of clients WATCHing `key`, invalidates it in client side
+/// caches and counts it as a change the next save has to write.
+fn signal_modified_key(persistence: &State, key: &str) {
+    persistence.saving.touch(1);
+    persistence.watched.touch(key);
+    persistence
+        .tracking
+        .invalidate(key, client::current(), &|id| {
+            persistence.pubsub.subscribed(INVALIDATE_CHANNEL, id)
+        });
+}
+
+/// Publishes a keyspace notification when the configured classes include
+/// `class`, as `__keyspace@0__:<key>` and/or `__keyevent@0__:<event>`.
+fn notify_keyspace_event(persistence: &State, class: u32, event: &str, key: &str) {
+    let flags = persistence.config.read().unwrap().notify_keyspace_events;
+
+    if flags & class == 0 {
+        return;
+    }
+
+    if flags & notify::KEYSPACE != 0 {
+        persistence
+            .pubsub
+            .publish(&format!("__keyspace@0__:{}", key), event);
+    }
+
+    if flags & notify::KEYEVENT != 0 {
+        persistence
+            .pubsub
+            .publish(&format!("__keyevent@0__:{}", event), key);
+    }
+}
+
+/// Removes a key whose TTL ran out, as the first thing reading it does.
+fn expire_if_needed(persistence: &State, key: &str) {
+    if persistence.persisted.expire(key) {
+        signal_modified_key(persistence, key);
+        notify_keyspace_event(persistence, notify::EXPIRED, "expired", key);
+    }
+}
+
+/// Removes every key whose TTL ran out, so they go away (and get
+/// announced) even when nothing reads them. Runs between commands, never
+/// inside an EXEC.
+pub fn active_expire_cycle(persistence: &State) {
+    let _exec = persistence.exec.read().unwrap();
+
+    for key in persistence.persisted.remove_expired() {
+        signal_modified_key(persistence, &key);
+        notify_keyspace_event(persistence, notify::EXPIRED, "expired", &key);
+    }
+}
 
-    match &info.role {
+fn write_replicas(persistence: &State, vals: &[RespData]) {
+    let mut gone = vec![];
+
+    match persistence.info.write().unwrap().role.borrow_mut() {
         Role::Master(master) => {
+            let send = &RespData::Array(vals.to_vec()).as_bytes();
+
             for slave in &master.slave_ports {
-                let mut conn = master.slave_stream.get(&slave).unwrap().lock().unwrap();
-                let send = &RespData::Array(vals.to_vec()).as_bytes();
-                conn.write(send).unwrap();
+                // Replicas still receiving their snapshot get it afterwards.
+                if let Some(held) = master.syncing.get_mut(slave) {
+                    held.extend_from_slice(send);
+                    continue;
+                }
+
+                let written = match master.slave_stream.get(slave) {
+                    Some(conn) => conn.lock().unwrap().write_all(send).is_ok(),
+                    None => false,
+                };
+
+                if !written {
+                    gone.push(*slave);
+                }
             }
         }
-        Role::Slave(_) => return,
+        Role::Slave(_) => (),
+    }
+
+    // Replicas that went away are dropped once the lock is released.
+    for port in gone {
+        drop_replica(persistence, port);
+    }
+}
+
+/// Replicas apply the writes their master propagates without answering them.
+fn write_reply(persistence: &State, stream: &mut Connection, reply: &RespData) {
+    if persistence.info.read().unwrap().is_master() {
+        write_stream(stream, &reply.as_bytes());
+    }
+}
+
+/// Redis style arity: a positive value is the exact number of arguments
+/// (command name included), a negative one is the minimum.
+fn check_arity(stream: &mut Connection, vals: &[RespData], arity: i32) -> bool {
+    let len = vals.len() as i32;
+
+    if (arity > 0 && len != arity) || (arity < 0 && len < -arity) {
+        let command = vals.first().unwrap().inside_value().unwrap().to_lowercase();
+        handle_error(
+            stream,
+            &format!("ERR wrong number of arguments for '{}' command", command),
+        );
+        return false;
+    }
+
+    true
+}
+
+fn handle_wrong_type(stream: &mut Connection) {
+    handle_error(
+        stream,
+        "WRONGTYPE Operation against a key holding the wrong kind of value",
+    );
+}
+
+fn is_type_or_empty(persistence: &State, key: &str, p_type: PersistedType) -> bool {
+    match persistence.persisted.key_type(key) {
+        Some(current) => current == p_type,
+        None => true,
+    }
+}
+
+fn parse_int(stream: &mut Connection, val: &RespData) -> Option<i64> {
+    match val.inside_value().and_then(|v| v.parse::<i64>().ok()) {
+        Some(v) => Some(v),
+        None => {
+            handle_error(stream, "ERR value is not an integer or out of range");
+            None
+        }
+    }
+}
+
+fn parse_timeout(stream: &mut Connection, val: &RespData) -> Option<Duration> {
+    match val.inside_value().and_then(|v| v.parse::<f64>().ok()) {
+        Some(timeout) if timeout < 0.0 => {
+            handle_error(stream, "ERR timeout is negative");
+            None
+        }
+        Some(timeout) if timeout.is_finite() => Some(Duration::from_secs_f64(timeout)),
+        _ => {
+            handle_error(stream, "ERR timeout is not a float or out of range");
+            None
+        }
+    }
+}
+
+/// A blocked client only notices its peer went away by peeking at the socket.
+fn is_disconnected(connection: &Connection) -> bool {
+    let mut buf = [0; 1];
+
+    // Nobody waits on a detached client, it has nothing to block for.
+    let Some(stream) = connection.socket() else {
+        return true;
+    };
+
+    if stream.set_nonblocking(true).is_err() {
+        return true;
+    }
+
+    let closed = matches!(stream.peek(&mut buf), Ok(0));
+    let _ = stream.set_nonblocking(false);
+
+    closed
+}
+
+/// Retries `attempt` until it yields, see `BlockingKeys::block_on`; `None`
+/// tries once. Attempts run under the read side of the EXEC lock, and a
+/// command inside a transaction never blocks.
+fn block_on<T>(
+    persistence: &State,
+    stream: &Connection,
+    keys: &[String],
+    timeout: Option<Duration>,
+    mut attempt: impl FnMut() -> Option<T>,
+) -> Option<T> {
+    if persistence.in_transaction() {
+        return attempt();
+    }
+
+    let mut locked = || {
+        let _exec = persistence.exec.read().unwrap();
+        attempt()
     };
+
+    match timeout {
+        Some(timeout) => {
+            persistence
+                .blocking
+                .block_on(keys, timeout, || is_disconnected(stream), locked)
+        }
+        None => locked(),
+    }
+}
+
+fn parse_float(stream: &mut Connection, val: &RespData) -> Option<f64> {
+    match val.inside_value().and_then(parse_score) {
+        Some(v) => Some(v),
+        None => {
+            handle_error(stream, "ERR value is not a valid float");
+            None
+        }
+    }
 }
 
-fn handle_set(persistence: &State, stream: &mut TcpStream, vals: &[RespData]) {
+fn string_args(vals: &[RespData]) -> Vec<String> {
+    vals.iter()
+        .map(|v| v.inside_value().unwrap().to_string())
+        .collect()
+}
+
+fn bulk_array(vals: Vec<String>) -> RespData {
+    RespData::Array(vals.into_iter().map(RespData::from).collect())
+}
+
+fn handle_set(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
     let key = vals.get(1).unwrap().inside_value().unwrap();
     let value = vals.get(2).unwrap();
 
     let has_expiry = match vals.get(3) {
-        Some(val) => match val {
-            RespData::BulkString(v) => match v.to_lowercase().as_str() {
-                "px" => match vals.get(4).unwrap() {
-                    RespData::BulkString(v) => v.parse::<u128>().unwrap(),
-                    _ => panic!(),
+        Some(val) => match val.inside_value() {
+            Some(v) => match v.to_lowercase().as_str() {
+                "px" => match vals.get(4).unwrap().inside_value() {
+                    Some(v) => v.parse::<u128>().unwrap(),
+                    None => panic!(),
+                },
+                // The unix time in milliseconds, how the AOF logs expiries.
+                "pxat" => match vals.get(4).map(|val| parse_int(stream, val)) {
+                    Some(Some(at)) if at <= 0 => {
+                        return handle_error(stream, "ERR invalid expire time in 'set' command");
+                    }
+                    Some(Some(at)) => {
+                        let at = at as u128;
+                        let now = now_ms() as u128;
+
+                        // Already expired, the key just goes away.
+                        if at <= now {
+                            if persistence.persisted.delete(key) {
+                                notify_keyspace_event(persistence, notify::GENERIC, "del", key);
+                            }
+
+                            write_reply(persistence, stream, &RespData::new_simple_string("OK"));
+                            propagate(persistence, vals);
+                            return;
+                        }
+
+                        at - now
+                    }
+                    Some(None) => return,
+                    None => return handle_error(stream, "ERR syntax error"),
                 },
                 _ => 0,
             },
-            _ => panic!(),
+            None => panic!(),
         },
         None => 0,
     };
@@ -87,362 +411,7017 @@ fn handle_set(persistence: &State, stream: &mut TcpStream, vals: &[RespData]) {
         expiry: has_expiry,
     };
 
-    let mut persist = persistence.persisted.key_value.lock().unwrap();
-    persist.0.insert(key.to_string(), insert_val);
+    let created = persistence.persisted.key_type(key).is_none();
 
-    if persistence.info.read().unwrap().is_master() {
-        write_stream(stream, format!("+OK\r\n").as_bytes());
-        propagate(persistence, vals);
+    // SET overwrites a value of any type, which lives in its own map.
+    persistence.persisted.delete(key);
+    persistence
+        .persisted
+        .key_value
+        .lock()
+        .unwrap()
+        .0
+        .insert(key.to_string(), insert_val);
+
+    write_reply(persistence, stream, &RespData::new_simple_string("OK"));
+    propagate(persistence, vals);
+
+    if created {
+        notify_keyspace_event(persistence, notify::NEW, "new", key);
+    }
+
+    notify_keyspace_event(persistence, notify::STRING, "set", key);
+
+    if has_expiry > 0 {
+        notify_keyspace_event(persistence, notify::GENERIC, "expire", key);
     }
 }
 
-fn handle_xrange(persistence: &State, stream: &mut TcpStream, vals: &[RespData]) {
-    let mut vals = vals.iter().skip(1);
-    let stream_key = vals.next().unwrap().inside_value().unwrap().to_string();
+fn handle_invalid_stream_id(stream: &mut Connection) {
+    handle_error(
+        stream,
+        "ERR Invalid stream ID specified as stream command argument",
+    );
+}
 
-    let start = vals.next().unwrap().inside_value().unwrap().to_string();
-    let end = vals.next().unwrap().inside_value().unwrap().to_string();
+/// One end of an XRANGE interval. A missing sequence number means 0 for
+/// the start and the highest sequence for the end, and a `(` prefix
+/// excludes the ID itself.
+fn parse_interval_id(stream: &mut Connection, val: &RespData, is_start: bool) -> Option<StreamId> {
+    let val = val.inside_value().unwrap();
+    let missing_seq = if is_start { 0 } else { u64::MAX };
 
-    let streams = persistence.persisted.stream.lock().unwrap();
+    let exclusive = match val.strip_prefix('(') {
+        Some(id) if !id.is_empty() => id,
+        _ => {
+            return match val {
+                "-" => Some(StreamId::MIN),
+                "+" => Some(StreamId::MAX),
+                id => match StreamId::parse(id, missing_seq) {
+                    Ok(id) => Some(id),
+                    Err(_) => {
+                        handle_invalid_stream_id(stream);
+                        None
+                    }
+                },
+            };
+        }
+    };
 
-    {
-        let range: Vec<StreamVal> = match (start, end) {
-            (st, en) if st == "-" => streams.get_range_to_start(stream_key, en),
-            (st, en) if en == "+" => streams.get_range(stream_key, st, en, Some(true)),
-            (st, en) => streams.get_range(stream_key, st, en, None),
-        };
+    let id = match StreamId::parse(exclusive, missing_seq) {
+        Ok(id) => id,
+        Err(_) => {
+            handle_invalid_stream_id(stream);
+            return None;
+        }
+    };
 
-        let map = range.into_iter().map(|val| {
-            let resp: RespData = val.into();
-            resp
-        });
+    let adjusted = match is_start {
+        true => id.incr(),
+        false => id.decr(),
+    };
 
-        write_stream(stream, &RespData::Array(map.collect()).as_bytes());
+    if adjusted.is_none() {
+        match is_start {
+            true => handle_error(stream, "ERR invalid start ID for the interval"),
+            false => handle_error(stream, "ERR invalid end ID for the interval"),
+        }
     }
+
+    adjusted
 }
 
-fn handle_xread(persistence: &State, stream: &mut TcpStream, vals: &[RespData]) {
-    let mut vals = vals.iter().skip(1);
-    let mut get_ids: Vec<String> = vec![];
-    let mut stream_keys: Vec<String> = vec![];
+/// XRANGE and XREVRANGE, the latter takes the end of the interval first.
+fn handle_xrange(persistence: &State, stream: &mut Connection, vals: &[RespData], rev: bool) {
+    if !check_arity(stream, vals, -4) {
+        return;
+    }
 
-    let mut block: Option<u64> = None;
+    let stream_key = vals.get(1).unwrap().inside_value().unwrap();
 
-    loop {
-        let val = match vals.next() {
-            Some(v) => v.inside_value().unwrap(),
-            None => break,
-        };
+    let (start_arg, end_arg) = match rev {
+        true => (vals.get(3).unwrap(), vals.get(2).unwrap()),
+        false => (vals.get(2).unwrap(), vals.get(3).unwrap()),
+    };
+
+    let start = match parse_interval_id(stream, start_arg, true) {
+        Some(start) => start,
+        None => return,
+    };
 
-        let per = persistence.persisted.stream.lock().unwrap();
+    let end = match parse_interval_id(stream, end_arg, false) {
+        Some(end) => end,
+        None => return,
+    };
 
-        match val {
-            val if val.contains("$") => {
-                get_ids.push(per.get_last(stream_keys.last().unwrap()).unwrap().id())
+    let count = match &vals[4..] {
+        [] => None,
+        [option, count] if option.inside_value().unwrap().eq_ignore_ascii_case("count") => {
+            match parse_int(stream, count) {
+                Some(count) => Some(count.max(0) as usize),
+                None => return,
             }
-            val if val.contains("streams") => {}
-            val if val.contains("-") => get_ids.push(val.to_string()),
-            val if val.contains("block") => {
-                block = Some(
-                    vals.next()
-                        .unwrap()
-                        .inside_value()
-                        .unwrap()
-                        .parse()
-                        .unwrap(),
-                )
-            }
-            val => stream_keys.push(val.to_string()),
         }
+        _ => {
+            handle_error(stream, "ERR syntax error");
+            return;
+        }
+    };
+
+    if !is_type_or_empty(persistence, stream_key, PersistedType::Stream) {
+        handle_wrong_type(stream);
+        return;
     }
 
-    let start = std::time::Instant::now();
+    let streams = persistence.persisted.stream.lock().unwrap();
 
-    match block {
-        Some(0) => {
-            let per = persistence.persisted.stream.lock().unwrap();
-            let _wait = persistence.cond.wait(per).unwrap();
+    let entries = match streams.map.get(stream_key) {
+        Some(entries) => entries,
+        None => {
+            write_stream(stream, &RespData::Array(vec![]).as_bytes());
+            return;
         }
-        Some(v) => 
-        {
-            std::thread::sleep(time::Duration::from_millis(v));
-        },
-        _ => {}
     };
 
-    let per = persistence.persisted.stream.lock().unwrap();
+    let limit = match count {
+        Some(0) => {
+            write_stream(stream, &RespData::NullArray.as_bytes());
+            return;
+        }
+        Some(count) => count,
+        None => usize::MAX,
+    };
 
-    let range = per.xread(
-        stream_keys.clone(),
-        get_ids,
-        block,
-        start
-    );
+    let range: Vec<RespData> = match rev {
+        true => entries
+            .rev_range(start, end)
+            .take(limit)
+            .map(RespData::from)
+            .collect(),
+        false => entries
+            .range(start, end)
+            .take(limit)
+            .map(RespData::from)
+            .collect(),
+    };
 
-    println!("range");
+    write_stream(stream, &RespData::Array(range).as_bytes());
+}
 
-    let mut stream_iter = stream_keys.iter();
+/// One XREAD pass: entries after `after` in each stream, only the streams
+/// that have some. `None` means there was nothing and the caller may block.
+fn read_streams(persistence: &State, args: &XReadArgs, after: &[StreamId]) -> Option<RespData> {
+    let streams = persistence.persisted.stream.lock().unwrap();
+    let count = if args.count == 0 { usize::MAX } else { args.count };
 
-    let map: Vec<RespData> = range
-        .into_iter()
-        .map(|val| {
-            let res: Vec<RespData> = val.into_iter().map(|v| v.into()).collect();
+    let reply: Vec<RespData> = args
+        .keys
+        .iter()
+        .zip(after)
+        .filter_map(|(key, after)| {
+            let entries: Vec<RespData> = streams
+                .map
+                .get(key)?
+                .range(after.incr()?, StreamId::MAX)
+                .take(count)
+                .map(RespData::from)
+                .collect();
 
-            match res.len() {
-                0 => RespData::Array(vec![
-                    RespData::BulkString(stream_iter.next().unwrap().to_string()),
-                    RespData::BulkString("".to_string()),
-                ]),
-                _ => RespData::Array(vec![
-                    RespData::BulkString(stream_iter.next().unwrap().to_string()),
-                    RespData::Array(res),
-                ]),
+            match entries.is_empty() {
+                true => None,
+                false => Some(RespData::Array(vec![
+                    RespData::from(key.to_string()),
+                    RespData::Array(entries),
+                ])),
             }
         })
         .collect();
 
-    write_stream(stream, &RespData::Array(map).as_bytes());
+    match reply.is_empty() {
+        true => None,
+        false => Some(RespData::Array(reply)),
+    }
 }
 
-fn handle_xadd(persistence: &State, stream: &mut TcpStream, vals: &[RespData]) {
-    let mut iter = vals.iter().skip(1);
-    let stream_key = iter.next().unwrap().inside_value().unwrap();
+fn handle_xread(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -4) {
+        return;
+    }
 
-    let id = iter.next().unwrap().inside_value().unwrap();
-    let insert_id = StreamVal::parse_id(
-        &id.to_string(),
-        &stream_key.to_string(),
-        &persistence.persisted.stream,
-    );
+    let args = match parse_xread(stream, vals, false) {
+        Some(args) => args,
+        None => return,
+    };
 
-    let mut stream_vals: Vec<(String, String)> = vec![];
+    let mut after: Vec<StreamId> = vec![];
 
-    for (first, second) in iter.step_by(2).zip(vals.iter().skip(4).step_by(2)) {
-        match (first, second) {
-            (RespData::BulkString(key), RespData::BulkString(value)) => {
-                stream_vals.push((key.to_string(), value.to_string()));
-            }
-            _ => panic!(),
-        };
+    for (key, id) in args.keys.iter().zip(&args.ids) {
+        if !is_type_or_empty(persistence, key, PersistedType::Stream) {
+            handle_wrong_type(stream);
+            return;
+        }
+
+        // `$` is resolved once, so blocking only sees entries added later.
+        match id.as_str() {
+            "$" => after.push(
+                persistence
+                    .persisted
+                    .stream
+                    .lock()
+                    .unwrap()
+                    .last_id(key)
+                    .unwrap_or_default(),
+            ),
+            id => match StreamId::parse(id, 0) {
+                Ok(id) => after.push(id),
+                Err(_) => {
+                    handle_invalid_stream_id(stream);
+                    return;
+                }
+            },
+        }
     }
 
-    let insert_val = StreamVal {
-        id: insert_id.unwrap(),
-        pairs: stream_vals,
-        added: std::time::Instant::now(),
-    };
+    let served = block_on(persistence, stream, &args.keys, args.block, || {
+        read_streams(persistence, &args, &after)
+    });
 
-    {
-        match persistence
-            .persisted
-            .stream
-            .lock()
-            .unwrap()
-            .insert(&stream_key.to_string(), insert_val)
-        {
-            Ok(new_id) => {
-                persistence.cond.notify_all();
-                println!("Notifying");
-                write_stream(stream, &RespData::new_bulk(&new_id).as_bytes())
-            }
-            Err(StreamError::IllegalId) => {
-                write_stream(stream, "-ERR The ID specified in XADD is equal or smaller than the target stream top item\r\n".as_bytes());
+    match served {
+        Some(reply) => write_stream(stream, &reply.as_bytes()),
+        None => write_stream(stream, &RespData::NullArray.as_bytes()),
+    }
+}
+
+// Redis' default LIMIT for approximate trimming, 100 times
+// `stream-node-max-entries`.
+const STREAM_TRIM_DEFAULT_LIMIT: usize = 10000;
+
+/// Trimming options shared by XADD and XTRIM, starting at `vals[idx]`.
+/// For XADD parsing stops at the ID, whose position is returned along
+/// with the NOMKSTREAM flag.
+fn parse_trim(
+    stream: &mut Connection,
+    vals: &[RespData],
+    mut idx: usize,
+    xadd: bool,
+) -> Option<(Option<TrimSpec>, usize, bool)> {
+    let mut strategy: Option<TrimStrategy> = None;
+    let mut approx = false;
+    let mut limit: Option<usize> = None;
+    let mut no_mkstream = false;
+
+    while let Some(option) = vals.get(idx) {
+        let more = vals.len() - idx - 1;
+        let option = option.inside_value().unwrap().to_lowercase();
+
+        match option.as_str() {
+            "*" if xadd => break,
+            "maxlen" | "minid" if more >= 1 => {
+                if strategy.is_some() {
+                    handle_error(
+                        stream,
+                        "ERR syntax error, MAXLEN and MINID options at the same time are not compatible",
+                    );
+                    return None;
+                }
+
+                approx = false;
+
+                match vals[idx + 1].inside_value().unwrap() {
+                    "~" if more >= 2 => {
+                        approx = true;
+                        idx += 1;
+                    }
+                    "=" if more >= 2 => idx += 1,
+                    _ => {}
+                }
+
+                strategy = Some(match option.as_str() {
+                    "maxlen" => match parse_int(stream, &vals[idx + 1])? {
+                        max_len if max_len < 0 => {
+                            handle_error(stream, "ERR The MAXLEN argument must be >= 0.");
+                            return None;
+                        }
+                        max_len => TrimStrategy::MaxLen(max_len as usize),
+                    },
+                    _ => TrimStrategy::MinId(parse_stream_id(stream, &vals[idx + 1])?),
+                });
+                idx += 1;
             }
-            Err(StreamError::IdShouldBeHigher) => {
-                write_stream(
-                    stream,
-                    "-ERR The ID specified in XADD must be greater than 0-0\r\n".as_bytes(),
-                );
+            "limit" if more >= 1 => {
+                limit = match parse_int(stream, &vals[idx + 1])? {
+                    limit if limit < 0 => {
+                        handle_error(stream, "ERR The LIMIT argument must be >= 0.");
+                        return None;
+                    }
+                    limit => Some(limit as usize),
+                };
+                idx += 1;
             }
+            "nomkstream" if xadd => no_mkstream = true,
+            _ if xadd => break,
             _ => {
-                panic!();
+                handle_error(stream, "ERR syntax error");
+                return None;
             }
         }
+
+        idx += 1;
     }
-}
 
-fn handle_type(persistence: &State, stream: &mut TcpStream, vals: &[RespData]) {
-    let key = vals.get(1).unwrap().inside_value().unwrap();
+    if limit.is_some_and(|limit| limit > 0) && strategy.is_none() {
+        handle_error(
+            stream,
+            "ERR syntax error, LIMIT cannot be used without specifying a trimming strategy",
+        );
+        return None;
+    }
 
-    match persistence.persisted.stream.lock().unwrap().map.get(key) {
-        Some(_) => {
-            write_stream(stream, &RespData::new_simple_string("stream").as_bytes());
-        }
-        None => {}
-    };
+    if !xadd && strategy.is_none() {
+        handle_error(
+            stream,
+            "ERR syntax error, XTRIM must be called with a trimming strategy",
+        );
+        return None;
+    }
 
-    match persistence.persisted.key_value.lock().unwrap().0.get(key) {
-        Some(val) => {
-            match &val.p_type {
-                &PersistedType::String => {
-                    write_stream(stream, &RespData::new_simple_string("string").as_bytes());
-                }
-                //_ => write_stream(stream, &RespData::new_simple_string("none").as_bytes()),
-            };
-        }
-        None => {
-            write_stream(stream, &RespData::new_simple_string("none").as_bytes());
-        }
+    if limit.is_some() && !approx {
+        handle_error(
+            stream,
+            "ERR syntax error, LIMIT cannot be used without the special ~ option",
+        );
+        return None;
     }
+
+    let limit = match approx {
+        true => limit.unwrap_or(STREAM_TRIM_DEFAULT_LIMIT),
+        false => 0,
+    };
+
+    let spec = strategy.map(|strategy| TrimSpec {
+        strategy,
+        approx,
+        limit,
+    });
+
+    Some((spec, idx, no_mkstream))
 }
 
-fn handle_get(persistence: &State, stream: &mut TcpStream, vals: &[RespData]) {
-    let key = vals.get(1).unwrap().inside_value().unwrap();
-    println!("KEY: {:?}", key);
+/// Whatever trimming the master did, replicas reproduce it exactly by
+/// trimming to the resulting length.
+fn exact_trim_args(entries: &Stream) -> [RespData; 3] {
+    [
+        RespData::new_bulk("MAXLEN"),
+        RespData::new_bulk("="),
+        RespData::from(entries.len().to_string()),
+    ]
+}
 
-    let persist = &persistence.persisted.key_value.lock().unwrap().0;
-    let value = match persist.get(&key.to_string()) {
-        Some(v) => v,
+fn handle_xadd(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -5) {
+        return;
+    }
+
+    let stream_key = vals.get(1).unwrap().inside_value().unwrap();
+
+    let (trim, id_idx, no_mkstream) = match parse_trim(stream, vals, 2, true) {
+        Some(parsed) => parsed,
         None => return,
     };
 
-    let now = std::time::SystemTime::now();
+    // Field value pairs follow the ID.
+    let fields = vals.len().saturating_sub(id_idx + 1);
+
+    if fields < 2 || !fields.is_multiple_of(2) {
+        handle_error(stream, "ERR wrong number of arguments for 'xadd' command");
+        return;
+    }
 
-    if value.expiry > 0 {
-        if now.duration_since(value.timestamp).unwrap().as_millis() > value.expiry {
-            write_stream(stream, b"$-1\r\n");
+    let spec = match IdSpec::parse(vals.get(id_idx).unwrap().inside_value().unwrap()) {
+        Ok(spec) => spec,
+        Err(_) => {
+            handle_invalid_stream_id(stream);
             return;
         }
-    }
+    };
 
-    write_stream(stream, &value.data.as_bytes());
-}
+    let pairs: Vec<(String, String)> = string_args(&vals[id_idx + 1..])
+        .chunks(2)
+        .map(|pair| (pair[0].clone(), pair[1].clone()))
+        .collect();
 
-fn handle_info(persistence: &State, stream: &mut TcpStream, vals: &[RespData]) {
-    match vals.get(1).unwrap() {
-        RespData::BulkString(val) => match val.as_str() {
-            "replication" => {
-                let response = persistence.info.read().unwrap().replication();
-                write_stream(stream, format!("{}\r\n", response.to_string()).as_bytes());
-            }
-            _ => {}
-        },
-        _ => panic!(),
+    if !is_type_or_empty(persistence, stream_key, PersistedType::Stream) {
+        handle_wrong_type(stream);
+        return;
     }
-}
-
-fn handle_error(stream: &mut TcpStream, msg: &str) {
-    let resp = RespData::Error(String::from(msg));
-    write_stream(stream, &resp.as_bytes());
-}
 
-fn handle_replconf(persistence: &State, stream: &mut TcpStream, vals: &[RespData]) {
-    let port_addr = stream.peer_addr().unwrap().port();
+    let mut streams = persistence.persisted.stream.lock().unwrap();
 
-    println!("REPLCONF: {:?}", vals);
+    let created = !streams.map.contains_key(stream_key);
 
-    if let RespData::BulkString(command) = &vals[1] {
-        match command.to_lowercase().as_str() {
-            "getack" => {
-                match persistence.info.write().unwrap().role.borrow_mut() {
-                    Role::Master(master) => {
-                        for slave in &master.slave_stream {
-                            let response = RespData::new_bulk_array(&["REPLFCONF", "GETACK", "*"]);
+    if created && no_mkstream {
+        write_reply(persistence, stream, &RespData::Null);
+        return;
+    }
 
-                            let mut slave_stream = slave.1.lock().unwrap();
+    let entries = streams.map.entry(stream_key.to_string()).or_default();
 
-                            slave_stream.write(&response.as_bytes()).unwrap();
+    let id = match entries.append(spec, pairs) {
+        Ok(id) => id,
+        Err(e) => {
+            // A stream only comes into existence with its first entry.
+            if created {
+                streams.map.remove(stream_key);
+            }
 
-                            let mut buf = [0; 2024];
-                            slave_stream.read(&mut buf).unwrap();
-
-                            let received = String::from_utf8_lossy(&buf).to_string();
-                            let parse = Resp::parse(received);
-
-                            match parse {
-                                Some(resp) => match resp.data {
-                                    RespData::Array(vals) => match vals.get(1).unwrap() {
-                                        RespData::BulkString(val) => {
-                                            let val_under = val.to_lowercase();
-                                            if val_under == "ack" {
-                                                let _offset = vals.get(2).unwrap();
-                                            }
-                                        }
-                                        _ => {}
-                                    },
-                                    _ => {}
-                                },
-                                None => {}
-                            }
+            match e {
+                StreamError::IdShouldBeHigher => handle_error(
+                    stream,
+                    "ERR The ID specified in XADD must be greater than 0-0",
+                ),
+                _ => handle_error(
+                    stream,
+                    "ERR The ID specified in XADD is equal or smaller than the target stream top item",
+                ),
+            }
+            return;
+        }
+    };
+
+    // Replicas must store the same ID, not generate their own.
+    let mut propagated = vec![RespData::new_bulk("XADD"), vals[1].clone()];
+    let mut trimmed = false;
+
+    if let Some(trim) = trim {
+        trimmed = entries.trim(&trim) > 0;
+        propagated.extend(exact_trim_args(entries));
+    }
+
+    propagated.push(RespData::from(id.to_string()));
+    propagated.extend_from_slice(&vals[id_idx + 1..]);
+
+    propagate(persistence, &propagated);
+    drop(streams);
+
+    persistence.blocking.signal(stream_key);
+
+    write_reply(persistence, stream, &RespData::from(id.to_string()));
+
+    if created {
+        notify_keyspace_event(persistence, notify::NEW, "new", stream_key);
+    }
+
+    notify_keyspace_event(persistence, notify::STREAM, "xadd", stream_key);
+
+    if trimmed {
+        notify_keyspace_event(persistence, notify::STREAM, "xtrim", stream_key);
+    }
+}
+
+fn handle_xtrim(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -4) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    let trim = match parse_trim(stream, vals, 2, false) {
+        Some((trim, _, _)) => trim.unwrap(),
+        None => return,
+    };
+
+    if !is_type_or_empty(persistence, key, PersistedType::Stream) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let mut streams = persistence.persisted.stream.lock().unwrap();
+
+    let deleted = match streams.map.get_mut(key) {
+        Some(entries) => {
+            let deleted = entries.trim(&trim);
+
+            if deleted > 0 {
+                let mut propagated = vec![RespData::new_bulk("XTRIM"), vals[1].clone()];
+                propagated.extend(exact_trim_args(entries));
+                propagate(persistence, &propagated);
+            }
+
+            deleted
+        }
+        None => 0,
+    };
+
+    drop(streams);
+
+    if deleted > 0 {
+        notify_keyspace_event(persistence, notify::STREAM, "xtrim", key);
+    }
+
+    write_reply(persistence, stream, &RespData::Integer(deleted as i64));
+}
+
+fn handle_xdel(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -3) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+    let mut ids: Vec<StreamId> = vec![];
+
+    for val in &vals[2..] {
+        match parse_stream_id(stream, val) {
+            Some(id) => ids.push(id),
+            None => return,
+        }
+    }
+
+    if !is_type_or_empty(persistence, key, PersistedType::Stream) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let deleted = match persistence
+        .persisted
+        .stream
+        .lock()
+        .unwrap()
+        .map
+        .get_mut(key)
+    {
+        Some(entries) => ids.into_iter().filter(|id| entries.delete(*id)).count(),
+        None => 0,
+    };
+
+    write_reply(persistence, stream, &RespData::Integer(deleted as i64));
+
+    if deleted > 0 {
+        propagate(persistence, vals);
+        notify_keyspace_event(persistence, notify::STREAM, "xdel", key);
+    }
+}
+
+fn handle_xlen(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, 2) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::Stream) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let len = match persistence.persisted.stream.lock().unwrap().map.get(key) {
+        Some(entries) => entries.len(),
+        None => 0,
+    };
+
+    write_stream(stream, &RespData::Integer(len as i64).as_bytes());
+}
+
+/// Like `check_arity`, for container commands such as `XGROUP CREATE`.
+fn check_subcommand_arity(stream: &mut Connection, vals: &[RespData], arity: i32) -> bool {
+    let len = vals.len() as i32;
+
+    if (arity > 0 && len != arity) || (arity < 0 && len < -arity) {
+        let command = vals.first().unwrap().inside_value().unwrap().to_lowercase();
+        let subcommand = vals.get(1).unwrap().inside_value().unwrap().to_lowercase();
+        handle_error(
+            stream,
+            &format!(
+                "ERR wrong number of arguments for '{}|{}' command",
+                command, subcommand
+            ),
+        );
+        return false;
+    }
+
+    true
+}
+
+fn handle_unknown_subcommand(stream: &mut Connection, vals: &[RespData]) {
+    let command = vals.first().unwrap().inside_value().unwrap().to_uppercase();
+    let subcommand = vals.get(1).unwrap().inside_value().unwrap();
+
+    handle_error(
+        stream,
+        &format!(
+            "ERR unknown subcommand '{}'. Try {} HELP.",
+            subcommand, command
+        ),
+    );
+}
+
+/// A complete stream ID argument, `<ms>` alone meaning `<ms>-0`.
+fn parse_stream_id(stream: &mut Connection, val: &RespData) -> Option<StreamId> {
+    match StreamId::parse(val.inside_value().unwrap(), 0) {
+        Ok(id) => Some(id),
+        Err(_) => {
+            handle_invalid_stream_id(stream);
+            None
+        }
+    }
+}
+
+/// Entries read back from a PEL may have been deleted from the stream
+/// since, those are replied as the ID with a null body.
+fn pending_entry_reply(id: StreamId, val: Option<&StreamVal>) -> RespData {
+    match val {
+        Some(val) => RespData::from(val),
+        None => RespData::Array(vec![RespData::from(id.to_string()), RespData::NullArray]),
+    }
+}
+
+fn handle_nogroup(stream: &mut Connection, key: &str, group: &str) {
+    handle_error(
+        stream,
+        &format!(
+            "NOGROUP No such key '{}' or consumer group '{}'",
+            key, group
+        ),
+    );
+}
+
+/// Replicas get group state changes as the XCLAIM that forces the same
+/// pending entry on them, the way Redis propagates them.
+fn propagate_xclaim(
+    persistence: &State,
+    key: &str,
+    group_name: &str,
+    group: &ConsumerGroup,
+    id: StreamId,
+) {
+    let command = match group.pending.get(&id) {
+        Some(entry) => vec![
+            "XCLAIM".to_string(),
+            key.to_string(),
+            group_name.to_string(),
+            entry.consumer.clone(),
+            "0".to_string(),
+            id.to_string(),
+            "TIME".to_string(),
+            entry.delivery_time.to_string(),
+            "RETRYCOUNT".to_string(),
+            entry.delivery_count.to_string(),
+            "FORCE".to_string(),
+            "JUSTID".to_string(),
+            "LASTID".to_string(),
+            group.last_id.to_string(),
+        ],
+        // Dropped from the PEL because the entry is gone from the stream.
+        None => vec![
+            "XACK".to_string(),
+            key.to_string(),
+            group_name.to_string(),
+            id.to_string(),
+        ],
+    };
+
+    let command: Vec<RespData> = command.into_iter().map(RespData::from).collect();
+    propagate(persistence, &command);
+}
+
+fn propagate_group_id(persistence: &State, key: &str, group_name: &str, group: &ConsumerGroup) {
+    propagate(
+        persistence,
+        &[
+            RespData::new_bulk("XGROUP"),
+            RespData::new_bulk("SETID"),
+            RespData::new_bulk(key),
+            RespData::new_bulk(group_name),
+            RespData::from(group.last_id.to_string()),
+            RespData::new_bulk("ENTRIESREAD"),
+            RespData::from(group.entries_read.map_or(-1, |read| read as i64).to_string()),
+        ],
+    );
+}
+
+fn handle_xgroup(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -2) {
+        return;
+    }
+
+    let subcommand = vals.get(1).unwrap().inside_value().unwrap().to_lowercase();
+
+    let arity = match subcommand.as_str() {
+        "create" | "setid" => -5,
+        "destroy" => 4,
+        "createconsumer" | "delconsumer" => 5,
+        _ => {
+            handle_unknown_subcommand(stream, vals);
+            return;
+        }
+    };
+
+    if !check_subcommand_arity(stream, vals, arity) {
+        return;
+    }
+
+    let key = vals.get(2).unwrap().inside_value().unwrap();
+    let group_name = vals.get(3).unwrap().inside_value().unwrap();
+
+    let mut mkstream = false;
+    let mut entries_read = None;
+
+    if subcommand == "create" || subcommand == "setid" {
+        let mut idx = 5;
+
+        while let Some(option) = vals.get(idx) {
+            match option.inside_value().unwrap().to_lowercase().as_str() {
+                "mkstream" if subcommand == "create" => mkstream = true,
+                "entriesread" if idx + 1 < vals.len() => {
+                    idx += 1;
+
+                    entries_read = match parse_int(stream, &vals[idx]) {
+                        Some(-1) => None,
+                        Some(read) if read >= 0 => Some(read as u64),
+                        Some(_) => {
+                            handle_error(stream, "ERR value for ENTRIESREAD must be positive or -1");
+                            return;
                         }
-                    }
-                    Role::Slave(_) => {
-                        let response = RespData::new_bulk_array(&["REPLCONF", "ACK", "0"]);
+                        None => return,
+                    };
+                }
+                _ => {
+                    handle_error(stream, "ERR syntax error");
+                    return;
+                }
+            }
+
+            idx += 1;
+        }
+    }
+
+    // Checked before MKSTREAM creates anything, `None` stands for `$`.
+    let id = match subcommand.as_str() {
+        "create" | "setid" => match vals.get(4).unwrap().inside_value().unwrap() {
+            "$" => None,
+            _ => match parse_stream_id(stream, vals.get(4).unwrap()) {
+                Some(id) => Some(id),
+                None => return,
+            },
+        },
+        _ => None,
+    };
+
+    if !is_type_or_empty(persistence, key, PersistedType::Stream) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let mut streams = persistence.persisted.stream.lock().unwrap();
+    let created = !streams.map.contains_key(key);
+
+    if created {
+        if !mkstream {
+            handle_error(stream, "ERR The XGROUP subcommand requires the key to exist. Note that for CREATE you may want to use the MKSTREAM option to create an empty stream automatically.");
+            return;
+        }
+
+        streams.map.insert(key.to_string(), Stream::default());
+    }
+
+    let entries = streams.map.get_mut(key).unwrap();
+
+    if matches!(
+        subcommand.as_str(),
+        "setid" | "createconsumer" | "delconsumer"
+    ) && !entries.groups.contains_key(group_name)
+    {
+        handle_error(
+            stream,
+            &format!(
+                "NOGROUP No such consumer group '{}' for key name '{}'",
+                group_name, key
+            ),
+        );
+        return;
+    }
+
+    // Whether anything changed, which the reply doesn't always tell.
+    let (reply, changed) = match subcommand.as_str() {
+        "create" | "setid" => {
+            let id = id.unwrap_or_else(|| entries.last_id());
+
+            if subcommand == "setid" {
+                let group = entries.groups.get_mut(group_name).unwrap();
+                group.last_id = id;
+                group.entries_read = entries_read;
+            } else if entries.groups.contains_key(group_name) {
+                handle_error(stream, "BUSYGROUP Consumer Group name already exists");
+                return;
+            } else {
+                entries.groups.insert(
+                    group_name.to_string(),
+                    ConsumerGroup::new(id, entries_read),
+                );
+            }
+
+            (RespData::new_simple_string("OK"), true)
+        }
+        "destroy" => {
+            let destroyed = entries.groups.remove(group_name).is_some();
+
+            // Readers blocked on the group find out it is gone.
+            if destroyed {
+                persistence.blocking.signal(key);
+            }
+
+            (RespData::Integer(destroyed as i64), destroyed)
+        }
+        "createconsumer" => {
+            let consumer = vals.get(4).unwrap().inside_value().unwrap();
+            let group = entries.groups.get_mut(group_name).unwrap();
+            let created = group.create_consumer(consumer);
+
+            (RespData::Integer(created as i64), created)
+        }
+        _ => {
+            let consumer = vals.get(4).unwrap().inside_value().unwrap();
+            let group = entries.groups.get_mut(group_name).unwrap();
+
+            match group.delete_consumer(consumer) {
+                Some(pending) => (RespData::Integer(pending as i64), true),
+                None => (RespData::Integer(0), false),
+            }
+        }
+    };
+
+    if changed {
+        propagate(persistence, vals);
+    }
+
+    drop(streams);
+
+    if created {
+        notify_keyspace_event(persistence, notify::NEW, "new", key);
+    }
+
+    if changed {
+        let event = format!("xgroup-{}", subcommand);
+        notify_keyspace_event(persistence, notify::STREAM, &event, key);
+    }
+
+    write_reply(persistence, stream, &reply);
+}
+
+struct XReadArgs {
+    group: Option<(String, String)>,
+    count: usize,
+    block: Option<Duration>,
+    no_ack: bool,
+    keys: Vec<String>,
+    ids: Vec<String>,
+}
+
+fn parse_xread(stream: &mut Connection, vals: &[RespData], group: bool) -> Option<XReadArgs> {
+    let command = vals.first().unwrap().inside_value().unwrap().to_lowercase();
 
-                        stream.write(&response.as_bytes()).unwrap();
+    let mut args = XReadArgs {
+        group: None,
+        count: 0,
+        block: None,
+        no_ack: false,
+        keys: vec![],
+        ids: vec![],
+    };
+
+    let mut idx = 1;
+
+    while let Some(option) = vals.get(idx) {
+        let more = vals.len() - idx - 1;
+
+        match option.inside_value().unwrap().to_lowercase().as_str() {
+            "block" if more >= 1 => {
+                let timeout = vals[idx + 1]
+                    .inside_value()
+                    .and_then(|v| v.parse::<i64>().ok());
+
+                args.block = match timeout {
+                    Some(ms) if ms < 0 => {
+                        handle_error(stream, "ERR timeout is negative");
+                        return None;
+                    }
+                    Some(ms) => Some(Duration::from_millis(ms as u64)),
+                    None => {
+                        handle_error(stream, "ERR timeout is not an integer or out of range");
+                        return None;
                     }
                 };
+                idx += 1;
             }
-            "listening-port" => {
-                if let Role::Master(master) = persistence.info.write().unwrap().role.borrow_mut() {
-                    if let Some(RespData::BulkString(_v)) = vals.get(2) {
-                        master.slave_ports.push(port_addr);
-                        master
-                            .slave_stream
-                            .insert(port_addr, Arc::new(Mutex::new(stream.try_clone().unwrap())));
-                        write_stream(stream, b"+OK\r\n");
-                    }
-                } else {
-                    handle_error(stream, "Slave can't treat REPLCONF");
+            "count" if more >= 1 => {
+                args.count = parse_int(stream, &vals[idx + 1])?.max(0) as usize;
+                idx += 1;
+            }
+            "streams" if more >= 1 => {
+                let streams = string_args(&vals[idx + 1..]);
+
+                if !streams.len().is_multiple_of(2) {
+                    handle_error(
+                        stream,
+                        &format!(
+                            "ERR Unbalanced '{}' list of streams: for each stream key an ID or '{}' must be specified.",
+                            command,
+                            if group { '>' } else { '$' }
+                        ),
+                    );
+                    return None;
+                }
+
+                let (keys, ids) = streams.split_at(streams.len() / 2);
+                args.keys = keys.to_vec();
+                args.ids = ids.to_vec();
+                break;
+            }
+            "group" if more >= 2 => {
+                if !group {
+                    handle_error(stream, "ERR The GROUP option is only supported by XREADGROUP. You called XREAD instead.");
+                    return None;
+                }
+
+                args.group = Some((
+                    vals[idx + 1].inside_value().unwrap().to_string(),
+                    vals[idx + 2].inside_value().unwrap().to_string(),
+                ));
+                idx += 2;
+            }
+            "noack" => {
+                if !group {
+                    handle_error(stream, "ERR The NOACK option is only supported by XREADGROUP. You called XREAD instead.");
+                    return None;
                 }
+
+                args.no_ack = true;
             }
             _ => {
-                write_stream(stream, b"+OK\r\n");
+                handle_error(stream, "ERR syntax error");
+                return None;
             }
-        };
-    };
+        }
+
+        idx += 1;
+    }
+
+    if args.keys.is_empty() {
+        handle_error(stream, "ERR syntax error");
+        return None;
+    }
+
+    if group && args.group.is_none() {
+        handle_error(stream, "ERR Missing GROUP option for XREADGROUP");
+        return None;
+    }
+
+    Some(args)
 }
 
-fn handle_psync(persistence: &State, stream: &mut TcpStream, _vals: &[RespData]) {
-    let role = &persistence.info.read().unwrap().role;
+/// One XREADGROUP pass over every stream. `None` means nothing could be
+/// served and the caller may block, an error means a key or the group
+/// went away meanwhile.
+fn read_groups(
+    persistence: &State,
+    args: &XReadArgs,
+    from: &[Option<StreamId>],
+) -> Option<Result<RespData, String>> {
+    let (group_name, consumer) = args.group.as_ref().unwrap();
+    let mut streams = persistence.persisted.stream.lock().unwrap();
 
-    let (rep_id, offset) = match role {
-        Role::Master(master) => (&master.replication_id, master.offset),
-        Role::Slave(_) => {
-            handle_error(stream, "Slave can't handle PSYNC");
-            return;
+    let mut reply: Vec<RespData> = vec![];
+
+    for (key, from) in args.keys.iter().zip(from) {
+        let entries = match streams.map.get_mut(key) {
+            Some(entries) if entries.groups.contains_key(group_name) => entries,
+            _ => {
+                return Some(Err(format!(
+                "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                key, group_name
+            )))
+            }
+        };
+
+        if entries
+            .groups
+            .get_mut(group_name)
+            .unwrap()
+            .touch_consumer(consumer)
+        {
+            propagate(
+                persistence,
+                &[
+                    RespData::new_bulk("XGROUP"),
+                    RespData::new_bulk("CREATECONSUMER"),
+                    RespData::new_bulk(key),
+                    RespData::new_bulk(group_name),
+                    RespData::new_bulk(consumer),
+                ],
+            );
         }
+
+        let served: Vec<RespData> = match from {
+            Some(after) => entries
+                .read_group_history(group_name, consumer, *after, args.count)
+                .into_iter()
+                .map(|(id, val)| pending_entry_reply(id, val.as_ref()))
+                .collect(),
+            None => {
+                let read = entries.read_group_new(group_name, consumer, args.count, args.no_ack);
+
+                if read.is_empty() {
+                    continue;
+                }
+
+                let group = &entries.groups[group_name];
+
+                // Entries nobody will acknowledge only move the group forward.
+                if !args.no_ack {
+                    for val in &read {
+                        propagate_xclaim(persistence, key, group_name, group, val.id);
+                    }
+                }
+
+                propagate_group_id(persistence, key, group_name, group);
+
+                read.iter().map(RespData::from).collect()
+            }
+        };
+
+        reply.push(RespData::Array(vec![
+            RespData::from(key.to_string()),
+            RespData::Array(served),
+        ]));
+    }
+
+    match reply.is_empty() {
+        true => None,
+        false => Some(Ok(RespData::Array(reply))),
+    }
+}
+
+fn handle_xreadgroup(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -7) {
+        return;
+    }
+
+    let args = match parse_xread(stream, vals, true) {
+        Some(args) => args,
+        None => return,
     };
 
-    write_stream(
-        stream,
-        &RespData::SimpleString(format!("FULLRESYNC {} {}", rep_id, offset)).as_bytes(),
-    );
+    let (group_name, _) = args.group.as_ref().unwrap();
+    let mut from: Vec<Option<StreamId>> = vec![];
+
+    for (key, id) in args.keys.iter().zip(&args.ids) {
+        if !is_type_or_empty(persistence, key, PersistedType::Stream) {
+            handle_wrong_type(stream);
+            return;
+        }
 
-    let empty_rdb = "524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2";
+        let exists = persistence
+            .persisted
+            .stream
+            .lock()
+            .unwrap()
+            .map
+            .get(key)
+            .is_some_and(|entries| entries.groups.contains_key(group_name));
 
-    let hex: Result<Vec<u8>, ParseIntError> = (0..empty_rdb.len())
-        .step_by(2)
-        .map(|i| u8::from_str_radix(&empty_rdb[i..i + 2], 16))
-        .collect();
+        if !exists {
+            handle_error(
+                stream,
+                &format!(
+                    "NOGROUP No such key '{}' or consumer group '{}' in XREADGROUP with GROUP option",
+                    key, group_name
+                ),
+            );
+            return;
+        }
 
-    let res = hex.unwrap();
-
-    let size = format!("${}\r\n", res.len());
-
-    write_stream(stream, size.as_bytes());
-    write_stream(stream, &res);
-}
-
-pub fn handle_request(persistence: &State, stream: &mut TcpStream, req: &Resp) {
-    match &req.data {
-        RespData::Array(vals) => match vals.get(0).unwrap() {
-            RespData::BulkString(command) => match command.to_lowercase().as_str() {
-                "xread" => handle_xread(persistence, stream, vals),
-                "xadd" => handle_xadd(persistence, stream, vals),
-                "xrange" => handle_xrange(persistence, stream, vals),
-                "ping" => handle_ping(stream),
-                "echo" => handle_echo(stream, vals.get(1).unwrap()),
-                "set" => handle_set(persistence, stream, vals),
-                "get" => handle_get(persistence, stream, vals),
-                "type" => handle_type(persistence, stream, vals),
-                "info" => handle_info(persistence, stream, vals),
-                "replconf" => handle_replconf(persistence, stream, vals),
-                "psync" => handle_psync(persistence, stream, vals),
-                _ => handle_error(stream, "Unexpected command"),
+        match id.as_str() {
+            ">" => from.push(None),
+            "$" => {
+                handle_error(stream, "ERR The $ ID is meaningless in the context of XREADGROUP: you want to read the history of this consumer by specifying a proper ID, or use the > ID to get new messages. The $ ID would just return an empty result set.");
+                return;
+            }
+            id => match StreamId::parse(id, 0) {
+                Ok(id) => from.push(Some(id)),
+                Err(_) => {
+                    handle_invalid_stream_id(stream);
+                    return;
+                }
             },
-            _ => handle_error(stream, "Unexpected data type"),
-        },
-        _ => handle_error(stream, "Unexpected data type"),
+        }
+    }
+
+    // Reading a consumer's history never blocks.
+    let timeout = args.block.filter(|_| from.iter().all(Option::is_none));
+
+    let served = block_on(persistence, stream, &args.keys, timeout, || {
+        read_groups(persistence, &args, &from)
+    });
+
+    match served {
+        Some(Ok(reply)) => write_stream(stream, &reply.as_bytes()),
+        Some(Err(e)) => handle_error(stream, &e),
+        None => write_stream(stream, &RespData::NullArray.as_bytes()),
+    }
+}
+
+fn handle_xack(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -4) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+    let group_name = vals.get(2).unwrap().inside_value().unwrap();
+
+    let mut ids: Vec<StreamId> = vec![];
+
+    for val in &vals[3..] {
+        match parse_stream_id(stream, val) {
+            Some(id) => ids.push(id),
+            None => return,
+        }
+    }
+
+    if !is_type_or_empty(persistence, key, PersistedType::Stream) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let acked = {
+        let mut streams = persistence.persisted.stream.lock().unwrap();
+
+        match streams
+            .map
+            .get_mut(key)
+            .and_then(|entries| entries.groups.get_mut(group_name))
+        {
+            Some(group) => ids.into_iter().filter(|id| group.ack(*id)).count(),
+            None => 0,
+        }
+    };
+
+    write_reply(persistence, stream, &RespData::Integer(acked as i64));
+
+    if acked > 0 {
+        propagate(persistence, vals);
+    }
+}
+
+fn handle_xpending(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -3) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+    let group_name = vals.get(2).unwrap().inside_value().unwrap();
+
+    let mut args = &vals[3..];
+    let mut min_idle: u64 = 0;
+
+    if args
+        .first()
+        .is_some_and(|arg| arg.inside_value().unwrap().eq_ignore_ascii_case("idle"))
+    {
+        if args.len() < 5 {
+            handle_error(stream, "ERR syntax error");
+            return;
+        }
+
+        min_idle = match parse_int(stream, &args[1]) {
+            Some(idle) => idle.max(0) as u64,
+            None => return,
+        };
+        args = &args[2..];
+    }
+
+    // start, end, count and an optional consumer.
+    let extended = match args.len() {
+        0 => None,
+        3 | 4 => {
+            let start = match parse_interval_id(stream, &args[0], true) {
+                Some(start) => start,
+                None => return,
+            };
+
+            let end = match parse_interval_id(stream, &args[1], false) {
+                Some(end) => end,
+                None => return,
+            };
+
+            let count = match parse_int(stream, &args[2]) {
+                Some(count) => count.max(0) as usize,
+                None => return,
+            };
+
+            let consumer = args.get(3).map(|c| c.inside_value().unwrap().to_string());
+
+            Some((start, end, count, consumer))
+        }
+        _ => {
+            handle_error(stream, "ERR syntax error");
+            return;
+        }
+    };
+
+    if !is_type_or_empty(persistence, key, PersistedType::Stream) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let streams = persistence.persisted.stream.lock().unwrap();
+
+    let group = match streams
+        .map
+        .get(key)
+        .and_then(|entries| entries.groups.get(group_name))
+    {
+        Some(group) => group,
+        None => {
+            handle_nogroup(stream, key, group_name);
+            return;
+        }
+    };
+
+    let now = now_ms();
+
+    let reply = match extended {
+        None => {
+            let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+
+            for entry in group.pending.values() {
+                *counts.entry(&entry.consumer).or_default() += 1;
+            }
+
+            match (
+                group.pending.first_key_value(),
+                group.pending.last_key_value(),
+            ) {
+                (Some((first, _)), Some((last, _))) => RespData::Array(vec![
+                    RespData::Integer(group.pending.len() as i64),
+                    RespData::from(first.to_string()),
+                    RespData::from(last.to_string()),
+                    RespData::Array(
+                        counts
+                            .into_iter()
+                            .map(|(consumer, count)| {
+                                bulk_array(vec![consumer.to_string(), count.to_string()])
+                            })
+                            .collect(),
+                    ),
+                ]),
+                _ => RespData::Array(vec![
+                    RespData::Integer(0),
+                    RespData::Null,
+                    RespData::Null,
+                    RespData::NullArray,
+                ]),
+            }
+        }
+        Some((start, end, count, consumer)) => {
+            let ids: Box<dyn Iterator<Item = &StreamId>> = match &consumer {
+                Some(consumer) => match group.consumers.get(consumer) {
+                    Some(consumer) if start <= end => Box::new(consumer.pending.range(start..=end)),
+                    _ => Box::new(std::iter::empty()),
+                },
+                None if start <= end => {
+                    Box::new(group.pending.range(start..=end).map(|(id, _)| id))
+                }
+                None => Box::new(std::iter::empty()),
+            };
+
+            RespData::Array(
+                ids.filter_map(|id| {
+                    let entry = &group.pending[id];
+                    let idle = now.saturating_sub(entry.delivery_time);
+
+                    (idle >= min_idle).then(|| {
+                        RespData::Array(vec![
+                            RespData::from(id.to_string()),
+                            RespData::from(entry.consumer.clone()),
+                            RespData::Integer(idle as i64),
+                            RespData::Integer(entry.delivery_count as i64),
+                        ])
+                    })
+                })
+                .take(count)
+                .collect(),
+            )
+        }
+    };
+
+    write_stream(stream, &reply.as_bytes());
+}
+
+fn parse_min_idle(stream: &mut Connection, val: &RespData, command: &str) -> Option<u64> {
+    match val.inside_value().and_then(|v| v.parse::<i64>().ok()) {
+        Some(idle) => Some(idle.max(0) as u64),
+        None => {
+            handle_error(
+                stream,
+                &format!("ERR Invalid min-idle-time argument for {}", command),
+            );
+            None
+        }
+    }
+}
+
+fn handle_xclaim(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -6) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+    let group_name = vals.get(2).unwrap().inside_value().unwrap();
+    let consumer = vals.get(3).unwrap().inside_value().unwrap();
+
+    let min_idle = match parse_min_idle(stream, vals.get(4).unwrap(), "XCLAIM") {
+        Some(idle) => idle,
+        None => return,
+    };
+
+    // IDs run until the first argument that isn't one.
+    let mut ids: Vec<StreamId> = vec![];
+    let mut idx = 5;
+
+    while let Some(Ok(id)) = vals
+        .get(idx)
+        .map(|val| StreamId::parse(val.inside_value().unwrap(), 0))
+    {
+        ids.push(id);
+        idx += 1;
+    }
+
+    let now = now_ms();
+
+    let mut options = ClaimOptions {
+        min_idle,
+        delivery_time: now,
+        retry_count: None,
+        force: false,
+        just_id: false,
+    };
+    let mut delivery_time: Option<i64> = None;
+    let mut last_id: Option<StreamId> = None;
+
+    while let Some(option) = vals.get(idx) {
+        let option = option.inside_value().unwrap();
+        let arg = vals.get(idx + 1);
+
+        let invalid = |stream: &mut Connection, name: &str| {
+            handle_error(
+                stream,
+                &format!("ERR Invalid {} option argument for XCLAIM", name),
+            )
+        };
+
+        match (option.to_lowercase().as_str(), arg) {
+            ("force", _) => options.force = true,
+            ("justid", _) => options.just_id = true,
+            ("idle", Some(arg)) => {
+                match arg.inside_value().and_then(|v| v.parse::<i64>().ok()) {
+                    Some(idle) => delivery_time = Some(now as i64 - idle),
+                    None => return invalid(stream, "IDLE"),
+                }
+                idx += 1;
+            }
+            ("time", Some(arg)) => {
+                match arg.inside_value().and_then(|v| v.parse::<i64>().ok()) {
+                    Some(time) => delivery_time = Some(time),
+                    None => return invalid(stream, "TIME"),
+                }
+                idx += 1;
+            }
+            ("retrycount", Some(arg)) => {
+                match arg.inside_value().and_then(|v| v.parse::<i64>().ok()) {
+                    Some(count) => options.retry_count = Some(count.max(0) as u64),
+                    None => return invalid(stream, "RETRYCOUNT"),
+                }
+                idx += 1;
+            }
+            ("lastid", Some(arg)) => {
+                match parse_stream_id(stream, arg) {
+                    Some(id) => last_id = Some(id),
+                    None => return,
+                }
+                idx += 1;
+            }
+            _ => {
+                handle_error(
+                    stream,
+                    &format!("ERR Unrecognized XCLAIM option '{}'", option),
+                );
+                return;
+            }
+        }
+
+        idx += 1;
+    }
+
+    // Delivery times in the future or before the epoch mean now.
+    if let Some(time) = delivery_time {
+        if time >= 0 && time as u64 <= now {
+            options.delivery_time = time as u64;
+        }
+    }
+
+    if !is_type_or_empty(persistence, key, PersistedType::Stream) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let mut streams = persistence.persisted.stream.lock().unwrap();
+
+    let entries = match streams.map.get_mut(key) {
+        Some(entries) if entries.groups.contains_key(group_name) => entries,
+        _ => {
+            handle_nogroup(stream, key, group_name);
+            return;
+        }
+    };
+
+    let mut moved_last_id = false;
+
+    if let Some(last_id) = last_id {
+        let group = entries.groups.get_mut(group_name).unwrap();
+
+        if last_id > group.last_id {
+            group.last_id = last_id;
+            moved_last_id = true;
+        }
+    }
+
+    let mut reply: Vec<RespData> = vec![];
+    let mut propagated = false;
+
+    for id in ids {
+        match entries.claim(group_name, consumer, id, options) {
+            ClaimOutcome::Claimed(val) => {
+                reply.push(match val {
+                    Some(val) => RespData::from(&val),
+                    None => RespData::from(id.to_string()),
+                });
+            }
+            ClaimOutcome::Deleted => {}
+            ClaimOutcome::Skipped => continue,
+        }
+
+        propagate_xclaim(
+            persistence,
+            key,
+            group_name,
+            &entries.groups[group_name],
+            id,
+        );
+        propagated = true;
+    }
+
+    if moved_last_id && !propagated {
+        propagate_group_id(persistence, key, group_name, &entries.groups[group_name]);
+    }
+
+    drop(streams);
+
+    write_reply(persistence, stream, &RespData::Array(reply));
+}
+
+fn handle_xautoclaim(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -6) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+    let group_name = vals.get(2).unwrap().inside_value().unwrap();
+    let consumer = vals.get(3).unwrap().inside_value().unwrap();
+
+    let min_idle = match parse_min_idle(stream, vals.get(4).unwrap(), "XAUTOCLAIM") {
+        Some(idle) => idle,
+        None => return,
+    };
+
+    let start = match parse_interval_id(stream, vals.get(5).unwrap(), true) {
+        Some(start) => start,
+        None => return,
+    };
+
+    let mut count: usize = 100;
+    let mut just_id = false;
+    let mut options = vals[6..].iter();
+
+    while let Some(option) = options.next() {
+        match option.inside_value().unwrap().to_lowercase().as_str() {
+            "justid" => just_id = true,
+            "count" => {
+                let val = match options.next() {
+                    Some(val) => val,
+                    None => {
+                        handle_error(stream, "ERR syntax error");
+                        return;
+                    }
+                };
+
+                count = match parse_int(stream, val) {
+                    Some(c) if (1..=i64::MAX / 10).contains(&c) => c as usize,
+                    Some(_) => {
+                        handle_error(stream, "ERR COUNT must be > 0");
+                        return;
+                    }
+                    None => return,
+                };
+            }
+            _ => {
+                handle_error(stream, "ERR syntax error");
+                return;
+            }
+        }
+    }
+
+    if !is_type_or_empty(persistence, key, PersistedType::Stream) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let mut streams = persistence.persisted.stream.lock().unwrap();
+
+    let entries = match streams.map.get_mut(key) {
+        Some(entries) if entries.groups.contains_key(group_name) => entries,
+        _ => {
+            handle_nogroup(stream, key, group_name);
+            return;
+        }
+    };
+
+    let options = ClaimOptions {
+        min_idle,
+        delivery_time: now_ms(),
+        retry_count: None,
+        force: false,
+        just_id,
+    };
+
+    let (next, claimed, deleted) = entries.autoclaim(group_name, consumer, start, count, options);
+
+    for id in claimed.iter().map(|(id, _)| id).chain(&deleted) {
+        propagate_xclaim(
+            persistence,
+            key,
+            group_name,
+            &entries.groups[group_name],
+            *id,
+        );
+    }
+
+    drop(streams);
+
+    let claimed = claimed
+        .into_iter()
+        .map(|(id, val)| match val {
+            Some(val) => RespData::from(&val),
+            None => RespData::from(id.to_string()),
+        })
+        .collect();
+
+    let reply = RespData::Array(vec![
+        RespData::from(next.to_string()),
+        RespData::Array(claimed),
+        bulk_array(deleted.iter().map(StreamId::to_string).collect()),
+    ]);
+
+    write_reply(persistence, stream, &reply);
+}
+
+fn optional_integer(val: Option<u64>) -> RespData {
+    match val {
+        Some(val) => RespData::Integer(val as i64),
+        None => RespData::Null,
+    }
+}
+
+fn xinfo_stream_reply(entries: &Stream, full: Option<usize>) -> RespData {
+    let mut reply = vec![
+        RespData::new_bulk("length"),
+        RespData::Integer(entries.len() as i64),
+        RespData::new_bulk("radix-tree-keys"),
+        RespData::Integer(entries.node_count() as i64),
+        RespData::new_bulk("radix-tree-nodes"),
+        RespData::Integer(entries.node_count() as i64),
+        RespData::new_bulk("last-generated-id"),
+        RespData::from(entries.last_id().to_string()),
+        RespData::new_bulk("max-deleted-entry-id"),
+        RespData::from(entries.max_deleted_id().to_string()),
+        RespData::new_bulk("entries-added"),
+        RespData::Integer(entries.entries_added() as i64),
+        RespData::new_bulk("recorded-first-entry-id"),
+        RespData::from(entries.first_id().to_string()),
+    ];
+
+    let count = match full {
+        Some(count) => count,
+        None => {
+            reply.extend([
+                RespData::new_bulk("groups"),
+                RespData::Integer(entries.groups.len() as i64),
+                RespData::new_bulk("first-entry"),
+                entries.first_entry().map_or(RespData::Null, RespData::from),
+                RespData::new_bulk("last-entry"),
+                entries.last_entry().map_or(RespData::Null, RespData::from),
+            ]);
+
+            return RespData::Array(reply);
+        }
+    };
+
+    let count = if count == 0 { usize::MAX } else { count };
+
+    let stream_entries = entries
+        .range(StreamId::MIN, StreamId::MAX)
+        .take(count)
+        .map(RespData::from)
+        .collect();
+
+    let groups = entries
+        .groups
+        .iter()
+        .map(|(name, group)| {
+            let pending = group
+                .pending
+                .iter()
+                .take(count)
+                .map(|(id, entry)| {
+                    RespData::Array(vec![
+                        RespData::from(id.to_string()),
+                        RespData::new_bulk(&entry.consumer),
+                        RespData::Integer(entry.delivery_time as i64),
+                        RespData::Integer(entry.delivery_count as i64),
+                    ])
+                })
+                .collect();
+
+            let consumers = group
+                .consumers
+                .iter()
+                .map(|(consumer_name, consumer)| {
+                    let pending = consumer
+                        .pending
+                        .iter()
+                        .take(count)
+                        .map(|id| {
+                            let entry = &group.pending[id];
+
+                            RespData::Array(vec![
+                                RespData::from(id.to_string()),
+                                RespData::Integer(entry.delivery_time as i64),
+                                RespData::Integer(entry.delivery_count as i64),
+                            ])
+                        })
+                        .collect();
+
+                    RespData::Array(vec![
+                        RespData::new_bulk("name"),
+                        RespData::new_bulk(consumer_name),
+                        RespData::new_bulk("seen-time"),
+                        RespData::Integer(consumer.seen_time as i64),
+                        RespData::new_bulk("active-time"),
+                        RespData::Integer(consumer.active_time.map_or(-1, |t| t as i64)),
+                        RespData::new_bulk("pel-count"),
+                        RespData::Integer(consumer.pending.len() as i64),
+                        RespData::new_bulk("pending"),
+                        RespData::Array(pending),
+                    ])
+                })
+                .collect();
+
+            RespData::Array(vec![
+                RespData::new_bulk("name"),
+                RespData::new_bulk(name),
+                RespData::new_bulk("last-delivered-id"),
+                RespData::from(group.last_id.to_string()),
+                RespData::new_bulk("entries-read"),
+                optional_integer(group.entries_read),
+                RespData::new_bulk("lag"),
+                optional_integer(entries.lag(group)),
+                RespData::new_bulk("pel-count"),
+                RespData::Integer(group.pending.len() as i64),
+                RespData::new_bulk("pending"),
+                RespData::Array(pending),
+                RespData::new_bulk("consumers"),
+                RespData::Array(consumers),
+            ])
+        })
+        .collect();
+
+    reply.extend([
+        RespData::new_bulk("entries"),
+        RespData::Array(stream_entries),
+        RespData::new_bulk("groups"),
+        RespData::Array(groups),
+    ]);
+
+    RespData::Array(reply)
+}
+
+fn handle_xinfo(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -2) {
+        return;
+    }
+
+    let subcommand = vals.get(1).unwrap().inside_value().unwrap().to_lowercase();
+
+    let arity = match subcommand.as_str() {
+        "stream" => -3,
+        "groups" => 3,
+        "consumers" => 4,
+        _ => {
+            handle_unknown_subcommand(stream, vals);
+            return;
+        }
+    };
+
+    if !check_subcommand_arity(stream, vals, arity) {
+        return;
+    }
+
+    let key = vals.get(2).unwrap().inside_value().unwrap();
+
+    // `XINFO STREAM key [FULL [COUNT count]]`, COUNT defaults to 10.
+    let mut full = None;
+
+    if subcommand == "stream" && vals.len() > 3 {
+        let option = |idx: usize| vals[idx].inside_value().unwrap().to_lowercase();
+
+        if option(3) != "full" || !matches!(vals.len(), 4 | 6) {
+            handle_error(stream, "ERR syntax error");
+            return;
+        }
+
+        full = Some(10);
+
+        if vals.len() == 6 {
+            if option(4) != "count" {
+                handle_error(stream, "ERR syntax error");
+                return;
+            }
+
+            full = match parse_int(stream, &vals[5]) {
+                Some(count) => Some(count.max(0) as usize),
+                None => return,
+            };
+        }
+    }
+
+    if !is_type_or_empty(persistence, key, PersistedType::Stream) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let streams = persistence.persisted.stream.lock().unwrap();
+
+    let entries = match streams.map.get(key) {
+        Some(entries) => entries,
+        None => {
+            handle_error(stream, "ERR no such key");
+            return;
+        }
+    };
+
+    let reply = match subcommand.as_str() {
+        "stream" => xinfo_stream_reply(entries, full),
+        "groups" => RespData::Array(
+            entries
+                .groups
+                .iter()
+                .map(|(name, group)| {
+                    RespData::Array(vec![
+                        RespData::new_bulk("name"),
+                        RespData::new_bulk(name),
+                        RespData::new_bulk("consumers"),
+                        RespData::Integer(group.consumers.len() as i64),
+                        RespData::new_bulk("pending"),
+                        RespData::Integer(group.pending.len() as i64),
+                        RespData::new_bulk("last-delivered-id"),
+                        RespData::from(group.last_id.to_string()),
+                        RespData::new_bulk("entries-read"),
+                        optional_integer(group.entries_read),
+                        RespData::new_bulk("lag"),
+                        optional_integer(entries.lag(group)),
+                    ])
+                })
+                .collect(),
+        ),
+        _ => {
+            let group_name = vals.get(3).unwrap().inside_value().unwrap();
+
+            let group = match entries.groups.get(group_name) {
+                Some(group) => group,
+                None => {
+                    handle_error(
+                        stream,
+                        &format!(
+                            "NOGROUP No such consumer group '{}' for key name '{}'",
+                            group_name, key
+                        ),
+                    );
+                    return;
+                }
+            };
+
+            let now = now_ms();
+
+            RespData::Array(
+                group
+                    .consumers
+                    .iter()
+                    .map(|(name, consumer)| {
+                        let inactive = consumer
+                            .active_time
+                            .map_or(-1, |t| now.saturating_sub(t) as i64);
+
+                        RespData::Array(vec![
+                            RespData::new_bulk("name"),
+                            RespData::new_bulk(name),
+                            RespData::new_bulk("pending"),
+                            RespData::Integer(consumer.pending.len() as i64),
+                            RespData::new_bulk("idle"),
+                            RespData::Integer(now.saturating_sub(consumer.seen_time) as i64),
+                            RespData::new_bulk("inactive"),
+                            RespData::Integer(inactive),
+                        ])
+                    })
+                    .collect(),
+            )
+        }
+    };
+
+    drop(streams);
+
+    write_stream(stream, &reply.as_bytes());
+}
+
+fn handle_xsetid(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -3) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    let last_id = match parse_stream_id(stream, vals.get(2).unwrap()) {
+        Some(id) => id,
+        None => return,
+    };
+
+    let mut entries_added = None;
+    let mut max_deleted_id = StreamId::MIN;
+    let mut idx = 3;
+
+    while let Some(option) = vals.get(idx) {
+        match option.inside_value().unwrap().to_lowercase().as_str() {
+            "entriesadded" if idx + 1 < vals.len() => {
+                entries_added = match parse_int(stream, &vals[idx + 1]) {
+                    Some(added) if added >= 0 => Some(added as u64),
+                    Some(_) => {
+                        handle_error(stream, "ERR entries_added must be positive");
+                        return;
+                    }
+                    None => return,
+                };
+            }
+            "maxdeletedid" if idx + 1 < vals.len() => {
+                max_deleted_id = match parse_stream_id(stream, &vals[idx + 1]) {
+                    Some(id) => id,
+                    None => return,
+                };
+
+                if last_id < max_deleted_id {
+                    handle_error(stream, "ERR The ID specified in XSETID is smaller than the provided max_deleted_entry_id");
+                    return;
+                }
+            }
+            _ => {
+                handle_error(stream, "ERR syntax error");
+                return;
+            }
+        }
+
+        idx += 2;
+    }
+
+    if !is_type_or_empty(persistence, key, PersistedType::Stream) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let mut streams = persistence.persisted.stream.lock().unwrap();
+
+    let entries = match streams.map.get_mut(key) {
+        Some(entries) => entries,
+        None => {
+            handle_error(stream, "ERR no such key");
+            return;
+        }
+    };
+
+    if entries_added.is_some_and(|added| (entries.len() as u64) > added) {
+        handle_error(stream, "ERR The entries_added specified in XSETID is smaller than the target stream length");
+        return;
+    }
+
+    if !entries.is_empty() && last_id < entries.last_entry().unwrap().id {
+        handle_error(stream, "ERR The ID specified in XSETID is smaller than the target stream top item");
+        return;
+    }
+
+    entries.set_id(last_id, entries_added, max_deleted_id);
+
+    drop(streams);
+
+    propagate(persistence, vals);
+    notify_keyspace_event(persistence, notify::STREAM, "xsetid", key);
+    write_reply(persistence, stream, &RespData::new_simple_string("OK"));
+}
+
+fn handle_sadd(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -3) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::Set) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let (added, created) = {
+        let mut sets = persistence.persisted.set.lock().unwrap();
+        let created = !sets.0.contains_key(key);
+        let set = sets.0.entry(key.to_string()).or_default();
+
+        let added = string_args(&vals[2..])
+            .iter()
+            .filter(|member| set.add(member))
+            .count();
+
+        (added, created)
+    };
+
+    write_reply(persistence, stream, &RespData::Integer(added as i64));
+
+    if added > 0 {
+        propagate(persistence, vals);
+
+        if created {
+            notify_keyspace_event(persistence, notify::NEW, "new", key);
+        }
+
+        notify_keyspace_event(persistence, notify::SET, "sadd", key);
+    }
+}
+
+fn handle_srem(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -3) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::Set) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let (removed, emptied) = {
+        let mut sets = persistence.persisted.set.lock().unwrap();
+        let removed = sets.remove(key, &string_args(&vals[2..]));
+
+        (removed, !sets.0.contains_key(key))
+    };
+
+    write_reply(persistence, stream, &RespData::Integer(removed as i64));
+
+    if removed > 0 {
+        propagate(persistence, vals);
+        notify_keyspace_event(persistence, notify::SET, "srem", key);
+
+        if emptied {
+            notify_keyspace_event(persistence, notify::GENERIC, "del", key);
+        }
+    }
+}
+
+fn handle_smembers(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, 2) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::Set) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let members = match persistence.persisted.set.lock().unwrap().0.get(key) {
+        Some(set) => set.members(),
+        None => vec![],
+    };
+
+    write_stream(stream, &bulk_array(members).as_bytes());
+}
+
+fn handle_sismember(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, 3) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+    let member = vals.get(2).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::Set) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let is_member = match persistence.persisted.set.lock().unwrap().0.get(key) {
+        Some(set) => set.contains(member),
+        None => false,
+    };
+
+    write_stream(stream, &RespData::Integer(is_member as i64).as_bytes());
+}
+
+fn handle_smismember(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -3) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::Set) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let sets = persistence.persisted.set.lock().unwrap();
+    let set = sets.0.get(key);
+
+    let flags = string_args(&vals[2..])
+        .iter()
+        .map(|member| {
+            let is_member = set.map(|s| s.contains(member)).unwrap_or(false);
+            RespData::Integer(is_member as i64)
+        })
+        .collect();
+
+    write_stream(stream, &RespData::Array(flags).as_bytes());
+}
+
+fn handle_scard(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, 2) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::Set) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let card = match persistence.persisted.set.lock().unwrap().0.get(key) {
+        Some(set) => set.len(),
+        None => 0,
+    };
+
+    write_stream(stream, &RespData::Integer(card as i64).as_bytes());
+}
+
+fn handle_spop(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -2) {
+        return;
+    }
+
+    if vals.len() > 3 {
+        handle_error(stream, "ERR syntax error");
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    let count = match vals.get(2) {
+        Some(val) => match parse_int(stream, val) {
+            Some(count) if count < 0 => {
+                handle_error(stream, "ERR value is out of range, must be positive");
+                return;
+            }
+            Some(count) => Some(count as usize),
+            None => return,
+        },
+        None => None,
+    };
+
+    if !is_type_or_empty(persistence, key, PersistedType::Set) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let (popped, emptied) = {
+        let mut sets = persistence.persisted.set.lock().unwrap();
+        let popped = sets.pop(key, count.unwrap_or(1));
+
+        (popped, !sets.0.contains_key(key))
+    };
+
+    let reply = match count {
+        Some(_) => bulk_array(popped.clone()),
+        None => match popped.first() {
+            Some(member) => RespData::new_bulk(member),
+            None => RespData::Null,
+        },
+    };
+
+    write_reply(persistence, stream, &reply);
+
+    // Replicas must drop the same members, so the random pick is propagated
+    // as an explicit SREM.
+    if !popped.is_empty() {
+        let mut srem = vec![RespData::new_bulk("SREM"), RespData::new_bulk(key)];
+        srem.extend(popped.into_iter().map(RespData::from));
+        propagate(persistence, &srem);
+        notify_keyspace_event(persistence, notify::SET, "spop", key);
+
+        if emptied {
+            notify_keyspace_event(persistence, notify::GENERIC, "del", key);
+        }
+    }
+}
+
+fn handle_srandmember(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -2) {
+        return;
+    }
+
+    if vals.len() > 3 {
+        handle_error(stream, "ERR syntax error");
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    let count = match vals.get(2) {
+        // Like Redis, the count's magnitude must fit in a positive i64.
+        Some(val) => match parse_int(stream, val) {
+            Some(i64::MIN) => return handle_error(stream, "ERR value is out of range"),
+            Some(count) => Some(count),
+            None => return,
+        },
+        None => None,
+    };
+
+    if !is_type_or_empty(persistence, key, PersistedType::Set) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let sets = persistence.persisted.set.lock().unwrap();
+    let set = sets.0.get(key);
+
+    let reply = match (count, set) {
+        (None, Some(set)) => RespData::new_bulk(&set.random_member().unwrap()),
+        (None, None) => RespData::Null,
+        (Some(_), None) => RespData::Array(vec![]),
+        // A negative count may return the same member several times. The
+        // picks come from a copy of the members, so a slow reader of a huge
+        // reply doesn't hold up every other set command.
+        (Some(count), Some(set)) if count < 0 => {
+            let members = set.members();
+            drop(sets);
+            return write_random_with_repeats(stream, &members, count.unsigned_abs());
+        }
+        (Some(count), Some(set)) => bulk_array(set.random_distinct(count as usize)),
+    };
+
+    drop(sets);
+    write_stream(stream, &reply.as_bytes());
+}
+
+/// Nothing bounds the count of a negative SRANDMEMBER, so the reply goes
+/// out in chunks rather than being built whole. It stops early when the
+/// client is gone.
+fn write_random_with_repeats(stream: &mut Connection, members: &[String], count: u64) {
+    const CHUNK: u64 = 1024;
+
+    if members.is_empty() {
+        return write_stream(stream, b"*0\r\n");
+    }
+
+    if stream.write_all(format!("*{}\r\n", count).as_bytes()).is_err() {
+        return;
+    }
+
+    let mut left = count;
+
+    while left > 0 {
+        let chunk = left.min(CHUNK);
+        left -= chunk;
+
+        let reply: Vec<u8> = (0..chunk)
+            .flat_map(|_| RespData::new_bulk(&members[random_index(members.len())]).as_bytes())
+            .collect();
+
+        if stream.write_all(&reply).is_err() {
+            return;
+        }
+    }
+}
+
+fn handle_smove(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, 4) {
+        return;
+    }
+
+    let source = vals.get(1).unwrap().inside_value().unwrap();
+    let destination = vals.get(2).unwrap().inside_value().unwrap();
+    let member = vals.get(3).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, source, PersistedType::Set)
+        || !is_type_or_empty(persistence, destination, PersistedType::Set)
+    {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    // Whether it moved, then whether that emptied the source, created the
+    // destination and added to it, the member may have been there already.
+    let (moved, emptied, created, added) = {
+        let mut sets = persistence.persisted.set.lock().unwrap();
+
+        match sets.0.get(source) {
+            Some(set) if set.contains(member) && source != destination => {
+                let created = !sets.0.contains_key(destination);
+
+                sets.remove(source, &[member.to_string()]);
+                let added = sets
+                    .0
+                    .entry(destination.to_string())
+                    .or_default()
+                    .add(member);
+
+                (true, !sets.0.contains_key(source), created, added)
+            }
+            Some(set) => (set.contains(member), false, false, false),
+            None => (false, false, false, false),
+        }
+    };
+
+    write_reply(persistence, stream, &RespData::Integer(moved as i64));
+
+    if moved && source != destination {
+        propagate(persistence, vals);
+        notify_keyspace_event(persistence, notify::SET, "srem", source);
+
+        if emptied {
+            notify_keyspace_event(persistence, notify::GENERIC, "del", source);
+        }
+
+        if created {
+            notify_keyspace_event(persistence, notify::NEW, "new", destination);
+        }
+
+        if added {
+            notify_keyspace_event(persistence, notify::SET, "sadd", destination);
+        }
+    }
+}
+
+/// Notifies about a *STORE command that left `len` members at `key`: its
+/// event when it stored something, with `new` if that created the key,
+/// or `del` when an empty result removed what was there.
+fn notify_store(
+    persistence: &State,
+    class: u32,
+    event: &str,
+    key: &str,
+    existed: bool,
+    len: usize,
+) {
+    match (existed, len > 0) {
+        (false, true) => {
+            notify_keyspace_event(persistence, notify::NEW, "new", key);
+            notify_keyspace_event(persistence, class, event, key);
+        }
+        (true, true) => notify_keyspace_event(persistence, class, event, key),
+        (true, false) => notify_keyspace_event(persistence, notify::GENERIC, "del", key),
+        (false, false) => {}
+    }
+}
+
+#[derive(Clone, Copy)]
+enum SetOp {
+    Inter,
+    Union,
+    Diff,
+}
+
+/// SINTER, SUNION, SDIFF and, with `store`, their STORE variants.
+fn handle_set_algebra(
+    persistence: &State,
+    stream: &mut Connection,
+    vals: &[RespData],
+    op: SetOp,
+    store: bool,
+) {
+    if !check_arity(stream, vals, if store { -3 } else { -2 }) {
+        return;
+    }
+
+    let args = string_args(&vals[1..]);
+    let (destination, keys) = match store {
+        true => (Some(&args[0]), &args[1..]),
+        false => (None, &args[..]),
+    };
+
+    if keys
+        .iter()
+        .any(|key| !is_type_or_empty(persistence, key, PersistedType::Set))
+    {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let existed = destination.is_some_and(|key| persistence.persisted.key_type(key).is_some());
+
+    // The STORE variants are EXCLUSIVE, so from the type checks on nobody
+    // sees the sources change or the destination go missing in between.
+    if let Some(destination) = destination {
+        if !is_type_or_empty(persistence, destination, PersistedType::Set) {
+            persistence.persisted.delete(destination);
+        }
+    }
+
+    let mut sets = persistence.persisted.set.lock().unwrap();
+
+    let result = match op {
+        SetOp::Inter => sets.inter(keys, 0),
+        SetOp::Union => sets.union(keys),
+        SetOp::Diff => sets.diff(keys),
+    };
+
+    match destination {
+        Some(destination) => {
+            let len = sets.store(destination, result);
+            drop(sets);
+
+            write_reply(persistence, stream, &RespData::Integer(len as i64));
+            propagate(persistence, vals);
+
+            let event = match op {
+                SetOp::Inter => "sinterstore",
+                SetOp::Union => "sunionstore",
+                SetOp::Diff => "sdiffstore",
+            };
+
+            notify_store(persistence, notify::SET, event, destination, existed, len);
+        }
+        None => write_stream(stream, &bulk_array(result.members()).as_bytes()),
+    }
+}
+
+fn handle_sintercard(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -3) {
+        return;
+    }
+
+    let numkeys = match parse_int(stream, vals.get(1).unwrap()) {
+        Some(n) if n <= 0 => {
+            handle_error(stream, "ERR numkeys should be greater than 0");
+            return;
+        }
+        Some(n) => n as usize,
+        None => return,
+    };
+
+    if numkeys > vals.len() - 2 {
+        handle_error(
+            stream,
+            "ERR Number of keys can't be greater than number of args",
+        );
+        return;
+    }
+
+    let keys = string_args(&vals[2..2 + numkeys]);
+    let mut limit: usize = 0;
+
+    let mut options = vals[2 + numkeys..].iter();
+
+    while let Some(option) = options.next() {
+        match (
+            option.inside_value().unwrap().to_lowercase().as_str(),
+            options.next(),
+        ) {
+            ("limit", Some(val)) => match parse_int(stream, val) {
+                Some(l) if l < 0 => {
+                    handle_error(stream, "ERR LIMIT can't be negative");
+                    return;
+                }
+                Some(l) => limit = l as usize,
+                None => return,
+            },
+            _ => {
+                handle_error(stream, "ERR syntax error");
+                return;
+            }
+        }
+    }
+
+    if keys
+        .iter()
+        .any(|key| !is_type_or_empty(persistence, key, PersistedType::Set))
+    {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let card = persistence
+        .persisted
+        .set
+        .lock()
+        .unwrap()
+        .inter(&keys, limit)
+        .len();
+
+    write_stream(stream, &RespData::Integer(card as i64).as_bytes());
+}
+
+fn handle_zadd(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -4) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    let mut flags = ZAddFlags::default();
+    let mut ch = false;
+    let mut idx = 2;
+
+    while let Some(val) = vals.get(idx) {
+        match val.inside_value().unwrap().to_lowercase().as_str() {
+            "nx" => flags.nx = true,
+            "xx" => flags.xx = true,
+            "gt" => flags.gt = true,
+            "lt" => flags.lt = true,
+            "ch" => ch = true,
+            "incr" => flags.incr = true,
+            _ => break,
+        }
+
+        idx += 1;
+    }
+
+    let pairs = &vals[idx..];
+
+    if pairs.is_empty() || !pairs.len().is_multiple_of(2) {
+        handle_error(stream, "ERR syntax error");
+        return;
+    }
+
+    if flags.nx && flags.xx {
+        handle_error(
+            stream,
+            "ERR XX and NX options at the same time are not compatible",
+        );
+        return;
+    }
+
+    if (flags.gt && flags.lt) || ((flags.gt || flags.lt) && flags.nx) {
+        handle_error(
+            stream,
+            "ERR GT, LT, and/or NX options at the same time are not compatible",
+        );
+        return;
+    }
+
+    if flags.incr && pairs.len() > 2 {
+        handle_error(
+            stream,
+            "ERR INCR option supports a single increment-element pair",
+        );
+        return;
+    }
+
+    // Every score is validated before the set is touched.
+    let mut elements: Vec<(f64, &str)> = vec![];
+
+    for pair in pairs.chunks(2) {
+        match parse_float(stream, &pair[0]) {
+            Some(score) => elements.push((score, pair[1].inside_value().unwrap())),
+            None => return,
+        }
+    }
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let mut added = 0;
+    let mut updated = 0;
+    let mut incr_score: Option<f64> = None;
+
+    let created = {
+        let mut zsets = persistence.persisted.sorted_set.lock().unwrap();
+        let created = !zsets.0.contains_key(key);
+        let zset = zsets.0.entry(key.to_string()).or_default();
+
+        for (score, member) in elements {
+            match zset.add(score, member, flags) {
+                ZAddOutcome::Added(score) => {
+                    added += 1;
+                    incr_score = Some(score);
+                }
+                ZAddOutcome::Updated(score) => {
+                    updated += 1;
+                    incr_score = Some(score);
+                }
+                ZAddOutcome::Unchanged(score) => incr_score = Some(score),
+                ZAddOutcome::Skipped => {}
+                ZAddOutcome::Nan => {
+                    handle_error(stream, "ERR resulting score is not a number (NaN)");
+                    return;
+                }
+            }
+        }
+
+        if zset.is_empty() {
+            zsets.0.remove(key);
+        }
+
+        created
+    };
+
+    let reply = match flags.incr {
+        true => match incr_score {
+            Some(score) => RespData::from(format_double(score)),
+            None => RespData::Null,
+        },
+        false => RespData::Integer(added + if ch { updated } else { 0 }),
+    };
+
+    write_reply(persistence, stream, &reply);
+
+    if added > 0 {
+        persistence.blocking.signal(key);
+    }
+
+    if added + updated > 0 {
+        propagate(persistence, vals);
+
+        if created {
+            notify_keyspace_event(persistence, notify::NEW, "new", key);
+        }
+
+        let event = if flags.incr { "zincr" } else { "zadd" };
+        notify_keyspace_event(persistence, notify::ZSET, event, key);
+    }
+}
+
+fn handle_zincrby(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, 4) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+    let member = vals.get(3).unwrap().inside_value().unwrap();
+
+    let increment = match parse_float(stream, vals.get(2).unwrap()) {
+        Some(increment) => increment,
+        None => return,
+    };
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let flags = ZAddFlags {
+        incr: true,
+        ..Default::default()
+    };
+
+    let (outcome, created) = {
+        let mut zsets = persistence.persisted.sorted_set.lock().unwrap();
+        let created = !zsets.0.contains_key(key);
+        let outcome = zsets
+            .0
+            .entry(key.to_string())
+            .or_default()
+            .add(increment, member, flags);
+
+        (outcome, created)
+    };
+
+    match outcome {
+        ZAddOutcome::Added(score) | ZAddOutcome::Updated(score) => {
+            persistence.blocking.signal(key);
+            write_reply(persistence, stream, &RespData::from(format_double(score)));
+            propagate(persistence, vals);
+
+            if created {
+                notify_keyspace_event(persistence, notify::NEW, "new", key);
+            }
+
+            notify_keyspace_event(persistence, notify::ZSET, "zincr", key);
+        }
+        ZAddOutcome::Unchanged(score) => {
+            write_reply(persistence, stream, &RespData::from(format_double(score)))
+        }
+        _ => handle_error(stream, "ERR resulting score is not a number (NaN)"),
+    }
+}
+
+fn handle_zrem(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -3) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let (removed, emptied) = {
+        let mut zsets = persistence.persisted.sorted_set.lock().unwrap();
+        let removed = zsets.remove(key, &string_args(&vals[2..]));
+
+        (removed, !zsets.0.contains_key(key))
+    };
+
+    write_reply(persistence, stream, &RespData::Integer(removed as i64));
+
+    if removed > 0 {
+        propagate(persistence, vals);
+        notify_keyspace_event(persistence, notify::ZSET, "zrem", key);
+
+        if emptied {
+            notify_keyspace_event(persistence, notify::GENERIC, "del", key);
+        }
+    }
+}
+
+fn handle_zscore(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, 3) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+    let member = vals.get(2).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let score = match persistence.persisted.sorted_set.lock().unwrap().0.get(key) {
+        Some(zset) => zset.score(member),
+        None => None,
+    };
+
+    let reply = match score {
+        Some(score) => RespData::from(format_double(score)),
+        None => RespData::Null,
+    };
+
+    write_stream(stream, &reply.as_bytes());
+}
+
+fn handle_zmscore(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -3) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let zsets = persistence.persisted.sorted_set.lock().unwrap();
+    let zset = zsets.0.get(key);
+
+    let scores = string_args(&vals[2..])
+        .iter()
+        .map(|member| match zset.and_then(|z| z.score(member)) {
+            Some(score) => RespData::from(format_double(score)),
+            None => RespData::Null,
+        })
+        .collect();
+
+    write_stream(stream, &RespData::Array(scores).as_bytes());
+}
+
+fn handle_zcard(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, 2) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let card = match persistence.persisted.sorted_set.lock().unwrap().0.get(key) {
+        Some(zset) => zset.len(),
+        None => 0,
+    };
+
+    write_stream(stream, &RespData::Integer(card as i64).as_bytes());
+}
+
+fn handle_zrank(persistence: &State, stream: &mut Connection, vals: &[RespData], reverse: bool) {
+    if !check_arity(stream, vals, -3) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+    let member = vals.get(2).unwrap().inside_value().unwrap();
+
+    let with_score = match vals.get(3) {
+        Some(val)
+            if vals.len() == 4
+                && val
+                    .inside_value()
+                    .unwrap()
+                    .eq_ignore_ascii_case("withscore") =>
+        {
+            true
+        }
+        Some(_) => {
+            handle_error(stream, "ERR syntax error");
+            return;
+        }
+        None => false,
+    };
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let zsets = persistence.persisted.sorted_set.lock().unwrap();
+
+    let found = zsets.0.get(key).and_then(|zset| {
+        zset.rank(member, reverse)
+            .map(|rank| (rank, zset.score(member).unwrap()))
+    });
+
+    let reply = match (found, with_score) {
+        (Some((rank, score)), true) => RespData::Array(vec![
+            RespData::Integer(rank as i64),
+            RespData::from(format_double(score)),
+        ]),
+        (Some((rank, _)), false) => RespData::Integer(rank as i64),
+        (None, _) => RespData::Null,
+    };
+
+    write_stream(stream, &reply.as_bytes());
+}
+
+fn handle_zcount(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, 4) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    let range = match ScoreRange::parse(
+        vals.get(2).unwrap().inside_value().unwrap(),
+        vals.get(3).unwrap().inside_value().unwrap(),
+    ) {
+        Some(range) => range,
+        None => {
+            handle_error(stream, "ERR min or max is not a float");
+            return;
+        }
+    };
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let count = match persistence.persisted.sorted_set.lock().unwrap().0.get(key) {
+        Some(zset) => zset.count(&range),
+        None => 0,
+    };
+
+    write_stream(stream, &RespData::Integer(count as i64).as_bytes());
+}
+
+/// Parses `start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count] [WITHSCORES]`,
+/// returning the range and whether scores were asked for.
+fn parse_zrange(stream: &mut Connection, args: &[RespData]) -> Option<(ZRangeSpec, bool)> {
+    let mut by_score = false;
+    let mut by_lex = false;
+    let mut rev = false;
+    let mut with_scores = false;
+    let mut limit: Option<(i64, i64)> = None;
+
+    let mut options = args[2..].iter();
+
+    while let Some(option) = options.next() {
+        match option.inside_value().unwrap().to_lowercase().as_str() {
+            "byscore" => by_score = true,
+            "bylex" => by_lex = true,
+            "rev" => rev = true,
+            "withscores" => with_scores = true,
+            "limit" => match (options.next(), options.next()) {
+                (Some(offset), Some(count)) => {
+                    let offset = parse_int(stream, offset)?;
+                    let count = parse_int(stream, count)?;
+                    limit = Some((offset, count));
+                }
+                _ => {
+                    handle_error(stream, "ERR syntax error");
+                    return None;
+                }
+            },
+            _ => {
+                handle_error(stream, "ERR syntax error");
+                return None;
+            }
+        }
+    }
+
+    if by_score && by_lex {
+        handle_error(stream, "ERR syntax error");
+        return None;
+    }
+
+    if limit.is_some() && !by_score && !by_lex {
+        handle_error(
+            stream,
+            "ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX",
+        );
+        return None;
+    }
+
+    if with_scores && by_lex {
+        handle_error(
+            stream,
+            "ERR syntax error, WITHSCORES not supported in combination with BYLEX",
+        );
+        return None;
+    }
+
+    let start = args[0].inside_value().unwrap();
+    let stop = args[1].inside_value().unwrap();
+
+    // With REV, score and lex ranges are given from max to min.
+    let (min, max) = match rev && (by_score || by_lex) {
+        true => (stop, start),
+        false => (start, stop),
+    };
+
+    let by = if by_score {
+        match ScoreRange::parse(min, max) {
+            Some(range) => ZRangeBy::Score(range),
+            None => {
+                handle_error(stream, "ERR min or max is not a float");
+                return None;
+            }
+        }
+    } else if by_lex {
+        match LexRange::parse(min, max) {
+            Some(range) => ZRangeBy::Lex(range),
+            None => {
+                handle_error(stream, "ERR min or max not valid string range item");
+                return None;
+            }
+        }
+    } else {
+        ZRangeBy::Rank(parse_int(stream, &args[0])?, parse_int(stream, &args[1])?)
+    };
+
+    let (offset, limit) = limit.unwrap_or((0, -1));
+
+    Some((
+        ZRangeSpec {
+            by,
+            rev,
+            offset,
+            limit,
+        },
+        with_scores,
+    ))
+}
+
+fn zset_entries_reply(entries: Vec<(String, f64)>, with_scores: bool) -> RespData {
+    let mut reply: Vec<RespData> = vec![];
+
+    for (member, score) in entries {
+        reply.push(RespData::from(member));
+
+        if with_scores {
+            reply.push(RespData::from(format_double(score)));
+        }
+    }
+
+    RespData::Array(reply)
+}
+
+fn handle_zrange(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -4) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    let (spec, with_scores) = match parse_zrange(stream, &vals[2..]) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let entries = match persistence.persisted.sorted_set.lock().unwrap().0.get(key) {
+        Some(zset) => zset.range(&spec),
+        None => vec![],
+    };
+
+    write_stream(stream, &zset_entries_reply(entries, with_scores).as_bytes());
+}
+
+#[derive(Clone, Copy)]
+enum ZSetOp {
+    Union,
+    Inter,
+    Diff,
+}
+
+/// ZUNIONSTORE, ZINTERSTORE and ZDIFFSTORE.
+fn handle_zset_store(persistence: &State, stream: &mut Connection, vals: &[RespData], op: ZSetOp) {
+    if !check_arity(stream, vals, -4) {
+        return;
+    }
+
+    let command = vals.first().unwrap().inside_value().unwrap().to_lowercase();
+    let destination = vals.get(1).unwrap().inside_value().unwrap();
+
+    let numkeys = match parse_int(stream, vals.get(2).unwrap()) {
+        Some(n) if n <= 0 => {
+            handle_error(
+                stream,
+                &format!(
+                    "ERR at least 1 input key is needed for '{}' command",
+                    command
+                ),
+            );
+            return;
+        }
+        Some(n) => n as usize,
+        None => return,
+    };
+
+    if numkeys > vals.len() - 3 {
+        handle_error(stream, "ERR syntax error");
+        return;
+    }
+
+    let keys = string_args(&vals[3..3 + numkeys]);
+    let mut weights = vec![1.0; numkeys];
+    let mut aggregate = Aggregate::Sum;
+
+    let mut options = vals[3 + numkeys..].iter();
+
+    while let Some(option) = options.next() {
+        match (option.inside_value().unwrap().to_lowercase().as_str(), op) {
+            ("weights", ZSetOp::Union | ZSetOp::Inter) => {
+                for weight in weights.iter_mut() {
+                    let val = match options.next() {
+                        Some(val) => val,
+                        None => {
+                            handle_error(stream, "ERR syntax error");
+                            return;
+                        }
+                    };
+
+                    match val.inside_value().and_then(parse_score) {
+                        Some(v) => *weight = v,
+                        None => {
+                            handle_error(stream, "ERR weight value is not a float");
+                            return;
+                        }
+                    }
+                }
+            }
+            ("aggregate", ZSetOp::Union | ZSetOp::Inter) => {
+                aggregate = match options
+                    .next()
+                    .and_then(|v| v.inside_value())
+                    .map(|v| v.to_lowercase())
+                    .as_deref()
+                {
+                    Some("sum") => Aggregate::Sum,
+                    Some("min") => Aggregate::Min,
+                    Some("max") => Aggregate::Max,
+                    _ => {
+                        handle_error(stream, "ERR syntax error");
+                        return;
+                    }
+                }
+            }
+            _ => {
+                handle_error(stream, "ERR syntax error");
+                return;
+            }
+        }
+    }
+
+    let mut plain_sets = vec![];
+
+    for key in &keys {
+        match persistence.persisted.key_type(key) {
+            None | Some(PersistedType::SortedSet) => plain_sets.push(None),
+            Some(PersistedType::Set) => plain_sets.push(
+                persistence
+                    .persisted
+                    .set
+                    .lock()
+                    .unwrap()
+                    .0
+                    .get(key)
+                    .cloned(),
+            ),
+            Some(_) => {
+                handle_wrong_type(stream);
+                return;
+            }
+        }
+    }
+
+    let existed = persistence.persisted.key_type(destination).is_some();
+
+    // Safe between the locks, the command is EXCLUSIVE: the sources read
+    // above and the destination stay as they are until it's stored.
+    if !is_type_or_empty(persistence, destination, PersistedType::SortedSet) {
+        persistence.persisted.delete(destination);
+    }
+
+    let mut zsets = persistence.persisted.sorted_set.lock().unwrap();
+
+    let result = {
+        let sources: Vec<ZSetSource> = keys
+            .iter()
+            .zip(plain_sets)
+            .map(|(key, plain)| match (plain, zsets.0.get(key)) {
+                (Some(set), _) => ZSetSource::Set(set),
+                (None, Some(zset)) => ZSetSource::Sorted(zset),
+                (None, None) => ZSetSource::Missing,
+            })
+            .collect();
+
+        match op {
+            ZSetOp::Union => zunion(&sources, &weights, aggregate),
+            ZSetOp::Inter => zinter(&sources, &weights, aggregate),
+            ZSetOp::Diff => zdiff(&sources),
+        }
+    };
+
+    let len = zsets.store(destination, result);
+    drop(zsets);
+
+    if len > 0 {
+        persistence.blocking.signal(destination);
+    }
+
+    write_reply(persistence, stream, &RespData::Integer(len as i64));
+    propagate(persistence, vals);
+
+    let event = match op {
+        ZSetOp::Union => "zunionstore",
+        ZSetOp::Inter => "zinterstore",
+        ZSetOp::Diff => "zdiffstore",
+    };
+
+    notify_store(persistence, notify::ZSET, event, destination, existed, len);
+}
+
+fn handle_zrangestore(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -5) {
+        return;
+    }
+
+    let destination = vals.get(1).unwrap().inside_value().unwrap();
+    let source = vals.get(2).unwrap().inside_value().unwrap();
+
+    let spec = match parse_zrange(stream, &vals[3..]) {
+        Some((_, true)) => {
+            handle_error(stream, "ERR syntax error");
+            return;
+        }
+        Some((spec, false)) => spec,
+        None => return,
+    };
+
+    if !is_type_or_empty(persistence, source, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let existed = persistence.persisted.key_type(destination).is_some();
+
+    // EXCLUSIVE too, nobody sees the destination missing in between.
+    if !is_type_or_empty(persistence, destination, PersistedType::SortedSet) {
+        persistence.persisted.delete(destination);
+    }
+
+    let len = {
+        let mut zsets = persistence.persisted.sorted_set.lock().unwrap();
+
+        let mut result = SortedSet::default();
+
+        if let Some(zset) = zsets.0.get(source) {
+            for (member, score) in zset.range(&spec) {
+                result.add(score, &member, ZAddFlags::default());
+            }
+        }
+
+        zsets.store(destination, result)
+    };
+
+    if len > 0 {
+        persistence.blocking.signal(destination);
+    }
+
+    write_reply(persistence, stream, &RespData::Integer(len as i64));
+    propagate(persistence, vals);
+    notify_store(
+        persistence,
+        notify::ZSET,
+        "zrangestore",
+        destination,
+        existed,
+        len,
+    );
+}
+
+fn pop_command(max: bool) -> &'static str {
+    match max {
+        true => "ZPOPMAX",
+        false => "ZPOPMIN",
+    }
+}
+
+/// Pops from the first of `keys` holding a sorted set. Replicas get the
+/// equivalent ZPOPMIN/ZPOPMAX, so blocking variants never block there.
+fn pop_first_zset(
+    persistence: &State,
+    keys: &[String],
+    count: usize,
+    max: bool,
+) -> Option<(String, Vec<(String, f64)>)> {
+    let mut zsets = persistence.persisted.sorted_set.lock().unwrap();
+
+    let key = keys.iter().find(|key| zsets.0.contains_key(*key))?;
+    let popped = zsets.pop(key, count, max);
+    let emptied = !zsets.0.contains_key(key);
+
+    propagate(
+        persistence,
+        &[
+            RespData::new_bulk(pop_command(max)),
+            RespData::new_bulk(key),
+            RespData::from(popped.len().to_string()),
+        ],
+    );
+
+    drop(zsets);
+
+    let event = pop_command(max).to_lowercase();
+    notify_keyspace_event(persistence, notify::ZSET, &event, key);
+
+    if emptied {
+        notify_keyspace_event(persistence, notify::GENERIC, "del", key);
+    }
+
+    Some((key.to_string(), popped))
+}
+
+fn handle_zpop(persistence: &State, stream: &mut Connection, vals: &[RespData], max: bool) {
+    if !check_arity(stream, vals, -2) {
+        return;
+    }
+
+    if vals.len() > 3 {
+        handle_error(stream, "ERR syntax error");
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    let count = match vals.get(2) {
+        Some(val) => match parse_int(stream, val) {
+            Some(count) if count < 0 => {
+                handle_error(stream, "ERR value is out of range, must be positive");
+                return;
+            }
+            Some(count) => count as usize,
+            None => return,
+        },
+        None => 1,
+    };
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let popped = match count {
+        0 => vec![],
+        count => match pop_first_zset(persistence, &[key.to_string()], count, max) {
+            Some((_, popped)) => popped,
+            None => vec![],
+        },
+    };
+
+    write_reply(persistence, stream, &zset_entries_reply(popped, true));
+}
+
+fn handle_bzpop(persistence: &State, stream: &mut Connection, vals: &[RespData], max: bool) {
+    if !check_arity(stream, vals, -3) {
+        return;
+    }
+
+    let keys = string_args(&vals[1..vals.len() - 1]);
+
+    let timeout = match parse_timeout(stream, vals.last().unwrap()) {
+        Some(timeout) => timeout,
+        None => return,
+    };
+
+    if keys
+        .iter()
+        .any(|key| !is_type_or_empty(persistence, key, PersistedType::SortedSet))
+    {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let popped = block_on(persistence, stream, &keys, Some(timeout), || {
+        pop_first_zset(persistence, &keys, 1, max)
+    });
+
+    let reply = match popped {
+        Some((key, entries)) => {
+            let (member, score) = entries.into_iter().next().unwrap();
+
+            RespData::Array(vec![
+                RespData::from(key),
+                RespData::from(member),
+                RespData::from(format_double(score)),
+            ])
+        }
+        None => RespData::NullArray,
+    };
+
+    write_reply(persistence, stream, &reply);
+}
+
+/// Parses `numkeys key [key ...] MIN|MAX [COUNT count]`.
+fn parse_mpop(stream: &mut Connection, args: &[RespData]) -> Option<(Vec<String>, bool, usize)> {
+    let numkeys = match parse_int(stream, &args[0])? {
+        n if n <= 0 => {
+            handle_error(stream, "ERR numkeys should be greater than 0");
+            return None;
+        }
+        n => n as usize,
+    };
+
+    if numkeys >= args.len() - 1 {
+        handle_error(stream, "ERR syntax error");
+        return None;
+    }
+
+    let keys = string_args(&args[1..1 + numkeys]);
+
+    let max = match args[1 + numkeys]
+        .inside_value()
+        .unwrap()
+        .to_lowercase()
+        .as_str()
+    {
+        "min" => false,
+        "max" => true,
+        _ => {
+            handle_error(stream, "ERR syntax error");
+            return None;
+        }
+    };
+
+    let count = match &args[2 + numkeys..] {
+        [] => 1,
+        [option, count] if option.inside_value().unwrap().eq_ignore_ascii_case("count") => {
+            match parse_int(stream, count)? {
+                c if c <= 0 => {
+                    handle_error(stream, "ERR count should be greater than 0");
+                    return None;
+                }
+                c => c as usize,
+            }
+        }
+        _ => {
+            handle_error(stream, "ERR syntax error");
+            return None;
+        }
+    };
+
+    Some((keys, max, count))
+}
+
+fn mpop_reply(popped: Option<(String, Vec<(String, f64)>)>) -> RespData {
+    match popped {
+        Some((key, entries)) => RespData::Array(vec![
+            RespData::from(key),
+            RespData::Array(
+                entries
+                    .into_iter()
+                    .map(|(member, score)| {
+                        RespData::Array(vec![
+                            RespData::from(member),
+                            RespData::from(format_double(score)),
+                        ])
+                    })
+                    .collect(),
+            ),
+        ]),
+        None => RespData::NullArray,
+    }
+}
+
+fn handle_zmpop(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -4) {
+        return;
+    }
+
+    let (keys, max, count) = match parse_mpop(stream, &vals[1..]) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+
+    if keys
+        .iter()
+        .any(|key| !is_type_or_empty(persistence, key, PersistedType::SortedSet))
+    {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let popped = pop_first_zset(persistence, &keys, count, max);
+
+    write_reply(persistence, stream, &mpop_reply(popped));
+}
+
+fn handle_bzmpop(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -5) {
+        return;
+    }
+
+    let timeout = match parse_timeout(stream, vals.get(1).unwrap()) {
+        Some(timeout) => timeout,
+        None => return,
+    };
+
+    let (keys, max, count) = match parse_mpop(stream, &vals[2..]) {
+        Some(parsed) => parsed,
+        None => return,
+    };
+
+    if keys
+        .iter()
+        .any(|key| !is_type_or_empty(persistence, key, PersistedType::SortedSet))
+    {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let popped = block_on(persistence, stream, &keys, Some(timeout), || {
+        pop_first_zset(persistence, &keys, count, max)
+    });
+
+    write_reply(persistence, stream, &mpop_reply(popped));
+}
+
+fn handle_geoadd(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -5) {
+        return;
+    }
+
+    let mut idx = 2;
+
+    while let Some(val) = vals.get(idx) {
+        match val.inside_value().unwrap().to_lowercase().as_str() {
+            "nx" | "xx" | "ch" => idx += 1,
+            _ => break,
+        }
+    }
+
+    let triples = &vals[idx..];
+
+    if triples.is_empty() || !triples.len().is_multiple_of(3) {
+        handle_error(
+            stream,
+            "ERR syntax error. Try GEOADD key [x1] [y1] [name1] [x2] [y2] [name2] ... ",
+        );
+        return;
+    }
+
+    // Like Redis, GEOADD is a ZADD with the positions turned into geohash
+    // scores, which is also what replicas receive.
+    let mut zadd = vec![RespData::new_bulk("ZADD")];
+    zadd.extend_from_slice(&vals[1..idx]);
+
+    for triple in triples.chunks(3) {
+        let (longitude, latitude) = match parse_lonlat(stream, &triple[..2]) {
+            Some(pos) => pos,
+            None => return,
+        };
+
+        let score = geo::encode_score(longitude, latitude).unwrap();
+
+        zadd.push(RespData::from((score as u64).to_string()));
+        zadd.push(triple[2].clone());
+    }
+
+    handle_zadd(persistence, stream, &zadd);
+}
+
+fn parse_lonlat(stream: &mut Connection, args: &[RespData]) -> Option<(f64, f64)> {
+    let longitude = parse_float(stream, &args[0])?;
+    let latitude = parse_float(stream, &args[1])?;
+
+    if !geo::valid_coordinates(longitude, latitude) {
+        handle_error(
+            stream,
+            &format!(
+                "ERR invalid longitude,latitude pair {:.6},{:.6}",
+                longitude, latitude
+            ),
+        );
+        return None;
+    }
+
+    Some((longitude, latitude))
+}
+
+fn parse_geo_unit(stream: &mut Connection, val: &RespData) -> Option<f64> {
+    match geo::unit_to_meters(val.inside_value().unwrap()) {
+        Some(conversion) => Some(conversion),
+        None => {
+            handle_error(
+                stream,
+                "ERR unsupported unit provided. please use M, KM, FT, MI",
+            );
+            None
+        }
+    }
+}
+
+/// Parses a non negative GEOSEARCH size, `what` names it in the error.
+fn parse_geo_size(stream: &mut Connection, val: &RespData, what: &str) -> Option<f64> {
+    match val.inside_value().and_then(parse_score) {
+        Some(v) => Some(v),
+        None => {
+            handle_error(stream, &format!("ERR need numeric {}", what));
+            None
+        }
+    }
+}
+
+fn geo_coordinates_reply(longitude: f64, latitude: f64) -> RespData {
+    bulk_array(vec![
+        geo::format_coordinate(longitude),
+        geo::format_coordinate(latitude),
+    ])
+}
+
+fn handle_geopos(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -2) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let zsets = persistence.persisted.sorted_set.lock().unwrap();
+    let zset = zsets.0.get(key);
+
+    let reply = vals[2..]
+        .iter()
+        .map(
+            |member| match zset.and_then(|zset| zset.score(member.inside_value().unwrap())) {
+                Some(score) => {
+                    let (longitude, latitude) = geo::decode_score(score);
+                    geo_coordinates_reply(longitude, latitude)
+                }
+                None => RespData::NullArray,
+            },
+        )
+        .collect();
+
+    write_stream(stream, &RespData::Array(reply).as_bytes());
+}
+
+fn handle_geodist(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -4) {
+        return;
+    }
+
+    let conversion = match vals.len() {
+        4 => 1.0,
+        5 => match parse_geo_unit(stream, vals.get(4).unwrap()) {
+            Some(conversion) => conversion,
+            None => return,
+        },
+        _ => {
+            handle_error(stream, "ERR syntax error");
+            return;
+        }
+    };
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let zsets = persistence.persisted.sorted_set.lock().unwrap();
+
+    let scores = zsets.0.get(key).and_then(|zset| {
+        let first = zset.score(vals.get(2).unwrap().inside_value().unwrap())?;
+        let second = zset.score(vals.get(3).unwrap().inside_value().unwrap())?;
+        Some((first, second))
+    });
+
+    let reply = match scores {
+        Some((first, second)) => {
+            let (lon1, lat1) = geo::decode_score(first);
+            let (lon2, lat2) = geo::decode_score(second);
+            let distance = geo::distance(lon1, lat1, lon2, lat2) / conversion;
+
+            RespData::from(format!("{:.4}", distance))
+        }
+        None => RespData::Null,
+    };
+
+    write_stream(stream, &reply.as_bytes());
+}
+
+fn handle_geohash(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    if !check_arity(stream, vals, -2) {
+        return;
+    }
+
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let zsets = persistence.persisted.sorted_set.lock().unwrap();
+    let zset = zsets.0.get(key);
+
+    let reply = vals[2..]
+        .iter()
+        .map(
+            |member| match zset.and_then(|zset| zset.score(member.inside_value().unwrap())) {
+                Some(score) => RespData::from(geo::geohash_string(score)),
+                None => RespData::Null,
+            },
+        )
+        .collect();
+
+    write_stream(stream, &RespData::Array(reply).as_bytes());
+}
+
+enum GeoCenter {
+    Member(String),
+    LonLat(f64, f64),
+}
+
+#[derive(Default)]
+struct GeoSearchOptions {
+    center: Option<GeoCenter>,
+    shape: Option<(Shape, f64)>,
+    desc: Option<bool>,
+    count: usize,
+    any: bool,
+    with_coord: bool,
+    with_dist: bool,
+    with_hash: bool,
+    store_dist: bool,
+}
+
+fn parse_geosearch(
+    stream: &mut Connection,
+    command: &str,
+    args: &[RespData],
+    store: bool,
+) -> Option<GeoSearchOptions> {
+    let mut options = GeoSearchOptions::default();
+    let mut idx = 0;
+
+    while let Some(arg) = args.get(idx) {
+        let remaining = args.len() - idx - 1;
+
+        match arg.inside_value().unwrap().to_lowercase().as_str() {
+            "withdist" => options.with_dist = true,
+            "withhash" => options.with_hash = true,
+            "withcoord" => options.with_coord = true,
+            "any" => options.any = true,
+            "asc" => options.desc = Some(false),
+            "desc" => options.desc = Some(true),
+            "count" if remaining >= 1 => {
+                options.count = match parse_int(stream, &args[idx + 1])? {
+                    count if count <= 0 => {
+                        handle_error(stream, "ERR COUNT must be > 0");
+                        return None;
+                    }
+                    count => count as usize,
+                };
+                idx += 1;
+            }
+            "frommember" if remaining >= 1 && options.center.is_none() => {
+                let member = args[idx + 1].inside_value().unwrap().to_string();
+                options.center = Some(GeoCenter::Member(member));
+                idx += 1;
+            }
+            "fromlonlat" if remaining >= 2 && options.center.is_none() => {
+                let (longitude, latitude) = parse_lonlat(stream, &args[idx + 1..idx + 3])?;
+                options.center = Some(GeoCenter::LonLat(longitude, latitude));
+                idx += 2;
+            }
+            "byradius" if remaining >= 2 && options.shape.is_none() => {
+                let radius = parse_geo_size(stream, &args[idx + 1], "radius")?;
+
+                if radius < 0.0 {
+                    handle_error(stream, "ERR radius cannot be negative");
+                    return None;
+                }
+
+                let conversion = parse_geo_unit(stream, &args[idx + 2])?;
+                options.shape = Some((Shape::Radius(radius), conversion));
+                idx += 2;
+            }
+            "bybox" if remaining >= 3 && options.shape.is_none() => {
+                let width = parse_geo_size(stream, &args[idx + 1], "width")?;
+                let height = parse_geo_size(stream, &args[idx + 2], "height")?;
+
+                if width < 0.0 || height < 0.0 {
+                    handle_error(stream, "ERR height or width cannot be negative");
+                    return None;
+                }
+
+                let conversion = parse_geo_unit(stream, &args[idx + 3])?;
+                options.shape = Some((Shape::Box { width, height }, conversion));
+                idx += 3;
+            }
+            "storedist" if store => options.store_dist = true,
+            _ => {
+                handle_error(stream, "ERR syntax error");
+                return None;
+            }
+        }
+
+        idx += 1;
+    }
+
+    if store && (options.with_dist || options.with_hash || options.with_coord) {
+        handle_error(
+            stream,
+            "ERR GEOSEARCHSTORE is not compatible with WITHDIST, WITHHASH and WITHCOORD options",
+        );
+        return None;
+    }
+
+    if options.center.is_none() {
+        handle_error(
+            stream,
+            &format!(
+                "ERR exactly one of FROMMEMBER or FROMLONLAT can be specified for {}",
+                command
+            ),
+        );
+        return None;
+    }
+
+    if options.shape.is_none() {
+        handle_error(
+            stream,
+            &format!(
+                "ERR exactly one of BYRADIUS and BYBOX can be specified for {}",
+                command
+            ),
+        );
+        return None;
+    }
+
+    if options.any && options.count == 0 {
+        handle_error(stream, "ERR the ANY argument requires COUNT argument");
+        return None;
+    }
+
+    // The closest N members can only be picked after sorting.
+    if options.count > 0 && options.desc.is_none() && !options.any {
+        options.desc = Some(false);
+    }
+
+    Some(options)
+}
+
+fn geo_point_reply(point: GeoPoint, options: &GeoSearchOptions, conversion: f64) -> RespData {
+    if !(options.with_dist || options.with_hash || options.with_coord) {
+        return RespData::from(point.member);
+    }
+
+    let mut reply = vec![RespData::from(point.member)];
+
+    if options.with_dist {
+        reply.push(RespData::BulkString(
+            format!("{:.4}", point.dist / conversion).into(),
+        ));
+    }
+
+    if options.with_hash {
+        reply.push(RespData::Integer(point.score as i64));
+    }
+
+    if options.with_coord {
+        reply.push(geo_coordinates_reply(point.longitude, point.latitude));
+    }
+
+    RespData::Array(reply)
+}
+
+/// GEOSEARCH and GEOSEARCHSTORE.
+fn handle_geosearch(persistence: &State, stream: &mut Connection, vals: &[RespData], store: bool) {
+    if !check_arity(stream, vals, if store { -8 } else { -7 }) {
+        return;
+    }
+
+    let command = vals.first().unwrap().inside_value().unwrap();
+    let (destination, source) = match store {
+        true => (vals.get(1).unwrap().inside_value(), 2),
+        false => (None, 1),
+    };
+    let key = vals.get(source).unwrap().inside_value().unwrap();
+
+    if !is_type_or_empty(persistence, key, PersistedType::SortedSet) {
+        handle_wrong_type(stream);
+        return;
+    }
+
+    let options = match parse_geosearch(stream, command, &vals[source + 1..], store) {
+        Some(options) => options,
+        None => return,
+    };
+
+    let (shape, conversion) = options.shape.unwrap();
+
+    let points = {
+        let zsets = persistence.persisted.sorted_set.lock().unwrap();
+
+        let zset = match zsets.0.get(key) {
+            Some(zset) => zset,
+            None => match destination {
+                Some(destination) => {
+                    drop(zsets);
+                    let existed = persistence.persisted.delete(destination);
+                    write_reply(persistence, stream, &RespData::Integer(0));
+                    propagate(persistence, vals);
+
+                    if existed {
+                        notify_keyspace_event(persistence, notify::GENERIC, "del", destination);
+                    }
+
+                    return;
+                }
+                None => {
+                    write_stream(stream, &RespData::Array(vec![]).as_bytes());
+                    return;
+                }
+            },
+        };
+
+        let (longitude, latitude) = match options.center.as_ref().unwrap() {
+            GeoCenter::LonLat(longitude, latitude) => (*longitude, *latitude),
+            GeoCenter::Member(member) => match zset.score(member) {
+                Some(score) => geo::decode_score(score),
+                None => {
+                    handle_error(stream, "ERR could not decode requested zset member");
+                    return;
+                }
+            },
+        };
+
+        let search = GeoSearch {
+            longitude,
+            latitude,
+            shape,
+            conversion,
+        };
+
+        let limit = if options.any { options.count } else { 0 };
+        let mut points = search.members(zset, limit);
+
+        if let Some(desc) = options.desc {
+            points.sort_by(|a, b| match desc {
+                true => b.dist.total_cmp(&a.dist),
+                false => a.dist.total_cmp(&b.dist),
+            });
+        }
+
+        if options.count > 0 {
+            points.truncate(options.count);
+        }
+
+        points
+    };
+
+    let destination = match destination {
+        Some(destination) => destination,
+        None => {
+            let reply = points
+                .into_iter()
+                .map(|point| geo_point_reply(point, &options, conversion))
+                .collect();
+
+            write_stream(stream, &RespData::Array(reply).as_bytes());
+            return;
+        }
+    };
+
+    let existed = persistence.persisted.key_type(destination).is_some();
+
+    // GEOSEARCHSTORE is EXCLUSIVE, the points found above are still what
+    // the source holds when they're stored.
+    if !is_type_or_empty(persistence, destination, PersistedType::SortedSet) {
+        persistence.persisted.delete(destination);
+    }
+
+    let len = {
+        let mut result = SortedSet::default();
+
+        for point in points {
+            let score = match options.store_dist {
+                true => point.dist / conversion,
+                false => point.score,
+            };
+
+            result.add(score, &point.member, ZAddFlags::default());
+        }
+
+        persistence
+            .persisted
+            .sorted_set
+            .lock()
+            .unwrap()
+            .store(destination, result)
+    };
+
+    if len > 0 {
+        persistence.blocking.signal(destination);
+    }
+
+    write_reply(persistence, stream, &RespData::Integer(len as i64));
+    propagate(persistence, vals);
+
+    let event = command.to_lowercase();
+    notify_store(persistence, notify::ZSET, &event, destination, existed, len);
+}
+
+fn handle_type(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    let type_name = match persistence.persisted.key_type(key) {
+        Some(p_type) => p_type.name(),
+        None => "none",
+    };
+
+    write_stream(stream, &RespData::new_simple_string(type_name).as_bytes());
+}
+
+fn handle_get(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+    println!("KEY: {:?}", key);
+
+    expire_if_needed(persistence, key);
+
+    let persist = &persistence.persisted.key_value.lock().unwrap().0;
+    let value = match persist.get(&key.to_string()) {
+        Some(v) => v,
+        None => {
+            write_stream(stream, &RespData::Null.as_bytes());
+            notify_keyspace_event(persistence, notify::KEY_MISS, "keymiss", key);
+            return;
+        }
+    };
+
+    let now = std::time::SystemTime::now();
+
+    if value.expiry > 0 && now.duration_since(value.timestamp).unwrap().as_millis() > value.expiry {
+        write_stream(stream, b"$-1\r\n");
+        return;
+    }
+
+    write_stream(stream, &value.data.as_bytes());
+}
+
+fn handle_del(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    let mut deleted = 0;
+
+    for key in vals[1..].iter().filter_map(RespData::inside_value) {
+        expire_if_needed(persistence, key);
+
+        if persistence.persisted.delete(key) {
+            notify_keyspace_event(persistence, notify::GENERIC, "del", key);
+            deleted += 1;
+        }
+    }
+
+    write_reply(persistence, stream, &RespData::Integer(deleted));
+
+    if deleted > 0 {
+        propagate(persistence, vals);
+    }
+}
+
+fn handle_dump(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+
+    expire_if_needed(persistence, key);
+
+    let reply = match rdb::save::entry(&persistence.persisted, key) {
+        Some(entry) => RespData::BulkString(rdb::save::dump(&entry.value)),
+        None => RespData::Null,
+    };
+
+    write_stream(stream, &reply.as_bytes());
+}
+
+/// RESTORE key ttl payload [REPLACE] [ABSTTL] [IDLETIME seconds] [FREQ
+/// frequency]. There is no LRU or LFU here, IDLETIME and FREQ are checked
+/// and then ignored.
+fn handle_restore(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    let key = vals.get(1).unwrap().inside_value().unwrap();
+    let mut replace = false;
+    let mut absttl = false;
+    let mut idle = None;
+    let mut freq = None;
+
+    let mut i = 4;
+
+    while let Some(arg) = vals.get(i).and_then(RespData::inside_value) {
+        match arg.to_lowercase().as_str() {
+            "replace" => replace = true,
+            "absttl" => absttl = true,
+            "idletime" if freq.is_none() && i + 1 < vals.len() => {
+                i += 1;
+
+                match parse_int(stream, &vals[i]) {
+                    Some(seconds) if seconds >= 0 => idle = Some(seconds),
+                    Some(_) => {
+                        return handle_error(stream, "ERR Invalid IDLETIME value, must be >= 0")
+                    }
+                    None => return,
+                }
+            }
+            "freq" if idle.is_none() && i + 1 < vals.len() => {
+                i += 1;
+
+                match parse_int(stream, &vals[i]) {
+                    Some(count) if (0..=255).contains(&count) => freq = Some(count),
+                    Some(_) => {
+                        return handle_error(
+                            stream,
+                            "ERR Invalid FREQ value, must be >= 0 and <= 255",
+                        )
+                    }
+                    None => return,
+                }
+            }
+            _ => return handle_error(stream, "ERR syntax error"),
+        }
+
+        i += 1;
+    }
+
+    let ttl = match parse_int(stream, &vals[2]) {
+        Some(ttl) if ttl >= 0 => ttl as u64,
+        Some(_) => return handle_error(stream, "ERR Invalid TTL value, must be >= 0"),
+        None => return,
+    };
+
+    expire_if_needed(persistence, key);
+
+    let existed = persistence.persisted.key_type(key).is_some();
+
+    if existed && !replace {
+        return handle_error(stream, "BUSYKEY Target key name already exists.");
+    }
+
+    let payload = vals.get(3).unwrap().inside_bytes().unwrap();
+
+    let value = match rdb::load::read_dump(payload) {
+        Ok(value) => value,
+        Err(rdb::RdbError::UnsupportedVersion(_) | rdb::RdbError::BadChecksum) => {
+            return handle_error(stream, "ERR DUMP payload version or checksum are wrong")
+        }
+        Err(_) => return handle_error(stream, "ERR Bad data format"),
+    };
+
+    let expire_ms = match ttl {
+        0 => None,
+        ttl if absttl => Some(ttl),
+        ttl => Some(now_ms() + ttl),
+    };
+
+    // Propagated with the TTL as a unix time, so a replay later on still
+    // expires the key when it should have.
+    let mut propagated = vals.to_vec();
+
+    if let (Some(at), false) = (expire_ms, absttl) {
+        propagated[2] = RespData::from(at.to_string());
+        propagated.push(RespData::new_bulk("ABSTTL"));
+    }
+
+    // Already expired, all that's left to do is replacing the key with
+    // nothing.
+    if expire_ms.is_some_and(|at| at <= now_ms()) {
+        if persistence.persisted.delete(key) {
+            notify_keyspace_event(persistence, notify::GENERIC, "del", key);
+            propagate(persistence, &propagated);
+        }
+
+        return write_reply(persistence, stream, &RespData::new_simple_string("OK"));
+    }
+
+    if expire_ms.is_some() && !matches!(value, rdb::RdbValue::String(_)) {
+        return handle_error(stream, "ERR only string keys can have a TTL on this server");
+    }
+
+    persistence.persisted.delete(key);
+    rdb::load::store(&persistence.persisted, key.to_string(), value, expire_ms);
+
+    write_reply(persistence, stream, &RespData::new_simple_string("OK"));
+    propagate(persistence, &propagated);
+
+    if !existed {
+        notify_keyspace_event(persistence, notify::NEW, "new", key);
+    }
+
+    notify_keyspace_event(persistence, notify::GENERIC, "restore", key);
+}
+
+fn is_timeout(e: &io::Error) -> bool {
+    matches!(e.kind(), io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock)
+}
+
+/// MIGRATE host port key|"" destination-db timeout [COPY] [REPLACE] [AUTH
+/// password | AUTH2 username password] [KEYS key [key ...]]. The keys go
+/// over as RESTOREs of their DUMP payloads, through a connection kept open
+/// for the next MIGRATE to the same target. Other clients wait until the
+/// target answered, so nothing changes the keys in between.
+fn handle_migrate(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    let args = string_args(vals);
+    let host = &args[1];
+    let mut keys = vec![args[3].clone()];
+    let mut copy = false;
+    let mut replace = false;
+    let mut auth = vec![];
+
+    let mut i = 6;
+
+    while i < args.len() {
+        match args[i].to_lowercase().as_str() {
+            "copy" => copy = true,
+            "replace" => replace = true,
+            "auth" if i + 1 < args.len() => {
+                auth = vec!["AUTH".to_string(), args[i + 1].clone()];
+                i += 1;
+            }
+            "auth2" if i + 2 < args.len() => {
+                auth = vec!["AUTH".to_string(), args[i + 1].clone(), args[i + 2].clone()];
+                i += 2;
+            }
+            "keys" => {
+                if !args[3].is_empty() {
+                    return handle_error(
+                        stream,
+                        "ERR When using MIGRATE KEYS option, the key argument must be set to empty string",
+                    );
+                }
+
+                keys = args[i + 1..].to_vec();
+                break;
+            }
+            _ => return handle_error(stream, "ERR syntax error"),
+        }
+
+        i += 1;
+    }
+
+    let (db, timeout) = match (parse_int(stream, &vals[4]), parse_int(stream, &vals[5])) {
+        (Some(db), Some(timeout)) => (db, timeout),
+        _ => return,
+    };
+
+    let timeout = Duration::from_millis(if timeout <= 0 { 1000 } else { timeout as u64 });
+
+    let mut entries = vec![];
+
+    for key in &keys {
+        expire_if_needed(persistence, key);
+        entries.extend(rdb::save::entry(&persistence.persisted, key));
+    }
+
+    if entries.is_empty() {
+        return write_stream(stream, b"+NOKEY\r\n");
+    }
+
+    let now = now_ms();
+    let mut restores = vec![];
+
+    for entry in &entries {
+        let ttl = entry
+            .expire_ms
+            .map_or(0, |at| at.saturating_sub(now).max(1));
+        let mut restore = vec![
+            RespData::new_bulk("RESTORE"),
+            RespData::new_bulk(&entry.key),
+            RespData::from(ttl.to_string()),
+            RespData::BulkString(rdb::save::dump(&entry.value)),
+        ];
+
+        if replace {
+            restore.push(RespData::new_bulk("REPLACE"));
+        }
+
+        restores.extend(RespData::Array(restore).as_bytes());
+    }
+
+    let port = match args[2].parse::<u16>() {
+        Ok(port) => port,
+        Err(_) => return handle_error(stream, "IOERR error or timeout connecting to the client"),
+    };
+
+    // Writing to a cached connection can still fail, the target may have
+    // gone away meanwhile. That gets one more try on a new connection,
+    // unless it was too slow to answer.
+    let mut retried = false;
+
+    let (mut socket, select) = loop {
+        let mut socket = match persistence.migrate.take(host, port, timeout) {
+            Ok(socket) => socket,
+            Err(_) => {
+                return handle_error(stream, "IOERR error or timeout connecting to the client")
+            }
+        };
+
+        let select = socket.db != Some(db);
+        let mut batch = vec![];
+
+        if !auth.is_empty() {
+            batch.extend(bulk_array(auth.clone()).as_bytes());
+        }
+
+        if select {
+            batch.extend(bulk_array(vec!["SELECT".to_string(), db.to_string()]).as_bytes());
+        }
+
+        batch.extend(&restores);
+
+        match socket.send(&batch, timeout) {
+            Ok(()) => break (socket, select),
+            Err(e) if !retried && !is_timeout(&e) => retried = true,
+            Err(_) => {
+                return handle_error(stream, "IOERR error or timeout writing to target instance")
+            }
+        }
+    };
+
+    // AUTH and SELECT answer first. Should either fail, so does every key.
+    let mut error = None;
+
+    for selecting in [false, true] {
+        if (selecting && !select) || (!selecting && auth.is_empty()) {
+            continue;
+        }
+
+        match socket.read_reply() {
+            Ok(Ok(())) if selecting => socket.db = Some(db),
+            // What the target has selected is anyone's guess now.
+            Ok(reply) if selecting => {
+                socket.db = None;
+                error = error.or(reply.err());
+            }
+            Ok(reply) => error = error.or(reply.err()),
+            Err(_) => {
+                return handle_error(stream, "IOERR error or timeout reading to target instance")
+            }
+        }
+    }
+
+    // Past that, a key the target refused doesn't keep the others from
+    // moving.
+    let refused_all = error.is_some();
+    let mut migrated = vec![];
+    let mut socket_error = false;
+
+    for entry in &entries {
+        match socket.read_reply() {
+            Ok(Ok(())) if !refused_all => migrated.push(entry.key.clone()),
+            Ok(reply) => error = error.or(reply.err()),
+            Err(_) => {
+                socket_error = true;
+                break;
+            }
+        }
+    }
+
+    if !copy && !migrated.is_empty() {
+        for key in &migrated {
+            persistence.persisted.delete(key);
+            notify_keyspace_event(persistence, notify::GENERIC, "del", key);
+        }
+
+        let del: Vec<RespData> = std::iter::once("DEL".to_string())
+            .chain(migrated)
+            .map(RespData::from)
+            .collect();
+        propagate(persistence, &del);
+    }
+
+    match (error, socket_error) {
+        (Some(error), _) => {
+            // A new connection is sure to be on database 0 again.
+            if socket.db.is_some() {
+                persistence.migrate.give_back(host, port, socket);
+            }
+
+            handle_error(
+                stream,
+                &format!("ERR Target instance replied with error: {}", error),
+            );
+        }
+        (None, true) => handle_error(stream, "IOERR error or timeout reading to target instance"),
+        (None, false) => {
+            persistence.migrate.give_back(host, port, socket);
+            write_stream(stream, b"+OK\r\n");
+        }
+    }
+}
+
+fn handle_info(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    // Replication and persistence are the only sections there are, unknown
+    // ones are empty.
+    match vals.get(1).and_then(RespData::inside_value) {
+        Some(section) if section.eq_ignore_ascii_case("persistence") => {
+            let response = format!(
+                "{}\r\n{}",
+                persistence.saving.info(),
+                persistence.aof.info()
+            );
+            write_stream(stream, &RespData::new_bulk(&response).as_bytes());
+        }
+        Some(section) if !section.eq_ignore_ascii_case("replication") => {
+            write_stream(stream, &RespData::new_bulk("").as_bytes());
+        }
+        _ => {
+            let response = persistence.info.read().unwrap().replication();
+            write_stream(stream, format!("{}\r\n", response).as_bytes());
+        }
+    }
+}
+
+fn handle_error(stream: &mut Connection, msg: &str) {
+    let resp = RespData::Error(String::from(msg));
+    write_stream(stream, &resp.as_bytes());
+}
+
+fn handle_replconf(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    let port_addr = stream.peer_addr().unwrap().port();
+
+    println!("REPLCONF: {:?}", vals);
+
+    if let Some(command) = vals[1].inside_value() {
+        match command.to_lowercase().as_str() {
+            "getack" => {
+                match persistence.info.write().unwrap().role.borrow_mut() {
+                    Role::Master(master) => {
+                        for slave in &master.slave_stream {
+                            let response = RespData::new_bulk_array(&["REPLFCONF", "GETACK", "*"]);
+
+                            let mut slave_stream = slave.1.lock().unwrap();
+
+                            slave_stream.write_all(&response.as_bytes()).unwrap();
+
+                            let mut buf = [0; 2024];
+                            let size = slave_stream.read(&mut buf).unwrap();
+
+                            let parse = Resp::parse(&buf[..size]);
+
+                            if let Some(resp) = parse {
+                                if let RespData::Array(vals) = resp.data {
+                                    if let Some(val) = vals.get(1).unwrap().inside_value() {
+                                        let val_under = val.to_lowercase();
+                                        if val_under == "ack" {
+                                            let _offset = vals.get(2).unwrap();
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    Role::Slave(_) => {
+                        let response = RespData::new_bulk_array(&["REPLCONF", "ACK", "0"]);
+
+                        stream.write_all(&response.as_bytes()).unwrap();
+                    }
+                };
+            }
+            "listening-port" => {
+                if let Role::Master(master) = persistence.info.write().unwrap().role.borrow_mut() {
+                    if let Some(RespData::BulkString(_v)) = vals.get(2) {
+                        master.slave_ports.push(port_addr);
+                        master
+                            .slave_stream
+                            .insert(port_addr, Arc::new(Mutex::new(stream.try_clone().unwrap())));
+                        // Nothing is sent before the snapshot PSYNC asks for.
+                        master.syncing.insert(port_addr, vec![]);
+                        write_stream(stream, b"+OK\r\n");
+                    }
+                } else {
+                    handle_error(stream, "Slave can't treat REPLCONF");
+                }
+            }
+            "capa" => {
+                let eof = vals[1..]
+                    .chunks(2)
+                    .any(|pair| pair.get(1).and_then(RespData::inside_value) == Some("eof"));
+
+                if let Role::Master(master) = persistence.info.write().unwrap().role.borrow_mut() {
+                    if eof {
+                        master.eof_capable.insert(port_addr);
+                    }
+                }
+
+                write_stream(stream, b"+OK\r\n");
+            }
+            _ => {
+                write_stream(stream, b"+OK\r\n");
+            }
+        };
+    };
+}
+
+fn handle_psync(persistence: &State, stream: &mut Connection, _vals: &[RespData]) {
+    let port = stream.peer_addr().unwrap().port();
+
+    let (reply, link, diskless) = match persistence.info.write().unwrap().role.borrow_mut() {
+        Role::Master(master) => {
+            if !master.slave_stream.contains_key(&port) {
+                master.slave_ports.push(port);
+                master
+                    .slave_stream
+                    .insert(port, Arc::new(Mutex::new(stream.try_clone().unwrap())));
+            }
+
+            // Writes from here on are not in the snapshot, they follow it.
+            master.syncing.insert(port, vec![]);
+
+            (
+                format!("FULLRESYNC {} {}", master.replication_id, master.offset),
+                Arc::clone(&master.slave_stream[&port]),
+                master.eof_capable.contains(&port),
+            )
+        }
+        Role::Slave(_) => {
+            handle_error(stream, "Slave can't handle PSYNC");
+            return;
+        }
+    };
+
+    // Diskless transfers need the replica to take the EOF marker framing.
+    let diskless = diskless && persistence.config.read().unwrap().repl_diskless_sync;
+
+    write_stream(stream, &RespData::SimpleString(reply).as_bytes());
+
+    // PSYNC holds every other client off, so this is the same point in
+    // time the held back writes start from.
+    let dirty = persistence.saving.dirty();
+    let entries = rdb::save::snapshot(&persistence.persisted);
+    let persistence = Arc::clone(persistence);
+
+    thread::spawn(move || {
+        transfer_snapshot(&persistence, port, &link, &entries, dirty, diskless);
+    });
+}
+
+/// A random 40 character marker ending a diskless transfer.
+fn eof_mark() -> String {
+    (0..40)
+        .map(|_| char::from_digit(random_index(16) as u32, 16).unwrap())
+        .collect()
+}
+
+/// Sends a replica the snapshot its full resync starts from, then the
+/// writes held back for it meanwhile. On disk the snapshot is saved as the
+/// RDB file first and sent with its length, diskless it goes straight to
+/// the socket between EOF markers.
+fn transfer_snapshot(
+    persistence: &State,
+    port: u16,
+    link: &Mutex<Connection>,
+    entries: &[rdb::RdbEntry],
+    dirty: u64,
+    diskless: bool,
+) {
+    let data = rdb::save::serialize(entries);
+
+    let payload = match diskless {
+        true => {
+            let mark = eof_mark();
+            [format!("$EOF:{}\r\n", mark).as_bytes(), &data, mark.as_bytes()].concat()
+        }
+        false => {
+            if !save_snapshot(persistence, &data, dirty, false) {
+                drop_replica(persistence, port);
+                return;
+            }
+
+            [format!("${}\r\n", data.len()).as_bytes(), &data[..]].concat()
+        }
+    };
+
+    if let Err(e) = link.lock().unwrap().write_all(&payload) {
+        println!("error: {}", e);
+        drop_replica(persistence, port);
+        return;
+    }
+
+    if let Role::Master(master) = persistence.info.write().unwrap().role.borrow_mut() {
+        if let Some(held) = master.syncing.remove(&port) {
+            if let Err(e) = link.lock().unwrap().write_all(&held) {
+                println!("error: {}", e);
+            }
+        }
+    }
+}
+
+/// Forgets a replica whose sync failed and hangs up on it.
+fn drop_replica(persistence: &State, port: u16) {
+    if let Role::Master(master) = persistence.info.write().unwrap().role.borrow_mut() {
+        master.slave_ports.retain(|&p| p != port);
+        master.syncing.remove(&port);
+        master.eof_capable.remove(&port);
+
+        if let Some(link) = master.slave_stream.remove(&port) {
+            let _ = link.lock().unwrap().shutdown(Shutdown::Both);
+        }
+    }
+}
+
+/// Writes through the client's shared writer, for replies that must not
+/// interleave with messages other connections publish to it.
+fn write_client(client: &Client, data: &RespData) {
+    if let Err(e) = client.writer.lock().unwrap().write_all(&data.as_bytes()) {
+        println!("error: {}", e);
+    }
+}
+
+/// Writes (un)subscription confirmations, pushed to RESP3 clients.
+fn write_push(client: &Client, data: RespData) {
+    write_client(client, &client.subscriber().frame(data));
+}
+
+fn handle_subscribe(persistence: &State, client: &mut Client, vals: &[RespData], pattern: bool) {
+    let subscriber = client.subscriber();
+    let kind = if pattern { "psubscribe" } else { "subscribe" };
+
+    // Holding the writer keeps messages from overtaking the confirmations.
+    let writer = Arc::clone(&client.writer);
+    let mut writer = writer.lock().unwrap();
+
+    for name in &vals[1..] {
+        let name = name.inside_value().unwrap();
+
+        let added = match pattern {
+            true => persistence.pubsub.psubscribe(name, &subscriber),
+            false => persistence.pubsub.subscribe(name, &subscriber),
+        };
+
+        if added {
+            match pattern {
+                true => client.patterns.push(name.to_string()),
+                false => client.channels.push(name.to_string()),
+            }
+        }
+
+        let reply = subscriber.frame(RespData::Array(vec![
+            RespData::new_bulk(kind),
+            RespData::new_bulk(name),
+            RespData::Integer(client.subscriptions() as i64),
+        ]));
+
+        if let Err(e) = writer.write_all(&reply.as_bytes()) {
+            println!("error: {}", e);
+        }
+    }
+}
+
+/// Drops the given subscriptions, all of them without arguments.
+fn handle_unsubscribe(persistence: &State, client: &mut Client, vals: &[RespData], pattern: bool) {
+    let kind = if pattern { "punsubscribe" } else { "unsubscribe" };
+
+    let names = match (vals.len(), pattern) {
+        (1, true) => client.patterns.clone(),
+        (1, false) => client.channels.clone(),
+        _ => string_args(&vals[1..]),
+    };
+
+    if names.is_empty() {
+        write_push(
+            client,
+            RespData::Array(vec![
+                RespData::new_bulk(kind),
+                RespData::Null,
+                RespData::Integer(client.subscriptions() as i64),
+            ]),
+        );
+        return;
+    }
+
+    for name in names {
+        match pattern {
+            true => {
+                persistence.pubsub.punsubscribe(&name, client.id);
+                client.patterns.retain(|p| *p != name);
+            }
+            false => {
+                persistence.pubsub.unsubscribe(&name, client.id);
+                client.channels.retain(|c| *c != name);
+            }
+        }
+
+        write_push(
+            client,
+            RespData::Array(vec![
+                RespData::new_bulk(kind),
+                RespData::from(name),
+                RespData::Integer(client.subscriptions() as i64),
+            ]),
+        );
+    }
+}
+
+fn unsubscribe_all(persistence: &State, client: &mut Client) {
+    for channel in client.channels.drain(..) {
+        persistence.pubsub.unsubscribe(&channel, client.id);
+    }
+
+    for pattern in client.patterns.drain(..) {
+        persistence.pubsub.punsubscribe(&pattern, client.id);
+    }
+
+    for channel in persistence.pubsub.shard_subscriptions(client.id) {
+        persistence.pubsub.sunsubscribe(&channel, client.id);
+    }
+}
+
+/// Whether the client subscribes to anything, sharded channels included.
+/// Those live in the registry only, losing a slot drops them behind the
+/// client's back.
+fn in_pubsub(persistence: &State, client: &Client) -> bool {
+    client.subscriptions() > 0 || !persistence.pubsub.shard_subscriptions(client.id).is_empty()
+}
+
+/// The slot all of `channels` hash to, or the error to reply with.
+fn shard_slot(persistence: &State, channels: &[RespData]) -> Result<u16, &'static str> {
+    let mut slots = channels
+        .iter()
+        .map(|channel| key_hash_slot(channel.inside_value().unwrap().as_bytes()));
+
+    let slot = slots.next().unwrap();
+
+    if slots.any(|other| other != slot) {
+        return Err("CROSSSLOT Keys in request don't hash to the same slot");
+    }
+
+    if !persistence.slots.serves(slot) {
+        return Err("CLUSTERDOWN Hash slot not served");
+    }
+
+    Ok(slot)
+}
+
+fn handle_ssubscribe(persistence: &State, client: &mut Client, vals: &[RespData]) {
+    if let Err(e) = shard_slot(persistence, &vals[1..]) {
+        write_client(client, &RespData::Error(e.to_string()));
+        return;
+    }
+
+    let subscriber = client.subscriber();
+
+    // Holding the writer keeps messages from overtaking the confirmations.
+    let mut writer = client.writer.lock().unwrap();
+
+    for channel in &vals[1..] {
+        let channel = channel.inside_value().unwrap();
+        let count = persistence.pubsub.ssubscribe(channel, &subscriber);
+
+        let reply = subscriber.frame(RespData::Array(vec![
+            RespData::new_bulk("ssubscribe"),
+            RespData::new_bulk(channel),
+            RespData::Integer(count as i64),
+        ]));
+
+        if let Err(e) = writer.write_all(&reply.as_bytes()) {
+            println!("error: {}", e);
+        }
+    }
+}
+
+/// Drops the given sharded subscriptions, all of them without arguments.
+/// Counts in the replies only cover sharded channels.
+fn handle_sunsubscribe(persistence: &State, client: &mut Client, vals: &[RespData]) {
+    let channels = match vals.len() {
+        1 => persistence.pubsub.shard_subscriptions(client.id),
+        _ => string_args(&vals[1..]),
+    };
+
+    if channels.is_empty() {
+        write_push(
+            client,
+            RespData::Array(vec![
+                RespData::new_bulk("sunsubscribe"),
+                RespData::Null,
+                RespData::Integer(0),
+            ]),
+        );
+        return;
+    }
+
+    for channel in channels {
+        let count = persistence.pubsub.sunsubscribe(&channel, client.id);
+
+        write_push(
+            client,
+            RespData::Array(vec![
+                RespData::new_bulk("sunsubscribe"),
+                RespData::from(channel),
+                RespData::Integer(count as i64),
+            ]),
+        );
+    }
+}
+
+fn handle_spublish(persistence: &State, client: &mut Client, vals: &[RespData]) {
+    if let Err(e) = shard_slot(persistence, &vals[1..2]) {
+        if !client.master_link {
+            handle_error(&mut client.stream, e);
+        }
+        return;
+    }
+
+    let channel = vals.get(1).unwrap().inside_value().unwrap();
+    let message = vals.get(2).unwrap().inside_value().unwrap();
+
+    let receivers = persistence.pubsub.spublish(channel, message);
+
+    propagate(persistence, vals);
+
+    if !client.master_link {
+        write_stream(
+            &mut client.stream,
+            &RespData::Integer(receivers as i64).as_bytes(),
+        );
+    }
+}
+
+fn handle_publish(persistence: &State, client: &mut Client, vals: &[RespData]) {
+    let channel = vals.get(1).unwrap().inside_value().unwrap();
+    let message = vals.get(2).unwrap().inside_value().unwrap();
+
+    let receivers = persistence.pubsub.publish(channel, message);
+
+    // Replicas deliver it to their own subscribers.
+    propagate(persistence, vals);
+
+    if !client.master_link {
+        write_stream(
+            &mut client.stream,
+            &RespData::Integer(receivers as i64).as_bytes(),
+        );
+    }
+}
+
+fn handle_pubsub(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    let subcommand = vals.get(1).unwrap().inside_value().unwrap().to_lowercase();
+
+    let arity = match subcommand.as_str() {
+        "channels" | "numsub" | "shardchannels" | "shardnumsub" => -2,
+        "numpat" => 2,
+        _ => {
+            handle_unknown_subcommand(stream, vals);
+            return;
+        }
+    };
+
+    if !check_subcommand_arity(stream, vals, arity) {
+        return;
+    }
+
+    if matches!(subcommand.as_str(), "channels" | "shardchannels") && vals.len() > 3 {
+        handle_unknown_subcommand(stream, vals);
+        return;
+    }
+
+    let pattern = vals.get(2).and_then(RespData::inside_value);
+
+    let numsub = |numsub: &dyn Fn(&str) -> usize| {
+        RespData::Array(
+            vals[2..]
+                .iter()
+                .flat_map(|channel| {
+                    let channel = channel.inside_value().unwrap();
+
+                    [
+                        RespData::new_bulk(channel),
+                        RespData::Integer(numsub(channel) as i64),
+                    ]
+                })
+                .collect(),
+        )
+    };
+
+    let reply = match subcommand.as_str() {
+        "channels" => bulk_array(persistence.pubsub.channels(pattern)),
+        "shardchannels" => bulk_array(persistence.pubsub.shard_channels(pattern)),
+        "numsub" => numsub(&|channel| persistence.pubsub.numsub(channel)),
+        "shardnumsub" => numsub(&|channel| persistence.pubsub.shard_numsub(channel)),
+        _ => RespData::Integer(persistence.pubsub.numpat() as i64),
+    };
+
+    write_stream(stream, &reply.as_bytes());
+}
+
+/// CONFIG GET with glob patterns, and CONFIG SET of any number of
+/// parameters, applied all together or not at all.
+fn handle_config(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    let subcommand = vals.get(1).unwrap().inside_value().unwrap().to_lowercase();
+
+    let arity = match subcommand.as_str() {
+        "get" => -3,
+        "set" => -4,
+        _ => {
+            handle_unknown_subcommand(stream, vals);
+            return;
+        }
+    };
+
+    if !check_subcommand_arity(stream, vals, arity) {
+        return;
+    }
+
+    if subcommand == "get" {
+        let config = persistence.config.read().unwrap();
+
+        let reply: Vec<String> = Config::parameters()
+            .iter()
+            .filter(|name| {
+                vals[2..].iter().any(|pattern| {
+                    let pattern = pattern.inside_value().unwrap().to_lowercase();
+                    glob_match(pattern.as_bytes(), name.as_bytes())
+                })
+            })
+            .flat_map(|name| [name.to_string(), config.get(name).unwrap()])
+            .collect();
+
+        write_stream(stream, &bulk_array(reply).as_bytes());
+        return;
+    }
+
+    if !vals.len().is_multiple_of(2) {
+        handle_error(
+            stream,
+            "ERR wrong number of arguments for 'config|set' command",
+        );
+        return;
+    }
+
+    let mut config = persistence.config.write().unwrap();
+    let mut updated = config.clone();
+
+    for pair in vals[2..].chunks(2) {
+        let name = pair[0].inside_value().unwrap();
+        let value = pair[1].inside_value().unwrap();
+
+        if !Config::is_mutable(name) {
+            handle_error(
+                stream,
+                &format!(
+                    "ERR CONFIG SET failed (possibly related to argument '{}') - can't set immutable config",
+                    name
+                ),
+            );
+            return;
+        }
+
+        match updated.set(name, value) {
+            Ok(()) => {}
+            Err(None) => {
+                handle_error(
+                    stream,
+                    &format!(
+                        "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                        name
+                    ),
+                );
+                return;
+            }
+            Err(Some(reason)) => {
+                handle_error(
+                    stream,
+                    &format!(
+                        "ERR CONFIG SET failed (possibly related to argument '{}') - {}",
+                        name, reason
+                    ),
+                );
+                return;
+            }
+        }
+    }
+
+    // Switching the AOF on waits for the background cycle to write the
+    // keyspace to it first.
+    match (config.appendonly, updated.appendonly) {
+        (false, true) => persistence.aof.request_start(),
+        (true, false) => persistence.aof.close(),
+        _ => {}
+    }
+
+    *config = updated;
+
+    write_stream(stream, &RespData::new_simple_string("OK").as_bytes());
+}
+
+/// CLUSTER KEYSLOT and the slot assignment subcommands. Without a cluster
+/// bus, ADDSLOTS/DELSLOTS are how a node gains or loses slots.
+fn handle_cluster(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    let subcommand = vals.get(1).unwrap().inside_value().unwrap().to_lowercase();
+
+    let arity = match subcommand.as_str() {
+        "keyslot" => 3,
+        "addslots" | "delslots" => -3,
+        _ => {
+            handle_unknown_subcommand(stream, vals);
+            return;
+        }
+    };
+
+    if !check_subcommand_arity(stream, vals, arity) {
+        return;
+    }
+
+    if subcommand == "keyslot" {
+        let key = vals.get(2).unwrap().inside_value().unwrap();
+        let slot = key_hash_slot(key.as_bytes());
+        write_stream(stream, &RespData::Integer(slot as i64).as_bytes());
+        return;
+    }
+
+    let mut slots = vec![];
+
+    for slot in &vals[2..] {
+        match slot.inside_value().unwrap().parse::<u16>() {
+            Ok(slot) if (slot as usize) < CLUSTER_SLOTS => slots.push(slot),
+            _ => {
+                handle_error(stream, "ERR Invalid or out of range slot");
+                return;
+            }
+        }
+    }
+
+    let serve = subcommand == "addslots";
+
+    if let Err(e) = persistence.slots.set_served(&slots, serve) {
+        handle_error(stream, &e);
+        return;
+    }
+
+    // Sharded subscribers follow their slot to whichever node takes it.
+    if !serve {
+        persistence.pubsub.drop_slots(&slots);
+    }
+
+    write_stream(stream, &RespData::new_simple_string("OK").as_bytes());
+}
+
+fn handle_reset(persistence: &State, client: &mut Client) {
+    client.discard_multi();
+    unwatch_all(persistence, client);
+    unsubscribe_all(persistence, client);
+
+    write_client(client, &RespData::new_simple_string("RESET"));
+}
+
+/// Releases what a connection holds in shared state once it closes.
+pub fn close_client(persistence: &State, client: &mut Client) {
+    unwatch_all(persistence, client);
+    unsubscribe_all(persistence, client);
+    persistence.tracking.disconnect(client.id);
+}
+
+/// Makes a new connection known, as somewhere invalidations can go.
+pub fn open_client(persistence: &State, client: &Client) {
+    persistence.tracking.connect(client.subscriber());
+}
+
+/// HELLO [protover], switches the connection between RESP2 and RESP3 and
+/// describes the server.
+fn handle_hello(persistence: &State, client: &mut Client, vals: &[RespData]) {
+    if let Some(option) = vals.get(2) {
+        handle_error(
+            &mut client.stream,
+            &format!(
+                "ERR Syntax error in HELLO option '{}'",
+                option.inside_value().unwrap()
+            ),
+        );
+        return;
+    }
+
+    if let Some(version) = vals.get(1) {
+        match version.inside_value().unwrap().parse::<i64>() {
+            Ok(2) => client.resp3.store(false, Ordering::Relaxed),
+            Ok(3) => client.resp3.store(true, Ordering::Relaxed),
+            Ok(_) => {
+                handle_error(&mut client.stream, "NOPROTO unsupported protocol version");
+                return;
+            }
+            Err(_) => {
+                handle_error(
+                    &mut client.stream,
+                    "ERR Protocol version is not an integer or out of range",
+                );
+                return;
+            }
+        }
+    }
+
+    let role = match persistence.info.read().unwrap().is_master() {
+        true => "master",
+        false => "replica",
+    };
+
+    let reply = RespData::Map(vec![
+        (RespData::new_bulk("server"), RespData::new_bulk("redis")),
+        (RespData::new_bulk("version"), RespData::new_bulk("7.2.0")),
+        (
+            RespData::new_bulk("proto"),
+            RespData::Integer(if client.resp3() { 3 } else { 2 }),
+        ),
+        (
+            RespData::new_bulk("id"),
+            RespData::Integer(client.id as i64),
+        ),
+        (RespData::new_bulk("mode"), RespData::new_bulk("standalone")),
+        (RespData::new_bulk("role"), RespData::new_bulk(role)),
+        (RespData::new_bulk("modules"), RespData::Array(vec![])),
+    ]);
+
+    write_client(client, &reply.for_protocol(client.resp3()));
+}
+
+fn parse_tracking_options(vals: &[RespData]) -> Result<TrackingOptions, String> {
+    let mut options = TrackingOptions::default();
+    let mut i = 0;
+
+    while i < vals.len() {
+        let option = vals[i].inside_value().unwrap().to_lowercase();
+
+        match (option.as_str(), vals.get(i + 1)) {
+            ("redirect", Some(id)) => {
+                let id = id
+                    .inside_value()
+                    .unwrap()
+                    .parse::<u64>()
+                    .map_err(|_| "ERR value is not an integer or out of range".to_string())?;
+
+                options.redirect = Some(id);
+                i += 1;
+            }
+            ("prefix", Some(prefix)) => {
+                options
+                    .prefixes
+                    .push(prefix.inside_value().unwrap().to_string());
+                i += 1;
+            }
+            ("bcast", _) => options.bcast = true,
+            ("optin", _) => options.optin = true,
+            ("optout", _) => options.optout = true,
+            ("noloop", _) => options.noloop = true,
+            _ => return Err("ERR syntax error".to_string()),
+        }
+
+        i += 1;
+    }
+
+    if !options.bcast && !options.prefixes.is_empty() {
+        return Err("ERR PREFIX option requires BCAST mode to be enabled".to_string());
+    }
+
+    if options.optin && options.optout {
+        return Err("ERR You can't use both OPTIN and OPTOUT".to_string());
+    }
+
+    if options.bcast && (options.optin || options.optout) {
+        return Err("ERR OPTIN and OPTOUT are not compatible with BCAST".to_string());
+    }
+
+    Ok(options)
+}
+
+/// CLIENT ID and the client side caching subcommands.
+fn handle_client(persistence: &State, client: &mut Client, vals: &[RespData]) {
+    let subcommand = vals.get(1).unwrap().inside_value().unwrap().to_lowercase();
+
+    let arity = match subcommand.as_str() {
+        "id" | "getredir" | "trackinginfo" => 2,
+        "caching" => 3,
+        "tracking" => -3,
+        _ => {
+            handle_unknown_subcommand(&mut client.stream, vals);
+            return;
+        }
+    };
+
+    if !check_subcommand_arity(&mut client.stream, vals, arity) {
+        return;
+    }
+
+    let reply = match subcommand.as_str() {
+        "id" => RespData::Integer(client.id as i64),
+        "getredir" => RespData::Integer(persistence.tracking.redirect(client.id)),
+        "trackinginfo" => {
+            let (flags, redirect, prefixes) = persistence.tracking.info(client.id, client.caching);
+
+            RespData::Map(vec![
+                (
+                    RespData::new_bulk("flags"),
+                    RespData::new_bulk_array(&flags),
+                ),
+                (RespData::new_bulk("redirect"), RespData::Integer(redirect)),
+                (RespData::new_bulk("prefixes"), bulk_array(prefixes)),
+            ])
+        }
+        "caching" => {
+            let (optin, optout) = match persistence.tracking.opt_mode(client.id) {
+                Some(mode) if mode != (false, false) => mode,
+                _ => {
+                    handle_error(
+                        &mut client.stream,
+                        "ERR CLIENT CACHING can be called only when the client is in tracking mode with OPTIN or OPTOUT mode enabled",
+                    );
+                    return;
+                }
+            };
+
+            let caching = vals.get(2).unwrap().inside_value().unwrap().to_lowercase();
+
+            match caching.as_str() {
+                "yes" if optin => client.caching = Some(true),
+                "no" if optout => client.caching = Some(false),
+                "yes" => {
+                    handle_error(
+                        &mut client.stream,
+                        "ERR CLIENT CACHING YES is only valid when tracking is enabled in OPTIN mode.",
+                    );
+                    return;
+                }
+                "no" => {
+                    handle_error(
+                        &mut client.stream,
+                        "ERR CLIENT CACHING NO is only valid when tracking is enabled in OPTOUT mode.",
+                    );
+                    return;
+                }
+                _ => {
+                    handle_error(&mut client.stream, "ERR syntax error");
+                    return;
+                }
+            }
+
+            RespData::new_simple_string("OK")
+        }
+        _ => {
+            let on = match vals
+                .get(2)
+                .unwrap()
+                .inside_value()
+                .unwrap()
+                .to_lowercase()
+                .as_str()
+            {
+                "on" => true,
+                "off" => false,
+                _ => {
+                    handle_error(&mut client.stream, "ERR syntax error");
+                    return;
+                }
+            };
+
+            let options = match parse_tracking_options(&vals[3..]) {
+                Ok(options) => options,
+                Err(e) => {
+                    handle_error(&mut client.stream, &e);
+                    return;
+                }
+            };
+
+            if on {
+                if let Err(e) = persistence.tracking.enable(client.id, options) {
+                    handle_error(&mut client.stream, &e);
+                    return;
+                }
+            } else {
+                persistence.tracking.disable(client.id);
+            }
+
+            RespData::new_simple_string("OK")
+        }
+    };
+
+    write_client(client, &reply.for_protocol(client.resp3()));
+}
+
+/// Writes a serialized snapshot to the RDB file, returns whether it worked.
+/// `dirty` is the change count the snapshot was taken at.
+fn save_snapshot(persistence: &State, data: &[u8], dirty: u64, background: bool) -> bool {
+    let path = persistence.config.read().unwrap().rdb_path();
+
+    let result = rdb::save::write_file(&path, data);
+
+    if let Err(e) = &result {
+        eprintln!("Failed saving the DB to {}: {}", path.display(), e);
+    }
+
+    persistence.saving.finish(result.is_ok(), dirty, background);
+    result.is_ok()
+}
+
+fn handle_save(persistence: &State, stream: &mut Connection) {
+    if persistence.saving.in_progress() {
+        handle_error(stream, "ERR Background save already in progress");
+        return;
+    }
+
+    let dirty = persistence.saving.dirty();
+    let data = rdb::save::serialize(&rdb::save::snapshot(&persistence.persisted));
+
+    match save_snapshot(persistence, &data, dirty, false) {
+        true => write_stream(stream, b"+OK\r\n"),
+        false => handle_error(stream, "ERR"),
+    }
+}
+
+/// Copies the keyspace and writes it out from a thread of its own, false
+/// when a BGSAVE is already running. The caller holds the write side of the
+/// exec lock, so the copy is a single point in time.
+fn bgsave(persistence: &State) -> bool {
+    if !persistence.saving.begin() {
+        return false;
+    }
+
+    let dirty = persistence.saving.dirty();
+    let entries = rdb::save::snapshot(&persistence.persisted);
+    let persistence = Arc::clone(persistence);
+
+    thread::spawn(move || {
+        if save_snapshot(&persistence, &rdb::save::serialize(&entries), dirty, true) {
+            println!("Background saving terminated with success");
+        }
+    });
+
+    true
+}
+
+fn handle_bgsave(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    let schedule = match vals.get(1).and_then(RespData::inside_value) {
+        None => false,
+        Some(arg) if vals.len() == 2 && arg.eq_ignore_ascii_case("schedule") => true,
+        Some(_) => {
+            handle_error(stream, "ERR syntax error");
+            return;
+        }
+    };
+
+    if bgsave(persistence) {
+        write_stream(stream, b"+Background saving started\r\n");
+    } else if schedule {
+        persistence.saving.schedule();
+        write_stream(stream, b"+Background saving scheduled\r\n");
+    } else {
+        handle_error(stream, "ERR Background save already in progress");
+    }
+}
+
+/// Starts an AOF rewrite in the background, the caller holding every
+/// command off. False when one is already running.
+fn bgrewriteaof(persistence: &State) -> io::Result<bool> {
+    let (dir, filename, use_rdb) = {
+        let config = persistence.config.read().unwrap();
+        (
+            config.aof_dir(),
+            config.appendfilename.clone(),
+            config.aof_use_rdb_preamble,
+        )
+    };
+
+    let rewrite = match persistence.aof.begin_rewrite(&dir, &filename, use_rdb)? {
+        Some(rewrite) => rewrite,
+        None => return Ok(false),
+    };
+
+    let entries = rdb::save::snapshot(&persistence.persisted);
+    let persistence = Arc::clone(persistence);
+
+    thread::spawn(move || {
+        let data = match rewrite.rdb() {
+            true => rdb::save::serialize(&entries),
+            false => aof::rewrite::commands(&entries),
+        };
+
+        let written = rdb::save::write_file(&rewrite.base_path(), &data).map(|_| data.len() as u64);
+
+        if persistence.aof.finish_rewrite(rewrite, written) {
+            println!("Background AOF rewrite terminated with success");
+        }
+    });
+
+    Ok(true)
+}
+
+fn handle_bgrewriteaof(persistence: &State, stream: &mut Connection) {
+    match bgrewriteaof(persistence) {
+        Ok(true) => write_stream(stream, b"+Background append only file rewriting started\r\n"),
+        Ok(false) => handle_error(
+            stream,
+            "ERR Background append only file rewriting already in progress",
+        ),
+        Err(e) => {
+            println!("Can't rewrite the append only file: {}", e);
+            handle_error(
+                stream,
+                "ERR Can't execute an AOF background rewriting. Please check the server logs for more information.",
+            );
+        }
+    }
+}
+
+/// Flushes the AOF once a second under `appendfsync everysec`, and starts a
+/// rewrite when it was switched on with CONFIG SET or grew enough.
+pub fn append_only_cycle(persistence: &State) {
+    let (fsync, percentage, min_size) = {
+        let config = persistence.config.read().unwrap();
+        (
+            config.appendfsync,
+            config.auto_aof_rewrite_percentage,
+            config.auto_aof_rewrite_min_size,
+        )
+    };
+
+    persistence.aof.fsync_cycle(fsync);
+
+    if !persistence.aof.rewrite_due(percentage, min_size) {
+        return;
+    }
+
+    let _exec = persistence.exec.write().unwrap();
+
+    if let Err(e) = bgrewriteaof(persistence) {
+        println!("Can't rewrite the append only file: {}", e);
+    }
+}
+
+/// Runs the commands loaded from the AOF, as a client with no peer to
+/// read the replies. Returns how many ran.
+pub fn replay_append_only(persistence: &State, commands: Vec<Vec<RespData>>) -> usize {
+    let mut client = Client::detached();
+    let count = commands.len();
+
+    for vals in commands {
+        let req = Resp {
+            data_type: RespType::Array,
+            data: RespData::Array(vals),
+        };
+
+        handle_request(persistence, &mut client, &req);
+    }
+
+    count
+}
+
+/// Starts a BGSAVE once a save point is reached or one was scheduled.
+pub fn save_points_cycle(persistence: &State) {
+    let points = persistence.config.read().unwrap().save.clone();
+
+    if persistence.saving.due(&points) {
+        let _exec = persistence.exec.write().unwrap();
+        bgsave(persistence);
+    }
+}
+
+fn handle_unknown_command(stream: &mut Connection, vals: &[RespData]) {
+    let name = vals.first().unwrap().inside_value().unwrap_or_default();
+
+    let args: String = vals[1..]
+        .iter()
+        .map(|arg| format!("'{}' ", arg.inside_value().unwrap_or_default()))
+        .collect();
+
+    handle_error(
+        stream,
+        &format!(
+            "ERR unknown command '{}', with args beginning with: {}",
+            name, args
+        ),
+    );
+}
+
+fn handle_multi(client: &mut Client) {
+    if client.multi.is_some() {
+        handle_error(&mut client.stream, "ERR MULTI calls can not be nested");
+        return;
+    }
+
+    client.multi = Some(vec![]);
+
+    if !client.master_link {
+        write_stream(&mut client.stream, b"+OK\r\n");
+    }
+}
+
+fn handle_discard(persistence: &State, client: &mut Client) {
+    if client.multi.is_none() {
+        handle_error(&mut client.stream, "ERR DISCARD without MULTI");
+        return;
+    }
+
+    client.discard_multi();
+    unwatch_all(persistence, client);
+    write_stream(&mut client.stream, b"+OK\r\n");
+}
+
+/// Queues a command inside MULTI. Anything `EXEC` could tell is wrong up
+/// front is answered right away and aborts the transaction.
+fn queue_command(client: &mut Client, command: &CommandSpec, vals: &[RespData]) {
+    let error = if command.has(NO_MULTI) {
+        Some("ERR Command not allowed inside a transaction".to_string())
+    } else if !command.check_arity(vals.len()) {
+        Some(format!(
+            "ERR wrong number of arguments for '{}' command",
+            command.name
+        ))
+    } else {
+        None
+    };
+
+    if let Some(error) = error {
+        client.multi_error = true;
+        handle_error(&mut client.stream, &error);
+        return;
+    }
+
+    client.multi.as_mut().unwrap().push(vals.to_vec());
+
+    if !client.master_link {
+        write_stream(&mut client.stream, b"+QUEUED\r\n");
+    }
+}
+
+fn handle_watch(persistence: &State, client: &mut Client, vals: &[RespData]) {
+    if client.multi.is_some() {
+        handle_error(&mut client.stream, "ERR WATCH inside MULTI is not allowed");
+        return;
+    }
+
+    let _exec = persistence.exec.read().unwrap();
+
+    for key in &vals[1..] {
+        let key = key.inside_value().unwrap();
+
+        if client.watched.iter().any(|(watched, _)| watched == key) {
+            continue;
+        }
+
+        persistence.watched.watch(key, &client.dirty);
+        client
+            .watched
+            .push((key.to_string(), persistence.persisted.is_expired(key)));
+    }
+
+    write_stream(&mut client.stream, b"+OK\r\n");
+}
+
+fn unwatch_all(persistence: &State, client: &mut Client) {
+    for (key, _) in client.watched.drain(..) {
+        persistence.watched.unwatch(&key, &client.dirty);
+    }
+
+    client.dirty.store(false, Ordering::SeqCst);
+}
+
+fn handle_flushdb(persistence: &State, stream: &mut Connection, vals: &[RespData]) {
+    // Flushing is always synchronous, the modes are accepted for
+    // compatibility.
+    if let Some(mode) = vals.get(1) {
+        let mode = mode.inside_value().unwrap().to_lowercase();
+
+        if vals.len() > 2 || (mode != "async" && mode != "sync") {
+            handle_error(stream, "ERR syntax error");
+            return;
+        }
+    }
+
+    persistence
+        .watched
+        .touch_existing(|key| persistence.persisted.key_type(key).is_some());
+    let removed = persistence.persisted.flush();
+    persistence.saving.touch(removed as u64);
+    persistence
+        .tracking
+        .invalidate_all(client::current(), &|id| {
+            persistence.pubsub.subscribed(INVALIDATE_CHANNEL, id)
+        });
+
+    propagate(persistence, vals);
+    write_reply(persistence, stream, &RespData::new_simple_string("OK"));
+}
+
+fn handle_exec(persistence: &State, client: &mut Client) {
+    let queued = match client.multi.take() {
+        Some(queued) => queued,
+        None => {
+            handle_error(&mut client.stream, "ERR EXEC without MULTI");
+            return;
+        }
+    };
+
+    if std::mem::take(&mut client.multi_error) {
+        unwatch_all(persistence, client);
+        handle_error(
+            &mut client.stream,
+            "EXECABORT Transaction discarded because of previous errors.",
+        );
+        return;
+    }
+
+    let _exec = persistence.exec.write().unwrap();
+
+    // A watched key that expired since WATCH counts as touched.
+    let dirty = client.dirty.load(Ordering::SeqCst)
+        || client
+            .watched
+            .iter()
+            .any(|(key, expired)| !expired && persistence.persisted.is_expired(key));
+
+    unwatch_all(persistence, client);
+
+    if dirty {
+        if !client.master_link {
+            write_stream(&mut client.stream, &RespData::NullArray.as_bytes());
+        }
+        return;
+    }
+
+    *persistence.transaction.lock().unwrap() = Some(Transaction {
+        client: client.id,
+        propagated: vec![],
+    });
+
+    // Each command writes its own reply as an element of the array.
+    if !client.master_link {
+        write_stream(&mut client.stream, format!("*{}\r\n", queued.len()).as_bytes());
+    }
+
+    for vals in &queued {
+        execute(persistence, client, vals);
+    }
+
+    let transaction = persistence.transaction.lock().unwrap().take().unwrap();
+
+    if !transaction.propagated.is_empty() {
+        let multi = [RespData::new_bulk("MULTI")];
+        let exec = [RespData::new_bulk("EXEC")];
+
+        for vals in [&multi[..]]
+            .into_iter()
+            .chain(transaction.propagated.iter().map(Vec::as_slice))
+            .chain([&exec[..]])
+        {
+            feed_append_only(persistence, vals);
+            write_replicas(persistence, vals);
+        }
+    }
+}
+
+pub fn handle_request(persistence: &State, client: &mut Client, req: &Resp) {
+    let caching = client.caching;
+
+    dispatch(persistence, client, req);
+
+    // CLIENT CACHING covers the next command, or the whole transaction.
+    if client.caching == caching && client.multi.is_none() {
+        client.caching = None;
+    }
+
+    persistence.tracking.flush(client.id);
+}
+
+/// The arguments with invalid UTF-8 replaced, the way commands working on
+/// text read them. `None` when there's nothing to replace. RESTORE's
+/// payload isn't text, it stays as sent.
+fn text_args(command: &CommandSpec, vals: &[RespData]) -> Option<Vec<RespData>> {
+    let binary = match command.name {
+        "restore" => Some(3),
+        _ => None,
+    };
+
+    let text = |(i, val): (usize, &RespData)| Some(i) == binary || val.inside_value().is_some();
+
+    if vals.iter().enumerate().all(text) {
+        return None;
+    }
+
+    let args = vals
+        .iter()
+        .enumerate()
+        .map(|(i, val)| match Some(i) == binary {
+            true => val.clone(),
+            false => val.to_text(),
+        });
+
+    Some(args.collect())
+}
+
+fn dispatch(persistence: &State, client: &mut Client, req: &Resp) {
+    let vals = match &req.data {
+        RespData::Array(vals) if matches!(vals.first(), Some(RespData::BulkString(_))) => vals,
+        _ => {
+            handle_error(&mut client.stream, "Unexpected data type");
+            return;
+        }
+    };
+
+    let name = vals.first().unwrap().inside_value().unwrap_or_default();
+
+    let command = match command::lookup(name) {
+        Some(command) => command,
+        None => {
+            if client.multi.is_some() {
+                client.multi_error = true;
+            }
+
+            handle_unknown_command(&mut client.stream, vals);
+            return;
+        }
+    };
+
+    let text = text_args(command, vals);
+    let vals = text.as_deref().unwrap_or(vals);
+
+    // RESP2 subscribers only get to manage their subscriptions.
+    if !client.resp3()
+        && in_pubsub(persistence, client)
+        && !matches!(
+            command.name,
+            "subscribe"
+                | "unsubscribe"
+                | "psubscribe"
+                | "punsubscribe"
+                | "ssubscribe"
+                | "sunsubscribe"
+                | "ping"
+                | "quit"
+                | "reset"
+        )
+    {
+        write_client(
+            client,
+            &RespData::Error(format!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                command.name
+            )),
+        );
+        return;
+    }
+
+    if client.multi.is_some()
+        && !matches!(
+            command.name,
+            "multi" | "exec" | "discard" | "watch" | "quit" | "reset"
+        )
+    {
+        queue_command(client, command, vals);
+        return;
+    }
+
+    if !command.check_arity(vals.len()) {
+        handle_error(
+            &mut client.stream,
+            &format!("ERR wrong number of arguments for '{}' command", command.name),
+        );
+        return;
+    }
+
+    match command.name {
+        "multi" => handle_multi(client),
+        "exec" => handle_exec(persistence, client),
+        "discard" => handle_discard(persistence, client),
+        "watch" => handle_watch(persistence, client, vals),
+        "reset" => handle_reset(persistence, client),
+        "quit" => {
+            write_client(client, &RespData::new_simple_string("OK"));
+            let _ = client.stream.shutdown(Shutdown::Both);
+        }
+        _ => {
+            // Blocking commands take the lock per attempt instead, exclusive
+            // ones take the write side like EXEC.
+            let _exec =
+                (!command.has(BLOCKING | EXCLUSIVE)).then(|| persistence.exec.read().unwrap());
+            let _exclusive = command.has(EXCLUSIVE).then(|| persistence.exec.write().unwrap());
+
+            execute(persistence, client, vals);
+        }
+    }
+}
+
+/// Runs a command once it is known to exist with a valid arity.
+fn execute(persistence: &State, client: &mut Client, vals: &[RespData]) {
+    let command = vals.first().unwrap().inside_value().unwrap().to_lowercase();
+
+    let _running = client.enter();
+
+    // Remembered before reading, a write racing the read still invalidates.
+    if command::lookup(&command).is_some_and(|spec| spec.has(READONLY)) {
+        persistence
+            .tracking
+            .remember(client.id, &command::read_keys(vals), client.caching);
+    }
+
+    let stream = &mut client.stream;
+
+    match command.as_str() {
+        "xread" => handle_xread(persistence, stream, vals),
+        "xadd" => handle_xadd(persistence, stream, vals),
+        "xrange" => handle_xrange(persistence, stream, vals, false),
+        "xrevrange" => handle_xrange(persistence, stream, vals, true),
+        "xlen" => handle_xlen(persistence, stream, vals),
+        "xinfo" => handle_xinfo(persistence, stream, vals),
+        "xsetid" => handle_xsetid(persistence, stream, vals),
+        "xdel" => handle_xdel(persistence, stream, vals),
+        "xtrim" => handle_xtrim(persistence, stream, vals),
+        "xgroup" => handle_xgroup(persistence, stream, vals),
+        "xreadgroup" => handle_xreadgroup(persistence, stream, vals),
+        "xack" => handle_xack(persistence, stream, vals),
+        "xpending" => handle_xpending(persistence, stream, vals),
+        "xclaim" => handle_xclaim(persistence, stream, vals),
+        "xautoclaim" => handle_xautoclaim(persistence, stream, vals),
+        "ping" => handle_ping(persistence, client, vals),
+        "echo" => handle_echo(stream, vals.get(1).unwrap()),
+        "set" => handle_set(persistence, stream, vals),
+        "get" => handle_get(persistence, stream, vals),
+        "type" => handle_type(persistence, stream, vals),
+        "dump" => handle_dump(persistence, stream, vals),
+        "restore" => handle_restore(persistence, stream, vals),
+        "migrate" => handle_migrate(persistence, stream, vals),
+        "del" => handle_del(persistence, stream, vals),
+        "sadd" => handle_sadd(persistence, stream, vals),
+        "srem" => handle_srem(persistence, stream, vals),
+        "smembers" => handle_smembers(persistence, stream, vals),
+        "sismember" => handle_sismember(persistence, stream, vals),
+        "smismember" => handle_smismember(persistence, stream, vals),
+        "scard" => handle_scard(persistence, stream, vals),
+        "spop" => handle_spop(persistence, stream, vals),
+        "srandmember" => handle_srandmember(persistence, stream, vals),
+        "smove" => handle_smove(persistence, stream, vals),
+        "sinter" => handle_set_algebra(persistence, stream, vals, SetOp::Inter, false),
+        "sunion" => handle_set_algebra(persistence, stream, vals, SetOp::Union, false),
+        "sdiff" => handle_set_algebra(persistence, stream, vals, SetOp::Diff, false),
+        "sinterstore" => handle_set_algebra(persistence, stream, vals, SetOp::Inter, true),
+        "sunionstore" => handle_set_algebra(persistence, stream, vals, SetOp::Union, true),
+        "sdiffstore" => handle_set_algebra(persistence, stream, vals, SetOp::Diff, true),
+        "sintercard" => handle_sintercard(persistence, stream, vals),
+        "zadd" => handle_zadd(persistence, stream, vals),
+        "zincrby" => handle_zincrby(persistence, stream, vals),
+        "zrem" => handle_zrem(persistence, stream, vals),
+        "zscore" => handle_zscore(persistence, stream, vals),
+        "zmscore" => handle_zmscore(persistence, stream, vals),
+        "zcard" => handle_zcard(persistence, stream, vals),
+        "zrank" => handle_zrank(persistence, stream, vals, false),
+        "zrevrank" => handle_zrank(persistence, stream, vals, true),
+        "zcount" => handle_zcount(persistence, stream, vals),
+        "zrange" => handle_zrange(persistence, stream, vals),
+        "zunionstore" => handle_zset_store(persistence, stream, vals, ZSetOp::Union),
+        "zinterstore" => handle_zset_store(persistence, stream, vals, ZSetOp::Inter),
+        "zdiffstore" => handle_zset_store(persistence, stream, vals, ZSetOp::Diff),
+        "zrangestore" => handle_zrangestore(persistence, stream, vals),
+        "zpopmin" => handle_zpop(persistence, stream, vals, false),
+        "zpopmax" => handle_zpop(persistence, stream, vals, true),
+        "bzpopmin" => handle_bzpop(persistence, stream, vals, false),
+        "bzpopmax" => handle_bzpop(persistence, stream, vals, true),
+        "zmpop" => handle_zmpop(persistence, stream, vals),
+        "bzmpop" => handle_bzmpop(persistence, stream, vals),
+        "geoadd" => handle_geoadd(persistence, stream, vals),
+        "geopos" => handle_geopos(persistence, stream, vals),
+        "geodist" => handle_geodist(persistence, stream, vals),
+        "geohash" => handle_geohash(persistence, stream, vals),
+        "geosearch" => handle_geosearch(persistence, stream, vals, false),
+        "geosearchstore" => handle_geosearch(persistence, stream, vals, true),
+        "info" => handle_info(persistence, stream, vals),
+        "replconf" => handle_replconf(persistence, stream, vals),
+        "psync" => handle_psync(persistence, stream, vals),
+        "flushdb" => handle_flushdb(persistence, stream, vals),
+        "unwatch" => {
+            unwatch_all(persistence, client);
+            write_stream(&mut client.stream, b"+OK\r\n");
+        }
+        "subscribe" => handle_subscribe(persistence, client, vals, false),
+        "psubscribe" => handle_subscribe(persistence, client, vals, true),
+        "unsubscribe" => handle_unsubscribe(persistence, client, vals, false),
+        "punsubscribe" => handle_unsubscribe(persistence, client, vals, true),
+        "ssubscribe" => handle_ssubscribe(persistence, client, vals),
+        "sunsubscribe" => handle_sunsubscribe(persistence, client, vals),
+        "publish" => handle_publish(persistence, client, vals),
+        "spublish" => handle_spublish(persistence, client, vals),
+        "pubsub" => handle_pubsub(persistence, stream, vals),
+        "cluster" => handle_cluster(persistence, stream, vals),
+        "hello" => handle_hello(persistence, client, vals),
+        "client" => handle_client(persistence, client, vals),
+        "config" => handle_config(persistence, stream, vals),
+        "save" => handle_save(persistence, stream),
+        "bgsave" => handle_bgsave(persistence, stream, vals),
+        "bgrewriteaof" => handle_bgrewriteaof(persistence, stream),
+        "lastsave" => write_stream(
+            stream,
+            &RespData::Integer(persistence.saving.lastsave() as i64).as_bytes(),
+        ),
+        _ => handle_unknown_command(stream, vals),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader},
+        net::{TcpListener, TcpStream},
+        sync::mpsc,
+        thread,
+    };
+
+    use super::*;
+
+    fn state() -> State {
+        Arc::new(StateInner {
+            persisted: PersistenceInner::default(),
+            info: RwLock::new(Info::default()),
+            blocking: BlockingKeys::default(),
+            exec: RwLock::new(()),
+            transaction: Mutex::new(None),
+            watched: WatchedKeys::default(),
+            pubsub: PubSub::default(),
+            slots: Slots::default(),
+            config: RwLock::new(Config::default()),
+            tracking: Tracking::default(),
+            saving: SaveState::default(),
+            aof: Aof::default(),
+            migrate: MigrateSockets::default(),
+        })
+    }
+
+    /// A client on a loopback socket, with the end its replies arrive at.
+    struct Session {
+        state: State,
+        client: Client,
+        peer: BufReader<TcpStream>,
+    }
+
+    impl Session {
+        fn new(state: &State) -> Session {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let (peer, _) = listener.accept().unwrap();
+
+            peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+            let client = Client::new(stream);
+            open_client(state, &client);
+
+            Session {
+                state: Arc::clone(state),
+                client,
+                peer: BufReader::new(peer),
+            }
+        }
+
+        fn run(&mut self, args: &[&[u8]]) {
+            let vals = args
+                .iter()
+                .map(|arg| RespData::BulkString(arg.to_vec()))
+                .collect();
+
+            let req = Resp {
+                data_type: RespType::Array,
+                data: RespData::Array(vals),
+            };
+
+            handle_request(&self.state, &mut self.client, &req);
+        }
+
+        /// The next whole reply, as sent.
+        fn reply(&mut self) -> Vec<u8> {
+            let mut out = vec![];
+            self.peer.read_until(b'\n', &mut out).unwrap();
+
+            let len = std::str::from_utf8(&out[1..out.len() - 2])
+                .ok()
+                .and_then(|len| len.parse::<i64>().ok())
+                .unwrap_or(-1);
+
+            match out[0] {
+                b'$' if len >= 0 => {
+                    let mut data = vec![0; len as usize + 2];
+                    self.peer.read_exact(&mut data).unwrap();
+                    out.extend(data);
+                }
+                b'*' | b'>' | b'~' if len >= 0 => {
+                    for _ in 0..len {
+                        out.extend(self.reply());
+                    }
+                }
+                b'%' => {
+                    for _ in 0..len * 2 {
+                        out.extend(self.reply());
+                    }
+                }
+                _ => {}
+            }
+
+            out
+        }
+
+        fn call<T: AsRef<[u8]>>(&mut self, args: &[T]) -> String {
+            let args: Vec<&[u8]> = args.iter().map(|arg| arg.as_ref()).collect();
+            self.run(&args);
+            String::from_utf8_lossy(&self.reply()).into_owned()
+        }
+
+        /// Events received through `PSUBSCRIBE __keyevent@0__:*`, up to the
+        /// `end` one a test publishes to mark where its commands stopped.
+        fn events(&mut self) -> Vec<String> {
+            let mut events = vec![];
+
+            loop {
+                let reply = String::from_utf8(self.reply()).unwrap();
+                let channel = reply.split("\r\n").nth(6).unwrap();
+                let event = channel.strip_prefix("__keyevent@0__:").unwrap();
+
+                if event == "end" {
+                    return events;
+                }
+
+                events.push(event.to_string());
+            }
+        }
+
+        /// Runs a command, returning what it propagated to replicas and the AOF.
+        fn propagated<T: AsRef<[u8]>>(&mut self, args: &[T]) -> Vec<String> {
+            *self.state.transaction.lock().unwrap() = Some(Transaction {
+                client: u64::MAX,
+                propagated: vec![],
+            });
+
+            self.call(args);
+
+            let transaction = self.state.transaction.lock().unwrap().take().unwrap();
+
+            transaction
+                .propagated
+                .iter()
+                .map(|command| {
+                    let args: Vec<String> = command
+                        .iter()
+                        .map(|arg| {
+                            String::from_utf8_lossy(arg.inside_bytes().unwrap()).into_owned()
+                        })
+                        .collect();
+                    args.join(" ")
+                })
+                .collect()
+        }
+    }
+
+    #[test]
+    fn set_replaces_a_value_of_another_type() {
+        let state = state();
+        let mut session = Session::new(&state);
+
+        session.call(&["SADD", "k", "a"]);
+        session.call(&["ZADD", "z", "1", "a"]);
+
+        for key in ["k", "z"] {
+            assert_eq!(session.call(&["SET", key, "v"]), "+OK\r\n");
+            assert_eq!(session.call(&["TYPE", key]), "+string\r\n");
+        }
+
+        assert!(state.persisted.set.lock().unwrap().0.is_empty());
+        assert!(state.persisted.sorted_set.lock().unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn set_pxat_checks_its_argument() {
+        let state = state();
+        let mut session = Session::new(&state);
+
+        assert_eq!(
+            session.call(&["SET", "k", "v", "PXAT"]),
+            "-ERR syntax error\r\n"
+        );
+        assert_eq!(
+            session.call(&["SET", "k", "v", "PXAT", "abc"]),
+            "-ERR value is not an integer or out of range\r\n"
+        );
+        assert_eq!(
+            session.call(&["SET", "k", "v", "PXAT", "-1"]),
+            "-ERR invalid expire time in 'set' command\r\n"
+        );
+        assert_eq!(session.call(&["TYPE", "k"]), "+none\r\n");
+
+        let later = (now_ms() + 60000).to_string();
+
+        assert_eq!(session.call(&["SET", "k", "v", "PXAT", &later]), "+OK\r\n");
+        assert_eq!(session.call(&["GET", "k"]), "+v\r\n");
+
+        // A time already past leaves no key.
+        assert_eq!(session.call(&["SET", "k", "v", "PXAT", "1"]), "+OK\r\n");
+        assert_eq!(session.call(&["TYPE", "k"]), "+none\r\n");
+    }
+
+    #[test]
+    fn set_algebra_stores_over_a_key_of_another_type() {
+        let state = state();
+        let mut session = Session::new(&state);
+
+        session.call(&["SADD", "a", "1", "2", "x"]);
+        session.call(&["SADD", "b", "2", "x", "y"]);
+
+        for (command, len) in [("SINTERSTORE", 2), ("SUNIONSTORE", 4), ("SDIFFSTORE", 1)] {
+            session.call(&["SET", "dest", "v"]);
+
+            assert_eq!(
+                session.call(&[command, "dest", "a", "b"]),
+                format!(":{}\r\n", len)
+            );
+            assert_eq!(session.call(&["TYPE", "dest"]), "+set\r\n");
+            assert_eq!(session.call(&["SCARD", "dest"]), format!(":{}\r\n", len));
+        }
+
+        // An empty result leaves nothing behind, not even the old value.
+        session.call(&["SET", "dest", "v"]);
+
+        assert_eq!(
+            session.call(&["SINTERSTORE", "dest", "a", "missing"]),
+            ":0\r\n"
+        );
+        assert_eq!(session.call(&["TYPE", "dest"]), "+none\r\n");
+
+        // A source of another type fails the command before it stores.
+        session.call(&["SET", "dest", "v"]);
+
+        assert_eq!(
+            session.call(&["SUNIONSTORE", "a", "dest", "b"]),
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+        assert_eq!(session.call(&["SCARD", "a"]), ":3\r\n");
+    }
+
+    /// Whether `command` waits for the command another client is running
+    /// to end before it starts, and then succeeds. `setup` runs first.
+    fn runs_exclusively(setup: &[&[&str]], command: &[&str]) -> bool {
+        let state = state();
+        let mut session = Session::new(&state);
+
+        for args in setup {
+            session.call(args);
+        }
+
+        let command: Vec<String> = command.iter().map(|arg| arg.to_string()).collect();
+        let running = state.exec.read().unwrap();
+        let worker = thread::spawn(move || session.call(&command));
+
+        thread::sleep(Duration::from_millis(100));
+
+        let waited = !worker.is_finished();
+        drop(running);
+
+        waited && !worker.join().unwrap().starts_with('-')
+    }
+
+    #[test]
+    fn set_stores_run_exclusively() {
+        let setup: &[&[&str]] = &[&["SADD", "a", "1"], &["SET", "dest", "v"]];
+
+        for command in ["SINTERSTORE", "SUNIONSTORE", "SDIFFSTORE"] {
+            assert!(
+                runs_exclusively(setup, &[command, "dest", "a"]),
+                "{}",
+                command
+            );
+        }
+
+        assert!(!runs_exclusively(setup, &["SINTER", "a"]));
+    }
+
+    #[test]
+    fn sintercard_limits() {
+        let state = state();
+        let mut session = Session::new(&state);
+
+        session.call(&["SADD", "a", "1", "2", "3", "x"]);
+        session.call(&["SADD", "b", "1", "2", "3", "y"]);
+
+        for (limit, card) in [("0", 3), ("2", 2), ("10", 3)] {
+            assert_eq!(
+                session.call(&["SINTERCARD", "2", "a", "b", "LIMIT", limit]),
+                format!(":{}\r\n", card)
+            );
+        }
+
+        assert_eq!(session.call(&["SINTERCARD", "2", "a", "b"]), ":3\r\n");
+        assert_eq!(session.call(&["SINTERCARD", "1", "missing"]), ":0\r\n");
+        assert_eq!(
+            session.call(&["SINTERCARD", "2", "a", "b", "LIMIT", "-1"]),
+            "-ERR LIMIT can't be negative\r\n"
+        );
+        assert_eq!(
+            session.call(&["SINTERCARD", "0", "a"]),
+            "-ERR numkeys should be greater than 0\r\n"
+        );
+        assert_eq!(
+            session.call(&["SINTERCARD", "3", "a", "b"]),
+            "-ERR Number of keys can't be greater than number of args\r\n"
+        );
+        assert_eq!(
+            session.call(&["SINTERCARD", "2", "a", "b", "LIMIT"]),
+            "-ERR syntax error\r\n"
+        );
+    }
+
+    #[test]
+    fn srandmember_counts() {
+        let mut session = Session::new(&state());
+
+        session.call(&["SADD", "s", "a"]);
+
+        assert_eq!(
+            session.call(&["SRANDMEMBER", "s", "-3"]),
+            "*3\r\n$1\r\na\r\n$1\r\na\r\n$1\r\na\r\n"
+        );
+        assert_eq!(
+            session.call(&["SRANDMEMBER", "s", "3"]),
+            "*1\r\n$1\r\na\r\n"
+        );
+        assert_eq!(session.call(&["SRANDMEMBER", "missing", "-3"]), "*0\r\n");
+        assert_eq!(
+            session.call(&["SRANDMEMBER", "s", "-9223372036854775808"]),
+            "-ERR value is out of range\r\n"
+        );
+    }
+
+    #[test]
+    fn srandmember_streams_large_negative_counts() {
+        let mut session = Session::new(&state());
+
+        session.call(&["SADD", "s", "a"]);
+        session.run(&[b"SRANDMEMBER", b"s", b"-5000"]);
+
+        let reply = session.reply();
+
+        assert!(reply.starts_with(b"*5000\r\n"));
+        assert_eq!(reply.len(), "*5000\r\n".len() + 5000 * "$1\r\na\r\n".len());
+    }
+
+    #[test]
+    fn srandmember_lets_go_of_sets_while_writing() {
+        let state = state();
+        let mut reader = Session::new(&state);
+        let mut other = Session::new(&state);
+        let peer = reader.peer.get_ref().try_clone().unwrap();
+
+        reader.call(&["SADD", "s", "a", "b"]);
+
+        // Nobody reads this reply, writing it stalls once the socket
+        // buffers are full.
+        let stalled = thread::spawn(move || reader.run(&[b"SRANDMEMBER", b"s", b"-100000000"]));
+
+        thread::sleep(Duration::from_millis(200));
+
+        assert!(state.persisted.set.try_lock().is_ok());
+        assert_eq!(other.call(&["SADD", "s", "c"]), ":1\r\n");
+
+        peer.shutdown(std::net::Shutdown::Both).unwrap();
+        stalled.join().unwrap();
+    }
+
+    #[test]
+    fn xrange_intervals() {
+        let mut session = Session::new(&state());
+
+        for id in ["1-1", "1-2", "2-0", "3-0"] {
+            session.call(&["XADD", "s", id, "f", "v"]);
+        }
+
+        let ids = |reply: String| -> Vec<String> {
+            reply
+                .split("\r\n")
+                .filter(|line| line.contains('-') && !line.starts_with('-'))
+                .map(String::from)
+                .collect()
+        };
+
+        assert_eq!(
+            ids(session.call(&["XRANGE", "s", "1", "1"])),
+            ["1-1", "1-2"]
+        );
+        assert_eq!(
+            ids(session.call(&["XRANGE", "s", "(1-1", "(3-0"])),
+            ["1-2", "2-0"]
+        );
+        assert_eq!(
+            ids(session.call(&["XRANGE", "s", "-", "+", "COUNT", "2"])),
+            ["1-1", "1-2"]
+        );
+        assert_eq!(ids(session.call(&["XREVRANGE", "s", "+", "(2"])), ["3-0"]);
+        assert_eq!(
+            session.call(&["XRANGE", "s", "-", "+", "COUNT", "0"]),
+            "*-1\r\n"
+        );
+        assert_eq!(
+            session.call(&[
+                "XRANGE",
+                "s",
+                "(18446744073709551615-18446744073709551615",
+                "+"
+            ]),
+            "-ERR invalid start ID for the interval\r\n"
+        );
+        assert_eq!(
+            session.call(&["XRANGE", "s", "x", "+"]),
+            "-ERR Invalid stream ID specified as stream command argument\r\n"
+        );
+    }
+
+    #[test]
+    fn xgroup_create_checks_the_id_before_mkstream() {
+        let mut session = Session::new(&state());
+
+        assert_eq!(
+            session.propagated(&["XGROUP", "CREATE", "s", "g", "bad", "MKSTREAM"]),
+            [] as [String; 0]
+        );
+        assert_eq!(session.call(&["TYPE", "s"]), "+none\r\n");
+        assert_eq!(
+            session.call(&["XGROUP", "CREATE", "s", "g", "$", "MKSTREAM"]),
+            "+OK\r\n"
+        );
+        assert_eq!(session.call(&["TYPE", "s"]), "+stream\r\n");
+    }
+
+    #[test]
+    fn xgroup_propagates_only_changes() {
+        let mut session = Session::new(&state());
+
+        session.call(&["XADD", "s", "1-0", "f", "v"]);
+        session.call(&["XGROUP", "CREATE", "s", "g", "0"]);
+
+        assert_eq!(
+            session.propagated(&["XGROUP", "CREATECONSUMER", "s", "g", "c"]),
+            ["XGROUP CREATECONSUMER s g c"]
+        );
+        assert_eq!(
+            session.propagated(&["XGROUP", "CREATECONSUMER", "s", "g", "c"]),
+            [] as [String; 0]
+        );
+        assert_eq!(
+            session.propagated(&["XGROUP", "DELCONSUMER", "s", "g", "c"]),
+            ["XGROUP DELCONSUMER s g c"]
+        );
+        assert_eq!(
+            session.propagated(&["XGROUP", "DELCONSUMER", "s", "g", "c"]),
+            [] as [String; 0]
+        );
+        assert_eq!(
+            session.propagated(&["XGROUP", "DESTROY", "s", "g"]),
+            ["XGROUP DESTROY s g"]
+        );
+        assert_eq!(
+            session.propagated(&["XGROUP", "DESTROY", "s", "g"]),
+            [] as [String; 0]
+        );
+    }
+
+    #[test]
+    fn xadd_trims_and_propagates_an_exact_trim() {
+        let mut session = Session::new(&state());
+
+        for id in ["1-0", "2-0", "3-0"] {
+            session.call(&["XADD", "s", id, "f", "v"]);
+        }
+
+        assert_eq!(
+            session.propagated(&["XADD", "s", "MAXLEN", "2", "4-0", "f", "v"]),
+            ["XADD s MAXLEN = 2 4-0 f v"]
+        );
+        assert_eq!(session.call(&["XLEN", "s"]), ":2\r\n");
+        assert_eq!(session.call(&["XDEL", "s", "3-0", "9-0"]), ":1\r\n");
+        assert_eq!(session.call(&["XTRIM", "s", "MINID", "5"]), ":1\r\n");
+        assert_eq!(session.call(&["XLEN", "s"]), ":0\r\n");
+        assert_eq!(
+            session.call(&["XADD", "s", "MAXLEN", "1", "LIMIT", "1", "*", "f", "v"]),
+            "-ERR syntax error, LIMIT cannot be used without the special ~ option\r\n"
+        );
+    }
+
+    #[test]
+    fn xsetid_checks_against_the_stream() {
+        let mut session = Session::new(&state());
+
+        assert_eq!(
+            session.call(&["XSETID", "s", "1-0"]),
+            "-ERR no such key\r\n"
+        );
+
+        session.call(&["XADD", "s", "5-0", "f", "v"]);
+
+        assert_eq!(
+            session.call(&["XSETID", "s", "4-0"]),
+            "-ERR The ID specified in XSETID is smaller than the target stream top item\r\n"
+        );
+        assert_eq!(
+            session.call(&["XSETID", "s", "6-0", "ENTRIESADDED", "0"]),
+            "-ERR The entries_added specified in XSETID is smaller than the target stream length\r\n"
+        );
+        assert_eq!(
+            session.call(&[
+                "XSETID",
+                "s",
+                "6-0",
+                "ENTRIESADDED",
+                "3",
+                "MAXDELETEDID",
+                "7-0"
+            ]),
+            "-ERR The ID specified in XSETID is smaller than the provided max_deleted_entry_id\r\n"
+        );
+        assert_eq!(
+            session.call(&[
+                "XSETID",
+                "s",
+                "9-0",
+                "ENTRIESADDED",
+                "3",
+                "MAXDELETEDID",
+                "7-0"
+            ]),
+            "+OK\r\n"
+        );
+
+        let streams = session.state.persisted.stream.lock().unwrap();
+        let stream = &streams.map["s"];
+
+        assert_eq!(stream.last_id().to_string(), "9-0");
+        assert_eq!(stream.entries_added(), 3);
+        assert_eq!(stream.max_deleted_id().to_string(), "7-0");
+    }
+
+    #[test]
+    fn xinfo_reports_groups_and_consumers() {
+        let mut session = Session::new(&state());
+
+        session.call(&["XADD", "s", "1-0", "f", "v"]);
+        session.call(&["XADD", "s", "2-0", "f", "v"]);
+        session.call(&["XGROUP", "CREATE", "s", "g", "0"]);
+        session.call(&[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "c",
+            "COUNT",
+            "1",
+            "STREAMS",
+            "s",
+            ">",
+        ]);
+
+        let groups = session.call(&["XINFO", "GROUPS", "s"]);
+
+        assert!(groups.contains("$4\r\nname\r\n$1\r\ng\r\n"), "{}", groups);
+        assert!(groups.contains("$7\r\npending\r\n:1\r\n"), "{}", groups);
+        assert!(groups.contains("$3\r\nlag\r\n:1\r\n"), "{}", groups);
+
+        let consumers = session.call(&["XINFO", "CONSUMERS", "s", "g"]);
+
+        assert!(consumers.starts_with("*1\r\n"), "{}", consumers);
+        assert!(consumers.contains("$1\r\nc\r\n"), "{}", consumers);
+        assert_eq!(
+            session.call(&["XINFO", "CONSUMERS", "s", "missing"]),
+            "-NOGROUP No such consumer group 'missing' for key name 's'\r\n"
+        );
+    }
+
+    #[test]
+    fn xread_block_times_out_with_a_null_reply() {
+        let mut session = Session::new(&state());
+
+        session.call(&["XADD", "s", "1-0", "f", "v"]);
+
+        assert_eq!(
+            session.call(&["XREAD", "BLOCK", "50", "STREAMS", "s", "$"]),
+            "*-1\r\n"
+        );
+    }
+
+    #[test]
+    fn xread_block_wakes_on_its_stream_only() {
+        let state = state();
+        let mut writer = Session::new(&state);
+        let mut reader = Session::new(&state);
+
+        writer.call(&["XADD", "s", "1-0", "f", "v"]);
+
+        let blocked = thread::spawn(move || {
+            reader.call(&[
+                "XREAD", "BLOCK", "5000", "COUNT", "1", "STREAMS", "other", "s", "$", "$",
+            ])
+        });
+
+        thread::sleep(Duration::from_millis(100));
+        writer.call(&["XADD", "unrelated", "1-0", "f", "v"]);
+        thread::sleep(Duration::from_millis(100));
+
+        assert!(!blocked.is_finished());
+
+        writer.call(&["XADD", "s", "2-0", "a", "1"]);
+        writer.call(&["XADD", "s", "3-0", "b", "2"]);
+
+        let reply = blocked.join().unwrap();
+
+        assert!(reply.starts_with("*1\r\n*2\r\n$1\r\ns\r\n"), "{}", reply);
+        assert!(reply.contains("2-0") && !reply.contains("3-0"), "{}", reply);
+    }
+
+    #[test]
+    fn exec_runs_the_queued_commands() {
+        let mut session = Session::new(&state());
+
+        assert_eq!(session.call(&["MULTI"]), "+OK\r\n");
+        assert_eq!(
+            session.call(&["MULTI"]),
+            "-ERR MULTI calls can not be nested\r\n"
+        );
+        assert_eq!(session.call(&["SET", "k", "v"]), "+QUEUED\r\n");
+        assert_eq!(session.call(&["GET", "k"]), "+QUEUED\r\n");
+        assert_eq!(session.call(&["EXEC"]), "*2\r\n+OK\r\n+v\r\n");
+        assert_eq!(session.call(&["EXEC"]), "-ERR EXEC without MULTI\r\n");
+        assert_eq!(session.call(&["DISCARD"]), "-ERR DISCARD without MULTI\r\n");
+    }
+
+    #[test]
+    fn queueing_errors_abort_exec() {
+        let mut session = Session::new(&state());
+
+        session.call(&["MULTI"]);
+        session.call(&["SET", "k", "v"]);
+
+        assert_eq!(
+            session.call(&["GET"]),
+            "-ERR wrong number of arguments for 'get' command\r\n"
+        );
+        assert_eq!(
+            session.call(&["EXEC"]),
+            "-EXECABORT Transaction discarded because of previous errors.\r\n"
+        );
+        assert_eq!(session.call(&["GET", "k"]), "$-1\r\n");
+
+        session.call(&["MULTI"]);
+
+        assert!(session
+            .call(&["NOSUCHCOMMAND"])
+            .starts_with("-ERR unknown command"));
+        assert!(session.call(&["EXEC"]).starts_with("-EXECABORT"));
+    }
+
+    #[test]
+    fn discard_drops_the_queue() {
+        let mut session = Session::new(&state());
+
+        session.call(&["MULTI"]);
+        session.call(&["SET", "k", "v"]);
+
+        assert_eq!(session.call(&["DISCARD"]), "+OK\r\n");
+        assert_eq!(session.call(&["GET", "k"]), "$-1\r\n");
+    }
+
+    #[test]
+    fn exec_fails_once_a_watched_key_changed() {
+        let state = state();
+        let mut session = Session::new(&state);
+        let mut other = Session::new(&state);
+
+        assert_eq!(session.call(&["WATCH", "k"]), "+OK\r\n");
+        other.call(&["SET", "k", "changed"]);
+
+        session.call(&["MULTI"]);
+        session.call(&["SET", "k", "mine"]);
+
+        assert_eq!(session.call(&["EXEC"]), "*-1\r\n");
+        assert_eq!(session.call(&["GET", "k"]), "+changed\r\n");
+
+        // EXEC unwatched everything, the next transaction goes through.
+        other.call(&["SET", "k", "again"]);
+        session.call(&["MULTI"]);
+        session.call(&["SET", "k", "mine"]);
+
+        assert_eq!(session.call(&["EXEC"]), "*1\r\n+OK\r\n");
+    }
+
+    #[test]
+    fn execabort_unwatches_keys() {
+        let state = state();
+        let mut session = Session::new(&state);
+        let mut other = Session::new(&state);
+
+        session.call(&["WATCH", "k"]);
+        session.call(&["MULTI"]);
+        session.call(&["NOSUCHCOMMAND"]);
+
+        assert!(session.call(&["EXEC"]).starts_with("-EXECABORT"));
+        assert!(session.client.watched.is_empty());
+
+        other.call(&["SET", "k", "changed"]);
+        session.call(&["MULTI"]);
+        session.call(&["GET", "k"]);
+
+        assert_eq!(session.call(&["EXEC"]), "*1\r\n+changed\r\n");
+    }
+
+    #[test]
+    fn watch_is_refused_inside_multi() {
+        let mut session = Session::new(&state());
+
+        session.call(&["MULTI"]);
+
+        assert_eq!(
+            session.call(&["WATCH", "k"]),
+            "-ERR WATCH inside MULTI is not allowed\r\n"
+        );
+    }
+
+    #[test]
+    fn subscribers_receive_published_messages() {
+        let state = state();
+        let mut subscriber = Session::new(&state);
+        let mut publisher = Session::new(&state);
+
+        assert_eq!(
+            subscriber.call(&["SUBSCRIBE", "news"]),
+            "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+        assert_eq!(
+            subscriber.call(&["PSUBSCRIBE", "n*"]),
+            "*3\r\n$10\r\npsubscribe\r\n$2\r\nn*\r\n:2\r\n"
+        );
+        assert_eq!(publisher.call(&["PUBLISH", "news", "hi"]), ":2\r\n");
+        assert_eq!(
+            String::from_utf8(subscriber.reply()).unwrap(),
+            "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(
+            String::from_utf8(subscriber.reply()).unwrap(),
+            "*4\r\n$8\r\npmessage\r\n$2\r\nn*\r\n$4\r\nnews\r\n$2\r\nhi\r\n"
+        );
+        assert_eq!(
+            subscriber.call(&["GET", "k"]),
+            "-ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n"
+        );
+        assert_eq!(
+            publisher.call(&["PUBSUB", "NUMSUB", "news", "other"]),
+            "*4\r\n$4\r\nnews\r\n:1\r\n$5\r\nother\r\n:0\r\n"
+        );
+    }
+
+    #[test]
+    fn keyspace_events_for_sets_sorted_sets_and_streams() {
+        let state = state();
+        let mut subscriber = Session::new(&state);
+        let mut session = Session::new(&state);
+
+        session.call(&["CONFIG", "SET", "notify-keyspace-events", "EAn"]);
+        subscriber.call(&["PSUBSCRIBE", "__keyevent@0__:*"]);
+
+        for command in [
+            &["SADD", "s", "a", "b"][..],
+            &["SADD", "s", "a"],
+            &["SMOVE", "s", "t", "a"],
+            &["SPOP", "s"],
+            &["SINTERSTORE", "u", "t"],
+            &["SINTERSTORE", "u", "missing"],
+            &["ZADD", "z", "1", "a"],
+            &["ZINCRBY", "z", "1", "a"],
+            &["ZPOPMIN", "z"],
+            &["XADD", "x", "1-0", "f", "v"],
+            &["XGROUP", "CREATE", "x", "g", "0"],
+            &["XTRIM", "x", "MAXLEN", "5"],
+            &["XTRIM", "x", "MAXLEN", "0"],
+            &["XDEL", "x", "1-0"],
+            &["PUBLISH", "__keyevent@0__:end", "x"],
+        ] {
+            session.call(command);
+        }
+
+        assert_eq!(
+            subscriber.events(),
+            [
+                "new",
+                "sadd",
+                "srem",
+                "new",
+                "sadd",
+                "spop",
+                "del",
+                "new",
+                "sinterstore",
+                "del",
+                "new",
+                "zadd",
+                "zincr",
+                "zpopmin",
+                "del",
+                "new",
+                "xadd",
+                "xgroup-create",
+                "xtrim"
+            ]
+        );
+    }
+
+    #[test]
+    fn tracking_noloop_tells_clients_apart() {
+        let state = state();
+        let mut tracker = Session::new(&state);
+        let mut other = Session::new(&state);
+
+        tracker.run(&[b"HELLO", b"3"]);
+        tracker.reply();
+
+        assert_eq!(
+            tracker.call(&["CLIENT", "TRACKING", "ON", "BCAST", "NOLOOP"]),
+            "+OK\r\n"
+        );
+        assert_eq!(tracker.call(&["SET", "mine", "v"]), "+OK\r\n");
+
+        other.call(&["SET", "theirs", "v"]);
+
+        assert_eq!(
+            String::from_utf8(tracker.reply()).unwrap(),
+            ">2\r\n$10\r\ninvalidate\r\n*1\r\n$6\r\ntheirs\r\n"
+        );
+    }
+
+    #[test]
+    fn replay_append_only_runs_commands_without_a_peer() {
+        let state = state();
+        let commands = [
+            vec!["SET", "k", "v"],
+            vec!["MULTI"],
+            vec!["SADD", "s", "a", "b"],
+            vec!["EXEC"],
+            vec!["NOSUCHCOMMAND"],
+        ];
+        let commands = commands
+            .iter()
+            .map(|command| command.iter().map(|arg| RespData::new_bulk(arg)).collect())
+            .collect();
+
+        assert_eq!(replay_append_only(&state, commands), 5);
+
+        let mut session = Session::new(&state);
+
+        assert_eq!(session.call(&["GET", "k"]), "+v\r\n");
+        assert_eq!(session.call(&["SCARD", "s"]), ":2\r\n");
+    }
+
+    #[test]
+    fn rewritten_commands_build_the_keyspace_again() {
+        let (state, copy) = (state(), state());
+        let mut session = Session::new(&state);
+
+        session.call(&["SET", "k", "v", "PX", "60000"]);
+        session.call(&["SADD", "s", "a", "b", "c"]);
+        session.call(&["ZADD", "z", "1.5", "a", "inf", "b"]);
+        session.call(&["XADD", "x", "1-1", "f", "v"]);
+        session.call(&["XADD", "x", "2-1", "f", "w"]);
+        session.call(&["XADD", "x", "3-1", "f", "u"]);
+        session.call(&["XDEL", "x", "3-1"]);
+        session.call(&["XGROUP", "CREATE", "x", "g", "0"]);
+        session.call(&[
+            "XREADGROUP",
+            "GROUP",
+            "g",
+            "c",
+            "COUNT",
+            "1",
+            "STREAMS",
+            "x",
+            ">",
+        ]);
+
+        let commands = aof::rewrite::commands(&rdb::save::snapshot(&state.persisted));
+        let contents = aof::load::parse(&commands).unwrap();
+        replay_append_only(&copy, contents.commands);
+
+        let mut replayed = Session::new(&copy);
+
+        for command in [
+            &["GET", "k"][..],
+            &["SMISMEMBER", "s", "a", "b", "c"],
+            &["ZRANGE", "z", "0", "-1", "WITHSCORES"],
+            &["XRANGE", "x", "-", "+"],
+            &["XINFO", "STREAM", "x"],
+            &["XINFO", "GROUPS", "x"],
+            &["XPENDING", "x", "g"],
+        ] {
+            assert_eq!(
+                replayed.call(command),
+                session.call(command),
+                "{:?}",
+                command
+            );
+        }
+
+        let expiry = |state: &State| rdb::save::entry(&state.persisted, "k").unwrap().expire_ms;
+
+        assert!(expiry(&state).is_some());
+        assert_eq!(expiry(&copy), expiry(&state));
+    }
+
+    #[test]
+    fn dump_replies_with_the_raw_payload() {
+        let state = state();
+        let mut session = Session::new(&state);
+
+        session.call(&["SADD", "s", "1", "2"]);
+        session.run(&[b"DUMP", b"s"]);
+
+        let reply = session.reply();
+        let payload = rdb::save::dump(&rdb::save::entry(&state.persisted, "s").unwrap().value);
+
+        assert_eq!(reply, RespData::BulkString(payload.clone()).as_bytes());
+        assert_eq!(session.call(&["DUMP", "missing"]), "$-1\r\n");
+        assert_eq!(
+            session.call(&[b"RESTORE".as_slice(), b"copy", b"0", &payload]),
+            "+OK\r\n"
+        );
+        assert_eq!(session.call(&["SCARD", "copy"]), ":2\r\n");
+    }
+
+    #[test]
+    fn restore_checks_its_payload_and_key() {
+        let state = state();
+        let mut session = Session::new(&state);
+        let payload = rdb::save::dump(&rdb::RdbValue::String("v".to_string()));
+
+        assert_eq!(
+            session.call(&["RESTORE", "k", "0", "garbage"]),
+            "-ERR DUMP payload version or checksum are wrong\r\n"
+        );
+
+        session.call(&["SET", "k", "old"]);
+
+        assert_eq!(
+            session.call(&[b"RESTORE".as_slice(), b"k", b"0", &payload]),
+            "-BUSYKEY Target key name already exists.\r\n"
+        );
+        assert_eq!(
+            session.call(&[b"RESTORE".as_slice(), b"k", b"-1", &payload, b"REPLACE"]),
+            "-ERR Invalid TTL value, must be >= 0\r\n"
+        );
+
+        assert_eq!(
+            session.call(&[
+                b"RESTORE".as_slice(),
+                b"k",
+                b"0",
+                &payload,
+                b"REPLACE",
+                b"FREQ",
+                b"256"
+            ]),
+            "-ERR Invalid FREQ value, must be >= 0 and <= 255\r\n"
+        );
+        assert_eq!(
+            session.call(&[
+                b"RESTORE".as_slice(),
+                b"k",
+                b"0",
+                &payload,
+                b"IDLETIME",
+                b"5",
+                b"REPLACE"
+            ]),
+            "+OK\r\n"
+        );
+        assert_eq!(session.call(&["GET", "k"]), "+v\r\n");
+    }
+
+    #[test]
+    fn restore_propagates_an_absolute_ttl() {
+        let state = state();
+        let mut session = Session::new(&state);
+        let payload = rdb::save::dump(&rdb::RdbValue::String("v".to_string()));
+        let before = now_ms();
+
+        let propagated = session.propagated(&[b"RESTORE".as_slice(), b"k", b"60000", &payload]);
+        let args: Vec<&str> = propagated[0].split(' ').collect();
+        let at: u64 = args[2].parse().unwrap();
+
+        assert_eq!(args.last(), Some(&"ABSTTL"));
+        assert!(at >= before + 60000 && at <= now_ms() + 60000);
+
+        // Already expired, nothing is left of the key.
+        session.call(&["SET", "gone", "v"]);
+
+        assert_eq!(
+            session.propagated(&[
+                b"RESTORE".as_slice(),
+                b"gone",
+                b"1",
+                &payload,
+                b"REPLACE",
+                b"ABSTTL"
+            ]),
+            [format!(
+                "RESTORE gone 1 {} REPLACE ABSTTL",
+                String::from_utf8_lossy(&payload)
+            )]
+        );
+        assert_eq!(session.call(&["TYPE", "gone"]), "+none\r\n");
+    }
+
+    /// A MIGRATE target on its own thread: sends every command it gets
+    /// over the channel, and answers RESTOREs of `busy` with an error, all
+    /// else with `+OK`. It takes `connections` connections, one after the
+    /// other.
+    fn target(connections: usize) -> (u16, mpsc::Receiver<Vec<Vec<u8>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for _ in 0..connections {
+                let (stream, _) = listener.accept().unwrap();
+                let mut writer = stream.try_clone().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut line = String::new();
+
+                while reader.read_line(&mut line).unwrap_or(0) > 0 {
+                    let count: usize = line.trim()[1..].parse().unwrap();
+                    let mut command = vec![];
+
+                    for _ in 0..count {
+                        line.clear();
+                        reader.read_line(&mut line).unwrap();
+
+                        let mut arg = vec![0; line.trim()[1..].parse::<usize>().unwrap() + 2];
+                        reader.read_exact(&mut arg).unwrap();
+                        arg.truncate(arg.len() - 2);
+                        command.push(arg);
+                    }
+
+                    let reply: &[u8] = match command.get(1) {
+                        Some(key) if key == b"busy" => {
+                            b"-BUSYKEY Target key name already exists.\r\n"
+                        }
+                        _ => b"+OK\r\n",
+                    };
+
+                    writer.write_all(reply).unwrap();
+                    sender.send(command).unwrap();
+                    line.clear();
+                }
+            }
+        });
+
+        (port, receiver)
+    }
+
+    fn received(receiver: &mpsc::Receiver<Vec<Vec<u8>>>) -> Vec<String> {
+        receiver
+            .recv_timeout(Duration::from_secs(5))
+            .unwrap()
+            .iter()
+            .take(3)
+            .map(|arg| String::from_utf8_lossy(arg).into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn migrate_moves_keys_to_the_target() {
+        let state = state();
+        let mut session = Session::new(&state);
+        let (port, receiver) = target(1);
+        let port = port.to_string();
+
+        session.call(&["SET", "k", "v"]);
+        session.call(&["SADD", "s", "a"]);
+
+        assert_eq!(
+            session.call(&["MIGRATE", "127.0.0.1", &port, "k", "0", "1000", "COPY"]),
+            "+OK\r\n"
+        );
+        assert_eq!(received(&receiver), ["RESTORE", "k", "0"]);
+        assert_eq!(session.call(&["GET", "k"]), "+v\r\n");
+
+        // The connection is kept, and on the database it selected.
+        assert_eq!(
+            session.propagated(&[
+                "MIGRATE",
+                "127.0.0.1",
+                &port,
+                "",
+                "2",
+                "1000",
+                "KEYS",
+                "k",
+                "s"
+            ]),
+            ["DEL k s"]
+        );
+        assert_eq!(received(&receiver), ["SELECT", "2"]);
+        assert_eq!(received(&receiver)[..2], ["RESTORE", "k"]);
+        assert_eq!(received(&receiver)[..2], ["RESTORE", "s"]);
+        assert_eq!(session.call(&["TYPE", "s"]), "+none\r\n");
+        assert_eq!(
+            session.call(&["MIGRATE", "127.0.0.1", &port, "k", "2", "1000"]),
+            "+NOKEY\r\n"
+        );
+    }
+
+    #[test]
+    fn migrate_keeps_keys_the_target_refused() {
+        let state = state();
+        let mut session = Session::new(&state);
+        let (port, receiver) = target(1);
+        let port = port.to_string();
+
+        session.call(&["SET", "busy", "v"]);
+        session.call(&["SET", "k", "v"]);
+
+        assert_eq!(
+            session.call(&[
+                "MIGRATE",
+                "127.0.0.1",
+                &port,
+                "",
+                "0",
+                "1000",
+                "KEYS",
+                "busy",
+                "k"
+            ]),
+            "-ERR Target instance replied with error: BUSYKEY Target key name already exists.\r\n"
+        );
+        assert_eq!(received(&receiver)[..2], ["RESTORE", "busy"]);
+        assert_eq!(received(&receiver)[..2], ["RESTORE", "k"]);
+        assert_eq!(session.call(&["GET", "busy"]), "+v\r\n");
+        assert_eq!(session.call(&["TYPE", "k"]), "+none\r\n");
+    }
+
+    #[test]
+    fn migrate_checks_its_arguments() {
+        let state = state();
+        let mut session = Session::new(&state);
+
+        session.call(&["SET", "k", "v"]);
+
+        assert_eq!(
+            session.call(&["MIGRATE", "127.0.0.1", "1", "k", "0", "1000", "KEYS", "k"]),
+            "-ERR When using MIGRATE KEYS option, the key argument must be set to empty string\r\n"
+        );
+        assert_eq!(
+            session.call(&["MIGRATE", "127.0.0.1", "1", "k", "0", "1000", "MOVE"]),
+            "-ERR syntax error\r\n"
+        );
+        assert_eq!(
+            session.call(&["MIGRATE", "127.0.0.1", "1", "k", "0", "1000"]),
+            "-IOERR error or timeout connecting to the client\r\n"
+        );
+        assert_eq!(session.call(&["GET", "k"]), "+v\r\n");
+    }
+
+    #[test]
+    fn sorted_set_stores_run_exclusively() {
+        let setup: &[&[&str]] = &[&["ZADD", "a", "1", "x"], &["SET", "dest", "v"]];
+
+        for command in ["ZUNIONSTORE", "ZINTERSTORE", "ZDIFFSTORE"] {
+            assert!(
+                runs_exclusively(setup, &[command, "dest", "1", "a"]),
+                "{}",
+                command
+            );
+        }
+
+        assert!(runs_exclusively(
+            setup,
+            &["ZRANGESTORE", "dest", "a", "0", "-1"]
+        ));
+        assert!(!runs_exclusively(setup, &["ZRANGE", "a", "0", "-1"]));
+    }
+
+    #[test]
+    fn geosearchstore_runs_exclusively() {
+        let setup: &[&[&str]] = &[
+            &["GEOADD", "a", "13.361389", "38.115556", "Palermo"],
+            &["SET", "dest", "v"],
+        ];
+        let search = ["FROMLONLAT", "15", "37", "BYRADIUS", "200", "km"];
+
+        assert!(runs_exclusively(
+            setup,
+            &[&["GEOSEARCHSTORE", "dest", "a"][..], &search].concat()
+        ));
+        assert!(!runs_exclusively(
+            setup,
+            &[&["GEOSEARCH", "a"][..], &search].concat()
+        ));
+    }
+
+    #[test]
+    fn writes_drop_replicas_that_went_away() {
+        let state = state();
+        let mut session = Session::new(&state);
+        let mut replica = Session::new(&state);
+
+        assert_eq!(
+            replica.call(&["REPLCONF", "listening-port", "6380"]),
+            "+OK\r\n"
+        );
+
+        // As if the snapshot had been sent already.
+        if let Role::Master(master) = state.info.write().unwrap().role.borrow_mut() {
+            master.syncing.clear();
+        }
+
+        drop(replica);
+
+        // The first write after the replica hung up may still go through.
+        for _ in 0..3 {
+            assert_eq!(session.call(&["SET", "k", "v"]), "+OK\r\n");
+        }
+
+        assert!(matches!(
+            &state.info.read().unwrap().role,
+            Role::Master(master) if master.slave_ports.is_empty()
+        ));
+        assert!(session
+            .call(&["INFO", "replication"])
+            .contains("role:master"));
     }
 }
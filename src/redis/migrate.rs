@@ -0,0 +1,243 @@
+use std::{
+    collections::HashMap,
+    io::{self, BufRead, BufReader, Write},
+    net::{TcpStream, ToSocketAddrs},
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long a cached connection may sit unused before it is closed.
+const SOCKET_TTL: Duration = Duration::from_secs(10);
+
+/// At most this many targets keep a connection, the least recently used
+/// one makes room for a new one.
+const MAX_SOCKETS: usize = 64;
+
+/// A connection MIGRATE opened to a target.
+pub struct MigrateSocket {
+    reader: BufReader<TcpStream>,
+    /// The database the target has this connection on, `None` when that
+    /// isn't known anymore. New connections start on 0.
+    pub db: Option<i64>,
+    last_use: Instant,
+}
+
+impl MigrateSocket {
+    fn connect(host: &str, port: u16, timeout: Duration) -> io::Result<MigrateSocket> {
+        let addr = (host, port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))?;
+
+        let stream = TcpStream::connect_timeout(&addr, timeout)?;
+        stream.set_nodelay(true)?;
+
+        Ok(MigrateSocket {
+            reader: BufReader::new(stream),
+            db: Some(0),
+            last_use: Instant::now(),
+        })
+    }
+
+    /// Whether the target hung up since the last use, or sent something no
+    /// one asked for. Writing to a connection the target closed still
+    /// works, only the replies would be missing.
+    fn closed(&self) -> bool {
+        let stream = self.reader.get_ref();
+        let mut byte = [0];
+
+        if stream.set_nonblocking(true).is_err() {
+            return true;
+        }
+
+        let closed =
+            !matches!(stream.peek(&mut byte), Err(e) if e.kind() == io::ErrorKind::WouldBlock);
+
+        stream.set_nonblocking(false).is_err() || closed
+    }
+
+    pub fn send(&mut self, data: &[u8], timeout: Duration) -> io::Result<()> {
+        let stream = self.reader.get_mut();
+        stream.set_write_timeout(Some(timeout))?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.write_all(data)
+    }
+
+    /// The next reply, which is a status line or an error line: `Err` with
+    /// the error's text for the latter.
+    pub fn read_reply(&mut self) -> io::Result<Result<(), String>> {
+        let mut line = String::new();
+
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        match line.trim_end().strip_prefix('-') {
+            Some(error) => Ok(Err(error.to_string())),
+            None => Ok(Ok(())),
+        }
+    }
+}
+
+/// Connections to MIGRATE targets, kept open for the next MIGRATE to the
+/// same target.
+#[derive(Default)]
+pub struct MigrateSockets {
+    sockets: Mutex<HashMap<String, MigrateSocket>>,
+}
+
+impl MigrateSockets {
+    /// The cached connection to `host:port`, or a new one. It is out of
+    /// the cache while in use, `give_back` returns it once it has proven
+    /// to still work.
+    pub fn take(&self, host: &str, port: u16, timeout: Duration) -> io::Result<MigrateSocket> {
+        let cached = self
+            .sockets
+            .lock()
+            .unwrap()
+            .remove(&format!("{}:{}", host, port));
+
+        match cached {
+            Some(socket) if !socket.closed() => Ok(socket),
+            _ => MigrateSocket::connect(host, port, timeout),
+        }
+    }
+
+    pub fn give_back(&self, host: &str, port: u16, mut socket: MigrateSocket) {
+        let mut sockets = self.sockets.lock().unwrap();
+
+        if sockets.len() >= MAX_SOCKETS {
+            let oldest = sockets
+                .iter()
+                .min_by_key(|(_, socket)| socket.last_use)
+                .map(|(name, _)| name.clone());
+
+            if let Some(oldest) = oldest {
+                sockets.remove(&oldest);
+            }
+        }
+
+        socket.last_use = Instant::now();
+        sockets.insert(format!("{}:{}", host, port), socket);
+    }
+
+    /// Closes the connections no MIGRATE used for a while.
+    pub fn close_idle(&self) {
+        self.sockets
+            .lock()
+            .unwrap()
+            .retain(|_, socket| socket.last_use.elapsed() <= SOCKET_TTL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener, thread};
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn listener() -> (TcpListener, u16) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (listener, port)
+    }
+
+    #[test]
+    fn replies_are_status_or_error_lines() {
+        let (listener, port) = listener();
+        let mut socket = MigrateSocket::connect("127.0.0.1", port, TIMEOUT).unwrap();
+        let (mut target, _) = listener.accept().unwrap();
+
+        target.write_all(b"+OK\r\n-BUSYKEY exists\r\n").unwrap();
+        drop(target);
+
+        assert_eq!(socket.read_reply().unwrap(), Ok(()));
+        assert_eq!(
+            socket.read_reply().unwrap(),
+            Err("BUSYKEY exists".to_string())
+        );
+        assert_eq!(
+            socket.read_reply().unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+
+    #[test]
+    fn connections_are_reused_while_open() {
+        let (listener, port) = listener();
+        let sockets = MigrateSockets::default();
+
+        let mut socket = sockets.take("127.0.0.1", port, TIMEOUT).unwrap();
+        let (mut target, _) = listener.accept().unwrap();
+
+        socket.send(b"PING", TIMEOUT).unwrap();
+        socket.db = Some(3);
+        sockets.give_back("127.0.0.1", port, socket);
+
+        let socket = sockets.take("127.0.0.1", port, TIMEOUT).unwrap();
+        let mut sent = [0; 4];
+
+        target.read_exact(&mut sent).unwrap();
+
+        assert_eq!(&sent, b"PING");
+        assert_eq!(socket.db, Some(3));
+
+        // Once the target hung up, the next MIGRATE connects again.
+        sockets.give_back("127.0.0.1", port, socket);
+        drop(target);
+        thread::sleep(Duration::from_millis(50));
+
+        let socket = sockets.take("127.0.0.1", port, TIMEOUT).unwrap();
+
+        assert_eq!(socket.db, Some(0));
+        assert!(listener.accept().is_ok());
+    }
+
+    #[test]
+    fn the_least_recently_used_connection_makes_room() {
+        let (listener, port) = listener();
+        let sockets = MigrateSockets::default();
+
+        for i in 0..=MAX_SOCKETS {
+            let socket = MigrateSocket::connect("127.0.0.1", port, TIMEOUT).unwrap();
+            sockets.give_back(&format!("target-{}", i), port, socket);
+        }
+
+        let cached = sockets.sockets.lock().unwrap();
+
+        assert_eq!(cached.len(), MAX_SOCKETS);
+        assert!(!cached.contains_key(&format!("target-0:{}", port)));
+        assert!(cached.contains_key(&format!("target-{}:{}", MAX_SOCKETS, port)));
+
+        drop(listener);
+    }
+
+    #[test]
+    fn idle_connections_are_closed() {
+        let (_listener, port) = listener();
+        let sockets = MigrateSockets::default();
+
+        for name in ["idle", "busy"] {
+            let socket = MigrateSocket::connect("127.0.0.1", port, TIMEOUT).unwrap();
+            sockets.give_back(name, port, socket);
+        }
+
+        sockets
+            .sockets
+            .lock()
+            .unwrap()
+            .get_mut(&format!("idle:{}", port))
+            .unwrap()
+            .last_use -= SOCKET_TTL + Duration::from_secs(1);
+        sockets.close_idle();
+
+        let cached = sockets.sockets.lock().unwrap();
+
+        assert_eq!(
+            cached.keys().collect::<Vec<_>>(),
+            [&format!("busy:{}", port)]
+        );
+    }
+}
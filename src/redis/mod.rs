@@ -1,4 +1,17 @@
+pub mod aof;
+pub mod blocking;
+pub mod client;
+pub mod cluster;
+pub mod command;
+pub mod config;
+pub mod geo;
+pub mod handler;
+pub mod migrate;
+pub mod notify;
 pub mod parse;
+pub mod persistence;
+pub mod pubsub;
+pub mod rdb;
 pub mod server;
-pub mod handler;
-pub mod persistence;
\ No newline at end of file
+pub mod tracking;
+pub mod watch;
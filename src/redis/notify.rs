@@ -0,0 +1,97 @@
+/// Keyspace notification classes, as set by `notify-keyspace-events`.
+pub const KEYSPACE: u32 = 1 << 0;
+pub const KEYEVENT: u32 = 1 << 1;
+pub const GENERIC: u32 = 1 << 2;
+pub const STRING: u32 = 1 << 3;
+pub const LIST: u32 = 1 << 4;
+pub const SET: u32 = 1 << 5;
+pub const HASH: u32 = 1 << 6;
+pub const ZSET: u32 = 1 << 7;
+pub const EXPIRED: u32 = 1 << 8;
+pub const EVICTED: u32 = 1 << 9;
+pub const STREAM: u32 = 1 << 10;
+pub const KEY_MISS: u32 = 1 << 11;
+pub const NEW: u32 = 1 << 12;
+
+/// What `A` stands for. Key misses and new keys are left out, both are
+/// noisy enough to be asked for explicitly.
+const ALL: u32 = GENERIC | STRING | LIST | SET | HASH | ZSET | EXPIRED | EVICTED | STREAM;
+
+const CLASSES: &[(char, u32)] = &[
+    ('g', GENERIC),
+    ('$', STRING),
+    ('l', LIST),
+    ('s', SET),
+    ('h', HASH),
+    ('z', ZSET),
+    ('x', EXPIRED),
+    ('e', EVICTED),
+    ('t', STREAM),
+    ('K', KEYSPACE),
+    ('E', KEYEVENT),
+    ('m', KEY_MISS),
+    ('n', NEW),
+];
+
+pub fn parse_classes(classes: &str) -> Result<u32, String> {
+    classes.chars().try_fold(0, |flags, class| {
+        if class == 'A' {
+            return Ok(flags | ALL);
+        }
+
+        match CLASSES.iter().find(|(c, _)| *c == class) {
+            Some((_, flag)) => Ok(flags | flag),
+            None => Err("Invalid event class character. Use 'Ag$lshzxeKEtmn'.".to_string()),
+        }
+    })
+}
+
+/// The shortest string `parse_classes` reads back as `flags`.
+pub fn classes_to_string(flags: u32) -> String {
+    let mut classes = String::new();
+
+    if flags & ALL == ALL {
+        classes.push('A');
+    }
+
+    for &(class, flag) in CLASSES {
+        let covered = flag & ALL != 0 && flags & ALL == ALL;
+
+        if flags & flag != 0 && !covered {
+            classes.push(class);
+        }
+    }
+
+    classes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_classes() {
+        assert_eq!(parse_classes(""), Ok(0));
+        assert_eq!(parse_classes("Kg"), Ok(KEYSPACE | GENERIC));
+        assert_eq!(parse_classes("EA"), Ok(KEYEVENT | ALL));
+        assert_eq!(parse_classes("Amn"), Ok(ALL | KEY_MISS | NEW));
+        assert!(parse_classes("Kq").is_err());
+    }
+
+    #[test]
+    fn prints_the_shortest_form() {
+        assert_eq!(classes_to_string(0), "");
+        assert_eq!(classes_to_string(KEYSPACE | ALL), "AK");
+        assert_eq!(classes_to_string(KEYEVENT | SET | ZSET), "szE");
+        assert_eq!(classes_to_string(ALL | KEYEVENT | NEW), "AEn");
+    }
+
+    #[test]
+    fn round_trips() {
+        for classes in ["KEA", "g$lshzxet", "Kmn", "Et"] {
+            let flags = parse_classes(classes).unwrap();
+
+            assert_eq!(parse_classes(&classes_to_string(flags)), Ok(flags));
+        }
+    }
+}
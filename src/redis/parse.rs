@@ -7,17 +7,21 @@ pub enum RespType {
     Integer,
     BulkString,
     Array,
+    Map,
+    Push,
     None,
 }
 
-impl Into<&str> for RespType {
-    fn into(self) -> &'static str {
-        match self {
+impl From<RespType> for &str {
+    fn from(val: RespType) -> Self {
+        match val {
             RespType::SimpleString => "+",
             RespType::Error => "-",
             RespType::Integer => ":",
             RespType::BulkString => "$",
             RespType::Array => "*",
+            RespType::Map => "%",
+            RespType::Push => ">",
             RespType::None => "\0",
         }
     }
@@ -41,11 +45,17 @@ impl From<char> for RespType {
 pub enum RespData {
     SimpleString(String),
     Error(String),
-    #[allow(dead_code)]
     Integer(i64),
-    BulkString(String),
+    /// Kept as sent, not every bulk string is text.
+    BulkString(Vec<u8>),
+    Null,
+    NullArray,
     Array(Vec<RespData>),
     RequestArray(Vec<RespData>),
+    /// RESP3 only, see `RespData::for_protocol` for RESP2 clients.
+    Map(Vec<(RespData, RespData)>),
+    /// RESP3 out-of-band data, such as published messages.
+    Push(Vec<RespData>),
 }
 
 impl RespData {
@@ -53,31 +63,103 @@ impl RespData {
         let mut array: Vec<RespData> = vec![];
 
         for val in data {
-            array.push(RespData::BulkString(val.to_string()));
+            array.push(RespData::new_bulk(val));
         }
 
         RespData::Array(array)
     }
 
     pub fn new_bulk(data: &str) -> RespData {
-        RespData::BulkString(data.to_string())
+        RespData::BulkString(data.as_bytes().to_vec())
     }
 
     pub fn new_simple_string(val: &str) -> RespData {
         RespData::SimpleString(val.to_string())
     }
 
+    /// Serialized for the wire. Unlike `to_string` it keeps bulk strings
+    /// that aren't UTF-8 intact.
     pub fn as_bytes(&self) -> Vec<u8> {
-        self.to_string().into_bytes()
+        let mut out = vec![];
+        self.write_bytes(&mut out);
+        out
+    }
+
+    fn write_bytes(&self, out: &mut Vec<u8>) {
+        let header = |out: &mut Vec<u8>, data_type: RespType, len: usize| {
+            out.extend(format!("{}{}\r\n", <RespType as Into<&str>>::into(data_type), len).bytes())
+        };
+
+        match self {
+            RespData::BulkString(b) => {
+                header(out, RespType::BulkString, b.len());
+                out.extend(b);
+                out.extend(b"\r\n");
+            }
+            RespData::Array(items) => {
+                header(out, RespType::Array, items.len());
+                items.iter().for_each(|item| item.write_bytes(out));
+            }
+            RespData::Map(pairs) => {
+                header(out, RespType::Map, pairs.len());
+
+                for (key, value) in pairs {
+                    key.write_bytes(out);
+                    value.write_bytes(out);
+                }
+            }
+            RespData::Push(items) => {
+                header(out, RespType::Push, items.len());
+                items.iter().for_each(|item| item.write_bytes(out));
+            }
+            data => out.extend(data.to_string().into_bytes()),
+        }
+    }
+
+    /// A copy where a bulk string that isn't valid UTF-8 has the invalid
+    /// sequences replaced, for the commands that only handle text.
+    pub fn to_text(&self) -> RespData {
+        match self {
+            RespData::BulkString(b) => {
+                RespData::BulkString(String::from_utf8_lossy(b).into_owned().into_bytes())
+            }
+            data => data.clone(),
+        }
+    }
+
+    /// RESP2 has no maps or pushes, it gets them as flat arrays.
+    pub fn for_protocol(self, resp3: bool) -> RespData {
+        match (self, resp3) {
+            (RespData::Map(pairs), false) => RespData::Array(
+                pairs
+                    .into_iter()
+                    .flat_map(|(key, value)| [key, value.for_protocol(false)])
+                    .collect(),
+            ),
+            (RespData::Push(items), false) => RespData::Array(items),
+            (RespData::Array(items), resp3) => RespData::Array(
+                items
+                    .into_iter()
+                    .map(|item| item.for_protocol(resp3))
+                    .collect(),
+            ),
+            (data, _) => data,
+        }
+    }
+}
+
+impl From<String> for RespData {
+    fn from(val: String) -> RespData {
+        RespData::BulkString(val.into_bytes())
     }
 }
 
 impl PartialEq for RespData {
     fn eq(&self, other: &Self) -> bool {
-        if self.inside_value() == other.inside_value() {
+        if self.inside_bytes() == other.inside_bytes() {
             return true;
         }
-        return false;
+        false
     }
 }
 
@@ -102,29 +184,23 @@ impl fmt::Display for RespData {
                 <RespType as Into<&str>>::into(RespType::Integer),
                 i
             ),
-            RespData::BulkString(b) => {
-                let size = b.len();
-
-                match size {
-                    0 => {
-                        write!(
-                            f,
-                            "{}-1\r\n{}\r\n",
-                            <RespType as Into<&str>>::into(RespType::BulkString),
-                            b
-                        )
-                    }
-                    _ => {
-                        write!(
-                            f,
-                            "{}{}\r\n{}\r\n",
-                            <RespType as Into<&str>>::into(RespType::BulkString),
-                            b.len(),
-                            b
-                        )
-                    }
-                }
-            }
+            RespData::BulkString(b) => write!(
+                f,
+                "{}{}\r\n{}\r\n",
+                <RespType as Into<&str>>::into(RespType::BulkString),
+                b.len(),
+                String::from_utf8_lossy(b)
+            ),
+            RespData::Null => write!(
+                f,
+                "{}-1\r\n",
+                <RespType as Into<&str>>::into(RespType::BulkString)
+            ),
+            RespData::NullArray => write!(
+                f,
+                "{}-1\r\n",
+                <RespType as Into<&str>>::into(RespType::Array)
+            ),
             RespData::Array(a) => {
                 let mut result = String::new();
                 result.push_str(&format!(
@@ -139,168 +215,237 @@ impl fmt::Display for RespData {
                 }
                 write!(f, "{}", result)
             }
+            RespData::Map(pairs) => {
+                write!(
+                    f,
+                    "{}{}\r\n",
+                    <RespType as Into<&str>>::into(RespType::Map),
+                    pairs.len()
+                )?;
+
+                for (key, value) in pairs {
+                    write!(f, "{}{}", key, value)?;
+                }
+
+                Ok(())
+            }
+            RespData::Push(items) => {
+                write!(
+                    f,
+                    "{}{}\r\n",
+                    <RespType as Into<&str>>::into(RespType::Push),
+                    items.len()
+                )?;
+
+                for item in items {
+                    write!(f, "{}", item)?;
+                }
+
+                Ok(())
+            }
             _ => panic!(),
         }
     }
 }
 
+/// The `\r\n` terminated line `bytes` starts with, and what follows it.
+fn split_line(bytes: &[u8]) -> (&[u8], &[u8]) {
+    match bytes.windows(2).position(|w| w == b"\r\n") {
+        Some(end) => (&bytes[..end], &bytes[end + 2..]),
+        None => (bytes, &[]),
+    }
+}
+
+fn parse_len(line: &[u8]) -> Option<usize> {
+    std::str::from_utf8(line).ok()?.parse().ok()
+}
+
 impl RespData {
-    pub fn parse(chars: &String, data_type: &RespType) -> Option<RespData> {
+    pub fn parse(bytes: &[u8], data_type: &RespType) -> Option<RespData> {
         match data_type {
-            RespType::SimpleString => RespData::parse_simple_string(chars),
-            RespType::Error => RespData::parse_error(chars),
-            RespType::Integer => RespData::parse_integer(chars),
-            RespType::BulkString => RespData::parse_bulk_string(chars),
-            RespType::Array => RespData::parse_array(chars),
-            RespType::None => None,
+            RespType::SimpleString => RespData::parse_simple_string(bytes),
+            RespType::Error => RespData::parse_error(bytes),
+            RespType::Integer => RespData::parse_integer(bytes),
+            RespType::BulkString => RespData::parse_bulk_string(bytes).map(|(data, _)| data),
+            RespType::Array => RespData::parse_array(bytes),
+            RespType::Map | RespType::Push | RespType::None => None,
         }
     }
 
-    pub fn parse_simple_string(chars: &String) -> Option<RespData> {
-        let mut return_array: Vec<RespData> = vec![];
-
-        let mut split = chars.split("\r\n");
-        let res = split.next().unwrap();
+    /// What follows a parsed value: more of them, pipelined.
+    fn parse_rest(rest: &[u8], parsed: &mut Vec<RespData>) {
+        let data_type = match rest.first() {
+            Some(&first) => RespType::from(first as char),
+            None => return,
+        };
 
-        return_array.push(RespData::SimpleString(res.to_string()));
+        match Self::parse(rest, &data_type) {
+            Some(RespData::RequestArray(array)) => parsed.extend(array),
+            Some(data) => parsed.push(data),
+            None => {}
+        }
+    }
 
-        let next_string: String = split.collect::<Vec<&str>>().join("\r\n");
-        let data_type = next_string.chars().next().map(RespType::from).unwrap();
+    pub fn parse_simple_string(bytes: &[u8]) -> Option<RespData> {
+        let (line, rest) = split_line(bytes);
+        let mut return_array = vec![RespData::SimpleString(
+            String::from_utf8_lossy(line).into_owned(),
+        )];
 
-        if data_type != RespType::None {
-            if let Some(data) = Self::parse(&next_string.to_string(), &data_type) {
-                match data {
-                    RespData::RequestArray(array) => {
-                        return_array.extend(array);
-                    }
-                    _ => {
-                        return_array.push(data);
-                    }
-                }
-            }
-        }
+        Self::parse_rest(rest, &mut return_array);
 
         Some(RespData::RequestArray(return_array))
     }
 
-    pub fn parse_error(_chars: &String) -> Option<RespData> {
+    pub fn parse_error(_bytes: &[u8]) -> Option<RespData> {
         None
     }
 
-    pub fn parse_integer(_chars: &String) -> Option<RespData> {
+    pub fn parse_integer(_bytes: &[u8]) -> Option<RespData> {
         None
     }
 
-    pub fn parse_bulk_string(serialized: &String) -> Option<RespData> {
-        let mut vals = serialized.split("\r\n");
-
-        let size_test = vals.next().unwrap();
-
-        let test: String;
-
-        if size_test
-            .to_string()
-            .chars()
-            .peekable()
-            .peek()
-            .unwrap()
-            .to_string()
-            == "$"
-        {
-            test = size_test.chars().skip(1).collect();
-        } else {
-            test = size_test.to_string();
-        }
-
-        let size = test.parse::<usize>().unwrap();
+    /// Read by its length, whatever bytes it holds, with what follows it.
+    pub fn parse_bulk_string(bytes: &[u8]) -> Option<(RespData, &[u8])> {
+        let (line, rest) = split_line(bytes);
+        let size = parse_len(line.strip_prefix(b"$").unwrap_or(line))?;
 
-        let data = vals
-            .next()
-            .unwrap()
-            .to_string()
-            .chars()
-            .take(size)
-            .collect();
+        if rest.get(size..size + 2)? != b"\r\n" {
+            return None;
+        }
 
-        Some(RespData::BulkString(data))
+        Some((
+            RespData::BulkString(rest[..size].to_vec()),
+            &rest[size + 2..],
+        ))
     }
 
-    pub fn parse_array(serialized: &String) -> Option<RespData> {
-        let binding = serialized.chars().skip(1).collect::<String>();
-
-        let mut vals = binding.split("\r\n").into_iter();
-
-        let size = match vals.next().unwrap().parse::<u32>() {
-            Ok(size) => size,
-            Err(_) => return None,
-        };
+    pub fn parse_array(bytes: &[u8]) -> Option<RespData> {
+        let (line, mut rest) = split_line(bytes);
+        let size = parse_len(line.get(1..)?)?;
 
         let mut array: Vec<RespData> = vec![];
 
         for _ in 0..size {
-            let mut cur = vals.next().unwrap().chars();
-            let data_type = cur.next().map(RespType::from).unwrap();
+            let data_type = rest.first().map(|&first| RespType::from(first as char))?;
 
-            let data: RespData;
-
-            match data_type {
+            let data = match data_type {
                 RespType::BulkString => {
-                    let receive =
-                        format!("{}\r\n{}", cur.collect::<String>(), vals.next().unwrap());
-                    data = RespData::parse(&receive, &data_type).unwrap();
+                    let (data, after) = RespData::parse_bulk_string(rest)?;
+                    rest = after;
+                    data
                 }
                 _ => {
-                    data = RespData::parse(&cur.collect(), &data_type).unwrap();
+                    let (line, after) = split_line(rest);
+                    rest = after;
+                    RespData::parse(line.get(1..)?, &data_type)?
                 }
-            }
+            };
 
             array.push(data);
         }
 
-        let mut return_array: Vec<RespData> = vec![];
-
-        let data_type = serialized.chars().next().map(RespType::from).unwrap();
-
-        let serialized_string: String = vals.collect::<Vec<&str>>().join("\r\n");
-        if let Some(data) = Self::parse(&serialized_string, &data_type) {
-            match data {
-                RespData::RequestArray(array) => {
-                    return_array.extend(array);
-                }
-                _ => {
-                    return_array.push(data);
-                }
-            }
-        }
+        // Pipelined requests stay in the order they were sent.
+        let mut return_array: Vec<RespData> = vec![RespData::Array(array)];
 
-        return_array.push(RespData::Array(array));
+        Self::parse_rest(rest, &mut return_array);
 
         Some(RespData::RequestArray(return_array))
     }
 
-    pub fn inside_value(&self) -> Option<&str> {
+    /// The bytes of a string, exactly as sent.
+    pub fn inside_bytes(&self) -> Option<&[u8]> {
         match self {
-            RespData::SimpleString(s) => Some(s),
-            RespData::Error(e) => Some(e),
+            RespData::SimpleString(s) => Some(s.as_bytes()),
+            RespData::Error(e) => Some(e.as_bytes()),
             RespData::BulkString(b) => Some(b),
-            RespData::Array(_) => None,
             _ => None,
         }
     }
+
+    /// The string as text, `None` when it isn't valid UTF-8.
+    pub fn inside_value(&self) -> Option<&str> {
+        std::str::from_utf8(self.inside_bytes()?).ok()
+    }
 }
 
 #[derive(Debug)]
 pub struct Resp {
+    #[allow(dead_code)]
     pub data_type: RespType,
     pub data: RespData,
 }
 
 impl Resp {
-    pub fn parse(serialized: String) -> Option<Resp> {
-        let data_type = serialized.chars().next().map(RespType::from).unwrap();
+    pub fn parse(serialized: &[u8]) -> Option<Resp> {
+        let data_type = serialized
+            .first()
+            .map(|&first| RespType::from(first as char))
+            .unwrap();
 
-        let data = RespData::parse(&serialized, &data_type).unwrap();
+        let data = RespData::parse(serialized, &data_type).unwrap();
 
         Some(Resp { data_type, data })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn requests(bytes: &[u8]) -> Vec<RespData> {
+        match Resp::parse(bytes).unwrap().data {
+            RespData::RequestArray(requests) => requests,
+            data => panic!("not requests: {:?}", data),
+        }
+    }
+
+    #[test]
+    fn bulk_strings_are_read_by_length() {
+        let bytes = b"*2\r\n$4\r\nECHO\r\n$6\r\na\r\n\xff\x00b\r\n";
+
+        assert_eq!(
+            requests(bytes),
+            [RespData::Array(vec![
+                RespData::new_bulk("ECHO"),
+                RespData::BulkString(b"a\r\n\xff\x00b".to_vec()),
+            ])]
+        );
+    }
+
+    #[test]
+    fn pipelined_requests_keep_their_order() {
+        let bytes = b"*1\r\n$4\r\nPING\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n*1\r\n$4\r\nEXEC\r\n";
+
+        assert_eq!(
+            requests(bytes),
+            [
+                RespData::new_bulk_array(&["PING"]),
+                RespData::new_bulk_array(&["GET", "k"]),
+                RespData::new_bulk_array(&["EXEC"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn bulk_strings_must_end_where_their_length_says() {
+        assert!(RespData::parse_bulk_string(b"$3\r\nab\r\n").is_none());
+        assert!(RespData::parse_bulk_string(b"$3\r\nabcd\r\n").is_none());
+        assert!(RespData::parse_bulk_string(b"$x\r\nabc\r\n").is_none());
+        assert!(RespData::parse_array(b"*2\r\n$1\r\na\r\n").is_none());
+
+        let (data, rest) = RespData::parse_bulk_string(b"$0\r\n\r\nmore").unwrap();
+
+        assert_eq!(data, RespData::new_bulk(""));
+        assert_eq!(rest, b"more");
+    }
+
+    #[test]
+    fn binary_bulk_strings_are_written_as_they_are() {
+        let data = RespData::BulkString(b"\x00\xff\r\n".to_vec());
+
+        assert_eq!(data.as_bytes(), b"$4\r\n\x00\xff\r\n\r\n");
+        assert_eq!(data.inside_bytes(), Some(&b"\x00\xff\r\n"[..]));
+        assert_eq!(data.inside_value(), None);
+    }
+}
@@ -13,4 +13,15 @@ pub struct PersistedValue {
 }
 
 #[derive(Default)]
-pub struct KeyValuePersistence(pub HashMap<String, PersistedValue>);
\ No newline at end of file
+pub struct KeyValuePersistence(pub HashMap<String, PersistedValue>);
+
+impl PersistedValue {
+    pub fn is_expired(&self) -> bool {
+        self.expiry > 0
+            && std::time::SystemTime::now()
+                .duration_since(self.timestamp)
+                .unwrap()
+                .as_millis()
+                > self.expiry
+    }
+}
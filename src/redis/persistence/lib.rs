@@ -1,14 +1,122 @@
 use std::sync::Mutex;
 
-use super::{kv_pair::KeyValuePersistence, stream::StreamPersistence};
+use super::{
+    kv_pair::KeyValuePersistence, set::SetPersistence, sorted_set::SortedSetPersistence,
+    stream::StreamPersistence,
+};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum PersistedType {
     String,
+    Stream,
+    Set,
+    SortedSet,
+}
+
+impl PersistedType {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PersistedType::String => "string",
+            PersistedType::Stream => "stream",
+            PersistedType::Set => "set",
+            PersistedType::SortedSet => "zset",
+        }
+    }
 }
 
 #[derive(Default)]
 pub struct PersistenceInner {
     pub key_value: Mutex<KeyValuePersistence>,
     pub stream: Mutex<StreamPersistence>,
-}
\ No newline at end of file
+    pub set: Mutex<SetPersistence>,
+    pub sorted_set: Mutex<SortedSetPersistence>,
+}
+
+impl PersistenceInner {
+    /// Every type lives in its own map, so this is the one place that knows
+    /// which of them currently holds `key`.
+    pub fn key_type(&self, key: &str) -> Option<PersistedType> {
+        if let Some(val) = self.key_value.lock().unwrap().0.get(key) {
+            if !val.is_expired() {
+                return Some(val.p_type);
+            }
+        }
+
+        if self.stream.lock().unwrap().map.contains_key(key) {
+            return Some(PersistedType::Stream);
+        }
+
+        if self.set.lock().unwrap().0.contains_key(key) {
+            return Some(PersistedType::Set);
+        }
+
+        if self.sorted_set.lock().unwrap().0.contains_key(key) {
+            return Some(PersistedType::SortedSet);
+        }
+
+        None
+    }
+
+    /// Removes `key` whatever its type is.
+    pub fn delete(&self, key: &str) -> bool {
+        let removed = [
+            self.key_value.lock().unwrap().0.remove(key).is_some(),
+            self.stream.lock().unwrap().map.remove(key).is_some(),
+            self.set.lock().unwrap().0.remove(key).is_some(),
+            self.sorted_set.lock().unwrap().0.remove(key).is_some(),
+        ];
+
+        removed.contains(&true)
+    }
+
+    /// Whether `key` holds a value whose TTL ran out but that nothing has
+    /// removed yet.
+    pub fn is_expired(&self, key: &str) -> bool {
+        self.key_value
+            .lock()
+            .unwrap()
+            .0
+            .get(key)
+            .is_some_and(|val| val.is_expired())
+    }
+
+    /// Removes `key` if its TTL ran out.
+    pub fn expire(&self, key: &str) -> bool {
+        let mut key_value = self.key_value.lock().unwrap();
+
+        match key_value.0.get(key) {
+            Some(val) if val.is_expired() => key_value.0.remove(key).is_some(),
+            _ => false,
+        }
+    }
+
+    /// Removes every value whose TTL ran out, returns their keys.
+    pub fn remove_expired(&self) -> Vec<String> {
+        let mut key_value = self.key_value.lock().unwrap();
+
+        let expired: Vec<String> = key_value
+            .0
+            .iter()
+            .filter(|(_, val)| val.is_expired())
+            .map(|(key, _)| key.clone())
+            .collect();
+
+        for key in &expired {
+            key_value.0.remove(key);
+        }
+
+        expired
+    }
+
+    /// Removes every key, returns how many there were.
+    pub fn flush(&self) -> usize {
+        [
+            self.key_value.lock().unwrap().0.drain().count(),
+            self.stream.lock().unwrap().map.drain().count(),
+            self.set.lock().unwrap().0.drain().count(),
+            self.sorted_set.lock().unwrap().0.drain().count(),
+        ]
+        .iter()
+        .sum()
+    }
+}
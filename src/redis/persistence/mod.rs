@@ -1,3 +1,5 @@
 pub mod kv_pair;
+pub mod lib;
+pub mod set;
+pub mod sorted_set;
 pub mod stream;
-pub mod lib;
\ No newline at end of file
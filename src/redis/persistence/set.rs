@@ -0,0 +1,471 @@
+use std::{
+    cell::Cell,
+    collections::{hash_map::RandomState, HashMap, HashSet},
+    hash::{BuildHasher, Hasher},
+};
+
+// Same default as Redis' `set-max-intset-entries`.
+const SET_MAX_INTSET_ENTRIES: usize = 512;
+
+thread_local! {
+    static SEED: Cell<u64> = Cell::new(RandomState::new().build_hasher().finish() | 1);
+}
+
+pub fn random_index(len: usize) -> usize {
+    SEED.with(|seed| {
+        let mut x = seed.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        seed.set(x);
+        (x % len as u64) as usize
+    })
+}
+
+fn as_set_int(member: &str) -> Option<i64> {
+    match member.parse::<i64>() {
+        Ok(v) if v.to_string() == member => Some(v),
+        _ => None,
+    }
+}
+
+/// A hash set that also keeps its members in a `Vec`, so picking a random
+/// member is O(1) and removals are a swap with the last slot.
+#[derive(Clone, Debug, Default)]
+pub struct IndexedSet {
+    members: Vec<String>,
+    index: HashMap<String, usize>,
+}
+
+impl IndexedSet {
+    fn insert(&mut self, member: String) -> bool {
+        if self.index.contains_key(&member) {
+            return false;
+        }
+
+        self.index.insert(member.clone(), self.members.len());
+        self.members.push(member);
+        true
+    }
+
+    fn remove(&mut self, member: &str) -> bool {
+        let pos = match self.index.remove(member) {
+            Some(pos) => pos,
+            None => return false,
+        };
+
+        self.members.swap_remove(pos);
+
+        if let Some(moved) = self.members.get(pos) {
+            self.index.insert(moved.clone(), pos);
+        }
+
+        true
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum SetValue {
+    /// Sorted integers, used while every member is a canonical 64 bit integer.
+    IntSet(Vec<i64>),
+    HashSet(IndexedSet),
+}
+
+impl Default for SetValue {
+    fn default() -> Self {
+        SetValue::IntSet(vec![])
+    }
+}
+
+impl SetValue {
+    fn upgrade(&mut self) {
+        if let SetValue::IntSet(ints) = self {
+            let mut set = IndexedSet::default();
+
+            for v in ints.iter() {
+                set.insert(v.to_string());
+            }
+
+            *self = SetValue::HashSet(set);
+        }
+    }
+
+    pub fn add(&mut self, member: &str) -> bool {
+        if let SetValue::IntSet(ints) = self {
+            match as_set_int(member) {
+                Some(v) => match ints.binary_search(&v) {
+                    Ok(_) => return false,
+                    Err(pos) => {
+                        ints.insert(pos, v);
+
+                        if ints.len() > SET_MAX_INTSET_ENTRIES {
+                            self.upgrade();
+                        }
+
+                        return true;
+                    }
+                },
+                None => self.upgrade(),
+            }
+        }
+
+        match self {
+            SetValue::HashSet(set) => set.insert(member.to_string()),
+            SetValue::IntSet(_) => unreachable!(),
+        }
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self {
+            SetValue::IntSet(ints) => match as_set_int(member) {
+                Some(v) => match ints.binary_search(&v) {
+                    Ok(pos) => {
+                        ints.remove(pos);
+                        true
+                    }
+                    Err(_) => false,
+                },
+                None => false,
+            },
+            SetValue::HashSet(set) => set.remove(member),
+        }
+    }
+
+    pub fn contains(&self, member: &str) -> bool {
+        match self {
+            SetValue::IntSet(ints) => match as_set_int(member) {
+                Some(v) => ints.binary_search(&v).is_ok(),
+                None => false,
+            },
+            SetValue::HashSet(set) => set.index.contains_key(member),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            SetValue::IntSet(ints) => ints.len(),
+            SetValue::HashSet(set) => set.members.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn members(&self) -> Vec<String> {
+        match self {
+            SetValue::IntSet(ints) => ints.iter().map(|v| v.to_string()).collect(),
+            SetValue::HashSet(set) => set.members.clone(),
+        }
+    }
+
+    fn member_at(&self, pos: usize) -> String {
+        match self {
+            SetValue::IntSet(ints) => ints[pos].to_string(),
+            SetValue::HashSet(set) => set.members[pos].clone(),
+        }
+    }
+
+    pub fn random_member(&self) -> Option<String> {
+        match self.len() {
+            0 => None,
+            len => Some(self.member_at(random_index(len))),
+        }
+    }
+
+    /// Up to `count` distinct members, in random order.
+    pub fn random_distinct(&self, count: usize) -> Vec<String> {
+        let len = self.len();
+
+        if count >= len {
+            return self.members();
+        }
+
+        // When most of the set is requested, shuffling the head of a copy
+        // is cheaper than rejecting duplicate picks.
+        if count * 3 > len {
+            let mut members = self.members();
+
+            for i in 0..count {
+                let j = i + random_index(len - i);
+                members.swap(i, j);
+            }
+
+            members.truncate(count);
+            return members;
+        }
+
+        let mut picked: HashSet<usize> = HashSet::new();
+        let mut result: Vec<String> = vec![];
+
+        while result.len() < count {
+            let pos = random_index(len);
+
+            if picked.insert(pos) {
+                result.push(self.member_at(pos));
+            }
+        }
+
+        result
+    }
+
+    /// Exactly `count` members, the same member may be returned more than once.
+    pub fn random_with_repeats(&self, count: usize) -> Vec<String> {
+        match self.len() {
+            0 => vec![],
+            len => (0..count)
+                .map(|_| self.member_at(random_index(len)))
+                .collect(),
+        }
+    }
+
+    pub fn pop(&mut self, count: usize) -> Vec<String> {
+        let popped = self.random_distinct(count);
+
+        for member in &popped {
+            self.remove(member);
+        }
+
+        popped
+    }
+}
+
+#[derive(Default)]
+pub struct SetPersistence(pub HashMap<String, SetValue>);
+
+impl SetPersistence {
+    /// Removes `members` from the set at `key`, dropping the key once empty.
+    pub fn remove(&mut self, key: &str, members: &[String]) -> usize {
+        let set = match self.0.get_mut(key) {
+            Some(set) => set,
+            None => return 0,
+        };
+
+        let removed = members.iter().filter(|m| set.remove(m)).count();
+
+        if set.is_empty() {
+            self.0.remove(key);
+        }
+
+        removed
+    }
+
+    fn sources(&self, keys: &[String]) -> Vec<Option<&SetValue>> {
+        keys.iter().map(|key| self.0.get(key)).collect()
+    }
+
+    /// Walks the smallest set and probes the others, stopping early once
+    /// `limit` members were found (0 means no limit).
+    pub fn inter(&self, keys: &[String], limit: usize) -> SetValue {
+        let mut result = SetValue::default();
+
+        let mut sets: Vec<&SetValue> = match self.sources(keys).into_iter().collect() {
+            Some(sets) => sets,
+            None => return result,
+        };
+
+        sets.sort_by_key(|set| set.len());
+
+        let (smallest, rest) = sets.split_first().unwrap();
+
+        for member in smallest.members() {
+            if rest.iter().all(|set| set.contains(&member)) {
+                result.add(&member);
+
+                if limit > 0 && result.len() >= limit {
+                    break;
+                }
+            }
+        }
+
+        result
+    }
+
+    pub fn union(&self, keys: &[String]) -> SetValue {
+        let mut result = SetValue::default();
+
+        for set in self.sources(keys).into_iter().flatten() {
+            for member in set.members() {
+                result.add(&member);
+            }
+        }
+
+        result
+    }
+
+    pub fn diff(&self, keys: &[String]) -> SetValue {
+        let mut result = SetValue::default();
+
+        let sources = self.sources(keys);
+        let (first, rest) = sources.split_first().unwrap();
+
+        if let Some(first) = first {
+            for member in first.members() {
+                if !rest.iter().flatten().any(|set| set.contains(&member)) {
+                    result.add(&member);
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Replaces `key` with `set`, an empty result removes the key.
+    pub fn store(&mut self, key: &str, set: SetValue) -> usize {
+        let len = set.len();
+
+        if set.is_empty() {
+            self.0.remove(key);
+        } else {
+            self.0.insert(key.to_string(), set);
+        }
+
+        len
+    }
+
+    pub fn pop(&mut self, key: &str, count: usize) -> Vec<String> {
+        let set = match self.0.get_mut(key) {
+            Some(set) => set,
+            None => return vec![],
+        };
+
+        let popped = set.pop(count);
+
+        if set.is_empty() {
+            self.0.remove(key);
+        }
+
+        popped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn set_of(members: &[&str]) -> SetValue {
+        let mut set = SetValue::default();
+
+        for member in members {
+            set.add(member);
+        }
+
+        set
+    }
+
+    fn sorted(mut members: Vec<String>) -> Vec<String> {
+        members.sort();
+        members
+    }
+
+    #[test]
+    fn integers_stay_an_intset() {
+        let set = set_of(&["3", "-1", "2", "3"]);
+
+        assert!(matches!(&set, SetValue::IntSet(ints) if ints == &[-1, 2, 3]));
+        assert_eq!(set.members(), ["-1", "2", "3"]);
+    }
+
+    #[test]
+    fn non_canonical_integers_upgrade_to_a_hash_set() {
+        for member in ["a", "01", "+1", " 1"] {
+            let set = set_of(&["1", member]);
+
+            assert!(matches!(set, SetValue::HashSet(_)), "{}", member);
+            assert!(set.contains("1") && set.contains(member));
+            assert_eq!(set.len(), 2);
+        }
+    }
+
+    #[test]
+    fn too_many_integers_upgrade_to_a_hash_set() {
+        let members: Vec<String> = (0..=SET_MAX_INTSET_ENTRIES)
+            .map(|v| v.to_string())
+            .collect();
+        let mut set = SetValue::default();
+
+        for member in &members[..SET_MAX_INTSET_ENTRIES] {
+            set.add(member);
+        }
+
+        assert!(matches!(set, SetValue::IntSet(_)));
+
+        set.add(&members[SET_MAX_INTSET_ENTRIES]);
+
+        assert!(matches!(set, SetValue::HashSet(_)));
+        assert_eq!(sorted(set.members()), sorted(members));
+    }
+
+    #[test]
+    fn removing_keeps_the_index_consistent() {
+        let mut set = set_of(&["a", "b", "c", "d"]);
+
+        assert!(set.remove("a"));
+        assert!(!set.remove("a"));
+        assert!(set.remove("c"));
+
+        assert_eq!(sorted(set.members()), ["b", "d"]);
+        assert!(set.contains("d") && !set.contains("c"));
+
+        // `d` was moved into a freed slot, it must still be found there.
+        assert!(set.remove("d"));
+        assert_eq!(set.members(), ["b"]);
+    }
+
+    #[test]
+    fn random_distinct_never_repeats() {
+        let set = set_of(&["a", "b", "c", "d", "e", "f", "g", "h", "i", "j"]);
+
+        for count in [1, 3, 4, 9, 10, 20] {
+            let picked = set.random_distinct(count);
+            let unique: HashSet<&String> = picked.iter().collect();
+
+            assert_eq!(picked.len(), count.min(10));
+            assert_eq!(unique.len(), picked.len());
+            assert!(picked.iter().all(|member| set.contains(member)));
+        }
+    }
+
+    #[test]
+    fn random_with_repeats_returns_exactly_count() {
+        assert_eq!(set_of(&["a"]).random_with_repeats(5), ["a"; 5]);
+        assert!(SetValue::default().random_with_repeats(5).is_empty());
+    }
+
+    #[test]
+    fn emptied_sets_are_dropped() {
+        let mut sets = SetPersistence::default();
+        sets.0.insert("s".to_string(), set_of(&["1", "2"]));
+
+        assert_eq!(sets.remove("s", &["1".to_string(), "3".to_string()]), 1);
+        assert!(sets.0.contains_key("s"));
+
+        assert_eq!(sets.pop("s", 5), ["2"]);
+        assert!(!sets.0.contains_key("s"));
+
+        assert_eq!(sets.store("s", SetValue::default()), 0);
+        assert!(!sets.0.contains_key("s"));
+    }
+
+    #[test]
+    fn algebra() {
+        let mut sets = SetPersistence::default();
+        sets.0.insert("a".to_string(), set_of(&["1", "2", "x"]));
+        sets.0.insert("b".to_string(), set_of(&["2", "x", "y"]));
+
+        let keys = |keys: &[&str]| -> Vec<String> { keys.iter().map(|k| k.to_string()).collect() };
+
+        assert_eq!(
+            sorted(sets.inter(&keys(&["a", "b"]), 0).members()),
+            ["2", "x"]
+        );
+        assert_eq!(sets.inter(&keys(&["a", "b"]), 1).len(), 1);
+        assert!(sets.inter(&keys(&["a", "missing"]), 0).is_empty());
+        assert_eq!(
+            sorted(sets.union(&keys(&["a", "b", "missing"])).members()),
+            ["1", "2", "x", "y"]
+        );
+        assert_eq!(sets.diff(&keys(&["a", "b"])).members(), ["1"]);
+        assert!(sets.diff(&keys(&["missing", "a"])).is_empty());
+    }
+}
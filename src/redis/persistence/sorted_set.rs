@@ -0,0 +1,1172 @@
+use std::collections::HashMap;
+
+use super::set::{random_index, SetValue};
+
+const ZSKIPLIST_MAXLEVEL: usize = 32;
+
+pub fn parse_score(val: &str) -> Option<f64> {
+    match val.parse::<f64>() {
+        Ok(v) if !v.is_nan() => Some(v),
+        _ => None,
+    }
+}
+
+/// Scores are replied the way Redis prints doubles: integers without a
+/// fractional part, and an exponent outside `%.17g` range.
+pub fn format_double(val: f64) -> String {
+    if val.is_infinite() {
+        return if val > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+
+    let exp_form = format!("{:e}", val);
+    let (mantissa, exp) = exp_form.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+
+    if !(-4..17).contains(&exp) {
+        let sign = if exp < 0 { '-' } else { '+' };
+        return format!("{}e{}{:02}", mantissa, sign, exp.abs());
+    }
+
+    format!("{}", val)
+}
+
+#[derive(Clone, Debug)]
+pub struct ScoreRange {
+    pub min: f64,
+    pub max: f64,
+    pub minex: bool,
+    pub maxex: bool,
+}
+
+impl ScoreRange {
+    fn parse_bound(val: &str) -> Option<(f64, bool)> {
+        match val.strip_prefix('(') {
+            Some(rest) => parse_score(rest).map(|v| (v, true)),
+            None => parse_score(val).map(|v| (v, false)),
+        }
+    }
+
+    pub fn parse(min: &str, max: &str) -> Option<ScoreRange> {
+        let (min, minex) = Self::parse_bound(min)?;
+        let (max, maxex) = Self::parse_bound(max)?;
+
+        Some(ScoreRange {
+            min,
+            max,
+            minex,
+            maxex,
+        })
+    }
+
+    pub fn gte_min(&self, val: f64) -> bool {
+        if self.minex {
+            val > self.min
+        } else {
+            val >= self.min
+        }
+    }
+
+    pub fn lte_max(&self, val: f64) -> bool {
+        if self.maxex {
+            val < self.max
+        } else {
+            val <= self.max
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum LexBound {
+    NegInf,
+    PosInf,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+#[derive(Clone, Debug)]
+pub struct LexRange {
+    pub min: LexBound,
+    pub max: LexBound,
+}
+
+impl LexRange {
+    fn parse_bound(val: &str) -> Option<LexBound> {
+        match val {
+            "-" => Some(LexBound::NegInf),
+            "+" => Some(LexBound::PosInf),
+            val if val.starts_with('[') => Some(LexBound::Inclusive(val[1..].to_string())),
+            val if val.starts_with('(') => Some(LexBound::Exclusive(val[1..].to_string())),
+            _ => None,
+        }
+    }
+
+    pub fn parse(min: &str, max: &str) -> Option<LexRange> {
+        Some(LexRange {
+            min: Self::parse_bound(min)?,
+            max: Self::parse_bound(max)?,
+        })
+    }
+
+    pub fn gte_min(&self, member: &str) -> bool {
+        match &self.min {
+            LexBound::NegInf => true,
+            LexBound::PosInf => false,
+            LexBound::Inclusive(min) => member >= min.as_str(),
+            LexBound::Exclusive(min) => member > min.as_str(),
+        }
+    }
+
+    pub fn lte_max(&self, member: &str) -> bool {
+        match &self.max {
+            LexBound::NegInf => false,
+            LexBound::PosInf => true,
+            LexBound::Inclusive(max) => member <= max.as_str(),
+            LexBound::Exclusive(max) => member < max.as_str(),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Level {
+    forward: Option<usize>,
+    span: usize,
+}
+
+#[derive(Clone, Debug)]
+struct Node {
+    member: String,
+    score: f64,
+    backward: Option<usize>,
+    levels: Vec<Level>,
+}
+
+impl Node {
+    fn is_before(&self, score: f64, member: &str) -> bool {
+        self.score < score || (self.score == score && self.member.as_str() < member)
+    }
+}
+
+/// The skiplist from Redis' `t_zset.c`, with nodes kept in an arena so
+/// links are plain indexes. Node 0 is the header. Spans count how many
+/// elements a forward link jumps over, which makes rank queries O(log n).
+/// Spans follow C unsigned arithmetic, as in Redis the value stored on a
+/// link to nowhere is never read.
+#[derive(Clone, Debug)]
+pub struct SkipList {
+    nodes: Vec<Option<Node>>,
+    free: Vec<usize>,
+    tail: Option<usize>,
+    length: usize,
+    level: usize,
+}
+
+impl Default for SkipList {
+    fn default() -> Self {
+        let header = Node {
+            member: String::new(),
+            score: 0.0,
+            backward: None,
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0,
+                };
+                ZSKIPLIST_MAXLEVEL
+            ],
+        };
+
+        Self {
+            nodes: vec![Some(header)],
+            free: vec![],
+            tail: None,
+            length: 0,
+            level: 1,
+        }
+    }
+}
+
+impl SkipList {
+    fn node(&self, idx: usize) -> &Node {
+        self.nodes[idx].as_ref().unwrap()
+    }
+
+    fn node_mut(&mut self, idx: usize) -> &mut Node {
+        self.nodes[idx].as_mut().unwrap()
+    }
+
+    fn random_level() -> usize {
+        let mut level = 1;
+
+        while level < ZSKIPLIST_MAXLEVEL && random_index(4) == 0 {
+            level += 1;
+        }
+
+        level
+    }
+
+    fn alloc(&mut self, node: Node) -> usize {
+        match self.free.pop() {
+            Some(idx) => {
+                self.nodes[idx] = Some(node);
+                idx
+            }
+            None => {
+                self.nodes.push(Some(node));
+                self.nodes.len() - 1
+            }
+        }
+    }
+
+    fn forward(&self, idx: usize, level: usize) -> Option<usize> {
+        self.node(idx).levels[level].forward
+    }
+
+    fn span(&self, idx: usize, level: usize) -> usize {
+        self.node(idx).levels[level].span
+    }
+
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn entry(&self, idx: usize) -> (&str, f64) {
+        let node = self.node(idx);
+        (&node.member, node.score)
+    }
+
+    pub fn next(&self, idx: usize) -> Option<usize> {
+        self.forward(idx, 0)
+    }
+
+    pub fn prev(&self, idx: usize) -> Option<usize> {
+        self.node(idx).backward
+    }
+
+    /// Inserts an element that is known not to be in the list yet.
+    pub fn insert(&mut self, score: f64, member: String) {
+        let mut update = [0usize; ZSKIPLIST_MAXLEVEL];
+        let mut rank = [0usize; ZSKIPLIST_MAXLEVEL];
+        let mut x = 0;
+
+        for i in (0..self.level).rev() {
+            rank[i] = if i == self.level - 1 { 0 } else { rank[i + 1] };
+
+            while let Some(next) = self.forward(x, i) {
+                if !self.node(next).is_before(score, &member) {
+                    break;
+                }
+
+                rank[i] += self.span(x, i);
+                x = next;
+            }
+
+            update[i] = x;
+        }
+
+        let level = Self::random_level();
+
+        if level > self.level {
+            for i in self.level..level {
+                rank[i] = 0;
+                update[i] = 0;
+                self.node_mut(0).levels[i].span = self.length;
+            }
+
+            self.level = level;
+        }
+
+        let new = self.alloc(Node {
+            member,
+            score,
+            backward: None,
+            levels: vec![
+                Level {
+                    forward: None,
+                    span: 0,
+                };
+                level
+            ],
+        });
+
+        for i in 0..level {
+            let prev = update[i];
+            let prev_level = self.node(prev).levels[i].clone();
+            let jumped = rank[0] - rank[i];
+
+            self.node_mut(new).levels[i] = Level {
+                forward: prev_level.forward,
+                span: prev_level.span.wrapping_sub(jumped),
+            };
+            self.node_mut(prev).levels[i] = Level {
+                forward: Some(new),
+                span: jumped + 1,
+            };
+        }
+
+        for (i, prev) in update.iter().enumerate().take(self.level).skip(level) {
+            let span = &mut self.node_mut(*prev).levels[i].span;
+            *span = span.wrapping_add(1);
+        }
+
+        self.node_mut(new).backward = if update[0] == 0 {
+            None
+        } else {
+            Some(update[0])
+        };
+
+        match self.forward(new, 0) {
+            Some(next) => self.node_mut(next).backward = Some(new),
+            None => self.tail = Some(new),
+        }
+
+        self.length += 1;
+    }
+
+    pub fn delete(&mut self, score: f64, member: &str) -> bool {
+        let mut update = [0usize; ZSKIPLIST_MAXLEVEL];
+        let mut x = 0;
+
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if !self.node(next).is_before(score, member) {
+                    break;
+                }
+
+                x = next;
+            }
+
+            update[i] = x;
+        }
+
+        match self.forward(x, 0) {
+            Some(target)
+                if self.node(target).score == score && self.node(target).member == member =>
+            {
+                self.delete_node(target, &update);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn delete_node(&mut self, x: usize, update: &[usize; ZSKIPLIST_MAXLEVEL]) {
+        for (i, prev) in update.iter().enumerate().take(self.level) {
+            if self.forward(*prev, i) == Some(x) {
+                let removed = self.node(x).levels[i].clone();
+                let level = &mut self.node_mut(*prev).levels[i];
+
+                level.span = level.span.wrapping_add(removed.span).wrapping_sub(1);
+                level.forward = removed.forward;
+            } else {
+                let level = &mut self.node_mut(*prev).levels[i];
+                level.span = level.span.wrapping_sub(1);
+            }
+        }
+
+        let backward = self.node(x).backward;
+
+        match self.forward(x, 0) {
+            Some(next) => self.node_mut(next).backward = backward,
+            None => self.tail = backward,
+        }
+
+        while self.level > 1 && self.forward(0, self.level - 1).is_none() {
+            self.level -= 1;
+        }
+
+        self.nodes[x] = None;
+        self.free.push(x);
+        self.length -= 1;
+    }
+
+    /// 1-based rank of the element, 0 when it is not in the list.
+    pub fn rank(&self, score: f64, member: &str) -> usize {
+        let mut x = 0;
+        let mut rank = 0;
+
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                let node = self.node(next);
+
+                if !(node.is_before(score, member)
+                    || (node.score == score && node.member == member))
+                {
+                    break;
+                }
+
+                rank += self.span(x, i);
+                x = next;
+            }
+
+            if x != 0 && self.node(x).member == member {
+                return rank;
+            }
+        }
+
+        0
+    }
+
+    /// Node at the 1-based `rank`.
+    pub fn by_rank(&self, rank: usize) -> Option<usize> {
+        let mut x = 0;
+        let mut traversed = 0;
+
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if traversed + self.span(x, i) > rank {
+                    break;
+                }
+
+                traversed += self.span(x, i);
+                x = next;
+            }
+
+            if traversed == rank && x != 0 {
+                return Some(x);
+            }
+        }
+
+        None
+    }
+
+    fn first_matching(&self, before_range: impl Fn(&Node) -> bool) -> Option<usize> {
+        let mut x = 0;
+
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if !before_range(self.node(next)) {
+                    break;
+                }
+
+                x = next;
+            }
+        }
+
+        self.forward(x, 0)
+    }
+
+    fn last_matching(&self, in_or_before_range: impl Fn(&Node) -> bool) -> Option<usize> {
+        let mut x = 0;
+
+        for i in (0..self.level).rev() {
+            while let Some(next) = self.forward(x, i) {
+                if !in_or_before_range(self.node(next)) {
+                    break;
+                }
+
+                x = next;
+            }
+        }
+
+        if x == 0 {
+            None
+        } else {
+            Some(x)
+        }
+    }
+
+    pub fn first_in_range(&self, range: &ScoreRange) -> Option<usize> {
+        self.first_matching(|node| !range.gte_min(node.score))
+            .filter(|idx| range.lte_max(self.node(*idx).score))
+    }
+
+    pub fn last_in_range(&self, range: &ScoreRange) -> Option<usize> {
+        self.last_matching(|node| range.lte_max(node.score))
+            .filter(|idx| range.gte_min(self.node(*idx).score))
+    }
+
+    pub fn first_in_lex_range(&self, range: &LexRange) -> Option<usize> {
+        self.first_matching(|node| !range.gte_min(&node.member))
+            .filter(|idx| range.lte_max(&self.node(*idx).member))
+    }
+
+    pub fn last_in_lex_range(&self, range: &LexRange) -> Option<usize> {
+        self.last_matching(|node| range.lte_max(&node.member))
+            .filter(|idx| range.gte_min(&self.node(*idx).member))
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ZAddFlags {
+    pub nx: bool,
+    pub xx: bool,
+    pub gt: bool,
+    pub lt: bool,
+    pub incr: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ZAddOutcome {
+    Added(f64),
+    Updated(f64),
+    Unchanged(f64),
+    /// NX, XX, GT or LT prevented the write.
+    Skipped,
+    Nan,
+}
+
+#[derive(Clone, Debug)]
+pub enum ZRangeBy {
+    Rank(i64, i64),
+    Score(ScoreRange),
+    Lex(LexRange),
+}
+
+/// One `ZRANGE` request. `limit` below zero means no limit.
+#[derive(Clone, Debug)]
+pub struct ZRangeSpec {
+    pub by: ZRangeBy,
+    pub rev: bool,
+    pub offset: i64,
+    pub limit: i64,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct SortedSet {
+    dict: HashMap<String, f64>,
+    zsl: SkipList,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.zsl.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.dict.get(member).copied()
+    }
+
+    pub fn add(&mut self, score: f64, member: &str, flags: ZAddFlags) -> ZAddOutcome {
+        match self.dict.get(member).copied() {
+            Some(current) => {
+                if flags.nx {
+                    return ZAddOutcome::Skipped;
+                }
+
+                let score = if flags.incr { current + score } else { score };
+
+                if score.is_nan() {
+                    return ZAddOutcome::Nan;
+                }
+
+                if (flags.lt && score >= current) || (flags.gt && score <= current) {
+                    return ZAddOutcome::Skipped;
+                }
+
+                if score == current {
+                    return ZAddOutcome::Unchanged(score);
+                }
+
+                self.zsl.delete(current, member);
+                self.zsl.insert(score, member.to_string());
+                self.dict.insert(member.to_string(), score);
+
+                ZAddOutcome::Updated(score)
+            }
+            None if flags.xx => ZAddOutcome::Skipped,
+            None => {
+                self.zsl.insert(score, member.to_string());
+                self.dict.insert(member.to_string(), score);
+
+                ZAddOutcome::Added(score)
+            }
+        }
+    }
+
+    pub fn remove(&mut self, member: &str) -> bool {
+        match self.dict.remove(member) {
+            Some(score) => self.zsl.delete(score, member),
+            None => false,
+        }
+    }
+
+    /// 0-based rank, counted from the highest score when `reverse`.
+    pub fn rank(&self, member: &str, reverse: bool) -> Option<usize> {
+        let score = self.score(member)?;
+        let rank = self.zsl.rank(score, member);
+
+        match reverse {
+            true => Some(self.len() - rank),
+            false => Some(rank - 1),
+        }
+    }
+
+    pub fn count(&self, range: &ScoreRange) -> usize {
+        match (
+            self.zsl.first_in_range(range),
+            self.zsl.last_in_range(range),
+        ) {
+            (Some(first), Some(last)) => {
+                let (first_member, first_score) = self.zsl.entry(first);
+                let (last_member, last_score) = self.zsl.entry(last);
+
+                self.zsl.rank(last_score, last_member) + 1
+                    - self.zsl.rank(first_score, first_member)
+            }
+            _ => 0,
+        }
+    }
+
+    /// Every member with its score, lowest score first.
+    pub fn entries(&self) -> Vec<(String, f64)> {
+        self.range(&ZRangeSpec {
+            by: ZRangeBy::Rank(0, -1),
+            rev: false,
+            offset: 0,
+            limit: -1,
+        })
+    }
+
+    /// Removes up to `count` members from the low end, or the high end when `max`.
+    pub fn pop(&mut self, count: usize, max: bool) -> Vec<(String, f64)> {
+        let popped = self.range(&ZRangeSpec {
+            by: ZRangeBy::Rank(0, count as i64 - 1),
+            rev: max,
+            offset: 0,
+            limit: -1,
+        });
+
+        for (member, _) in &popped {
+            self.remove(member);
+        }
+
+        popped
+    }
+
+    fn collect(
+        &self,
+        start: Option<usize>,
+        spec: &ZRangeSpec,
+        in_range: impl Fn(&str, f64) -> bool,
+    ) -> Vec<(String, f64)> {
+        let mut result: Vec<(String, f64)> = vec![];
+
+        if spec.offset < 0 {
+            return result;
+        }
+
+        // Jump over the offset by rank instead of walking it node by node.
+        let start = start.and_then(|idx| {
+            let (member, score) = self.zsl.entry(idx);
+            let rank = self.zsl.rank(score, member);
+            let offset = spec.offset as usize;
+
+            match spec.rev {
+                true if offset >= rank => None,
+                true => self.zsl.by_rank(rank - offset),
+                false => self.zsl.by_rank(rank + offset),
+            }
+        });
+
+        let mut cur = start;
+
+        while let Some(idx) = cur {
+            if spec.limit >= 0 && result.len() as i64 >= spec.limit {
+                break;
+            }
+
+            let (member, score) = self.zsl.entry(idx);
+
+            if !in_range(member, score) {
+                break;
+            }
+
+            result.push((member.to_string(), score));
+
+            cur = match spec.rev {
+                true => self.zsl.prev(idx),
+                false => self.zsl.next(idx),
+            };
+        }
+
+        result
+    }
+
+    pub fn range(&self, spec: &ZRangeSpec) -> Vec<(String, f64)> {
+        match &spec.by {
+            ZRangeBy::Rank(start, end) => {
+                let len = self.len() as i64;
+                let start = if *start < 0 {
+                    (start + len).max(0)
+                } else {
+                    *start
+                };
+                let end = if *end < 0 {
+                    end + len
+                } else {
+                    (*end).min(len - 1)
+                };
+
+                if start > end || start >= len {
+                    return vec![];
+                }
+
+                let first = match spec.rev {
+                    true => self.zsl.by_rank((len - start) as usize),
+                    false => self.zsl.by_rank(start as usize + 1),
+                };
+
+                let spec = ZRangeSpec {
+                    offset: 0,
+                    limit: end - start + 1,
+                    ..spec.clone()
+                };
+
+                self.collect(first, &spec, |_, _| true)
+            }
+            ZRangeBy::Score(range) => {
+                let first = match spec.rev {
+                    true => self.zsl.last_in_range(range),
+                    false => self.zsl.first_in_range(range),
+                };
+
+                self.collect(first, spec, |_, score| match spec.rev {
+                    true => range.gte_min(score),
+                    false => range.lte_max(score),
+                })
+            }
+            ZRangeBy::Lex(range) => {
+                let first = match spec.rev {
+                    true => self.zsl.last_in_lex_range(range),
+                    false => self.zsl.first_in_lex_range(range),
+                };
+
+                self.collect(first, spec, |member, _| match spec.rev {
+                    true => range.gte_min(member),
+                    false => range.lte_max(member),
+                })
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Aggregate {
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    fn apply(self, acc: f64, score: f64) -> f64 {
+        match self {
+            // inf + -inf is NaN, Redis stores those as 0.
+            Aggregate::Sum => match acc + score {
+                sum if sum.is_nan() => 0.0,
+                sum => sum,
+            },
+            Aggregate::Min => acc.min(score),
+            Aggregate::Max => acc.max(score),
+        }
+    }
+}
+
+/// An input of ZUNIONSTORE and friends. Plain sets count as sorted sets
+/// where every member scores 1.
+pub enum ZSetSource<'a> {
+    Missing,
+    Sorted(&'a SortedSet),
+    Set(SetValue),
+}
+
+impl ZSetSource<'_> {
+    fn len(&self) -> usize {
+        match self {
+            ZSetSource::Missing => 0,
+            ZSetSource::Sorted(zset) => zset.len(),
+            ZSetSource::Set(set) => set.len(),
+        }
+    }
+
+    fn entries(&self) -> Vec<(String, f64)> {
+        match self {
+            ZSetSource::Missing => vec![],
+            ZSetSource::Sorted(zset) => zset.entries(),
+            ZSetSource::Set(set) => set.members().into_iter().map(|m| (m, 1.0)).collect(),
+        }
+    }
+
+    fn score(&self, member: &str) -> Option<f64> {
+        match self {
+            ZSetSource::Missing => None,
+            ZSetSource::Sorted(zset) => zset.score(member),
+            ZSetSource::Set(set) => set.contains(member).then_some(1.0),
+        }
+    }
+}
+
+fn weighted(score: f64, weight: f64) -> f64 {
+    match score * weight {
+        product if product.is_nan() => 0.0,
+        product => product,
+    }
+}
+
+fn from_scores(scores: HashMap<String, f64>) -> SortedSet {
+    let mut zset = SortedSet::default();
+
+    for (member, score) in scores {
+        zset.add(score, &member, ZAddFlags::default());
+    }
+
+    zset
+}
+
+pub fn zunion(sources: &[ZSetSource], weights: &[f64], aggregate: Aggregate) -> SortedSet {
+    let mut scores: HashMap<String, f64> = HashMap::new();
+
+    for (source, weight) in sources.iter().zip(weights) {
+        for (member, score) in source.entries() {
+            let score = weighted(score, *weight);
+
+            scores
+                .entry(member)
+                .and_modify(|acc| *acc = aggregate.apply(*acc, score))
+                .or_insert(score);
+        }
+    }
+
+    from_scores(scores)
+}
+
+/// Walks the smallest input and probes the others for each of its members.
+pub fn zinter(sources: &[ZSetSource], weights: &[f64], aggregate: Aggregate) -> SortedSet {
+    let mut scores: HashMap<String, f64> = HashMap::new();
+
+    let smallest = match (0..sources.len()).min_by_key(|i| sources[*i].len()) {
+        Some(smallest) => smallest,
+        None => return SortedSet::default(),
+    };
+
+    'members: for (member, score) in sources[smallest].entries() {
+        let mut acc = weighted(score, weights[smallest]);
+
+        for (i, source) in sources.iter().enumerate() {
+            if i == smallest {
+                continue;
+            }
+
+            match source.score(&member) {
+                Some(score) => acc = aggregate.apply(acc, weighted(score, weights[i])),
+                None => continue 'members,
+            }
+        }
+
+        scores.insert(member, acc);
+    }
+
+    from_scores(scores)
+}
+
+pub fn zdiff(sources: &[ZSetSource]) -> SortedSet {
+    let mut scores: HashMap<String, f64> = HashMap::new();
+
+    if let Some((first, rest)) = sources.split_first() {
+        for (member, score) in first.entries() {
+            if rest.iter().all(|source| source.score(&member).is_none()) {
+                scores.insert(member, score);
+            }
+        }
+    }
+
+    from_scores(scores)
+}
+
+#[derive(Default)]
+pub struct SortedSetPersistence(pub HashMap<String, SortedSet>);
+
+impl SortedSetPersistence {
+    /// Removes `members` from the sorted set at `key`, dropping the key once empty.
+    pub fn remove(&mut self, key: &str, members: &[String]) -> usize {
+        let zset = match self.0.get_mut(key) {
+            Some(zset) => zset,
+            None => return 0,
+        };
+
+        let removed = members.iter().filter(|m| zset.remove(m)).count();
+
+        if zset.is_empty() {
+            self.0.remove(key);
+        }
+
+        removed
+    }
+
+    pub fn pop(&mut self, key: &str, count: usize, max: bool) -> Vec<(String, f64)> {
+        let zset = match self.0.get_mut(key) {
+            Some(zset) => zset,
+            None => return vec![],
+        };
+
+        let popped = zset.pop(count, max);
+
+        if zset.is_empty() {
+            self.0.remove(key);
+        }
+
+        popped
+    }
+
+    /// Replaces `key` with `zset`, an empty result removes the key.
+    pub fn store(&mut self, key: &str, zset: SortedSet) -> usize {
+        let len = zset.len();
+
+        if zset.is_empty() {
+            self.0.remove(key);
+        } else {
+            self.0.insert(key.to_string(), zset);
+        }
+
+        len
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn zset_of(entries: &[(f64, &str)]) -> SortedSet {
+        let mut zset = SortedSet::default();
+
+        for (score, member) in entries {
+            zset.add(*score, member, ZAddFlags::default());
+        }
+
+        zset
+    }
+
+    fn spec(by: ZRangeBy, rev: bool) -> ZRangeSpec {
+        ZRangeSpec {
+            by,
+            rev,
+            offset: 0,
+            limit: -1,
+        }
+    }
+
+    fn members(entries: Vec<(String, f64)>) -> Vec<String> {
+        entries.into_iter().map(|(member, _)| member).collect()
+    }
+
+    #[test]
+    fn skiplist_keeps_score_then_member_order() {
+        let zset = zset_of(&[(2.0, "b"), (1.0, "z"), (2.0, "a"), (-1.5, "c")]);
+
+        assert_eq!(
+            zset.entries(),
+            [
+                ("c".to_string(), -1.5),
+                ("z".to_string(), 1.0),
+                ("a".to_string(), 2.0),
+                ("b".to_string(), 2.0)
+            ]
+        );
+        assert_eq!(zset.rank("c", false), Some(0));
+        assert_eq!(zset.rank("b", false), Some(3));
+        assert_eq!(zset.rank("b", true), Some(0));
+        assert_eq!(zset.rank("missing", false), None);
+    }
+
+    #[test]
+    fn ranks_stay_consistent_across_many_writes() {
+        let mut zset = SortedSet::default();
+        let mut expected: Vec<(i64, String)> = vec![];
+
+        for i in 0..500i64 {
+            let score = (i * 7919) % 263;
+            let member = format!("m{}", i);
+
+            zset.add(score as f64, &member, ZAddFlags::default());
+            expected.push((score, member));
+        }
+
+        for i in (0..500).step_by(3) {
+            let member = format!("m{}", i);
+
+            assert!(zset.remove(&member));
+            expected.retain(|(_, m)| m != &member);
+        }
+
+        expected.sort();
+
+        assert_eq!(zset.len(), expected.len());
+
+        for (rank, (score, member)) in expected.iter().enumerate() {
+            assert_eq!(zset.rank(member, false), Some(rank));
+            assert_eq!(zset.score(member), Some(*score as f64));
+        }
+
+        let spec = spec(ZRangeBy::Rank(100, 109), false);
+        let expected: Vec<String> = expected[100..110].iter().map(|(_, m)| m.clone()).collect();
+
+        assert_eq!(members(zset.range(&spec)), expected);
+    }
+
+    #[test]
+    fn add_flags() {
+        let mut zset = zset_of(&[(5.0, "a")]);
+        let flags = |nx, xx, gt, lt, incr| ZAddFlags {
+            nx,
+            xx,
+            gt,
+            lt,
+            incr,
+        };
+
+        assert_eq!(
+            zset.add(1.0, "a", flags(true, false, false, false, false)),
+            ZAddOutcome::Skipped
+        );
+        assert_eq!(
+            zset.add(1.0, "b", flags(false, true, false, false, false)),
+            ZAddOutcome::Skipped
+        );
+        assert_eq!(
+            zset.add(4.0, "a", flags(false, false, true, false, false)),
+            ZAddOutcome::Skipped
+        );
+        assert_eq!(
+            zset.add(6.0, "a", flags(false, false, false, true, false)),
+            ZAddOutcome::Skipped
+        );
+        assert_eq!(
+            zset.add(2.0, "a", flags(false, false, false, false, true)),
+            ZAddOutcome::Updated(7.0)
+        );
+        assert_eq!(
+            zset.add(7.0, "a", ZAddFlags::default()),
+            ZAddOutcome::Unchanged(7.0)
+        );
+        assert_eq!(
+            zset.add(f64::INFINITY, "inf", ZAddFlags::default()),
+            ZAddOutcome::Added(f64::INFINITY)
+        );
+        assert_eq!(
+            zset.add(
+                f64::NEG_INFINITY,
+                "inf",
+                flags(false, false, false, false, true)
+            ),
+            ZAddOutcome::Nan
+        );
+        assert_eq!(zset.score("a"), Some(7.0));
+        assert!(zset.score("b").is_none());
+    }
+
+    #[test]
+    fn score_ranges() {
+        let zset = zset_of(&[(1.0, "a"), (2.0, "b"), (3.0, "c"), (4.0, "d")]);
+        let range = ScoreRange::parse("(1", "3").unwrap();
+
+        assert_eq!(zset.count(&range), 2);
+        assert_eq!(
+            members(zset.range(&spec(ZRangeBy::Score(range.clone()), false))),
+            ["b", "c"]
+        );
+        assert_eq!(
+            members(zset.range(&spec(ZRangeBy::Score(range), true))),
+            ["c", "b"]
+        );
+
+        let range = ScoreRange::parse("-inf", "+inf").unwrap();
+        let limited = ZRangeSpec {
+            offset: 1,
+            limit: 2,
+            ..spec(ZRangeBy::Score(range), true)
+        };
+
+        assert_eq!(members(zset.range(&limited)), ["c", "b"]);
+        assert!(ScoreRange::parse("nan", "1").is_none());
+        assert_eq!(zset.count(&ScoreRange::parse("(4", "5").unwrap()), 0);
+    }
+
+    #[test]
+    fn lex_ranges() {
+        let zset = zset_of(&[(0.0, "a"), (0.0, "b"), (0.0, "c"), (0.0, "d")]);
+        let range = LexRange::parse("[b", "(d").unwrap();
+
+        assert_eq!(
+            members(zset.range(&spec(ZRangeBy::Lex(range.clone()), false))),
+            ["b", "c"]
+        );
+        assert_eq!(
+            members(zset.range(&spec(ZRangeBy::Lex(range), true))),
+            ["c", "b"]
+        );
+
+        let all = LexRange::parse("-", "+").unwrap();
+
+        assert_eq!(
+            members(zset.range(&spec(ZRangeBy::Lex(all), false))),
+            ["a", "b", "c", "d"]
+        );
+        assert!(LexRange::parse("b", "+").is_none());
+    }
+
+    #[test]
+    fn rank_ranges_clamp_like_redis() {
+        let zset = zset_of(&[(1.0, "a"), (2.0, "b"), (3.0, "c")]);
+
+        assert_eq!(
+            members(zset.range(&spec(ZRangeBy::Rank(-2, 100), false))),
+            ["b", "c"]
+        );
+        assert_eq!(
+            members(zset.range(&spec(ZRangeBy::Rank(0, 0), true))),
+            ["c"]
+        );
+        assert!(zset.range(&spec(ZRangeBy::Rank(2, 1), false)).is_empty());
+        assert!(zset.range(&spec(ZRangeBy::Rank(5, 10), false)).is_empty());
+    }
+
+    #[test]
+    fn pop_from_either_end_and_drop_empty_keys() {
+        let mut zsets = SortedSetPersistence::default();
+        zsets.store("z", zset_of(&[(1.0, "a"), (2.0, "b"), (3.0, "c")]));
+
+        assert_eq!(zsets.pop("z", 1, true), [("c".to_string(), 3.0)]);
+        assert_eq!(members(zsets.pop("z", 5, false)), ["a", "b"]);
+        assert!(!zsets.0.contains_key("z"));
+        assert_eq!(zsets.store("z", SortedSet::default()), 0);
+        assert!(!zsets.0.contains_key("z"));
+    }
+
+    #[test]
+    fn set_algebra() {
+        let a = zset_of(&[(1.0, "x"), (2.0, "y")]);
+        let b = zset_of(&[(10.0, "y"), (20.0, "z")]);
+        let sources = [ZSetSource::Sorted(&a), ZSetSource::Sorted(&b)];
+
+        let union = zunion(&sources, &[1.0, 2.0], Aggregate::Sum);
+        assert_eq!(union.score("y"), Some(22.0));
+        assert_eq!(union.len(), 3);
+
+        let inter = zinter(&sources, &[1.0, 1.0], Aggregate::Max);
+        assert_eq!(inter.entries(), [("y".to_string(), 10.0)]);
+
+        assert_eq!(members(zdiff(&sources).entries()), ["x"]);
+        assert!(zinter(
+            &[ZSetSource::Sorted(&a), ZSetSource::Missing],
+            &[1.0, 1.0],
+            Aggregate::Sum
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn doubles_print_like_redis() {
+        assert_eq!(format_double(3.0), "3");
+        assert_eq!(format_double(1.5), "1.5");
+        assert_eq!(format_double(f64::NEG_INFINITY), "-inf");
+        assert_eq!(format_double(1e20), "1e+20");
+        assert_eq!(format_double(1e-5), "1e-05");
+    }
+}
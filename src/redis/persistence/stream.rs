@@ -1,16 +1,104 @@
 use std::{
-    collections::HashMap,
-    sync::Mutex,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt,
     time::{SystemTime, UNIX_EPOCH},
 };
 
 use crate::redis::parse::RespData;
 
+// Same default as Redis' `stream-node-max-entries`.
+pub const STREAM_NODE_MAX_ENTRIES: usize = 100;
+
+// XAUTOCLAIM looks at up to this many PEL entries per requested one.
+const AUTOCLAIM_ATTEMPTS_FACTOR: usize = 10;
+
+pub fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: StreamId = StreamId { ms: 0, seq: 0 };
+    pub const MAX: StreamId = StreamId {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// `<ms>-<seq>`, or just `<ms>` with `missing_seq` as sequence number.
+    pub fn parse(id: &str, missing_seq: u64) -> Result<StreamId, StreamError> {
+        let (ms, seq) = match id.split_once('-') {
+            Some((ms, seq)) => (ms, seq.parse().map_err(|_| StreamError::ParseError)?),
+            None => (id, missing_seq),
+        };
+
+        Ok(StreamId {
+            ms: ms.parse().map_err(|_| StreamError::ParseError)?,
+            seq,
+        })
+    }
+
+    /// The smallest ID greater than this one.
+    pub fn incr(&self) -> Option<StreamId> {
+        match (self.seq.checked_add(1), self.ms.checked_add(1)) {
+            (Some(seq), _) => Some(StreamId { ms: self.ms, seq }),
+            (None, Some(ms)) => Some(StreamId { ms, seq: 0 }),
+            (None, None) => None,
+        }
+    }
+
+    /// The greatest ID smaller than this one.
+    pub fn decr(&self) -> Option<StreamId> {
+        match (self.seq.checked_sub(1), self.ms.checked_sub(1)) {
+            (Some(seq), _) => Some(StreamId { ms: self.ms, seq }),
+            (None, Some(ms)) => Some(StreamId { ms, seq: u64::MAX }),
+            (None, None) => None,
+        }
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID argument of `XADD`, resolved against the stream's last ID while
+/// the stream is locked.
+#[derive(Clone, Copy, Debug)]
+pub enum IdSpec {
+    Auto,
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl IdSpec {
+    pub fn parse(id: &str) -> Result<IdSpec, StreamError> {
+        if id == "*" {
+            return Ok(IdSpec::Auto);
+        }
+
+        match id.strip_suffix("-*") {
+            Some(ms) => ms
+                .parse()
+                .map(IdSpec::AutoSeq)
+                .map_err(|_| StreamError::ParseError),
+            None => StreamId::parse(id, 0).map(IdSpec::Explicit),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct StreamVal {
-    pub id: (u128, u32),
+    pub id: StreamId,
     pub pairs: Vec<(String, String)>,
-    pub added: std::time::Instant,
 }
 
 #[derive(Debug)]
@@ -20,228 +108,968 @@ pub enum StreamError {
     IdShouldBeHigher,
 }
 
-impl StreamVal {
-    pub fn id(&self) -> String {
-        format!("{}-{}", self.id.0, self.id.1)
+impl From<&StreamVal> for RespData {
+    fn from(stream_val: &StreamVal) -> RespData {
+        let mut data: Vec<RespData> = vec![];
+        let mut inside_data: Vec<RespData> = vec![];
+
+        data.push(RespData::from(stream_val.id.to_string()));
+
+        for (key, val) in &stream_val.pairs {
+            inside_data.push(RespData::from(key.to_string()));
+            inside_data.push(RespData::from(val.to_string()));
+        }
+
+        data.push(RespData::Array(inside_data));
+
+        RespData::Array(data)
     }
+}
+
+/// An entry delivered to a consumer and not acknowledged yet.
+#[derive(Clone, Debug)]
+pub struct PendingEntry {
+    pub consumer: String,
+    pub delivery_time: u64,
+    pub delivery_count: u64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Consumer {
+    /// Last time the consumer was seen by any command.
+    pub seen_time: u64,
+    /// Last time it was actually handed entries, `None` if never.
+    pub active_time: Option<u64>,
+    pub pending: BTreeSet<StreamId>,
+}
+
+impl Consumer {
+    fn new(now: u64) -> Consumer {
+        Consumer {
+            seen_time: now,
+            active_time: None,
+            pending: BTreeSet::new(),
+        }
+    }
+}
+
+/// The group wide PEL owns the delivery metadata, each consumer only keeps
+/// the IDs it is responsible for.
+#[derive(Clone, Debug)]
+pub struct ConsumerGroup {
+    pub last_id: StreamId,
+    /// Logical count of entries read so far, `None` when it can't be known.
+    pub entries_read: Option<u64>,
+    pub pending: BTreeMap<StreamId, PendingEntry>,
+    pub consumers: BTreeMap<String, Consumer>,
+}
 
-    pub fn parse_explicit_id(id: String) -> Result<(u128, u32), StreamError> {
-        let (first, second) = id.split_once("-").unwrap();
+impl ConsumerGroup {
+    pub fn new(last_id: StreamId, entries_read: Option<u64>) -> ConsumerGroup {
+        ConsumerGroup {
+            last_id,
+            entries_read,
+            pending: BTreeMap::new(),
+            consumers: BTreeMap::new(),
+        }
+    }
 
-        match (first.parse(), second.parse()) {
-            (Ok(f), Ok(s)) => Ok((f, s)),
-            _ => Err(StreamError::ParseError),
+    /// Creates the consumer if needed, returns whether it did.
+    pub fn create_consumer(&mut self, name: &str) -> bool {
+        if self.consumers.contains_key(name) {
+            return false;
         }
+
+        self.consumers
+            .insert(name.to_string(), Consumer::new(now_ms()));
+        true
+    }
+
+    /// Looks a consumer up on behalf of a command, creating it if needed.
+    /// Returns whether it was created.
+    pub fn touch_consumer(&mut self, name: &str) -> bool {
+        let created = self.create_consumer(name);
+        self.consumers.get_mut(name).unwrap().seen_time = now_ms();
+        created
     }
 
-    fn parse_auto_generate_sequence_id(
-        id: &String,
-        key: &String,
-        per: &Mutex<StreamPersistence>,
-    ) -> Result<(u128, u32), StreamError> {
-        match per.lock().unwrap().get_last(&key) {
-            Some(last) => {
-                let new_id: u128 = id.split("-").next().unwrap().parse().unwrap();
+    /// Removes a consumer along with its pending entries, returning how
+    /// many it had.
+    pub fn delete_consumer(&mut self, name: &str) -> Option<usize> {
+        let consumer = self.consumers.remove(name)?;
+
+        for id in &consumer.pending {
+            self.pending.remove(id);
+        }
+
+        Some(consumer.pending.len())
+    }
 
-                if new_id == last.id.0 {
-                    Ok((new_id, last.id.1 + 1))
-                } else {
-                    Ok((new_id, 0))
+    pub fn ack(&mut self, id: StreamId) -> bool {
+        match self.pending.remove(&id) {
+            Some(entry) => {
+                if let Some(consumer) = self.consumers.get_mut(&entry.consumer) {
+                    consumer.pending.remove(&id);
                 }
+                true
             }
-            None => {
-                let first = id.split("-").next().unwrap();
+            None => false,
+        }
+    }
 
-                if first.parse::<u128>().unwrap() == 0 {
-                    Ok((first.parse().unwrap(), 1))
-                } else {
-                    Ok((first.parse().unwrap(), 0))
-                }
+    /// Makes `consumer` the owner of the pending entry `id`, creating the
+    /// entry if it wasn't pending.
+    fn assign(&mut self, id: StreamId, consumer: &str, delivery_time: u64, delivery_count: u64) {
+        if let Some(previous) = self.pending.get(&id) {
+            if let Some(owner) = self.consumers.get_mut(&previous.consumer) {
+                owner.pending.remove(&id);
             }
         }
+
+        self.create_consumer(consumer);
+        self.consumers.get_mut(consumer).unwrap().pending.insert(id);
+
+        self.pending.insert(
+            id,
+            PendingEntry {
+                consumer: consumer.to_string(),
+                delivery_time,
+                delivery_count,
+            },
+        );
     }
+}
+
+/// A pending entry read back, `None` once deleted from the stream.
+pub type PendingRead = (StreamId, Option<StreamVal>);
 
-    fn auto_generate_id() -> (u128, u32) {
-        let cur = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_millis();
-        (cur, 0)
+/// XCLAIM settings shared by every ID of one call.
+#[derive(Clone, Copy, Debug)]
+pub struct ClaimOptions {
+    pub min_idle: u64,
+    pub delivery_time: u64,
+    pub retry_count: Option<u64>,
+    pub force: bool,
+    pub just_id: bool,
+}
+
+#[derive(Debug)]
+pub enum ClaimOutcome {
+    Claimed(Option<StreamVal>),
+    /// The entry was deleted from the stream, so it left the PEL too.
+    Deleted,
+    Skipped,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum TrimStrategy {
+    MaxLen(usize),
+    MinId(StreamId),
+}
+
+/// `MAXLEN|MINID [=|~] threshold [LIMIT count]`, a zero limit means no limit.
+#[derive(Clone, Copy, Debug)]
+pub struct TrimSpec {
+    pub strategy: TrimStrategy,
+    pub approx: bool,
+    pub limit: usize,
+}
+
+/// Entries live in nodes of up to `STREAM_NODE_MAX_ENTRIES`, indexed by the
+/// ID they start at, so appends only touch the last node and ranges start
+/// with a single tree lookup.
+#[derive(Clone, Debug, Default)]
+pub struct Stream {
+    nodes: BTreeMap<StreamId, Vec<StreamVal>>,
+    length: usize,
+    last_id: StreamId,
+    entries_added: u64,
+    max_deleted_id: StreamId,
+    pub groups: BTreeMap<String, ConsumerGroup>,
+}
+
+impl Stream {
+    pub fn len(&self) -> usize {
+        self.length
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn last_id(&self) -> StreamId {
+        self.last_id
+    }
+
+    /// ID of the first entry, `0-0` for an empty stream.
+    pub fn first_id(&self) -> StreamId {
+        self.first_entry().map(|val| val.id).unwrap_or_default()
     }
 
-    pub fn parse_id(
-        id: &String,
-        key: &String,
-        per: &Mutex<StreamPersistence>,
-    ) -> Result<(u128, u32), StreamError> {
-        println!("PARSE ID {}", id);
+    pub fn first_entry(&self) -> Option<&StreamVal> {
+        self.nodes.values().next().and_then(|entries| entries.first())
+    }
+
+    pub fn last_entry(&self) -> Option<&StreamVal> {
+        self.nodes.values().next_back().and_then(|entries| entries.last())
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn entries_added(&self) -> u64 {
+        self.entries_added
+    }
+
+    pub fn max_deleted_id(&self) -> StreamId {
+        self.max_deleted_id
+    }
 
-        match &id {
-            val if val.contains("-*") => StreamVal::parse_auto_generate_sequence_id(id, key, per),
-            val if val.contains("*") => Ok(StreamVal::auto_generate_id()),
-            val if val.contains("-") => StreamVal::parse_explicit_id(id.to_string()),
-            _ => Err(StreamError::ParseError),
+    /// Applies `XSETID`; the caller has already checked the values against
+    /// the stream's content.
+    pub fn set_id(
+        &mut self,
+        last_id: StreamId,
+        entries_added: Option<u64>,
+        max_deleted_id: StreamId,
+    ) {
+        self.last_id = last_id;
+
+        if let Some(entries_added) = entries_added {
+            self.entries_added = entries_added;
+        }
+
+        if max_deleted_id != StreamId::MIN {
+            self.max_deleted_id = max_deleted_id;
         }
     }
-}
 
-impl Into<RespData> for StreamVal {
-    fn into(self) -> RespData {
-        let mut data: Vec<RespData> = vec![];
-        let mut inside_data: Vec<RespData> = vec![];
+    /// Whether an entry was deleted at or after `start`, which makes the
+    /// logical position of later IDs unknowable.
+    fn has_tombstones(&self, start: StreamId) -> bool {
+        if self.length == 0 || self.max_deleted_id == StreamId::MIN {
+            return false;
+        }
+
+        if self.first_id() > self.max_deleted_id {
+            return false;
+        }
 
-        data.push(RespData::BulkString(self.id()));
+        start <= self.max_deleted_id
+    }
 
-        for (key, val) in self.pairs {
-            inside_data.push(RespData::BulkString(key.to_string()));
-            inside_data.push(RespData::BulkString(val.to_string()));
+    /// Position of `id` counted from the first entry ever added, when it
+    /// can be worked out.
+    pub fn entries_read_at(&self, id: StreamId) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
         }
 
-        data.push(RespData::Array(inside_data));
+        if self.length == 0 && id <= self.last_id {
+            return Some(self.entries_added);
+        }
 
-        RespData::Array(data)
+        if id == self.last_id {
+            return Some(self.entries_added);
+        } else if id > self.last_id {
+            return None;
+        }
+
+        let first_id = self.first_id();
+
+        if self.max_deleted_id == StreamId::MIN || self.max_deleted_id < first_id {
+            if id < first_id {
+                return Some(self.entries_added - self.length as u64);
+            } else if id == first_id {
+                return Some(self.entries_added - self.length as u64 + 1);
+            }
+        }
+
+        None
     }
-}
 
-pub struct StreamPersistence {
-    pub map: HashMap<String, Vec<StreamVal>>,
-}
+    /// Number of entries the group has yet to read, when it can be known.
+    pub fn lag(&self, group: &ConsumerGroup) -> Option<u64> {
+        if self.entries_added == 0 {
+            return Some(0);
+        }
+
+        let entries_read = match group.entries_read {
+            Some(read) if !self.has_tombstones(group.last_id) => read,
+            _ => self.entries_read_at(group.last_id)?,
+        };
 
-impl Default for StreamPersistence {
-    fn default() -> Self {
-        Self { map: Default::default() }
+        Some(self.entries_added.saturating_sub(entries_read))
     }
-}
 
-impl StreamPersistence {
-    pub fn get_range(
-        &self,
-        key: String,
-        start: String,
-        end: String,
-        to_end: Option<bool>,
-    ) -> Vec<StreamVal> {
-        let mut resp_range: Vec<StreamVal> = vec![];
+    fn next_id(&self, spec: IdSpec) -> Result<StreamId, StreamError> {
+        let last = self.last_id;
 
-        // TODO : The sequence number doesn't need to be included
-        //        in the start and end IDs provided to the command.
-        //        If not provided, XRANGE defaults to a sequence number of 0 for
-        //        the start and the maximum sequence number for the end.
+        let id = match spec {
+            IdSpec::Auto => {
+                let now = now_ms();
 
-        let mut add = match to_end {
-            Some(v) => v && true,
-            None => false,
+                match now > last.ms {
+                    true => StreamId { ms: now, seq: 0 },
+                    false => last.incr().ok_or(StreamError::IllegalId)?,
+                }
+            }
+            IdSpec::AutoSeq(ms) if ms == last.ms => StreamId {
+                ms,
+                seq: last.seq.checked_add(1).ok_or(StreamError::IllegalId)?,
+            },
+            IdSpec::AutoSeq(ms) => StreamId { ms, seq: 0 },
+            IdSpec::Explicit(id) => id,
         };
 
-        for val in self.map.get(&key).unwrap() {
-            if val.id() == end {
-                add = true;
+        if id == StreamId::MIN {
+            return Err(StreamError::IdShouldBeHigher);
+        }
+
+        if id <= last {
+            return Err(StreamError::IllegalId);
+        }
+
+        Ok(id)
+    }
+
+    pub fn append(
+        &mut self,
+        spec: IdSpec,
+        pairs: Vec<(String, String)>,
+    ) -> Result<StreamId, StreamError> {
+        let id = self.next_id(spec)?;
+
+        let val = StreamVal { id, pairs };
+
+        match self.nodes.last_entry() {
+            Some(mut node) if node.get().len() < STREAM_NODE_MAX_ENTRIES => {
+                node.get_mut().push(val)
+            }
+            _ => {
+                self.nodes.insert(id, vec![val]);
             }
+        }
+
+        self.length += 1;
+        self.entries_added += 1;
+        self.last_id = id;
 
-            if add {
-                resp_range.push(val.clone());
+        Ok(id)
+    }
+
+    pub fn delete(&mut self, id: StreamId) -> bool {
+        let node = match self.nodes.range(..=id).next_back() {
+            Some((node, _)) => *node,
+            None => return false,
+        };
+
+        let entries = self.nodes.get_mut(&node).unwrap();
+
+        match entries.binary_search_by_key(&id, |val| val.id) {
+            Ok(pos) => {
+                entries.remove(pos);
+
+                if id > self.max_deleted_id {
+                    self.max_deleted_id = id;
+                }
+
+                if entries.is_empty() {
+                    self.nodes.remove(&node);
+                }
+
+                self.length -= 1;
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Removes entries from the head of the stream, returning how many.
+    /// Whole nodes go first; approximate trimming stops at the first node
+    /// that can't be dropped entirely, exact trimming then trims inside it.
+    pub fn trim(&mut self, spec: &TrimSpec) -> usize {
+        let mut deleted = 0;
+
+        while let Some((node, entries)) = self.nodes.first_key_value() {
+            let node = *node;
+            let node_len = entries.len();
+
+            if spec.limit > 0 && deleted + node_len > spec.limit {
+                break;
+            }
+
+            let remove_node = match spec.strategy {
+                TrimStrategy::MaxLen(max_len) => self.length - node_len >= max_len,
+                TrimStrategy::MinId(min_id) => entries.last().unwrap().id < min_id,
+            };
+
+            if remove_node {
+                self.nodes.remove(&node);
+                self.length -= node_len;
+                deleted += node_len;
+                continue;
             }
 
-            if val.id() == start {
-                add = false;
+            if spec.approx {
+                break;
             }
+
+            let count = match spec.strategy {
+                TrimStrategy::MaxLen(max_len) => self.length.saturating_sub(max_len),
+                TrimStrategy::MinId(min_id) => entries.partition_point(|val| val.id < min_id),
+            };
+
+            self.nodes.get_mut(&node).unwrap().drain(..count);
+            self.length -= count;
+            deleted += count;
+            break;
         }
 
-        resp_range.reverse();
+        deleted
+    }
 
-        resp_range
+    pub fn get(&self, id: StreamId) -> Option<&StreamVal> {
+        let (_, entries) = self.nodes.range(..=id).next_back()?;
+
+        entries
+            .binary_search_by_key(&id, |val| val.id)
+            .ok()
+            .map(|pos| &entries[pos])
     }
 
-    pub fn get_range_to_start(&self, key: String, end: String) -> Vec<StreamVal> {
-        let mut resp_range: Vec<StreamVal> = vec![];
+    /// Serves `XREADGROUP` with `>`: entries after the group's last
+    /// delivered ID, added to the consumer's PEL unless `no_ack` is set.
+    pub fn read_group_new(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        count: usize,
+        no_ack: bool,
+    ) -> Vec<StreamVal> {
+        let now = now_ms();
+
+        let entries: Vec<StreamVal> = match self.groups[group].last_id.incr() {
+            Some(start) => self
+                .range(start, StreamId::MAX)
+                .take(if count == 0 { usize::MAX } else { count })
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
 
-        let mut add = false;
+        let mut entries_read = self.groups[group].entries_read;
 
-        for val in self.map.get(&key).unwrap() {
-            if val.id() == end {
-                add = true;
+        for val in &entries {
+            entries_read = match entries_read {
+                Some(read) if !self.has_tombstones(val.id) => Some(read + 1),
+                _ if self.entries_added > 0 => self.entries_read_at(val.id),
+                other => other,
+            };
+        }
+
+        let group = self.groups.get_mut(group).unwrap();
+
+        if let Some(last) = entries.last() {
+            group.last_id = last.id;
+            group.entries_read = entries_read;
+            group.consumers.get_mut(consumer).unwrap().active_time = Some(now);
+        }
+
+        if !no_ack {
+            for val in &entries {
+                group.assign(val.id, consumer, now, 1);
             }
+        }
+
+        entries
+    }
 
-            if add {
-                resp_range.push(val.clone());
+    /// Serves `XREADGROUP` with an explicit ID: the consumer's own pending
+    /// entries after `after`. Entries deleted from the stream come back as
+    /// `None`, the others count as delivered again.
+    pub fn read_group_history(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        after: StreamId,
+        count: usize,
+    ) -> Vec<PendingRead> {
+        let now = now_ms();
+
+        let ids: Vec<StreamId> = match after.incr() {
+            Some(start) => self.groups[group].consumers[consumer]
+                .pending
+                .range(start..)
+                .take(if count == 0 { usize::MAX } else { count })
+                .copied()
+                .collect(),
+            None => vec![],
+        };
+
+        let mut entries = vec![];
+
+        for id in ids {
+            let val = self.get(id).cloned();
+
+            if val.is_some() {
+                let entry = self
+                    .groups
+                    .get_mut(group)
+                    .unwrap()
+                    .pending
+                    .get_mut(&id)
+                    .unwrap();
+                entry.delivery_time = now;
+                entry.delivery_count += 1;
             }
+
+            entries.push((id, val));
         }
 
-        resp_range.reverse();
+        entries
+    }
+
+    /// Transfers one pending entry to `consumer`, following XCLAIM rules.
+    pub fn claim(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        id: StreamId,
+        options: ClaimOptions,
+    ) -> ClaimOutcome {
+        let exists = self.get(id).is_some();
+        let group_state = self.groups.get_mut(group).unwrap();
+        let now = now_ms();
+
+        if !exists {
+            return match group_state.ack(id) {
+                true => ClaimOutcome::Deleted,
+                false => ClaimOutcome::Skipped,
+            };
+        }
+
+        let delivery_count = match group_state.pending.get(&id) {
+            Some(entry) => {
+                if options.min_idle > 0
+                    && now.saturating_sub(entry.delivery_time) < options.min_idle
+                {
+                    return ClaimOutcome::Skipped;
+                }
+
+                entry.delivery_count
+            }
+            // FORCE creates the entry, no idle time to check then.
+            None if options.force => 0,
+            None => return ClaimOutcome::Skipped,
+        };
+
+        let delivery_count = match (options.retry_count, options.just_id) {
+            (Some(count), _) => count,
+            (None, true) => delivery_count,
+            (None, false) => delivery_count + 1,
+        };
+
+        group_state.assign(id, consumer, options.delivery_time, delivery_count);
+
+        let claimer = group_state.consumers.get_mut(consumer).unwrap();
+        claimer.seen_time = now;
+        claimer.active_time = Some(now);
 
-        resp_range
+        match options.just_id {
+            true => ClaimOutcome::Claimed(None),
+            false => ClaimOutcome::Claimed(self.get(id).cloned()),
+        }
     }
 
-    pub fn xread(
-        &self,
-        keys: Vec<String>,
-        get_ids: Vec<String>,
-        block: Option<u64>,
-        start: std::time::Instant,
-    ) -> Vec<Vec<StreamVal>> {
-        let mut vals: Vec<Vec<StreamVal>> = vec![];
+    /// Scans the group PEL from `start` claiming idle entries, looking at
+    /// no more than `count` times the attempts factor entries. Returns the
+    /// cursor for the next call (0-0 once the PEL was fully scanned), the
+    /// claimed IDs with their entries and the IDs dropped because they
+    /// were deleted from the stream.
+    pub fn autoclaim(
+        &mut self,
+        group: &str,
+        consumer: &str,
+        start: StreamId,
+        count: usize,
+        options: ClaimOptions,
+    ) -> (StreamId, Vec<PendingRead>, Vec<StreamId>) {
+        let mut attempts = count.saturating_mul(AUTOCLAIM_ATTEMPTS_FACTOR);
+        let mut remaining = count;
 
-        for (key, get_id) in keys.into_iter().zip(get_ids.into_iter()) {
-            let mut resp_range: Vec<StreamVal> = vec![];
+        let mut claimed = vec![];
+        let mut deleted = vec![];
+        let mut cursor = Some(start);
 
-            resp_range.reverse();
+        while let Some(from) = cursor {
+            if attempts == 0 || remaining == 0 {
+                break;
+            }
 
-            let get_id = StreamVal::parse_explicit_id(get_id).unwrap();
+            let id = match self.groups[group].pending.range(from..).next() {
+                Some((id, _)) => *id,
+                None => {
+                    cursor = None;
+                    break;
+                }
+            };
 
-            for val in self.map.get(&key).unwrap() {
-                let id = val.id;
+            attempts -= 1;
+            cursor = id.incr();
 
-                match block {
-                    Some(v) => {
-                        if start.duration_since(val.added) > std::time::Duration::from_millis(v) {
-                            continue;
-                        }
-                    }
-                    _ => {}
+            match self.claim(group, consumer, id, options) {
+                ClaimOutcome::Claimed(val) => {
+                    claimed.push((id, val));
+                    remaining -= 1;
                 }
-                if id.0 > get_id.0 || (id.0 == get_id.0 && id.1 > get_id.1) {
-                    resp_range.push(val.clone());
+                ClaimOutcome::Deleted => {
+                    deleted.push(id);
+                    remaining -= 1;
                 }
+                ClaimOutcome::Skipped => {}
             }
-
-            vals.push(resp_range);
         }
 
-        vals
+        let next = cursor
+            .and_then(|from| self.groups[group].pending.range(from..).next())
+            .map(|(id, _)| *id)
+            .unwrap_or_default();
+
+        (next, claimed, deleted)
     }
 
-    pub fn insert(&mut self, id: &String, val: StreamVal) -> Result<String, StreamError> {
-        let return_id = val.id();
-        if self.map.contains_key(id) {
-            let values: &mut Vec<StreamVal> = self.map.get_mut(id).unwrap();
-            let last = values.get(0).unwrap();
+    /// Entries with `start <= id <= end`, in ID order.
+    pub fn range(&self, start: StreamId, end: StreamId) -> impl Iterator<Item = &StreamVal> {
+        // The node holding `start` is the last one beginning at or before it.
+        let first_node = match self.nodes.range(..=start).next_back() {
+            Some((node, _)) => *node,
+            None => start,
+        };
 
-            let new_id = val.id;
+        self.nodes
+            .range(first_node..)
+            .flat_map(|(_, entries)| entries)
+            .skip_while(move |val| val.id < start)
+            .take_while(move |val| val.id <= end)
+    }
 
-            if new_id.0 == 0 && new_id.1 == 0 {
-                return Err(StreamError::IdShouldBeHigher);
-            }
+    /// Entries with `start <= id <= end`, from the highest ID down.
+    pub fn rev_range(&self, start: StreamId, end: StreamId) -> impl Iterator<Item = &StreamVal> {
+        self.nodes
+            .range(..=end)
+            .rev()
+            .flat_map(|(_, entries)| entries.iter().rev())
+            .skip_while(move |val| val.id > end)
+            .take_while(move |val| val.id >= start)
+    }
+}
+
+#[derive(Default)]
+pub struct StreamPersistence {
+    pub map: HashMap<String, Stream>,
+}
+
+impl StreamPersistence {
+    pub fn last_id(&self, key: &str) -> Option<StreamId> {
+        self.map.get(key).map(|stream| stream.last_id())
+    }
+}
 
-            if new_id.0 > last.id.0 || (new_id.0 == last.id.0 && new_id.1 > last.id.1) {
-                values.insert(0, val);
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-                let values_copy = values.to_vec();
+    fn id(ms: u64, seq: u64) -> StreamId {
+        StreamId { ms, seq }
+    }
 
-                self.map.insert(id.to_string(), values_copy);
-            } else {
-                return Err(StreamError::IllegalId);
-            }
-        } else {
-            self.map.insert(id.to_string(), vec![val]);
+    fn stream_of(count: u64) -> Stream {
+        let mut stream = Stream::default();
+
+        for i in 1..=count {
+            let pairs = vec![("n".to_string(), i.to_string())];
+            stream.append(IdSpec::Explicit(id(i, 0)), pairs).unwrap();
         }
 
-        Ok(return_id)
+        stream
+    }
+
+    fn ids<'a>(vals: impl Iterator<Item = &'a StreamVal>) -> Vec<u64> {
+        vals.map(|val| val.id.ms).collect()
+    }
+
+    #[test]
+    fn ids_parse_and_step() {
+        assert_eq!(StreamId::parse("5-3", 0).unwrap(), id(5, 3));
+        assert_eq!(StreamId::parse("5", u64::MAX).unwrap(), id(5, u64::MAX));
+        assert!(StreamId::parse("5-x", 0).is_err());
+        assert!(StreamId::parse("-1", 0).is_err());
+
+        assert_eq!(id(1, u64::MAX).incr(), Some(id(2, 0)));
+        assert_eq!(id(2, 0).decr(), Some(id(1, u64::MAX)));
+        assert_eq!(StreamId::MAX.incr(), None);
+        assert_eq!(StreamId::MIN.decr(), None);
+        assert_eq!(id(7, 1).to_string(), "7-1");
+    }
+
+    #[test]
+    fn appends_check_the_id() {
+        let mut stream = Stream::default();
+
+        assert!(matches!(
+            stream.append(IdSpec::Explicit(StreamId::MIN), vec![]),
+            Err(StreamError::IdShouldBeHigher)
+        ));
+        assert_eq!(stream.append(IdSpec::AutoSeq(5), vec![]).unwrap(), id(5, 0));
+        assert_eq!(stream.append(IdSpec::AutoSeq(5), vec![]).unwrap(), id(5, 1));
+        assert!(matches!(
+            stream.append(IdSpec::Explicit(id(5, 1)), vec![]),
+            Err(StreamError::IllegalId)
+        ));
+        assert!(matches!(
+            stream.append(IdSpec::AutoSeq(4), vec![]),
+            Err(StreamError::IllegalId)
+        ));
+        assert!(stream.append(IdSpec::Auto, vec![]).unwrap() > id(5, 1));
+        assert_eq!(stream.len(), 3);
+        assert_eq!(stream.entries_added(), 3);
+    }
+
+    #[test]
+    fn entries_are_split_into_nodes() {
+        let count = STREAM_NODE_MAX_ENTRIES as u64 * 2 + 1;
+        let stream = stream_of(count);
+
+        assert_eq!(stream.node_count(), 3);
+        assert_eq!(stream.len(), count as usize);
+        assert_eq!(stream.first_id(), id(1, 0));
+        assert_eq!(stream.last_entry().unwrap().id, id(count, 0));
+        assert_eq!(stream.get(id(150, 0)).unwrap().pairs[0].1, "150");
+        assert!(stream.get(id(150, 1)).is_none());
+    }
+
+    #[test]
+    fn ranges_cross_nodes() {
+        let stream = stream_of(250);
+
+        assert_eq!(
+            ids(stream.range(id(98, 5), id(102, 0))),
+            [99, 100, 101, 102]
+        );
+        assert_eq!(
+            ids(stream.rev_range(id(199, 0), id(201, 0))),
+            [201, 200, 199]
+        );
+        assert_eq!(ids(stream.range(id(300, 0), StreamId::MAX)), [] as [u64; 0]);
+        assert_eq!(stream.range(StreamId::MIN, StreamId::MAX).count(), 250);
+        assert_eq!(
+            ids(stream.rev_range(StreamId::MIN, id(0, 9))),
+            [] as [u64; 0]
+        );
+    }
+
+    #[test]
+    fn deletes_drop_emptied_nodes() {
+        let mut stream = stream_of(STREAM_NODE_MAX_ENTRIES as u64 + 1);
+        let last = id(STREAM_NODE_MAX_ENTRIES as u64 + 1, 0);
+
+        assert!(stream.delete(last));
+        assert!(!stream.delete(last));
+        assert_eq!(stream.node_count(), 1);
+        assert_eq!(stream.max_deleted_id(), last);
+        // The last ID never goes backwards, even once its entry is gone.
+        assert_eq!(stream.last_id(), last);
+        assert_eq!(stream.len(), STREAM_NODE_MAX_ENTRIES);
     }
 
-    pub fn get_last(&self, id: &String) -> Option<StreamVal> {
-        match self.map.get(id) {
-            Some(val) => val.get(0).cloned(),
-            None => None,
+    #[test]
+    fn trimming() {
+        let trim = |strategy, approx, limit| TrimSpec {
+            strategy,
+            approx,
+            limit,
+        };
+
+        let mut stream = stream_of(250);
+        assert_eq!(stream.trim(&trim(TrimStrategy::MaxLen(120), true, 0)), 100);
+        assert_eq!(stream.len(), 150);
+
+        assert_eq!(stream.trim(&trim(TrimStrategy::MaxLen(120), false, 0)), 30);
+        assert_eq!(stream.first_id(), id(131, 0));
+
+        let mut stream = stream_of(250);
+        assert_eq!(
+            stream.trim(&trim(TrimStrategy::MinId(id(220, 0)), false, 150)),
+            100
+        );
+        assert_eq!(
+            stream.trim(&trim(TrimStrategy::MinId(id(220, 0)), false, 0)),
+            119
+        );
+        assert_eq!(stream.first_id(), id(220, 0));
+    }
+
+    fn grouped(count: u64) -> Stream {
+        let mut stream = stream_of(count);
+
+        stream
+            .groups
+            .insert("g".to_string(), ConsumerGroup::new(StreamId::MIN, Some(0)));
+        stream.groups.get_mut("g").unwrap().touch_consumer("alice");
+        stream.groups.get_mut("g").unwrap().touch_consumer("bob");
+
+        stream
+    }
+
+    fn claim_options(min_idle: u64, force: bool) -> ClaimOptions {
+        ClaimOptions {
+            min_idle,
+            delivery_time: now_ms(),
+            retry_count: None,
+            force,
+            just_id: false,
         }
     }
+
+    #[test]
+    fn reading_new_entries_fills_the_pel() {
+        let mut stream = grouped(5);
+
+        assert_eq!(
+            ids(stream.read_group_new("g", "alice", 2, false).iter()),
+            [1, 2]
+        );
+        assert_eq!(
+            ids(stream.read_group_new("g", "bob", 0, false).iter()),
+            [3, 4, 5]
+        );
+        assert!(stream.read_group_new("g", "bob", 0, false).is_empty());
+
+        let group = &stream.groups["g"];
+
+        assert_eq!(group.last_id, id(5, 0));
+        assert_eq!(group.entries_read, Some(5));
+        assert_eq!(stream.lag(group), Some(0));
+        assert_eq!(group.pending.len(), 5);
+        assert_eq!(group.pending[&id(2, 0)].consumer, "alice");
+        assert_eq!(group.consumers["bob"].pending.len(), 3);
+    }
+
+    #[test]
+    fn noack_reads_skip_the_pel() {
+        let mut stream = grouped(3);
+
+        assert_eq!(stream.read_group_new("g", "alice", 0, true).len(), 3);
+        assert!(stream.groups["g"].pending.is_empty());
+        assert_eq!(stream.groups["g"].last_id, id(3, 0));
+    }
+
+    #[test]
+    fn acks_and_consumer_deletion_keep_both_pels_in_sync() {
+        let mut stream = grouped(4);
+        stream.read_group_new("g", "alice", 2, false);
+        stream.read_group_new("g", "bob", 2, false);
+
+        let group = stream.groups.get_mut("g").unwrap();
+
+        assert!(group.ack(id(1, 0)));
+        assert!(!group.ack(id(1, 0)));
+        assert!(!group.consumers["alice"].pending.contains(&id(1, 0)));
+
+        assert_eq!(group.delete_consumer("bob"), Some(2));
+        assert_eq!(group.delete_consumer("bob"), None);
+        assert_eq!(
+            group.pending.keys().copied().collect::<Vec<_>>(),
+            [id(2, 0)]
+        );
+    }
+
+    #[test]
+    fn history_reads_count_deliveries() {
+        let mut stream = grouped(3);
+        stream.read_group_new("g", "alice", 0, false);
+        stream.delete(id(2, 0));
+
+        let history = stream.read_group_history("g", "alice", StreamId::MIN, 0);
+
+        assert_eq!(history.len(), 3);
+        assert!(history[1].1.is_none());
+        assert_eq!(stream.groups["g"].pending[&id(1, 0)].delivery_count, 2);
+        assert_eq!(stream.groups["g"].pending[&id(2, 0)].delivery_count, 1);
+
+        let after = stream.read_group_history("g", "alice", id(2, 0), 0);
+        assert_eq!(after.iter().map(|(id, _)| id.ms).collect::<Vec<_>>(), [3]);
+    }
+
+    #[test]
+    fn claims_move_entries_between_consumers() {
+        let mut stream = grouped(3);
+        stream.read_group_new("g", "alice", 2, false);
+
+        assert!(matches!(
+            stream.claim("g", "bob", id(1, 0), claim_options(60_000, false)),
+            ClaimOutcome::Skipped
+        ));
+        assert!(matches!(
+            stream.claim("g", "bob", id(1, 0), claim_options(0, false)),
+            ClaimOutcome::Claimed(Some(_))
+        ));
+        assert!(matches!(
+            stream.claim("g", "bob", id(3, 0), claim_options(0, false)),
+            ClaimOutcome::Skipped
+        ));
+        assert!(matches!(
+            stream.claim("g", "bob", id(3, 0), claim_options(0, true)),
+            ClaimOutcome::Claimed(Some(_))
+        ));
+
+        stream.delete(id(2, 0));
+
+        assert!(matches!(
+            stream.claim("g", "bob", id(2, 0), claim_options(0, false)),
+            ClaimOutcome::Deleted
+        ));
+
+        let group = &stream.groups["g"];
+
+        assert!(group.consumers["alice"].pending.is_empty());
+        assert_eq!(group.consumers["bob"].pending.len(), 2);
+        assert_eq!(group.pending[&id(1, 0)].delivery_count, 2);
+        assert_eq!(group.pending[&id(1, 0)].consumer, "bob");
+    }
+
+    #[test]
+    fn autoclaim_pages_through_the_pel() {
+        let mut stream = grouped(5);
+        stream.read_group_new("g", "alice", 0, false);
+        stream.delete(id(2, 0));
+
+        let (next, claimed, deleted) =
+            stream.autoclaim("g", "bob", StreamId::MIN, 2, claim_options(0, false));
+
+        assert_eq!(next, id(3, 0));
+        assert_eq!(claimed.iter().map(|(id, _)| id.ms).collect::<Vec<_>>(), [1]);
+        assert_eq!(deleted, [id(2, 0)]);
+
+        let (next, claimed, _) = stream.autoclaim("g", "bob", next, 10, claim_options(0, false));
+
+        assert_eq!(next, StreamId::MIN);
+        assert_eq!(claimed.len(), 3);
+        assert!(stream.groups["g"].consumers["alice"].pending.is_empty());
+    }
+
+    #[test]
+    fn lag_is_unknown_across_tombstones() {
+        let mut stream = grouped(4);
+        stream.read_group_new("g", "alice", 1, false);
+
+        assert_eq!(stream.lag(&stream.groups["g"]), Some(3));
+
+        stream.delete(id(3, 0));
+
+        assert_eq!(stream.lag(&stream.groups["g"]), None);
+        assert_eq!(stream.entries_read_at(id(4, 0)), Some(4));
+    }
 }
@@ -0,0 +1,555 @@
+use std::{
+    collections::HashMap,
+    io::Write,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use super::{client::Connection, cluster::key_hash_slot, parse::RespData};
+
+/// Where a client's replies and the messages published to it are written,
+/// shared so the two never interleave.
+pub type Writer = Arc<Mutex<Connection>>;
+
+#[derive(Clone)]
+pub struct Subscriber {
+    pub id: u64,
+    pub writer: Writer,
+    /// Shared with the client, HELLO may switch it after subscribing.
+    pub resp3: Arc<AtomicBool>,
+}
+
+impl Subscriber {
+    pub fn resp3(&self) -> bool {
+        self.resp3.load(Ordering::Relaxed)
+    }
+
+    /// Out-of-band data is pushed to RESP3 clients, RESP2 ones get arrays.
+    pub fn frame(&self, data: RespData) -> RespData {
+        match (data, self.resp3()) {
+            (RespData::Array(items), true) => RespData::Push(items),
+            (data, resp3) => data.for_protocol(resp3),
+        }
+    }
+
+    pub fn send(&self, data: &RespData) {
+        let data = self.frame(data.clone());
+
+        // A subscriber that went away is cleaned up by its own connection.
+        let _ = self.writer.lock().unwrap().write_all(&data.as_bytes());
+    }
+}
+
+type Subscriptions = Mutex<HashMap<String, Vec<Subscriber>>>;
+
+/// Sharded channels grouped by hash slot, so a slot can be dropped at once,
+/// along with each client's channels since a slot going away unsubscribes
+/// clients behind their backs.
+#[derive(Default)]
+struct ShardChannels {
+    by_slot: HashMap<u16, HashMap<String, Vec<Subscriber>>>,
+    by_client: HashMap<u64, Vec<String>>,
+}
+
+impl ShardChannels {
+    fn remove(&mut self, channel: &str, id: u64) -> bool {
+        let slot = key_hash_slot(channel.as_bytes());
+
+        let channels = match self.by_slot.get_mut(&slot) {
+            Some(channels) => channels,
+            None => return false,
+        };
+
+        if let Some(subscribers) = channels.get_mut(channel) {
+            subscribers.retain(|s| s.id != id);
+
+            if subscribers.is_empty() {
+                channels.remove(channel);
+            }
+        }
+
+        if channels.is_empty() {
+            self.by_slot.remove(&slot);
+        }
+
+        let client = match self.by_client.get_mut(&id) {
+            Some(client) => client,
+            None => return false,
+        };
+
+        let len = client.len();
+        client.retain(|c| c != channel);
+        let removed = client.len() < len;
+
+        if client.is_empty() {
+            self.by_client.remove(&id);
+        }
+
+        removed
+    }
+
+    fn count(&self, id: u64) -> usize {
+        self.by_client.get(&id).map_or(0, Vec::len)
+    }
+}
+
+/// Channel, pattern and sharded channel subscriptions of every client.
+#[derive(Default)]
+pub struct PubSub {
+    channels: Subscriptions,
+    patterns: Subscriptions,
+    shard: Mutex<ShardChannels>,
+}
+
+fn add(subscriptions: &Subscriptions, name: &str, subscriber: &Subscriber) -> bool {
+    let mut subscriptions = subscriptions.lock().unwrap();
+    let subscribers = subscriptions.entry(name.to_string()).or_default();
+
+    if subscribers.iter().any(|s| s.id == subscriber.id) {
+        return false;
+    }
+
+    subscribers.push(subscriber.clone());
+    true
+}
+
+fn remove(subscriptions: &Subscriptions, name: &str, id: u64) -> bool {
+    let mut subscriptions = subscriptions.lock().unwrap();
+
+    let subscribers = match subscriptions.get_mut(name) {
+        Some(subscribers) => subscribers,
+        None => return false,
+    };
+
+    let len = subscribers.len();
+    subscribers.retain(|s| s.id != id);
+    let removed = subscribers.len() < len;
+
+    if subscribers.is_empty() {
+        subscriptions.remove(name);
+    }
+
+    removed
+}
+
+impl PubSub {
+    pub fn subscribe(&self, channel: &str, subscriber: &Subscriber) -> bool {
+        add(&self.channels, channel, subscriber)
+    }
+
+    pub fn unsubscribe(&self, channel: &str, id: u64) -> bool {
+        remove(&self.channels, channel, id)
+    }
+
+    pub fn psubscribe(&self, pattern: &str, subscriber: &Subscriber) -> bool {
+        add(&self.patterns, pattern, subscriber)
+    }
+
+    pub fn punsubscribe(&self, pattern: &str, id: u64) -> bool {
+        remove(&self.patterns, pattern, id)
+    }
+
+    /// Sends `message` to the channel's subscribers and to those of every
+    /// matching pattern, returns how many messages went out.
+    pub fn publish(&self, channel: &str, message: &str) -> usize {
+        // Registries are unlocked before writing, a slow subscriber must not
+        // hold up (un)subscriptions.
+        let direct = self
+            .channels
+            .lock()
+            .unwrap()
+            .get(channel)
+            .cloned()
+            .unwrap_or_default();
+
+        let by_pattern: Vec<(String, Subscriber)> = self
+            .patterns
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern.as_bytes(), channel.as_bytes()))
+            .flat_map(|(pattern, subscribers)| {
+                subscribers.iter().map(|s| (pattern.clone(), s.clone()))
+            })
+            .collect();
+
+        for subscriber in &direct {
+            subscriber.send(&RespData::new_bulk_array(&["message", channel, message]));
+        }
+
+        for (pattern, subscriber) in &by_pattern {
+            subscriber.send(&RespData::new_bulk_array(&[
+                "pmessage", pattern, channel, message,
+            ]));
+        }
+
+        direct.len() + by_pattern.len()
+    }
+
+    /// Channels with at least one subscriber, optionally filtered by a
+    /// glob pattern.
+    pub fn channels(&self, pattern: Option<&str>) -> Vec<String> {
+        self.channels
+            .lock()
+            .unwrap()
+            .keys()
+            .filter(|channel| {
+                pattern.is_none_or(|pattern| glob_match(pattern.as_bytes(), channel.as_bytes()))
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn numsub(&self, channel: &str) -> usize {
+        self.channels
+            .lock()
+            .unwrap()
+            .get(channel)
+            .map_or(0, Vec::len)
+    }
+
+    pub fn subscribed(&self, channel: &str, id: u64) -> bool {
+        self.channels
+            .lock()
+            .unwrap()
+            .get(channel)
+            .is_some_and(|subscribers| subscribers.iter().any(|s| s.id == id))
+    }
+
+    /// Number of distinct patterns subscribed to.
+    pub fn numpat(&self) -> usize {
+        self.patterns.lock().unwrap().len()
+    }
+
+    /// Subscribes to a sharded channel, returns the client's resulting
+    /// number of sharded subscriptions.
+    pub fn ssubscribe(&self, channel: &str, subscriber: &Subscriber) -> usize {
+        let mut shard = self.shard.lock().unwrap();
+        let client = shard.by_client.entry(subscriber.id).or_default();
+
+        if !client.iter().any(|c| c == channel) {
+            client.push(channel.to_string());
+
+            shard
+                .by_slot
+                .entry(key_hash_slot(channel.as_bytes()))
+                .or_default()
+                .entry(channel.to_string())
+                .or_default()
+                .push(subscriber.clone());
+        }
+
+        shard.count(subscriber.id)
+    }
+
+    /// Returns the client's remaining number of sharded subscriptions.
+    pub fn sunsubscribe(&self, channel: &str, id: u64) -> usize {
+        let mut shard = self.shard.lock().unwrap();
+        shard.remove(channel, id);
+        shard.count(id)
+    }
+
+    pub fn shard_subscriptions(&self, id: u64) -> Vec<String> {
+        self.shard
+            .lock()
+            .unwrap()
+            .by_client
+            .get(&id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn spublish(&self, channel: &str, message: &str) -> usize {
+        let subscribers = self
+            .shard
+            .lock()
+            .unwrap()
+            .by_slot
+            .get(&key_hash_slot(channel.as_bytes()))
+            .and_then(|channels| channels.get(channel))
+            .cloned()
+            .unwrap_or_default();
+
+        for subscriber in &subscribers {
+            subscriber.send(&RespData::new_bulk_array(&["smessage", channel, message]));
+        }
+
+        subscribers.len()
+    }
+
+    pub fn shard_channels(&self, pattern: Option<&str>) -> Vec<String> {
+        self.shard
+            .lock()
+            .unwrap()
+            .by_slot
+            .values()
+            .flat_map(HashMap::keys)
+            .filter(|channel| {
+                pattern.is_none_or(|pattern| glob_match(pattern.as_bytes(), channel.as_bytes()))
+            })
+            .cloned()
+            .collect()
+    }
+
+    pub fn shard_numsub(&self, channel: &str) -> usize {
+        self.shard
+            .lock()
+            .unwrap()
+            .by_slot
+            .get(&key_hash_slot(channel.as_bytes()))
+            .and_then(|channels| channels.get(channel))
+            .map_or(0, Vec::len)
+    }
+
+    /// Unsubscribes everyone from the sharded channels of `slots`, which
+    /// this node no longer serves, telling each client with a
+    /// `sunsubscribe` message.
+    pub fn drop_slots(&self, slots: &[u16]) {
+        let mut notices: Vec<(Subscriber, String, usize)> = vec![];
+
+        {
+            let mut shard = self.shard.lock().unwrap();
+
+            for slot in slots {
+                let channels = match shard.by_slot.get(slot) {
+                    Some(channels) => channels.clone(),
+                    None => continue,
+                };
+
+                for (channel, subscribers) in channels {
+                    for subscriber in subscribers {
+                        shard.remove(&channel, subscriber.id);
+                        let count = shard.count(subscriber.id);
+                        notices.push((subscriber, channel.clone(), count));
+                    }
+                }
+            }
+        }
+
+        for (subscriber, channel, count) in notices {
+            subscriber.send(&RespData::Array(vec![
+                RespData::new_bulk("sunsubscribe"),
+                RespData::from(channel),
+                RespData::Integer(count as i64),
+            ]));
+        }
+    }
+}
+
+/// Redis' glob-style matching: `*`, `?`, `[...]` classes with `^` and
+/// ranges, and `\` escapes.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let mut p = 0;
+    let mut s = 0;
+
+    while p < pattern.len() {
+        match pattern[p] {
+            b'*' => {
+                while pattern.get(p + 1) == Some(&b'*') {
+                    p += 1;
+                }
+
+                if p + 1 == pattern.len() {
+                    return true;
+                }
+
+                return (s..=string.len()).any(|start| glob_match(&pattern[p + 1..], &string[start..]));
+            }
+            b'?' => {
+                if s == string.len() {
+                    return false;
+                }
+
+                s += 1;
+            }
+            b'[' => {
+                if s == string.len() {
+                    return false;
+                }
+
+                p += 1;
+
+                let negate = pattern.get(p) == Some(&b'^');
+
+                if negate {
+                    p += 1;
+                }
+
+                let mut matched = false;
+
+                loop {
+                    match pattern.get(p) {
+                        // An unterminated class ends with the pattern.
+                        None => {
+                            p -= 1;
+                            break;
+                        }
+                        Some(b']') => break,
+                        Some(b'\\') if p + 1 < pattern.len() => {
+                            p += 1;
+                            matched |= pattern[p] == string[s];
+                        }
+                        Some(&start) if pattern.get(p + 1) == Some(&b'-') && p + 2 < pattern.len() => {
+                            let end = pattern[p + 2];
+                            let (low, high) = if start <= end { (start, end) } else { (end, start) };
+
+                            p += 2;
+                            matched |= (low..=high).contains(&string[s]);
+                        }
+                        Some(&c) => matched |= c == string[s],
+                    }
+
+                    p += 1;
+                }
+
+                if matched == negate {
+                    return false;
+                }
+
+                s += 1;
+            }
+            b'\\' if p + 1 < pattern.len() => {
+                p += 1;
+
+                if string.get(s) != Some(&pattern[p]) {
+                    return false;
+                }
+
+                s += 1;
+            }
+            c => {
+                if string.get(s) != Some(&c) {
+                    return false;
+                }
+
+                s += 1;
+            }
+        }
+
+        p += 1;
+    }
+
+    s == string.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn subscriber(id: u64) -> Subscriber {
+        Subscriber {
+            id,
+            writer: Arc::new(Mutex::new(Connection::Detached)),
+            resp3: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    #[test]
+    fn glob_patterns() {
+        let cases: &[(&str, &str, bool)] = &[
+            ("*", "", true),
+            ("*", "anything", true),
+            ("h?llo", "hello", true),
+            ("h?llo", "hllo", false),
+            ("h*llo", "heeeello", true),
+            ("h*llo", "hello!", false),
+            ("h**o", "hello", true),
+            ("h[ae]llo", "hallo", true),
+            ("h[ae]llo", "hillo", false),
+            ("h[^e]llo", "hallo", true),
+            ("h[^e]llo", "hello", false),
+            ("h[a-b]llo", "hbllo", true),
+            ("h[b-a]llo", "hallo", true),
+            ("h[a-b]llo", "hcllo", false),
+            ("h\\*llo", "h*llo", true),
+            ("h\\*llo", "hello", false),
+            ("[\\]]", "]", true),
+            ("news.*", "news.tech", true),
+            ("news.*", "sport.tech", false),
+            ("a[bc", "ab", true),
+            ("?", "", false),
+        ];
+
+        for (pattern, string, expected) in cases {
+            assert_eq!(
+                glob_match(pattern.as_bytes(), string.as_bytes()),
+                *expected,
+                "{} ~ {}",
+                pattern,
+                string
+            );
+        }
+    }
+
+    #[test]
+    fn glob_is_binary_safe() {
+        assert!(glob_match(b"a?c", b"a\xffc"));
+        assert!(glob_match(b"[\x00-\x10]", b"\x05"));
+    }
+
+    #[test]
+    fn publish_counts_channel_and_pattern_receivers() {
+        let pubsub = PubSub::default();
+        let (a, b) = (subscriber(1), subscriber(2));
+
+        assert!(pubsub.subscribe("news", &a));
+        assert!(!pubsub.subscribe("news", &a));
+        assert!(pubsub.subscribe("news", &b));
+        assert!(pubsub.psubscribe("n*", &a));
+
+        assert_eq!(pubsub.publish("news", "hi"), 3);
+        assert_eq!(pubsub.publish("nope", "hi"), 1);
+        assert_eq!(pubsub.publish("other", "hi"), 0);
+
+        assert_eq!(pubsub.numsub("news"), 2);
+        assert_eq!(pubsub.numpat(), 1);
+        assert!(pubsub.subscribed("news", 2));
+
+        assert!(pubsub.unsubscribe("news", 2));
+        assert!(!pubsub.unsubscribe("news", 2));
+        assert!(pubsub.punsubscribe("n*", 1));
+        assert_eq!(pubsub.publish("news", "hi"), 1);
+        assert_eq!(pubsub.numpat(), 0);
+    }
+
+    #[test]
+    fn channels_filter_by_pattern() {
+        let pubsub = PubSub::default();
+        let a = subscriber(1);
+
+        for channel in ["news.tech", "news.sport", "weather"] {
+            pubsub.subscribe(channel, &a);
+        }
+
+        let mut channels = pubsub.channels(Some("news.*"));
+        channels.sort();
+
+        assert_eq!(channels, ["news.sport", "news.tech"]);
+        assert_eq!(pubsub.channels(None).len(), 3);
+
+        pubsub.unsubscribe("weather", 1);
+
+        assert_eq!(pubsub.channels(Some("w*")), [] as [String; 0]);
+    }
+
+    #[test]
+    fn dropping_slots_unsubscribes_sharded_channels() {
+        let pubsub = PubSub::default();
+        let a = subscriber(1);
+
+        assert_eq!(pubsub.ssubscribe("{x}.a", &a), 1);
+        assert_eq!(pubsub.ssubscribe("{x}.b", &a), 2);
+        assert_eq!(pubsub.ssubscribe("other", &a), 3);
+        assert_eq!(pubsub.spublish("{x}.a", "hi"), 1);
+
+        pubsub.drop_slots(&[key_hash_slot(b"x")]);
+
+        assert_eq!(pubsub.shard_subscriptions(1), ["other"]);
+        assert_eq!(pubsub.spublish("{x}.a", "hi"), 0);
+        assert_eq!(pubsub.shard_numsub("other"), 1);
+        assert_eq!(pubsub.sunsubscribe("other", 1), 0);
+        assert!(pubsub.shard_channels(None).is_empty());
+    }
+}
@@ -0,0 +1,563 @@
+use std::{collections::BTreeMap, fs};
+
+use crate::redis::persistence::{
+    sorted_set::format_double,
+    stream::{now_ms, Stream, StreamId},
+};
+
+use super::{
+    load::{read_value, Reader},
+    *,
+};
+
+/// Names of the RDB types by number, as `redis-check-rdb` prints them.
+const TYPE_NAMES: [&str; 22] = [
+    "string",
+    "list-linked",
+    "set-hashtable",
+    "zset-v1",
+    "hash-hashtable",
+    "zset-v2",
+    "module-pre-ga",
+    "module-value",
+    "",
+    "hash-zipmap",
+    "list-ziplist",
+    "set-intset",
+    "zset-ziplist",
+    "hash-ziplist",
+    "quicklist",
+    "stream",
+    "hash-listpack",
+    "zset-listpack",
+    "quicklist-v2",
+    "stream-v2",
+    "set-listpack",
+    "stream-v3",
+];
+
+/// Keys of one type, and how much they hold.
+#[derive(Default)]
+struct TypeStats {
+    keys: u64,
+    expires: u64,
+    /// Bytes of a string, members of a set or sorted set, stream entries.
+    items: u64,
+}
+
+/// Walks an RDB file the way the loader does, but keeps track of where it
+/// is and what it was doing, to tell what's wrong with a broken file.
+struct Checker<'a> {
+    data: &'a [u8],
+    reader: Reader<'a>,
+    doing: &'static str,
+    key: Option<String>,
+    rdb_type: Option<u8>,
+    /// More about the error than the error itself says.
+    detail: Option<String>,
+    /// The records go to stdout as JSON, so the log goes to stderr.
+    json: bool,
+    records: u64,
+    keys: u64,
+    expires: u64,
+    already_expired: u64,
+    types: BTreeMap<&'static str, TypeStats>,
+}
+
+impl<'a> Checker<'a> {
+    fn log(&self, message: &str) {
+        let line = format!("[offset {}] {}", self.reader.position(), message);
+
+        match self.json {
+            true => eprintln!("{}", line),
+            false => println!("{}", line),
+        }
+    }
+
+    fn info(&self, message: &str) {
+        match self.json {
+            true => eprintln!("{}", message),
+            false => println!("{}", message),
+        }
+    }
+
+    fn run(&mut self) -> Result<(), RdbError> {
+        self.doing = "start";
+
+        if self.reader.read_bytes(5).map_err(|_| RdbError::BadMagic)? != b"REDIS" {
+            return Err(RdbError::BadMagic);
+        }
+
+        let version = std::str::from_utf8(self.reader.read_bytes(4)?)
+            .ok()
+            .and_then(|version| version.parse::<u32>().ok())
+            .ok_or(RdbError::BadMagic)?;
+
+        if version == 0 || version > RDB_VERSION {
+            return Err(RdbError::UnsupportedVersion(version));
+        }
+
+        let mut db = 0;
+        let mut expire_ms = None;
+
+        loop {
+            self.doing = "read-type";
+            let opcode = self.reader.read_u8()?;
+
+            match opcode {
+                OPCODE_EOF => return self.verify_checksum(version),
+                OPCODE_AUX => {
+                    self.doing = "read-aux";
+                    let key = self.reader.read_str()?;
+                    let value = self.reader.read_str()?;
+                    self.log(&format!("AUX FIELD {} = '{}'", key, value));
+                }
+                OPCODE_SELECTDB => {
+                    self.doing = "read-len";
+                    db = self.reader.read_length()?;
+                    self.log(&format!("Selecting DB ID {}", db));
+                }
+                OPCODE_RESIZEDB => {
+                    self.doing = "read-len";
+                    self.reader.read_length()?;
+                    self.reader.read_length()?;
+                }
+                OPCODE_EXPIRETIME_MS => {
+                    self.doing = "read-expire";
+                    expire_ms = Some(self.reader.read_ms()? as u64);
+                }
+                OPCODE_EXPIRETIME => {
+                    self.doing = "read-expire";
+                    let secs = self.reader.read_bytes(4)?.try_into().unwrap();
+                    expire_ms = Some(u32::from_le_bytes(secs) as u64 * 1000);
+                }
+                OPCODE_IDLE => {
+                    self.doing = "read-len";
+                    self.reader.read_length()?;
+                }
+                OPCODE_FREQ => {
+                    self.doing = "read-len";
+                    self.reader.read_u8()?;
+                }
+                OPCODE_SLOT_INFO => {
+                    self.doing = "read-len";
+
+                    for _ in 0..3 {
+                        self.reader.read_length()?;
+                    }
+                }
+                OPCODE_FUNCTION2 => {
+                    self.doing = "read-functions";
+                    self.reader.read_string()?;
+                }
+                OPCODE_MODULE_AUX => {
+                    self.doing = "read-module-aux";
+                    return Err(RdbError::UnknownOpcode(opcode));
+                }
+                rdb_type => {
+                    self.rdb_type = Some(rdb_type);
+                    self.doing = "read-key";
+                    let key = self.reader.read_str()?;
+                    self.key = Some(key.clone());
+
+                    self.doing = "read-object-value";
+                    let value = read_value(&mut self.reader, rdb_type)?;
+
+                    let expire_ms = expire_ms.take();
+                    self.count(&value, expire_ms);
+
+                    if self.json {
+                        self.dump(RdbEntry {
+                            db,
+                            key,
+                            value,
+                            expire_ms,
+                        });
+                    }
+
+                    self.key = None;
+                    self.rdb_type = None;
+                }
+            }
+        }
+    }
+
+    fn verify_checksum(&mut self, version: u32) -> Result<(), RdbError> {
+        // Files from before version 5 have none.
+        if version < 5 {
+            return Ok(());
+        }
+
+        self.doing = "check-sum";
+
+        let computed = crc64(0, &self.data[..self.reader.position()]);
+        let stored = u64::from_le_bytes(self.reader.read_bytes(8)?.try_into().unwrap());
+
+        if stored == 0 {
+            self.log("RDB file was saved with checksum disabled: no check performed.");
+        } else if stored != computed {
+            self.detail = Some(format!(
+                "Stored checksum {:016x}, computed {:016x}",
+                stored, computed
+            ));
+            return Err(RdbError::BadChecksum);
+        } else {
+            self.log("Checksum OK");
+        }
+
+        Ok(())
+    }
+
+    fn count(&mut self, value: &RdbValue, expire_ms: Option<u64>) {
+        let (name, items) = match value {
+            RdbValue::String(value) => ("string", value.len()),
+            RdbValue::Set(set) => ("set", set.len()),
+            RdbValue::SortedSet(zset) => ("zset", zset.len()),
+            RdbValue::Stream(stream) => ("stream", stream.len()),
+        };
+
+        let stats = self.types.entry(name).or_default();
+        stats.keys += 1;
+        stats.items += items as u64;
+        self.keys += 1;
+
+        if let Some(at) = expire_ms {
+            stats.expires += 1;
+            self.expires += 1;
+
+            if at <= now_ms() {
+                self.already_expired += 1;
+            }
+        }
+    }
+
+    fn dump(&mut self, entry: RdbEntry) {
+        let mut record = format!("{{\"db\":{},\"key\":{}", entry.db, json_string(&entry.key));
+
+        if let Some(at) = entry.expire_ms {
+            record.push_str(&format!(",\"expire_ms\":{}", at));
+        }
+
+        let (name, value) = match &entry.value {
+            RdbValue::String(value) => ("string", json_string(value)),
+            RdbValue::Set(set) => (
+                "set",
+                json_list(set.members().iter().map(|member| json_string(member))),
+            ),
+            RdbValue::SortedSet(zset) => (
+                "zset",
+                json_object(
+                    zset.entries()
+                        .iter()
+                        .map(|(member, score)| (member.as_str(), json_score(*score))),
+                ),
+            ),
+            RdbValue::Stream(stream) => ("stream", json_stream(stream)),
+        };
+
+        record.push_str(&format!(",\"type\":\"{}\",\"value\":{}}}", name, value));
+
+        let separator = if self.records == 0 { "" } else { "," };
+        println!("{}{}", separator, record);
+        self.records += 1;
+    }
+
+    fn report(&self, result: &Result<(), RdbError>) {
+        match result {
+            Ok(()) => self.log("\\o/ RDB looks OK! \\o/"),
+            Err(e) => {
+                self.info("--- RDB ERROR DETECTED ---");
+                self.log(&e.to_string());
+                self.info(&format!("[additional info] While doing: {}", self.doing));
+
+                if let Some(detail) = &self.detail {
+                    self.info(&format!("[additional info] {}", detail));
+                }
+
+                if let Some(key) = &self.key {
+                    self.info(&format!("[additional info] Reading key '{}'", key));
+                }
+
+                if let Some(rdb_type) = self.rdb_type {
+                    let name = TYPE_NAMES.get(rdb_type as usize).unwrap_or(&"unknown");
+                    self.info(&format!(
+                        "[additional info] Reading type {} ({})",
+                        rdb_type, name
+                    ));
+                }
+            }
+        }
+
+        self.info(&format!("[info] {} keys read", self.keys));
+        self.info(&format!("[info] {} expires", self.expires));
+        self.info(&format!("[info] {} already expired", self.already_expired));
+
+        for (name, stats) in &self.types {
+            let unit = match *name {
+                "string" => "bytes",
+                "stream" => "entries",
+                _ => "members",
+            };
+
+            self.info(&format!(
+                "[info] {}: {} keys, {} expires, {} {}",
+                name, stats.keys, stats.expires, stats.items, unit
+            ));
+        }
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut out = String::with_capacity(value.len() + 2);
+    out.push('"');
+
+    for c in value.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+    out
+}
+
+fn json_list(items: impl Iterator<Item = String>) -> String {
+    format!("[{}]", items.collect::<Vec<String>>().join(","))
+}
+
+fn json_object<'a>(fields: impl Iterator<Item = (&'a str, String)>) -> String {
+    let fields: Vec<String> = fields
+        .map(|(name, value)| format!("{}:{}", json_string(name), value))
+        .collect();
+
+    format!("{{{}}}", fields.join(","))
+}
+
+/// JSON has no infinity, those scores go in as strings.
+fn json_score(score: f64) -> String {
+    match score.is_finite() {
+        true => format_double(score),
+        false => json_string(&format_double(score)),
+    }
+}
+
+fn json_stream(stream: &Stream) -> String {
+    let entries = stream.range(StreamId::MIN, StreamId::MAX).map(|entry| {
+        let fields = entry
+            .pairs
+            .iter()
+            .map(|(field, value)| (field.as_str(), json_string(value)));
+
+        json_object(
+            [
+                ("id", json_string(&entry.id.to_string())),
+                ("fields", json_object(fields)),
+            ]
+            .into_iter(),
+        )
+    });
+
+    let groups = stream.groups.iter().map(|(name, group)| {
+        let pending = group.pending.iter().map(|(id, entry)| {
+            json_object(
+                [
+                    ("id", json_string(&id.to_string())),
+                    ("consumer", json_string(&entry.consumer)),
+                    ("delivery_time", entry.delivery_time.to_string()),
+                    ("delivery_count", entry.delivery_count.to_string()),
+                ]
+                .into_iter(),
+            )
+        });
+
+        json_object(
+            [
+                ("name", json_string(name)),
+                ("last_id", json_string(&group.last_id.to_string())),
+                (
+                    "entries_read",
+                    group
+                        .entries_read
+                        .map_or("null".to_string(), |read| read.to_string()),
+                ),
+                (
+                    "consumers",
+                    json_list(group.consumers.keys().map(|name| json_string(name))),
+                ),
+                ("pending", json_list(pending)),
+            ]
+            .into_iter(),
+        )
+    });
+
+    json_object(
+        [
+            ("last_id", json_string(&stream.last_id().to_string())),
+            ("entries_added", stream.entries_added().to_string()),
+            (
+                "max_deleted_id",
+                json_string(&stream.max_deleted_id().to_string()),
+            ),
+            ("entries", json_list(entries)),
+            ("groups", json_list(groups)),
+        ]
+        .into_iter(),
+    )
+}
+
+/// Checks the RDB `data` starts with, logging what it finds along the way
+/// and a summary at the end. Returns where the RDB ends, `None` when it is
+/// broken. With `json` the keys are dumped to stdout as a JSON array, and
+/// the log goes to stderr.
+pub fn check(data: &[u8], name: &str, json: bool) -> Option<usize> {
+    let mut checker = Checker {
+        data,
+        reader: Reader::new(data),
+        doing: "start",
+        key: None,
+        rdb_type: None,
+        detail: None,
+        json,
+        records: 0,
+        keys: 0,
+        expires: 0,
+        already_expired: 0,
+        types: BTreeMap::new(),
+    };
+
+    checker.log(&format!("Checking RDB file {}", name));
+
+    if json {
+        println!("[");
+    }
+
+    let result = checker.run();
+
+    if json {
+        println!("]");
+    }
+
+    checker.report(&result);
+    result.ok().map(|()| checker.reader.position())
+}
+
+/// `redis-check-rdb <file> [--json]`, returns the exit code.
+pub fn main(args: &[String]) -> i32 {
+    let json = args[1..].iter().any(|arg| arg == "--json");
+    let files: Vec<&String> = args[1..].iter().filter(|arg| *arg != "--json").collect();
+
+    let path = match files.as_slice() {
+        [path] => *path,
+        _ => {
+            eprintln!("Usage: {} <rdb-file-name> [--json]", args[0]);
+            return 1;
+        }
+    };
+
+    let data = match fs::read(path) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Cannot open file {}: {}", path, e);
+            return 1;
+        }
+    };
+
+    match check(&data, path, json) {
+        Some(_) => 0,
+        None => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::redis::persistence::set::SetValue;
+
+    fn rdb() -> Vec<u8> {
+        let mut set = SetValue::default();
+        set.add("a");
+
+        save::serialize(&[
+            RdbEntry {
+                db: 0,
+                key: "k".to_string(),
+                value: RdbValue::String("v".to_string()),
+                expire_ms: Some(1),
+            },
+            RdbEntry {
+                db: 0,
+                key: "s".to_string(),
+                value: RdbValue::Set(set),
+                expire_ms: None,
+            },
+        ])
+    }
+
+    #[test]
+    fn sane_files_check_out_up_to_their_end() {
+        let data = rdb();
+
+        assert_eq!(check(&data, "dump.rdb", false), Some(data.len()));
+        assert_eq!(check(&data, "dump.rdb", true), Some(data.len()));
+
+        // The RDB preamble of an AOF, with commands after it.
+        let aof = [&data[..], b"*1\r\n$4\r\nPING\r\n"].concat();
+
+        assert_eq!(check(&aof, "appendonly.aof", false), Some(data.len()));
+    }
+
+    #[test]
+    fn broken_files_fail() {
+        let data = rdb();
+
+        for broken in [
+            &data[..data.len() - 9],
+            &data[..20],
+            b"REDIS",
+            b"NOTREDIS0011",
+            b"",
+        ] {
+            assert_eq!(check(broken, "dump.rdb", false), None);
+        }
+
+        let mut flipped = data.clone();
+        flipped[data.len() - 12] ^= 1;
+
+        assert_eq!(check(&flipped, "dump.rdb", false), None);
+    }
+
+    #[test]
+    fn json_escapes() {
+        assert_eq!(json_string("a\"b\\c\n\u{1}é"), "\"a\\\"b\\\\c\\n\\u0001é\"");
+        assert_eq!(
+            json_object(
+                [(
+                    "k",
+                    json_list(["1".to_string(), "2".to_string()].into_iter())
+                )]
+                .into_iter()
+            ),
+            "{\"k\":[1,2]}"
+        );
+        assert_eq!(json_score(1.5), "1.5");
+        assert_eq!(json_score(f64::NEG_INFINITY), "\"-inf\"");
+    }
+
+    #[test]
+    fn main_needs_one_readable_file() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|a| a.to_string()).collect() };
+
+        assert_eq!(main(&args(&["redis-check-rdb"])), 1);
+        assert_eq!(main(&args(&["redis-check-rdb", "a.rdb", "b.rdb"])), 1);
+        assert_eq!(
+            main(&args(&["redis-check-rdb", "/nonexistent/dump.rdb"])),
+            1
+        );
+    }
+}
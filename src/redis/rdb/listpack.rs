@@ -0,0 +1,274 @@
+use super::RdbError;
+
+const HEADER_SIZE: usize = 6;
+const EOF: u8 = 0xff;
+
+/// An element of a listpack. Strings that look like integers are stored as
+/// integers.
+#[derive(Debug)]
+pub enum LpValue {
+    Int(i64),
+    Str(String),
+}
+
+impl LpValue {
+    pub fn into_string(self) -> String {
+        match self {
+            LpValue::Int(v) => v.to_string(),
+            LpValue::Str(s) => s,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            LpValue::Int(v) => Some(*v),
+            LpValue::Str(s) => s.parse().ok(),
+        }
+    }
+}
+
+/// Builds a listpack one element at a time.
+#[derive(Default)]
+pub struct ListpackWriter {
+    entries: Vec<u8>,
+    count: usize,
+}
+
+/// The length of an entry, written after it so the list can be walked
+/// backwards too. Big end first, every byte but the first has the high bit
+/// set, so walking backwards stops at the one that doesn't.
+fn encode_backlen(len: usize) -> Vec<u8> {
+    let size = backlen_size(len);
+
+    (0..size)
+        .rev()
+        .map(|i| {
+            let byte = ((len >> (7 * i)) & 127) as u8;
+
+            match i == size - 1 {
+                true => byte,
+                false => byte | 128,
+            }
+        })
+        .collect()
+}
+
+fn backlen_size(len: usize) -> usize {
+    match len {
+        0..=127 => 1,
+        128..=16382 => 2,
+        16383..=2097150 => 3,
+        2097151..=268435454 => 4,
+        _ => 5,
+    }
+}
+
+impl ListpackWriter {
+    fn push_entry(&mut self, entry: &[u8]) {
+        self.entries.extend_from_slice(entry);
+        self.entries.extend(encode_backlen(entry.len()));
+        self.count += 1;
+    }
+
+    pub fn push_int(&mut self, v: i64) {
+        let entry = match v {
+            0..=127 => vec![v as u8],
+            -4096..=4095 => {
+                let v = (v as u16) & 0x1fff;
+                vec![(v >> 8) as u8 | 0xc0, v as u8]
+            }
+            -32768..=32767 => [&[0xf1], &(v as i16).to_le_bytes()[..]].concat(),
+            -8388608..=8388607 => [&[0xf2], &(v as i32).to_le_bytes()[..3]].concat(),
+            -2147483648..=2147483647 => [&[0xf3], &(v as i32).to_le_bytes()[..]].concat(),
+            _ => [&[0xf4], &v.to_le_bytes()[..]].concat(),
+        };
+
+        self.push_entry(&entry);
+    }
+
+    pub fn push_str(&mut self, s: &str) {
+        if let Ok(v) = s.parse::<i64>() {
+            if v.to_string() == s {
+                return self.push_int(v);
+            }
+        }
+
+        let len = s.len();
+        let mut entry = match len {
+            0..=63 => vec![0x80 | len as u8],
+            64..=4095 => vec![0xe0 | (len >> 8) as u8, len as u8],
+            _ => [&[0xf0], &(len as u32).to_le_bytes()[..]].concat(),
+        };
+
+        entry.extend_from_slice(s.as_bytes());
+        self.push_entry(&entry);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        let total = HEADER_SIZE + self.entries.len() + 1;
+        let mut out = Vec::with_capacity(total);
+
+        out.extend((total as u32).to_le_bytes());
+        out.extend((self.count.min(u16::MAX as usize) as u16).to_le_bytes());
+        out.extend(self.entries);
+        out.push(EOF);
+        out
+    }
+}
+
+/// Every element of a listpack, front to back.
+pub fn decode(data: &[u8]) -> Result<Vec<LpValue>, RdbError> {
+    let bytes = |from: usize, len: usize| data.get(from..from + len).ok_or(RdbError::BadEncoding);
+
+    let total = u32::from_le_bytes(bytes(0, 4)?.try_into().unwrap()) as usize;
+
+    if total != data.len() {
+        return Err(RdbError::BadEncoding);
+    }
+
+    let mut values = vec![];
+    let mut pos = HEADER_SIZE;
+
+    loop {
+        let first = *data.get(pos).ok_or(RdbError::BadEncoding)?;
+
+        if first == EOF {
+            break;
+        }
+
+        let (value, len) = match first {
+            0x00..=0x7f => (LpValue::Int(first as i64), 1),
+            0x80..=0xbf => {
+                let len = (first & 0x3f) as usize;
+                (LpValue::Str(lossy(bytes(pos + 1, len)?)), 1 + len)
+            }
+            0xc0..=0xdf => {
+                let v = (((first & 0x1f) as i64) << 8) | bytes(pos + 1, 1)?[0] as i64;
+                let v = if v >= 1 << 12 { v - (1 << 13) } else { v };
+                (LpValue::Int(v), 2)
+            }
+            0xe0..=0xef => {
+                let len = (((first & 0x0f) as usize) << 8) | bytes(pos + 1, 1)?[0] as usize;
+                (LpValue::Str(lossy(bytes(pos + 2, len)?)), 2 + len)
+            }
+            0xf0 => {
+                let len = u32::from_le_bytes(bytes(pos + 1, 4)?.try_into().unwrap()) as usize;
+                (LpValue::Str(lossy(bytes(pos + 5, len)?)), 5 + len)
+            }
+            0xf1 => {
+                let v = i16::from_le_bytes(bytes(pos + 1, 2)?.try_into().unwrap());
+                (LpValue::Int(v as i64), 3)
+            }
+            0xf2 => {
+                let b = bytes(pos + 1, 3)?;
+                let v = i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8;
+                (LpValue::Int(v as i64), 4)
+            }
+            0xf3 => {
+                let v = i32::from_le_bytes(bytes(pos + 1, 4)?.try_into().unwrap());
+                (LpValue::Int(v as i64), 5)
+            }
+            0xf4 => {
+                let v = i64::from_le_bytes(bytes(pos + 1, 8)?.try_into().unwrap());
+                (LpValue::Int(v), 9)
+            }
+            _ => return Err(RdbError::BadEncoding),
+        };
+
+        values.push(value);
+        pos += len + backlen_size(len);
+    }
+
+    Ok(values)
+}
+
+fn lossy(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers_round_trip_in_every_width() {
+        let ints = [
+            0,
+            127,
+            128,
+            -1,
+            -4096,
+            4095,
+            -32768,
+            32767,
+            -8388608,
+            8388607,
+            i32::MIN as i64,
+            i32::MAX as i64,
+            i64::MIN,
+            i64::MAX,
+        ];
+        let mut lp = ListpackWriter::default();
+
+        for v in ints {
+            lp.push_int(v);
+        }
+
+        let decoded: Vec<i64> = decode(&lp.finish())
+            .unwrap()
+            .iter()
+            .map(|v| v.as_int().unwrap())
+            .collect();
+
+        assert_eq!(decoded, ints);
+    }
+
+    #[test]
+    fn strings_round_trip() {
+        let strings = [
+            String::new(),
+            "field".to_string(),
+            "x".repeat(64),
+            "y".repeat(4096),
+            "007".to_string(),
+            "12".to_string(),
+        ];
+        let mut lp = ListpackWriter::default();
+
+        for s in &strings {
+            lp.push_str(s);
+        }
+
+        let decoded = decode(&lp.finish()).unwrap();
+
+        assert!(matches!(decoded[5], LpValue::Int(12)));
+        assert!(matches!(&decoded[4], LpValue::Str(s) if s == "007"));
+        assert_eq!(
+            decoded
+                .into_iter()
+                .map(LpValue::into_string)
+                .collect::<Vec<_>>(),
+            strings
+        );
+    }
+
+    #[test]
+    fn back_lengths() {
+        assert_eq!(encode_backlen(5), [5]);
+        assert_eq!(encode_backlen(127), [127]);
+        assert_eq!(encode_backlen(128), [0x01, 0x80]);
+        assert_eq!(encode_backlen(16383), [0x00, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn rejects_a_wrong_total() {
+        let mut lp = ListpackWriter::default();
+        lp.push_int(1);
+
+        let mut data = lp.finish();
+        data.push(0);
+
+        assert!(decode(&data).is_err());
+        assert!(decode(&data[..3]).is_err());
+    }
+}
@@ -0,0 +1,827 @@
+use std::{fs, io, path::Path, time::SystemTime};
+
+use crate::redis::{
+    parse::RespData,
+    persistence::{
+        kv_pair::PersistedValue,
+        lib::{PersistedType, PersistenceInner},
+        set::SetValue,
+        sorted_set::{parse_score, SortedSet, ZAddFlags},
+        stream::{
+            now_ms, Consumer, ConsumerGroup, IdSpec, PendingEntry, Stream, StreamId, StreamVal,
+        },
+    },
+};
+
+use super::{
+    listpack::{self, LpValue},
+    *,
+};
+
+const ENC_INT8: u8 = 0;
+const ENC_INT16: u8 = 1;
+const ENC_INT32: u8 = 2;
+const ENC_LZF: u8 = 3;
+
+/// A length, or which special encoding the string that follows uses.
+enum Length {
+    Len(u64),
+    Encoded(u8),
+}
+
+/// Cursor over an RDB payload.
+pub struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(buf: &'a [u8]) -> Reader<'a> {
+        Reader { buf, pos: 0 }
+    }
+
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], RdbError> {
+        let end = self.pos.checked_add(len).ok_or(RdbError::UnexpectedEof)?;
+        let bytes = self.buf.get(self.pos..end).ok_or(RdbError::UnexpectedEof)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, RdbError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_array<const N: usize>(&mut self) -> Result<[u8; N], RdbError> {
+        Ok(self.read_bytes(N)?.try_into().unwrap())
+    }
+
+    fn read_length_or_encoding(&mut self) -> Result<Length, RdbError> {
+        let first = self.read_u8()?;
+
+        Ok(match first >> 6 {
+            0 => Length::Len((first & 0x3f) as u64),
+            1 => Length::Len((((first & 0x3f) as u64) << 8) | self.read_u8()? as u64),
+            2 => match first {
+                0x80 => Length::Len(u32::from_be_bytes(self.read_array()?) as u64),
+                0x81 => Length::Len(u64::from_be_bytes(self.read_array()?)),
+                _ => return Err(RdbError::BadEncoding),
+            },
+            _ => Length::Encoded(first & 0x3f),
+        })
+    }
+
+    pub fn read_length(&mut self) -> Result<u64, RdbError> {
+        match self.read_length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => Err(RdbError::BadEncoding),
+        }
+    }
+
+    /// A string in any of its encodings, integers come back in decimal.
+    pub fn read_string(&mut self) -> Result<Vec<u8>, RdbError> {
+        match self.read_length_or_encoding()? {
+            Length::Len(len) => Ok(self.read_bytes(len as usize)?.to_vec()),
+            Length::Encoded(ENC_INT8) => Ok((self.read_u8()? as i8).to_string().into_bytes()),
+            Length::Encoded(ENC_INT16) => Ok(i16::from_le_bytes(self.read_array()?)
+                .to_string()
+                .into_bytes()),
+            Length::Encoded(ENC_INT32) => Ok(i32::from_le_bytes(self.read_array()?)
+                .to_string()
+                .into_bytes()),
+            Length::Encoded(ENC_LZF) => {
+                let compressed_len = self.read_length()? as usize;
+                let len = self.read_length()? as usize;
+                lzf_decompress(self.read_bytes(compressed_len)?, len)
+            }
+            Length::Encoded(_) => Err(RdbError::BadEncoding),
+        }
+    }
+
+    pub fn read_str(&mut self) -> Result<String, RdbError> {
+        Ok(String::from_utf8_lossy(&self.read_string()?).into_owned())
+    }
+
+    /// A unix time in milliseconds, as a little endian 64 bit integer.
+    pub fn read_ms(&mut self) -> Result<i64, RdbError> {
+        Ok(i64::from_le_bytes(self.read_array()?))
+    }
+
+    fn read_double(&mut self) -> Result<f64, RdbError> {
+        Ok(f64::from_le_bytes(self.read_array()?))
+    }
+
+    /// A stream ID in its raw form, both halves big endian.
+    fn read_stream_id(&mut self) -> Result<StreamId, RdbError> {
+        stream_id(self.read_bytes(16)?)
+    }
+
+    /// A stream ID stored as a pair of lengths.
+    fn read_stream_id_lengths(&mut self) -> Result<StreamId, RdbError> {
+        Ok(StreamId {
+            ms: self.read_length()?,
+            seq: self.read_length()?,
+        })
+    }
+}
+
+fn stream_id(raw: &[u8]) -> Result<StreamId, RdbError> {
+    if raw.len() != 16 {
+        return Err(RdbError::BadEncoding);
+    }
+
+    Ok(StreamId {
+        ms: u64::from_be_bytes(raw[..8].try_into().unwrap()),
+        seq: u64::from_be_bytes(raw[8..].try_into().unwrap()),
+    })
+}
+
+fn lzf_decompress(input: &[u8], len: usize) -> Result<Vec<u8>, RdbError> {
+    let mut out: Vec<u8> = Vec::with_capacity(len);
+    let mut i = 0;
+
+    while i < input.len() {
+        let ctrl = input[i] as usize;
+        i += 1;
+
+        if ctrl < 32 {
+            // A run of ctrl + 1 literal bytes.
+            let literal = input.get(i..i + ctrl + 1).ok_or(RdbError::BadEncoding)?;
+            out.extend_from_slice(literal);
+            i += ctrl + 1;
+            continue;
+        }
+
+        // A back reference, which may overlap what it copies.
+        let mut run = ctrl >> 5;
+
+        if run == 7 {
+            run += *input.get(i).ok_or(RdbError::BadEncoding)? as usize;
+            i += 1;
+        }
+
+        let offset =
+            ((ctrl & 0x1f) << 8) + *input.get(i).ok_or(RdbError::BadEncoding)? as usize + 1;
+        i += 1;
+
+        let start = out.len().checked_sub(offset).ok_or(RdbError::BadEncoding)?;
+
+        for j in 0..run + 2 {
+            out.push(out[start + j]);
+        }
+    }
+
+    match out.len() == len {
+        true => Ok(out),
+        false => Err(RdbError::BadEncoding),
+    }
+}
+
+/// An intset blob: the integer width, the count, then the integers.
+fn read_intset(blob: &[u8]) -> Result<SetValue, RdbError> {
+    let mut reader = Reader::new(blob);
+    let width = u32::from_le_bytes(reader.read_array()?) as usize;
+    let len = u32::from_le_bytes(reader.read_array()?) as usize;
+
+    let mut set = SetValue::default();
+
+    for _ in 0..len {
+        let bytes = reader.read_bytes(width)?;
+
+        let v = match width {
+            2 => i16::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            4 => i32::from_le_bytes(bytes.try_into().unwrap()) as i64,
+            8 => i64::from_le_bytes(bytes.try_into().unwrap()),
+            _ => return Err(RdbError::BadEncoding),
+        };
+
+        set.add(&v.to_string());
+    }
+
+    Ok(set)
+}
+
+/// Hands out the elements of a listpack in order.
+struct Elements(std::vec::IntoIter<LpValue>);
+
+impl Elements {
+    fn new(blob: &[u8]) -> Result<Elements, RdbError> {
+        Ok(Elements(listpack::decode(blob)?.into_iter()))
+    }
+
+    fn next(&mut self) -> Result<LpValue, RdbError> {
+        self.0.next().ok_or(RdbError::BadEncoding)
+    }
+
+    fn next_int(&mut self) -> Result<i64, RdbError> {
+        self.next()?.as_int().ok_or(RdbError::BadEncoding)
+    }
+
+    fn next_string(&mut self) -> Result<String, RdbError> {
+        Ok(self.next()?.into_string())
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.len() == 0
+    }
+}
+
+const STREAM_ITEM_FLAG_DELETED: i64 = 1 << 0;
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 1 << 1;
+
+/// The entries of one stream node. It starts with a master entry holding
+/// the entry count and the fields of the first entry; entries with the same
+/// fields only store their values, and IDs are relative to the node's.
+fn read_stream_node(
+    master_id: StreamId,
+    blob: &[u8],
+    entries: &mut Vec<StreamVal>,
+) -> Result<(), RdbError> {
+    let mut elements = Elements::new(blob)?;
+
+    let count = elements.next_int()?;
+    let deleted = elements.next_int()?;
+    let master_fields = (0..elements.next_int()?)
+        .map(|_| elements.next_string())
+        .collect::<Result<Vec<String>, RdbError>>()?;
+
+    // The master entry's terminator.
+    elements.next()?;
+
+    for _ in 0..count + deleted {
+        let flags = elements.next_int()?;
+        let id = StreamId {
+            ms: master_id.ms.wrapping_add(elements.next_int()? as u64),
+            seq: master_id.seq.wrapping_add(elements.next_int()? as u64),
+        };
+
+        let mut pairs = vec![];
+
+        match flags & STREAM_ITEM_FLAG_SAMEFIELDS != 0 {
+            true => {
+                for field in &master_fields {
+                    pairs.push((field.clone(), elements.next_string()?));
+                }
+            }
+            false => {
+                for _ in 0..elements.next_int()? {
+                    pairs.push((elements.next_string()?, elements.next_string()?));
+                }
+            }
+        }
+
+        // How many elements the entry took, for walking backwards.
+        elements.next()?;
+
+        if flags & STREAM_ITEM_FLAG_DELETED == 0 {
+            entries.push(StreamVal { id, pairs });
+        }
+    }
+
+    match elements.is_empty() {
+        true => Ok(()),
+        false => Err(RdbError::BadEncoding),
+    }
+}
+
+fn read_stream(reader: &mut Reader, rdb_type: u8) -> Result<Stream, RdbError> {
+    let mut entries = vec![];
+
+    for _ in 0..reader.read_length()? {
+        let master_id = stream_id(&reader.read_string()?)?;
+        read_stream_node(master_id, &reader.read_string()?, &mut entries)?;
+    }
+
+    let length = reader.read_length()?;
+    let last_id = reader.read_stream_id_lengths()?;
+
+    let (max_deleted_id, entries_added) = match rdb_type {
+        TYPE_STREAM_LISTPACKS => (StreamId::MIN, length),
+        _ => {
+            // The first ID is worked out from the entries instead.
+            reader.read_stream_id_lengths()?;
+            (reader.read_stream_id_lengths()?, reader.read_length()?)
+        }
+    };
+
+    if entries.len() as u64 != length {
+        return Err(RdbError::BadEncoding);
+    }
+
+    let mut stream = Stream::default();
+
+    for entry in entries {
+        stream
+            .append(IdSpec::Explicit(entry.id), entry.pairs)
+            .map_err(|_| RdbError::BadEncoding)?;
+    }
+
+    stream.set_id(last_id, Some(entries_added), max_deleted_id);
+
+    for _ in 0..reader.read_length()? {
+        let name = reader.read_str()?;
+        let last_id = reader.read_stream_id_lengths()?;
+
+        let entries_read = match rdb_type {
+            TYPE_STREAM_LISTPACKS => None,
+            _ => Some(reader.read_length()?).filter(|&read| read != u64::MAX),
+        };
+
+        let mut group = ConsumerGroup::new(last_id, entries_read);
+
+        // The group's PEL doesn't say who owns an entry, the consumers do.
+        for _ in 0..reader.read_length()? {
+            let id = reader.read_stream_id()?;
+            let delivery_time = reader.read_ms()? as u64;
+            let delivery_count = reader.read_length()?;
+
+            group.pending.insert(
+                id,
+                PendingEntry {
+                    consumer: String::new(),
+                    delivery_time,
+                    delivery_count,
+                },
+            );
+        }
+
+        for _ in 0..reader.read_length()? {
+            let consumer_name = reader.read_str()?;
+            let seen_time = reader.read_ms()? as u64;
+
+            let active_time = match rdb_type {
+                TYPE_STREAM_LISTPACKS_3 => Some(reader.read_ms()?).filter(|&t| t >= 0),
+                _ => Some(seen_time as i64),
+            };
+
+            let mut consumer = Consumer {
+                seen_time,
+                active_time: active_time.map(|t| t as u64),
+                pending: Default::default(),
+            };
+
+            for _ in 0..reader.read_length()? {
+                let id = reader.read_stream_id()?;
+                let entry = group.pending.get_mut(&id).ok_or(RdbError::BadEncoding)?;
+
+                entry.consumer = consumer_name.clone();
+                consumer.pending.insert(id);
+            }
+
+            group.consumers.insert(consumer_name, consumer);
+        }
+
+        if group
+            .pending
+            .values()
+            .any(|entry| entry.consumer.is_empty())
+        {
+            return Err(RdbError::BadEncoding);
+        }
+
+        stream.groups.insert(name, group);
+    }
+
+    Ok(stream)
+}
+
+/// Reads a value of the given RDB type.
+pub fn read_value(reader: &mut Reader, rdb_type: u8) -> Result<RdbValue, RdbError> {
+    Ok(match rdb_type {
+        TYPE_STRING => RdbValue::String(reader.read_str()?),
+        TYPE_SET => {
+            let mut set = SetValue::default();
+
+            for _ in 0..reader.read_length()? {
+                set.add(&reader.read_str()?);
+            }
+
+            RdbValue::Set(set)
+        }
+        TYPE_SET_INTSET => RdbValue::Set(read_intset(&reader.read_string()?)?),
+        TYPE_SET_LISTPACK => {
+            let mut set = SetValue::default();
+
+            for member in listpack::decode(&reader.read_string()?)? {
+                set.add(&member.into_string());
+            }
+
+            RdbValue::Set(set)
+        }
+        TYPE_ZSET_2 => {
+            let mut zset = SortedSet::default();
+
+            for _ in 0..reader.read_length()? {
+                let member = reader.read_str()?;
+                zset.add(reader.read_double()?, &member, ZAddFlags::default());
+            }
+
+            RdbValue::SortedSet(zset)
+        }
+        TYPE_ZSET_LISTPACK => {
+            let mut zset = SortedSet::default();
+            let mut elements = Elements::new(&reader.read_string()?)?;
+
+            while !elements.is_empty() {
+                let member = elements.next_string()?;
+                let score = parse_score(&elements.next_string()?).ok_or(RdbError::BadEncoding)?;
+                zset.add(score, &member, ZAddFlags::default());
+            }
+
+            RdbValue::SortedSet(zset)
+        }
+        TYPE_STREAM_LISTPACKS | TYPE_STREAM_LISTPACKS_2 | TYPE_STREAM_LISTPACKS_3 => {
+            RdbValue::Stream(read_stream(reader, rdb_type)?)
+        }
+        _ => return Err(RdbError::UnknownType(rdb_type)),
+    })
+}
+
+pub fn parse(data: &[u8]) -> Result<Rdb, RdbError> {
+    parse_prefix(data).map(|(rdb, _)| rdb)
+}
+
+/// Parses the RDB `data` starts with, and tells where it ends. Append only
+/// files start with one, followed by commands.
+pub fn parse_prefix(data: &[u8]) -> Result<(Rdb, usize), RdbError> {
+    let mut reader = Reader::new(data);
+
+    if reader.read_bytes(5).map_err(|_| RdbError::BadMagic)? != b"REDIS" {
+        return Err(RdbError::BadMagic);
+    }
+
+    let version = std::str::from_utf8(reader.read_bytes(4)?)
+        .ok()
+        .and_then(|version| version.parse::<u32>().ok())
+        .ok_or(RdbError::BadMagic)?;
+
+    if version == 0 || version > RDB_VERSION {
+        return Err(RdbError::UnsupportedVersion(version));
+    }
+
+    let mut rdb = Rdb {
+        version,
+        ..Rdb::default()
+    };
+
+    let mut db = 0;
+    let mut expire_ms = None;
+
+    loop {
+        let opcode = reader.read_u8()?;
+
+        match opcode {
+            OPCODE_EOF => {
+                verify_checksum(&mut reader, version, data)?;
+                break;
+            }
+            OPCODE_AUX => {
+                let key = reader.read_str()?;
+                let value = reader.read_str()?;
+                rdb.aux.push((key, value));
+            }
+            OPCODE_SELECTDB => db = reader.read_length()?,
+            OPCODE_RESIZEDB => {
+                let keys = reader.read_length()?;
+                let expires = reader.read_length()?;
+                rdb.sizes.push((db, keys, expires));
+            }
+            OPCODE_EXPIRETIME_MS => {
+                expire_ms = Some(reader.read_ms()? as u64);
+            }
+            OPCODE_EXPIRETIME => {
+                expire_ms = Some(u32::from_le_bytes(reader.read_array()?) as u64 * 1000);
+            }
+            // LRU/LFU hints, this server keeps neither.
+            OPCODE_IDLE => {
+                reader.read_length()?;
+            }
+            OPCODE_FREQ => {
+                reader.read_u8()?;
+            }
+            OPCODE_SLOT_INFO => {
+                for _ in 0..3 {
+                    reader.read_length()?;
+                }
+            }
+            // Function libraries are stored as their source code.
+            OPCODE_FUNCTION2 => {
+                reader.read_string()?;
+            }
+            OPCODE_MODULE_AUX => return Err(RdbError::UnknownOpcode(opcode)),
+            rdb_type => {
+                let key = reader.read_str()?;
+                let value = read_value(&mut reader, rdb_type)?;
+
+                rdb.entries.push(RdbEntry {
+                    db,
+                    key,
+                    value,
+                    expire_ms: expire_ms.take(),
+                });
+            }
+        }
+    }
+
+    Ok((rdb, reader.position()))
+}
+
+/// Checks the CRC64 that follows the EOF opcode. Files from before version
+/// 5 have none, and a checksum of 0 means it was never computed.
+fn verify_checksum(reader: &mut Reader, version: u32, data: &[u8]) -> Result<(), RdbError> {
+    if version < 5 {
+        return Ok(());
+    }
+
+    let computed = crc64(0, &data[..reader.position()]);
+    let stored = u64::from_le_bytes(reader.read_array()?);
+
+    match stored == 0 || stored == computed {
+        true => Ok(()),
+        false => Err(RdbError::BadChecksum),
+    }
+}
+
+/// The value in a DUMP payload, see `save::dump`. Payloads from a newer
+/// RDB version, or whose checksum doesn't match, are refused before
+/// anything is read from them.
+pub fn read_dump(payload: &[u8]) -> Result<RdbValue, RdbError> {
+    if payload.len() < 10 {
+        return Err(RdbError::BadChecksum);
+    }
+
+    let (body, footer) = payload.split_at(payload.len() - 10);
+    let version = u16::from_le_bytes(footer[..2].try_into().unwrap()) as u32;
+
+    if version > RDB_VERSION {
+        return Err(RdbError::UnsupportedVersion(version));
+    }
+
+    let stored = u64::from_le_bytes(footer[2..].try_into().unwrap());
+
+    if crc64(0, &payload[..payload.len() - 8]) != stored {
+        return Err(RdbError::BadChecksum);
+    }
+
+    let mut reader = Reader::new(body);
+    let rdb_type = reader.read_u8()?;
+    let value = read_value(&mut reader, rdb_type)?;
+
+    match reader.position() == body.len() {
+        true => Ok(value),
+        false => Err(RdbError::BadEncoding),
+    }
+}
+
+/// Reads and parses the RDB file at `path`, `None` when there is none.
+pub fn read_file(path: &Path) -> Result<Option<Rdb>, RdbError> {
+    match fs::read(path) {
+        Ok(data) => parse(&data).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Puts what was loaded into the keyspace, returns how many keys made it.
+/// Keys that expired already are left out. A key this server can't hold
+/// as it was saved, in a database other than 0 or a TTL on anything but a
+/// string, fails the whole load before anything is stored.
+pub fn restore(persistence: &PersistenceInner, rdb: Rdb) -> Result<usize, RdbError> {
+    let now = now_ms();
+
+    let entries: Vec<RdbEntry> = rdb
+        .entries
+        .into_iter()
+        .filter(|entry| entry.expire_ms.is_none_or(|at| at > now))
+        .collect();
+
+    if let Some(e) = entries.iter().find_map(unsupported) {
+        return Err(e);
+    }
+
+    let loaded = entries.len();
+
+    for entry in entries {
+        store(persistence, entry.key, entry.value, entry.expire_ms);
+    }
+
+    Ok(loaded)
+}
+
+/// Why `entry` can't be stored as it is.
+fn unsupported(entry: &RdbEntry) -> Option<RdbError> {
+    if entry.db != 0 {
+        return Some(RdbError::UnsupportedDb(entry.db));
+    }
+
+    match (&entry.value, entry.expire_ms) {
+        (RdbValue::String(_), _) | (_, None) => None,
+        _ => Some(RdbError::UnsupportedExpiry(entry.key.clone())),
+    }
+}
+
+/// Puts `value` under `key`, with `expire_ms` as its absolute expiry, which
+/// has to be in the future. Only strings can expire here, callers refuse
+/// a TTL on anything else.
+pub fn store(persistence: &PersistenceInner, key: String, value: RdbValue, expire_ms: Option<u64>) {
+    // Expiries are kept relative to when the value was stored.
+    let expiry = expire_ms.map_or(0, |at| at.saturating_sub(now_ms()).max(1) as u128);
+
+    match value {
+        RdbValue::String(value) => {
+            persistence.key_value.lock().unwrap().0.insert(
+                key,
+                PersistedValue {
+                    data: RespData::SimpleString(value),
+                    p_type: PersistedType::String,
+                    timestamp: SystemTime::now(),
+                    expiry,
+                },
+            );
+        }
+        RdbValue::Set(set) => {
+            persistence.set.lock().unwrap().0.insert(key, set);
+        }
+        RdbValue::SortedSet(zset) => {
+            persistence.sorted_set.lock().unwrap().0.insert(key, zset);
+        }
+        RdbValue::Stream(stream) => {
+            persistence.stream.lock().unwrap().map.insert(key, stream);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A string short enough for a one byte length.
+    fn string(value: &str) -> Vec<u8> {
+        let mut out = vec![value.len() as u8];
+        out.extend_from_slice(value.as_bytes());
+        out
+    }
+
+    /// An RDB file around `body`, with no checksum.
+    fn rdb(body: &[u8]) -> Vec<u8> {
+        let mut out = b"REDIS0011".to_vec();
+        out.extend_from_slice(body);
+        out.push(OPCODE_EOF);
+        out.extend_from_slice(&[0; 8]);
+        out
+    }
+
+    fn entry(key: &str, value: RdbValue, db: u64, expire_ms: Option<u64>) -> RdbEntry {
+        RdbEntry {
+            db,
+            key: key.to_string(),
+            value,
+            expire_ms,
+        }
+    }
+
+    #[test]
+    fn lzf_literals_and_back_references() {
+        assert_eq!(lzf_decompress(b"\x02abc", 3).unwrap(), b"abc");
+        // One literal, then 19 bytes copied from one byte back.
+        assert_eq!(
+            lzf_decompress(b"\x00a\xe0\x0a\x00", 20).unwrap(),
+            [b'a'; 20]
+        );
+        assert_eq!(lzf_decompress(b"\x03abcd\x20\x03", 7).unwrap(), b"abcdabc");
+    }
+
+    #[test]
+    fn lzf_rejects_corrupt_input() {
+        assert!(lzf_decompress(b"\x05ab", 6).is_err());
+        assert!(lzf_decompress(b"\x00a\x20\x05", 4).is_err());
+        assert!(lzf_decompress(b"\x02abc", 4).is_err());
+    }
+
+    #[test]
+    fn lengths() {
+        let mut reader = Reader::new(b"\x0a\x41\x02\x80\x00\x01\x00\x00");
+
+        assert_eq!(reader.read_length().unwrap(), 10);
+        assert_eq!(reader.read_length().unwrap(), 0x102);
+        assert_eq!(reader.read_length().unwrap(), 0x10000);
+        assert!(reader.read_length().is_err());
+    }
+
+    #[test]
+    fn string_encodings() {
+        let mut reader = Reader::new(
+            b"\x03abc\xc0\x85\xc1\x39\x30\xc2\x15\xcd\x5b\x07\xc3\x05\x14\x00a\xe0\x0a\x00",
+        );
+
+        assert_eq!(reader.read_string().unwrap(), b"abc");
+        assert_eq!(reader.read_string().unwrap(), b"-123");
+        assert_eq!(reader.read_string().unwrap(), b"12345");
+        assert_eq!(reader.read_string().unwrap(), b"123456789");
+        assert_eq!(reader.read_string().unwrap(), [b'a'; 20]);
+        assert!(matches!(reader.read_string(), Err(RdbError::UnexpectedEof)));
+    }
+
+    #[test]
+    fn parses_a_file() {
+        let mut body = vec![OPCODE_AUX];
+        body.extend(string("redis-ver"));
+        body.extend(string("7.2.0"));
+        body.extend([OPCODE_SELECTDB, 0, OPCODE_RESIZEDB, 2, 1]);
+        body.push(OPCODE_EXPIRETIME_MS);
+        body.extend(1_700_000_000_000u64.to_le_bytes());
+        body.push(TYPE_STRING);
+        body.extend(string("k"));
+        body.extend(string("v"));
+        body.push(TYPE_SET);
+        body.extend(string("s"));
+        body.push(2);
+        body.extend(string("a"));
+        body.extend(string("b"));
+
+        let rdb = parse(&rdb(&body)).unwrap();
+
+        assert_eq!(rdb.version, 11);
+        assert_eq!(rdb.aux, [("redis-ver".to_string(), "7.2.0".to_string())]);
+        assert_eq!(rdb.sizes, [(0, 2, 1)]);
+        assert_eq!(rdb.entries.len(), 2);
+        assert_eq!(rdb.entries[0].key, "k");
+        assert_eq!(rdb.entries[0].expire_ms, Some(1_700_000_000_000));
+        assert!(matches!(&rdb.entries[0].value, RdbValue::String(v) if v == "v"));
+        assert_eq!(rdb.entries[1].expire_ms, None);
+        assert!(matches!(&rdb.entries[1].value, RdbValue::Set(set) if set.len() == 2));
+    }
+
+    #[test]
+    fn refuses_other_files() {
+        assert!(matches!(parse(b"RADIS0011\xff"), Err(RdbError::BadMagic)));
+        assert!(matches!(
+            parse(b"REDIS0099\xff"),
+            Err(RdbError::UnsupportedVersion(99))
+        ));
+        assert!(matches!(
+            parse(&rdb(&[OPCODE_MODULE_AUX])),
+            Err(RdbError::UnknownOpcode(OPCODE_MODULE_AUX))
+        ));
+        assert!(matches!(parse(b"REDIS0011"), Err(RdbError::UnexpectedEof)));
+        assert!(matches!(
+            parse(&rdb(&[42, 1, b'k'])),
+            Err(RdbError::UnknownType(42))
+        ));
+    }
+
+    #[test]
+    fn restore_skips_expired_keys() {
+        let persistence = PersistenceInner::default();
+        let later = now_ms() + 60_000;
+
+        let rdb = Rdb {
+            entries: vec![
+                entry("old", RdbValue::String("v".to_string()), 0, Some(1)),
+                entry("new", RdbValue::String("v".to_string()), 0, Some(later)),
+                entry("set", RdbValue::Set(SetValue::default()), 0, None),
+            ],
+            ..Rdb::default()
+        };
+
+        assert_eq!(restore(&persistence, rdb).unwrap(), 2);
+        assert!(persistence.key_type("old").is_none());
+        assert!(persistence.key_type("new").is_some());
+        assert!(persistence.key_type("set").is_some());
+    }
+
+    #[test]
+    fn restore_refuses_what_it_cant_hold() {
+        let persistence = PersistenceInner::default();
+        let later = now_ms() + 60_000;
+        let string = || RdbValue::String("v".to_string());
+
+        let other_db = Rdb {
+            entries: vec![entry("a", string(), 0, None), entry("b", string(), 1, None)],
+            ..Rdb::default()
+        };
+
+        assert!(matches!(
+            restore(&persistence, other_db),
+            Err(RdbError::UnsupportedDb(1))
+        ));
+        assert!(persistence.key_type("a").is_none());
+
+        let set_ttl = Rdb {
+            entries: vec![
+                entry("a", string(), 0, Some(later)),
+                entry("s", RdbValue::Set(SetValue::default()), 0, Some(later)),
+            ],
+            ..Rdb::default()
+        };
+
+        assert!(matches!(
+            restore(&persistence, set_ttl),
+            Err(RdbError::UnsupportedExpiry(key)) if key == "s"
+        ));
+        assert!(persistence.key_type("a").is_none());
+    }
+}
@@ -0,0 +1,333 @@
+pub mod check;
+pub mod listpack;
+pub mod load;
+pub mod save;
+
+use std::{
+    fmt, io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use crate::redis::persistence::{
+    set::SetValue, sorted_set::SortedSet, stream::now_ms, stream::Stream,
+};
+
+/// Newest RDB version this server reads, the one Redis 7.4 writes.
+pub const RDB_VERSION: u32 = 12;
+
+pub const OPCODE_SLOT_INFO: u8 = 0xf4;
+pub const OPCODE_FUNCTION2: u8 = 0xf5;
+pub const OPCODE_MODULE_AUX: u8 = 0xf7;
+pub const OPCODE_IDLE: u8 = 0xf8;
+pub const OPCODE_FREQ: u8 = 0xf9;
+pub const OPCODE_AUX: u8 = 0xfa;
+pub const OPCODE_RESIZEDB: u8 = 0xfb;
+pub const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+pub const OPCODE_EXPIRETIME: u8 = 0xfd;
+pub const OPCODE_SELECTDB: u8 = 0xfe;
+pub const OPCODE_EOF: u8 = 0xff;
+
+pub const TYPE_STRING: u8 = 0;
+pub const TYPE_SET: u8 = 2;
+pub const TYPE_ZSET_2: u8 = 5;
+pub const TYPE_SET_INTSET: u8 = 11;
+pub const TYPE_ZSET_LISTPACK: u8 = 17;
+pub const TYPE_STREAM_LISTPACKS: u8 = 15;
+pub const TYPE_STREAM_LISTPACKS_2: u8 = 19;
+pub const TYPE_SET_LISTPACK: u8 = 20;
+pub const TYPE_STREAM_LISTPACKS_3: u8 = 21;
+
+#[derive(Debug)]
+pub enum RdbError {
+    Io(io::Error),
+    UnexpectedEof,
+    BadMagic,
+    UnsupportedVersion(u32),
+    UnknownOpcode(u8),
+    UnknownType(u8),
+    BadEncoding,
+    BadChecksum,
+    /// A key in a database other than 0, the only one there is here.
+    UnsupportedDb(u64),
+    /// A key of a type that can't expire here, only strings can.
+    UnsupportedExpiry(String),
+}
+
+impl fmt::Display for RdbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RdbError::Io(e) => write!(f, "{}", e),
+            RdbError::UnexpectedEof => write!(f, "Unexpected EOF reading RDB file"),
+            RdbError::BadMagic => write!(f, "Wrong signature trying to load DB from file"),
+            RdbError::UnsupportedVersion(version) => {
+                write!(f, "Can't handle RDB format version {}", version)
+            }
+            RdbError::UnknownOpcode(opcode) => write!(f, "Unknown RDB opcode {:#04x}", opcode),
+            RdbError::UnknownType(rdb_type) => {
+                write!(f, "Unknown RDB encoding type {}", rdb_type)
+            }
+            RdbError::BadEncoding => write!(f, "Invalid encoding of an RDB value"),
+            RdbError::BadChecksum => write!(f, "Wrong RDB checksum"),
+            RdbError::UnsupportedDb(db) => write!(
+                f,
+                "Data file was created with a Redis server configured to handle more than 1 databases (found a key in database {})",
+                db
+            ),
+            RdbError::UnsupportedExpiry(key) => write!(
+                f,
+                "Key '{}' has a TTL but isn't a string, only strings can expire here",
+                key
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for RdbError {
+    fn from(e: io::Error) -> RdbError {
+        RdbError::Io(e)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum RdbValue {
+    String(String),
+    Set(SetValue),
+    SortedSet(SortedSet),
+    Stream(Stream),
+}
+
+#[derive(Debug)]
+pub struct RdbEntry {
+    pub db: u64,
+    pub key: String,
+    pub value: RdbValue,
+    /// Absolute unix time in milliseconds.
+    pub expire_ms: Option<u64>,
+}
+
+/// A parsed RDB file.
+#[derive(Debug, Default)]
+pub struct Rdb {
+    pub version: u32,
+    pub aux: Vec<(String, String)>,
+    /// RESIZEDB hints per database: keys, and keys with an expiry.
+    pub sizes: Vec<(u64, u64, u64)>,
+    pub entries: Vec<RdbEntry>,
+}
+
+/// CRC-64/Jones, reflected, as Redis puts at the end of RDB files and DUMP
+/// payloads.
+pub fn crc64(crc: u64, data: &[u8]) -> u64 {
+    const POLY: u64 = 0x95ac_9329_ac4b_c9b5;
+
+    data.iter().fold(crc, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u64, |crc, _| match crc & 1 {
+            1 => (crc >> 1) ^ POLY,
+            _ => crc >> 1,
+        })
+    })
+}
+
+/// Unix time in seconds.
+fn now_secs() -> u64 {
+    now_ms() / 1000
+}
+
+/// How long a failed BGSAVE keeps the save points from trying again.
+const BGSAVE_RETRY_DELAY: u64 = 5;
+
+struct SaveStatus {
+    /// Unix time of the last successful save.
+    lastsave: u64,
+    /// Unix time the last BGSAVE started, whether it worked or not.
+    last_bgsave_try: u64,
+    last_bgsave_ok: bool,
+    in_progress: bool,
+    /// A BGSAVE SCHEDULE came in while another one was running.
+    scheduled: bool,
+}
+
+/// What SAVE, BGSAVE and the save points keep track of.
+pub struct SaveState {
+    /// Changes to the keyspace since the last successful save.
+    dirty: AtomicU64,
+    status: Mutex<SaveStatus>,
+}
+
+impl Default for SaveState {
+    fn default() -> SaveState {
+        SaveState {
+            dirty: AtomicU64::new(0),
+            status: Mutex::new(SaveStatus {
+                lastsave: now_secs(),
+                last_bgsave_try: 0,
+                last_bgsave_ok: true,
+                in_progress: false,
+                scheduled: false,
+            }),
+        }
+    }
+}
+
+impl SaveState {
+    pub fn touch(&self, changes: u64) {
+        self.dirty.fetch_add(changes, Ordering::SeqCst);
+    }
+
+    pub fn dirty(&self) -> u64 {
+        self.dirty.load(Ordering::SeqCst)
+    }
+
+    pub fn lastsave(&self) -> u64 {
+        self.status.lock().unwrap().lastsave
+    }
+
+    pub fn in_progress(&self) -> bool {
+        self.status.lock().unwrap().in_progress
+    }
+
+    /// Marks a BGSAVE as running, false when there already is one.
+    pub fn begin(&self) -> bool {
+        let mut status = self.status.lock().unwrap();
+
+        if status.in_progress {
+            return false;
+        }
+
+        status.in_progress = true;
+        status.scheduled = false;
+        status.last_bgsave_try = now_secs();
+        true
+    }
+
+    /// Asks for a BGSAVE once the running one is done.
+    pub fn schedule(&self) {
+        self.status.lock().unwrap().scheduled = true;
+    }
+
+    /// Records how a save of the keyspace as it was after `dirty` changes
+    /// went. Changes made while it was written still count as unsaved.
+    pub fn finish(&self, ok: bool, dirty: u64, background: bool) {
+        let mut status = self.status.lock().unwrap();
+
+        if ok {
+            self.dirty.fetch_sub(dirty, Ordering::SeqCst);
+            status.lastsave = now_secs();
+        }
+
+        if background {
+            status.in_progress = false;
+            status.last_bgsave_ok = ok;
+        }
+    }
+
+    /// Whether a BGSAVE should start now, because one was scheduled or
+    /// `points` (seconds and changes) say so. After a failure the save
+    /// points wait a little before trying again.
+    pub fn due(&self, points: &[(u64, u64)]) -> bool {
+        let status = self.status.lock().unwrap();
+
+        if status.in_progress {
+            return false;
+        }
+
+        if status.scheduled {
+            return true;
+        }
+
+        let now = now_secs();
+        let dirty = self.dirty();
+
+        let retry = status.last_bgsave_ok || now - status.last_bgsave_try > BGSAVE_RETRY_DELAY;
+
+        retry
+            && points.iter().any(|&(seconds, changes)| {
+                dirty >= changes && now.saturating_sub(status.lastsave) > seconds
+            })
+    }
+
+    /// The INFO persistence section.
+    pub fn info(&self) -> String {
+        let status = self.status.lock().unwrap();
+
+        [
+            "# Persistence".to_string(),
+            "loading:0".to_string(),
+            format!("rdb_changes_since_last_save:{}", self.dirty()),
+            format!("rdb_bgsave_in_progress:{}", status.in_progress as u8),
+            format!("rdb_last_save_time:{}", status.lastsave),
+            format!(
+                "rdb_last_bgsave_status:{}",
+                if status.last_bgsave_ok { "ok" } else { "err" }
+            ),
+        ]
+        .join("\r\n")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crc64_matches_redis() {
+        assert_eq!(crc64(0, b"123456789"), 0xe9c6_d914_c4b8_d9ca);
+        assert_eq!(crc64(0, b""), 0);
+    }
+
+    #[test]
+    fn crc64_continues_across_chunks() {
+        let data = b"This is a test of the emergency broadcast system.";
+        let (head, tail) = data.split_at(17);
+
+        assert_eq!(crc64(crc64(0, head), tail), crc64(0, data));
+    }
+
+    #[test]
+    fn save_points() {
+        let state = SaveState::default();
+        let points = [(0, 2)];
+
+        state.status.lock().unwrap().lastsave = now_secs() - 10;
+        state.touch(1);
+
+        assert!(!state.due(&points));
+
+        state.touch(1);
+
+        assert!(state.due(&points));
+        assert!(!state.due(&[(3600, 1)]));
+        assert!(state.begin());
+        assert!(!state.begin());
+        assert!(!state.due(&points));
+
+        // Changes made while saving stay unsaved.
+        state.touch(3);
+        state.finish(true, 2, true);
+
+        assert_eq!(state.dirty(), 3);
+        assert!(!state.in_progress());
+        assert!(!state.due(&points));
+    }
+
+    #[test]
+    fn failed_saves_wait_before_retrying() {
+        let state = SaveState::default();
+        let points = [(0, 1)];
+
+        state.status.lock().unwrap().lastsave = now_secs() - 10;
+        state.touch(1);
+        state.begin();
+        state.finish(false, 1, true);
+
+        assert_eq!(state.dirty(), 1);
+        assert!(!state.due(&points));
+
+        state.schedule();
+
+        assert!(state.due(&points));
+    }
+}
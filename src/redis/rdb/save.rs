@@ -0,0 +1,715 @@
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::Path,
+    process,
+    sync::atomic::{AtomicU64, Ordering},
+    time::UNIX_EPOCH,
+};
+
+use crate::redis::persistence::{
+    kv_pair::PersistedValue,
+    lib::PersistenceInner,
+    stream::{now_ms, Stream, StreamId, StreamVal, STREAM_NODE_MAX_ENTRIES},
+};
+
+use super::{listpack::ListpackWriter, *};
+
+const STREAM_ITEM_FLAG_SAMEFIELDS: i64 = 1 << 1;
+
+/// Tells apart the temporary files of saves running at the same time.
+static TEMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Builds an RDB payload, the counterpart of `load::Reader`.
+#[derive(Default)]
+pub struct Writer {
+    buf: Vec<u8>,
+}
+
+impl Writer {
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buf
+    }
+
+    pub fn write_u8(&mut self, byte: u8) {
+        self.buf.push(byte);
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn write_length(&mut self, len: u64) {
+        match len {
+            0..=0x3f => self.write_u8(len as u8),
+            0x40..=0x3fff => self.write_bytes(&[0x40 | (len >> 8) as u8, len as u8]),
+            0x4000..=0xffff_ffff => {
+                self.write_u8(0x80);
+                self.write_bytes(&(len as u32).to_be_bytes());
+            }
+            _ => {
+                self.write_u8(0x81);
+                self.write_bytes(&len.to_be_bytes());
+            }
+        }
+    }
+
+    pub fn write_raw_string(&mut self, bytes: &[u8]) {
+        self.write_length(bytes.len() as u64);
+        self.write_bytes(bytes);
+    }
+
+    /// Strings holding a small integer are stored as the integer, like
+    /// Redis does.
+    pub fn write_string(&mut self, s: &str) {
+        let int = match s.len() <= 11 {
+            true => s.parse::<i64>().ok().filter(|v| v.to_string() == s),
+            false => None,
+        };
+
+        match int {
+            Some(v) if i8::try_from(v).is_ok() => self.write_bytes(&[0xc0, v as i8 as u8]),
+            Some(v) if i16::try_from(v).is_ok() => {
+                self.write_u8(0xc1);
+                self.write_bytes(&(v as i16).to_le_bytes());
+            }
+            Some(v) if i32::try_from(v).is_ok() => {
+                self.write_u8(0xc2);
+                self.write_bytes(&(v as i32).to_le_bytes());
+            }
+            _ => self.write_raw_string(s.as_bytes()),
+        }
+    }
+
+    pub fn write_ms(&mut self, ms: i64) {
+        self.write_bytes(&ms.to_le_bytes());
+    }
+
+    fn write_double(&mut self, v: f64) {
+        self.write_bytes(&v.to_le_bytes());
+    }
+
+    fn write_stream_id(&mut self, id: StreamId) {
+        self.write_bytes(&stream_id(id));
+    }
+
+    fn write_stream_id_lengths(&mut self, id: StreamId) {
+        self.write_length(id.ms);
+        self.write_length(id.seq);
+    }
+
+    /// Writes `value` in the encoding `value_type` says.
+    pub fn write_value(&mut self, value: &RdbValue) {
+        match value {
+            RdbValue::String(s) => self.write_string(s),
+            RdbValue::Set(set) => {
+                let members = set.members();
+                self.write_length(members.len() as u64);
+
+                for member in &members {
+                    self.write_string(member);
+                }
+            }
+            RdbValue::SortedSet(zset) => {
+                let entries = zset.entries();
+                self.write_length(entries.len() as u64);
+
+                for (member, score) in &entries {
+                    self.write_string(member);
+                    self.write_double(*score);
+                }
+            }
+            RdbValue::Stream(stream) => self.write_stream(stream),
+        }
+    }
+
+    fn write_stream(&mut self, stream: &Stream) {
+        let entries: Vec<&StreamVal> = stream.range(StreamId::MIN, StreamId::MAX).collect();
+        let nodes: Vec<&[&StreamVal]> = entries.chunks(STREAM_NODE_MAX_ENTRIES).collect();
+
+        self.write_length(nodes.len() as u64);
+
+        for node in nodes {
+            self.write_raw_string(&stream_id(node[0].id));
+            self.write_raw_string(&stream_node(node));
+        }
+
+        self.write_length(stream.len() as u64);
+        self.write_stream_id_lengths(stream.last_id());
+        self.write_stream_id_lengths(stream.first_id());
+        self.write_stream_id_lengths(stream.max_deleted_id());
+        self.write_length(stream.entries_added());
+
+        self.write_length(stream.groups.len() as u64);
+
+        for (name, group) in &stream.groups {
+            self.write_raw_string(name.as_bytes());
+            self.write_stream_id_lengths(group.last_id);
+            self.write_length(group.entries_read.unwrap_or(u64::MAX));
+
+            self.write_length(group.pending.len() as u64);
+
+            for (id, entry) in &group.pending {
+                self.write_stream_id(*id);
+                self.write_ms(entry.delivery_time as i64);
+                self.write_length(entry.delivery_count);
+            }
+
+            self.write_length(group.consumers.len() as u64);
+
+            for (name, consumer) in &group.consumers {
+                self.write_raw_string(name.as_bytes());
+                self.write_ms(consumer.seen_time as i64);
+                self.write_ms(consumer.active_time.map_or(-1, |t| t as i64));
+                self.write_length(consumer.pending.len() as u64);
+
+                for id in &consumer.pending {
+                    self.write_stream_id(*id);
+                }
+            }
+        }
+    }
+}
+
+/// The RDB type `Writer::write_value` stores `value` as.
+pub fn value_type(value: &RdbValue) -> u8 {
+    match value {
+        RdbValue::String(_) => TYPE_STRING,
+        RdbValue::Set(_) => TYPE_SET,
+        RdbValue::SortedSet(_) => TYPE_ZSET_2,
+        RdbValue::Stream(_) => TYPE_STREAM_LISTPACKS_3,
+    }
+}
+
+fn stream_id(id: StreamId) -> [u8; 16] {
+    let mut raw = [0; 16];
+    raw[..8].copy_from_slice(&id.ms.to_be_bytes());
+    raw[8..].copy_from_slice(&id.seq.to_be_bytes());
+    raw
+}
+
+/// A stream node as a listpack, see `load::read_stream_node`.
+fn stream_node(entries: &[&StreamVal]) -> Vec<u8> {
+    let master = entries[0];
+    let mut lp = ListpackWriter::default();
+
+    lp.push_int(entries.len() as i64);
+    lp.push_int(0);
+    lp.push_int(master.pairs.len() as i64);
+
+    for (field, _) in &master.pairs {
+        lp.push_str(field);
+    }
+
+    lp.push_int(0);
+
+    for entry in entries {
+        let same_fields = entry.pairs.len() == master.pairs.len()
+            && entry
+                .pairs
+                .iter()
+                .zip(&master.pairs)
+                .all(|((field, _), (master_field, _))| field == master_field);
+
+        lp.push_int(match same_fields {
+            true => STREAM_ITEM_FLAG_SAMEFIELDS,
+            false => 0,
+        });
+        lp.push_int(entry.id.ms.wrapping_sub(master.id.ms) as i64);
+        lp.push_int(entry.id.seq.wrapping_sub(master.id.seq) as i64);
+
+        let fields = entry.pairs.len() as i64;
+
+        match same_fields {
+            true => {
+                for (_, value) in &entry.pairs {
+                    lp.push_str(value);
+                }
+
+                lp.push_int(3 + fields);
+            }
+            false => {
+                lp.push_int(fields);
+
+                for (field, value) in &entry.pairs {
+                    lp.push_str(field);
+                    lp.push_str(value);
+                }
+
+                lp.push_int(4 + 2 * fields);
+            }
+        }
+    }
+
+    lp.finish()
+}
+
+fn string_entry(key: &str, val: &PersistedValue) -> RdbEntry {
+    let expire_ms = (val.expiry > 0).then(|| {
+        (val.timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis()
+            + val.expiry) as u64
+    });
+
+    RdbEntry {
+        db: 0,
+        key: key.to_string(),
+        value: RdbValue::String(val.data.inside_value().unwrap_or_default().to_string()),
+        expire_ms,
+    }
+}
+
+/// Copies a single key, `None` when there is no such key.
+pub fn entry(persistence: &PersistenceInner, key: &str) -> Option<RdbEntry> {
+    if let Some(val) = persistence.key_value.lock().unwrap().0.get(key) {
+        return (!val.is_expired()).then(|| string_entry(key, val));
+    }
+
+    let value = persistence
+        .set
+        .lock()
+        .unwrap()
+        .0
+        .get(key)
+        .map(|set| RdbValue::Set(set.clone()))
+        .or_else(|| {
+            let sorted_set = persistence.sorted_set.lock().unwrap();
+            sorted_set
+                .0
+                .get(key)
+                .map(|zset| RdbValue::SortedSet(zset.clone()))
+        })
+        .or_else(|| {
+            let stream = persistence.stream.lock().unwrap();
+            stream
+                .map
+                .get(key)
+                .map(|stream| RdbValue::Stream(stream.clone()))
+        })?;
+
+    Some(RdbEntry {
+        db: 0,
+        key: key.to_string(),
+        value,
+        expire_ms: None,
+    })
+}
+
+/// Copies the keyspace as it is now. Callers keep every command out while
+/// it runs, so the copy is a single point in time.
+pub fn snapshot(persistence: &PersistenceInner) -> Vec<RdbEntry> {
+    let mut entries = vec![];
+
+    for (key, val) in persistence.key_value.lock().unwrap().0.iter() {
+        if val.is_expired() {
+            continue;
+        }
+
+        entries.push(string_entry(key, val));
+    }
+
+    let values = persistence
+        .set
+        .lock()
+        .unwrap()
+        .0
+        .iter()
+        .map(|(key, set)| (key.clone(), RdbValue::Set(set.clone())))
+        .chain(
+            persistence
+                .sorted_set
+                .lock()
+                .unwrap()
+                .0
+                .iter()
+                .map(|(key, zset)| (key.clone(), RdbValue::SortedSet(zset.clone()))),
+        )
+        .chain(
+            persistence
+                .stream
+                .lock()
+                .unwrap()
+                .map
+                .iter()
+                .map(|(key, stream)| (key.clone(), RdbValue::Stream(stream.clone()))),
+        )
+        .collect::<Vec<_>>();
+
+    entries.extend(values.into_iter().map(|(key, value)| RdbEntry {
+        db: 0,
+        key,
+        value,
+        expire_ms: None,
+    }));
+
+    entries
+}
+
+/// A whole RDB file holding `entries`, checksum included.
+pub fn serialize(entries: &[RdbEntry]) -> Vec<u8> {
+    let mut writer = Writer::default();
+
+    writer.write_bytes(format!("REDIS{:04}", RDB_VERSION).as_bytes());
+
+    for (key, value) in [
+        ("redis-ver", "7.4.0".to_string()),
+        ("redis-bits", "64".to_string()),
+        ("ctime", (now_ms() / 1000).to_string()),
+        ("aof-base", "0".to_string()),
+    ] {
+        writer.write_u8(OPCODE_AUX);
+        writer.write_string(key);
+        writer.write_string(&value);
+    }
+
+    if !entries.is_empty() {
+        let expires = entries.iter().filter(|e| e.expire_ms.is_some()).count();
+
+        writer.write_u8(OPCODE_SELECTDB);
+        writer.write_length(0);
+        writer.write_u8(OPCODE_RESIZEDB);
+        writer.write_length(entries.len() as u64);
+        writer.write_length(expires as u64);
+    }
+
+    for entry in entries {
+        if let Some(expire_ms) = entry.expire_ms {
+            writer.write_u8(OPCODE_EXPIRETIME_MS);
+            writer.write_ms(expire_ms as i64);
+        }
+
+        writer.write_u8(value_type(&entry.value));
+        writer.write_string(&entry.key);
+        writer.write_value(&entry.value);
+    }
+
+    writer.write_u8(OPCODE_EOF);
+
+    let mut data = writer.into_inner();
+    let checksum = crc64(0, &data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+    data
+}
+
+/// A DUMP payload: the value's type and the value, the way an RDB file has
+/// them, then the RDB version and a CRC64 of everything before it.
+pub fn dump(value: &RdbValue) -> Vec<u8> {
+    let mut writer = Writer::default();
+
+    writer.write_u8(value_type(value));
+    writer.write_value(value);
+    writer.write_bytes(&(RDB_VERSION as u16).to_le_bytes());
+
+    let mut data = writer.into_inner();
+    let checksum = crc64(0, &data);
+    data.extend_from_slice(&checksum.to_le_bytes());
+    data
+}
+
+/// Writes `data` to a temporary file next to `path`, then renames it over
+/// `path`, so a crash never leaves a half written file behind.
+pub fn write_file(path: &Path, data: &[u8]) -> io::Result<()> {
+    let temp = path.with_file_name(format!(
+        "temp-{}-{}.rdb",
+        process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let written = File::create(&temp).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    });
+
+    match written.and_then(|_| fs::rename(&temp, path)) {
+        Ok(()) => Ok(()),
+        Err(e) => {
+            let _ = fs::remove_file(&temp);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::redis::persistence::{
+        set::SetValue,
+        sorted_set::{SortedSet, ZAddFlags},
+        stream::{ConsumerGroup, IdSpec},
+    };
+
+    use super::{super::load, *};
+
+    fn entry(key: &str, value: RdbValue, expire_ms: Option<u64>) -> RdbEntry {
+        RdbEntry {
+            db: 0,
+            key: key.to_string(),
+            value,
+            expire_ms,
+        }
+    }
+
+    fn stream() -> Stream {
+        let mut stream = Stream::default();
+
+        for i in 1..=STREAM_NODE_MAX_ENTRIES as u64 + 5 {
+            let pairs = match i % 3 {
+                0 => vec![("other".to_string(), i.to_string())],
+                _ => vec![
+                    ("f".to_string(), "v".to_string()),
+                    ("n".to_string(), i.to_string()),
+                ],
+            };
+
+            stream
+                .append(IdSpec::Explicit(StreamId { ms: i, seq: 1 }), pairs)
+                .unwrap();
+        }
+
+        stream.delete(StreamId { ms: 2, seq: 1 });
+        stream
+            .groups
+            .insert("g".to_string(), ConsumerGroup::new(StreamId::MIN, Some(0)));
+        stream.groups.get_mut("g").unwrap().touch_consumer("c");
+        stream.read_group_new("g", "c", 3, false);
+
+        stream
+    }
+
+    #[test]
+    fn lengths_round_trip() {
+        let lengths = [
+            0,
+            0x3f,
+            0x40,
+            0x3fff,
+            0x4000,
+            0xffff_ffff,
+            0x1_0000_0000,
+            u64::MAX,
+        ];
+        let mut writer = Writer::default();
+
+        for len in lengths {
+            writer.write_length(len);
+        }
+
+        let data = writer.into_inner();
+        let mut reader = load::Reader::new(&data);
+
+        for len in lengths {
+            assert_eq!(reader.read_length().unwrap(), len);
+        }
+    }
+
+    #[test]
+    fn integer_strings_are_stored_as_integers() {
+        let strings = [
+            "12",
+            "-128",
+            "300",
+            "-70000",
+            "2147483648",
+            "012",
+            "",
+            "text",
+        ];
+        let mut writer = Writer::default();
+
+        for s in strings {
+            writer.write_string(s);
+        }
+
+        let data = writer.into_inner();
+
+        assert_eq!(&data[..2], [0xc0, 12]);
+
+        let mut reader = load::Reader::new(&data);
+
+        for s in strings {
+            assert_eq!(reader.read_str().unwrap(), s);
+        }
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let mut ints = SetValue::default();
+        let mut words = SetValue::default();
+        let mut zset = SortedSet::default();
+
+        for member in ["1", "2", "3"] {
+            ints.add(member);
+        }
+
+        for member in ["a", "b"] {
+            words.add(member);
+        }
+
+        zset.add(1.5, "a", ZAddFlags::default());
+        zset.add(f64::INFINITY, "b", ZAddFlags::default());
+
+        let later = now_ms() + 60_000;
+        let entries = [
+            entry("string", RdbValue::String("value".to_string()), Some(later)),
+            entry("ints", RdbValue::Set(ints), None),
+            entry("words", RdbValue::Set(words), None),
+            entry("zset", RdbValue::SortedSet(zset.clone()), None),
+            entry("stream", RdbValue::Stream(stream()), None),
+        ];
+
+        let rdb = load::parse(&serialize(&entries)).unwrap();
+
+        assert_eq!(rdb.version, RDB_VERSION);
+        assert_eq!(rdb.sizes, [(0, 5, 1)]);
+        assert_eq!(rdb.entries.len(), 5);
+        assert_eq!(rdb.entries[0].expire_ms, Some(later));
+        assert!(matches!(&rdb.entries[0].value, RdbValue::String(s) if s == "value"));
+        assert!(
+            matches!(&rdb.entries[1].value, RdbValue::Set(set) if set.members() == ["1", "2", "3"])
+        );
+        assert!(matches!(&rdb.entries[2].value, RdbValue::Set(set) if set.len() == 2));
+        assert!(matches!(
+            &rdb.entries[3].value,
+            RdbValue::SortedSet(loaded) if loaded.entries() == zset.entries()
+        ));
+
+        let RdbValue::Stream(loaded) = &rdb.entries[4].value else {
+            panic!("not a stream");
+        };
+        let saved = stream();
+
+        let all = |s: &Stream| -> Vec<(StreamId, Vec<(String, String)>)> {
+            s.range(StreamId::MIN, StreamId::MAX)
+                .map(|val| (val.id, val.pairs.clone()))
+                .collect()
+        };
+
+        assert_eq!(all(loaded), all(&saved));
+        assert_eq!(loaded.last_id(), saved.last_id());
+        assert_eq!(loaded.max_deleted_id(), saved.max_deleted_id());
+        assert_eq!(loaded.entries_added(), saved.entries_added());
+
+        let (group, saved_group) = (&loaded.groups["g"], &saved.groups["g"]);
+
+        assert_eq!(group.last_id, saved_group.last_id);
+        assert_eq!(group.entries_read, saved_group.entries_read);
+        assert_eq!(
+            group.pending.keys().collect::<Vec<_>>(),
+            saved_group.pending.keys().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            group.consumers["c"].pending,
+            saved_group.consumers["c"].pending
+        );
+    }
+
+    #[test]
+    fn corruption_fails_the_checksum() {
+        let mut data = serialize(&[entry("k", RdbValue::String("v".to_string()), None)]);
+
+        assert!(load::parse(&data).is_ok());
+
+        let len = data.len();
+        data[len - 12] ^= 1;
+
+        assert!(matches!(load::parse(&data), Err(RdbError::BadChecksum)));
+    }
+
+    #[test]
+    fn empty_keyspaces_still_make_a_file() {
+        let rdb = load::parse(&serialize(&[])).unwrap();
+
+        assert!(rdb.entries.is_empty() && rdb.sizes.is_empty());
+        assert!(rdb.aux.iter().any(|(key, _)| key == "redis-ver"));
+    }
+
+    #[test]
+    fn snapshots_skip_expired_strings() {
+        let persistence = PersistenceInner::default();
+        let later = now_ms() + 60_000;
+
+        load::store(
+            &persistence,
+            "live".to_string(),
+            RdbValue::String("v".to_string()),
+            Some(later),
+        );
+        load::store(
+            &persistence,
+            "set".to_string(),
+            RdbValue::Set(SetValue::default()),
+            None,
+        );
+
+        let mut keys: Vec<String> = snapshot(&persistence).into_iter().map(|e| e.key).collect();
+        keys.sort();
+
+        assert_eq!(keys, ["live", "set"]);
+        assert!(entry_expiry(&persistence, "live").unwrap() >= later - 1000);
+        assert!(super::entry(&persistence, "missing").is_none());
+    }
+
+    #[test]
+    fn dump_payloads_round_trip() {
+        let mut set = SetValue::default();
+        set.add("x");
+
+        for value in [
+            RdbValue::String("v".to_string()),
+            RdbValue::Set(set),
+            RdbValue::Stream(stream()),
+        ] {
+            let payload = dump(&value);
+            let footer = &payload[payload.len() - 10..];
+
+            assert_eq!(footer[..2], (RDB_VERSION as u16).to_le_bytes());
+            assert_eq!(dump(&load::read_dump(&payload).unwrap()), payload);
+        }
+    }
+
+    #[test]
+    fn dump_payloads_are_checked_before_reading() {
+        let payload = dump(&RdbValue::String("value".to_string()));
+        let len = payload.len();
+
+        let mut flipped = payload.clone();
+        flipped[1] ^= 1;
+
+        assert!(matches!(
+            load::read_dump(&flipped),
+            Err(RdbError::BadChecksum)
+        ));
+        assert!(matches!(
+            load::read_dump(&payload[..9]),
+            Err(RdbError::BadChecksum)
+        ));
+
+        // A newer version is refused even with a good checksum.
+        let mut newer = payload[..len - 10].to_vec();
+        newer.extend((RDB_VERSION as u16 + 1).to_le_bytes());
+        let checksum = crc64(0, &newer);
+        newer.extend(checksum.to_le_bytes());
+
+        assert!(matches!(
+            load::read_dump(&newer),
+            Err(RdbError::UnsupportedVersion(_))
+        ));
+
+        // Bytes left over after the value.
+        let mut longer = payload[..len - 10].to_vec();
+        longer.push(0);
+        longer.extend(&payload[len - 10..len - 8]);
+        let checksum = crc64(0, &longer);
+        longer.extend(checksum.to_le_bytes());
+
+        assert!(matches!(
+            load::read_dump(&longer),
+            Err(RdbError::BadEncoding)
+        ));
+    }
+
+    fn entry_expiry(persistence: &PersistenceInner, key: &str) -> Option<u64> {
+        super::entry(persistence, key)?.expire_ms
+    }
+}
@@ -1,20 +1,26 @@
-use core::panic;
 use std::{
-    collections::HashMap,
-    io::{Read, Write},
+    collections::{HashMap, HashSet},
+    io::{ErrorKind, Read, Write},
     net::TcpStream,
     sync::{Arc, Mutex},
     vec,
 };
 
-use super::parse::{Resp, RespData};
+use super::{client::Connection, parse::RespData};
+
+/// Length of the marker that ends a diskless snapshot transfer.
+const EOF_MARK_LEN: usize = 40;
 
 #[derive(Clone, Debug)]
 pub struct Master {
     pub replication_id: String,
     pub offset: u64,
     pub slave_ports: Vec<u16>,
-    pub slave_stream: HashMap<u16, Arc<Mutex<TcpStream>>>,
+    pub slave_stream: HashMap<u16, Arc<Mutex<Connection>>>,
+    /// Replicas that announced `capa eof`, they can take a diskless sync.
+    pub eof_capable: HashSet<u16>,
+    /// Writes held back for replicas still receiving their snapshot.
+    pub syncing: HashMap<u16, Vec<u8>>,
 }
 
 #[derive(Clone, Debug)]
@@ -35,11 +41,8 @@ impl Role {
     fn master_replication(&self) -> (RespData, RespData) {
         match self {
             Role::Master(master) => (
-                RespData::BulkString(format!(
-                    "master_replid:{}",
-                    master.replication_id.to_string()
-                )),
-                RespData::BulkString(format!("master_repl_offset:{}", master.offset.to_string())),
+                RespData::from(format!("master_replid:{}", master.replication_id)),
+                RespData::from(format!("master_repl_offset:{}", master.offset)),
             ),
             _ => (
                 RespData::Error("Slaves don't have a ReplicationId or Offset".to_string()),
@@ -56,19 +59,28 @@ impl Default for Role {
             offset: 0,
             slave_ports: vec![],
             slave_stream: HashMap::new(),
+            eof_capable: HashSet::new(),
+            syncing: HashMap::new(),
         })
     }
 }
 
-impl Into<String> for Role {
-    fn into(self) -> String {
-        match self {
+impl From<Role> for String {
+    fn from(val: Role) -> Self {
+        match val {
             Role::Master(_) => String::from("role:master"),
             Role::Slave(_) => String::from("role:slave"),
         }
     }
 }
 
+/// What a replica gets from its master on a full resync: the snapshot, and
+/// whatever came right after it.
+pub struct FullSync {
+    pub rdb: Vec<u8>,
+    pub rest: Vec<u8>,
+}
+
 #[derive(Debug)]
 pub struct Info {
     pub role: Role,
@@ -85,8 +97,8 @@ impl Default for Info {
 }
 
 impl Info {
-    pub fn slave(&mut self, host: String, port: u16) {
-        let mut connection = TcpStream::connect(format!("{}:{}", host.to_string(), port)).unwrap();
+    pub fn slave(&mut self, host: String, port: u16) -> FullSync {
+        let mut connection = TcpStream::connect(format!("{}:{}", host, port)).unwrap();
 
         self.role = Role::Slave(Slave {
             master_host: host,
@@ -102,10 +114,13 @@ impl Info {
             vec!["REPLCONF", "listening-port", &self.port.to_string()],
         )
         .unwrap();
-        self.replconf(&mut connection, vec!["REPLCONF", "capa", "psync2"])
-            .unwrap();
+        self.replconf(
+            &mut connection,
+            vec!["REPLCONF", "capa", "eof", "capa", "psync2"],
+        )
+        .unwrap();
         self.psync(&mut connection, vec!["PSYNC", "?", "-1"])
-            .unwrap();
+            .unwrap()
     }
 
     pub fn is_master(&self) -> bool {
@@ -137,96 +152,92 @@ impl Info {
         }
     }
 
-    fn psync(&self, connection: &mut TcpStream, args: Vec<&str>) -> Result<usize, std::io::Error> {
-        let mut fields: Vec<RespData> = vec![];
+    /// Reads more of what the master sends onto the end of `buf`.
+    fn read_more(connection: &mut TcpStream, buf: &mut Vec<u8>) -> Result<(), std::io::Error> {
+        let mut chunk = [0; 4096];
+
+        match connection.read(&mut chunk)? {
+            0 => Err(std::io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "Master closed the connection",
+            )),
+            size => {
+                buf.extend_from_slice(&chunk[..size]);
+                Ok(())
+            }
+        }
+    }
 
-        for arg in args.into_iter() {
-            fields.push(RespData::BulkString(arg.to_string()));
+    /// Takes a CRLF terminated line off the front of `buf`, reading until
+    /// there is one.
+    fn read_line(connection: &mut TcpStream, buf: &mut Vec<u8>) -> Result<String, std::io::Error> {
+        loop {
+            if let Some(end) = buf.windows(2).position(|w| w == b"\r\n") {
+                let line = String::from_utf8_lossy(&buf[..end]).to_string();
+                buf.drain(..end + 2);
+                return Ok(line);
+            }
+
+            Self::read_more(connection, buf)?;
         }
+    }
 
-        let send = RespData::Array(fields);
-        let _ = connection.write(send.to_string().as_bytes());
+    /// Asks for a full resync and reads the snapshot that follows, framed
+    /// by its length or, when diskless, by the EOF marker announced first.
+    fn psync(
+        &self,
+        connection: &mut TcpStream,
+        args: Vec<&str>,
+    ) -> Result<FullSync, std::io::Error> {
+        let send = RespData::new_bulk_array(&args);
+        connection.write_all(send.to_string().as_bytes())?;
 
-        match Self::read_from_stream(connection) {
-            Ok(received) => {
-                let resp = Resp::parse(received).unwrap();
-
-                let mut is_working = false;
-
-                println!("{:?}", resp.data);
-
-                match resp.data {
-                    RespData::RequestArray(mut array) => {
-                        for i in 0..2 {
-                            let req = array.get(i);
-                            match req {
-                                Some(req) => match req {
-                                    RespData::SimpleString(s) => {
-                                        if s.contains(&"FULLRESYNC") {
-                                            is_working = true;
-                                        }
-                                    }
-                                    RespData::BulkString(s) => {
-                                        if s.contains(&"REDIS0011") && is_working {
-                                            return Ok(1);
-                                        }
-                                        is_working = false;
-                                    }
-                                    RespData::Array(arr) => {
-                                        match (arr.get(0), arr.get(1), arr.get(2)) {
-                                            (Some(class), Some(comm), Some(idx)) => {
-                                                if class
-                                                    == &RespData::BulkString("REPLCONF".to_string())
-                                                    && comm
-                                                        == &RespData::BulkString(
-                                                            "GETACK".to_string(),
-                                                        )
-                                                    && idx == &RespData::BulkString("*".to_string())
-                                                {
-                                                    let response = RespData::Array(vec![
-                                                        RespData::BulkString(
-                                                            "REPLCONF".to_string(),
-                                                        ),
-                                                        RespData::BulkString("ACK".to_string()),
-                                                        RespData::BulkString("0".to_string()),
-                                                    ]);
-                                                    connection
-                                                        .write(response.to_string().as_bytes())
-                                                        .unwrap();
-                                                    is_working = true;
-                                                } else {
-                                                    is_working = false;
-                                                }
-                                            }
-                                            _ => {}
-                                        }
-                                    }
-                                    _ => {}
-                                },
-                                None => {
-                                    let par =
-                                        Resp::parse(Self::read_from_stream(connection).unwrap())
-                                            .unwrap();
-                                    println!("{:?}", par.data);
-                                    array.push(par.data);
-                                }
-                            }
-                        }
+        let mut buf = vec![];
+
+        let reply = Self::read_line(connection, &mut buf)?;
+
+        if !reply.starts_with("+FULLRESYNC") {
+            return Err(std::io::Error::other(format!("Could not PSYNC: {}", reply)));
+        }
+
+        let header = Self::read_line(connection, &mut buf)?;
+
+        let rdb = match header.strip_prefix("$EOF:") {
+            Some(mark) if mark.len() == EOF_MARK_LEN => {
+                let mut from = 0;
+
+                loop {
+                    if let Some(end) = buf[from..]
+                        .windows(EOF_MARK_LEN)
+                        .position(|w| w == mark.as_bytes())
+                    {
+                        let end = from + end;
+                        let rdb = buf[..end].to_vec();
+                        buf.drain(..end + EOF_MARK_LEN);
+                        break rdb;
                     }
-                    _ => panic!("PSYNC received wrong answer"),
-                };
 
-                if is_working {
-                    return Ok(1);
+                    from = buf.len().saturating_sub(EOF_MARK_LEN);
+                    Self::read_more(connection, &mut buf)?;
+                }
+            }
+            _ => {
+                let len = header
+                    .strip_prefix('$')
+                    .and_then(|len| len.parse::<usize>().ok())
+                    .ok_or_else(|| {
+                        std::io::Error::other(format!("Bad snapshot header: {}", header))
+                    })?;
+
+                while buf.len() < len {
+                    Self::read_more(connection, &mut buf)?;
                 }
 
-                Err(std::io::Error::new(
-                    std::io::ErrorKind::Other,
-                    "Could not PSYNC",
-                ))
+                buf.drain(..len).collect()
             }
-            Err(e) => return Err(e),
-        }
+        };
+
+        Ok(FullSync { rdb, rest: buf })
     }
 
     fn replconf(
@@ -237,7 +248,7 @@ impl Info {
         let mut fields: Vec<RespData> = vec![];
 
         for arg in args.into_iter() {
-            fields.push(RespData::BulkString(arg.to_string()));
+            fields.push(RespData::from(arg.to_string()));
         }
 
         let send = RespData::Array(fields);
@@ -250,16 +261,13 @@ impl Info {
     fn ping(&self, connection: &mut TcpStream) -> Result<String, String> {
         match &self.role {
             Role::Slave(_slave) => {
-                let data = RespData::Array(vec![RespData::BulkString("ping".to_string())]);
-                match connection.write(format!("{}", data.to_string()).as_bytes()) {
+                let data = RespData::Array(vec![RespData::new_bulk("ping")]);
+                match connection.write_all(format!("{}", data).as_bytes()) {
                     Ok(_) => {
                         let mut buf = [0; 2024];
 
-                        match connection.read(&mut buf) {
-                            Ok(_size) => {
-                                let _received = String::from_utf8_lossy(&buf).to_string();
-                            }
-                            _ => {}
+                        if let Ok(_size) = connection.read(&mut buf) {
+                            let _received = String::from_utf8_lossy(&buf).to_string();
                         }
                         Ok("Server online".to_string())
                     }
@@ -273,7 +281,7 @@ impl Info {
     pub fn replication(&self) -> RespData {
         let mut fields: Vec<RespData> = vec![];
 
-        fields.push(RespData::BulkString(self.role.clone().into()));
+        fields.push(RespData::from(String::from(self.role.clone())));
 
         match self.role {
             Role::Master(_) => {
@@ -286,13 +294,83 @@ impl Info {
                     .collect::<Vec<String>>()
                     .join("\r\n");
 
-                RespData::BulkString(joined_fields)
+                RespData::from(joined_fields)
             }
             Role::Slave(_) => self.get_role(),
         }
     }
 
     pub fn get_role(&self) -> RespData {
-        RespData::BulkString(self.role.clone().into())
+        RespData::from(String::from(self.role.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread, time::Duration};
+
+    use super::*;
+
+    /// Runs `psync` against a master that sends `chunks` one at a time.
+    fn full_sync(chunks: Vec<Vec<u8>>) -> Result<FullSync, std::io::Error> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let master = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 64];
+            let _ = stream.read(&mut request).unwrap();
+
+            for chunk in chunks {
+                stream.write_all(&chunk).unwrap();
+                stream.flush().unwrap();
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        let mut connection = TcpStream::connect(addr).unwrap();
+        let sync = Info::default().psync(&mut connection, vec!["PSYNC", "?", "-1"]);
+
+        master.join().unwrap();
+        sync
+    }
+
+    const FULLRESYNC: &[u8] = b"+FULLRESYNC 8371b4fb1155b71f4a04d3e1bc3e18c4a990aeeb 0\r\n";
+
+    #[test]
+    fn psync_reads_a_snapshot_by_length() {
+        let sync = full_sync(vec![
+            FULLRESYNC.to_vec(),
+            b"$9\r\nREDIS".to_vec(),
+            b"0011*1\r\n$4\r\nPING\r\n".to_vec(),
+        ])
+        .unwrap();
+
+        assert_eq!(sync.rdb, b"REDIS0011");
+        assert_eq!(sync.rest, b"*1\r\n$4\r\nPING\r\n");
+    }
+
+    #[test]
+    fn psync_reads_a_diskless_snapshot_up_to_its_marker() {
+        let mark = "a".repeat(EOF_MARK_LEN);
+        let body = [b"REDIS0011".as_slice(), mark.as_bytes(), b"+rest"].concat();
+
+        // The marker arrives split across reads.
+        let sync = full_sync(vec![
+            [FULLRESYNC, format!("$EOF:{}\r\n", mark).as_bytes()].concat(),
+            body[..20].to_vec(),
+            body[20..].to_vec(),
+        ])
+        .unwrap();
+
+        assert_eq!(sync.rdb, b"REDIS0011");
+        assert_eq!(sync.rest, b"+rest");
+    }
+
+    #[test]
+    fn psync_fails_without_fullresync() {
+        assert!(full_sync(vec![b"-ERR no\r\n".to_vec()]).is_err());
+        assert!(full_sync(vec![FULLRESYNC.to_vec(), b"$REDIS\r\n".to_vec()]).is_err());
+        assert!(full_sync(vec![FULLRESYNC.to_vec(), b"$20\r\nREDIS".to_vec()]).is_err());
     }
 }
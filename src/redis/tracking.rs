@@ -0,0 +1,526 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use super::{parse::RespData, pubsub::Subscriber};
+
+/// Channel RESP2 clients subscribe to for redirected invalidations.
+pub const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+#[derive(Default)]
+pub struct TrackingOptions {
+    pub redirect: Option<u64>,
+    pub bcast: bool,
+    pub prefixes: Vec<String>,
+    pub optin: bool,
+    pub optout: bool,
+    pub noloop: bool,
+}
+
+struct Tracker {
+    options: TrackingOptions,
+    redirect_broken: bool,
+    /// Invalidations caused by the client's own command, held until its
+    /// reply is out so they don't land in the middle of it.
+    pending: Vec<RespData>,
+}
+
+#[derive(Default)]
+struct TrackingInner {
+    /// Every open connection, as somewhere invalidations may be redirected.
+    connections: HashMap<u64, Subscriber>,
+    trackers: HashMap<u64, Tracker>,
+    /// Keys clients in default mode read, with who read them. An entry goes
+    /// away once the key is invalidated.
+    keys: HashMap<String, HashSet<u64>>,
+}
+
+/// Server-assisted client side caching, see CLIENT TRACKING.
+#[derive(Default)]
+pub struct Tracking {
+    inner: Mutex<TrackingInner>,
+}
+
+/// Whether either prefix starts with the other.
+fn overlaps(a: &str, b: &str) -> bool {
+    a.starts_with(b) || b.starts_with(a)
+}
+
+fn invalidation(keys: Option<Vec<String>>) -> RespData {
+    RespData::Push(vec![
+        RespData::new_bulk("invalidate"),
+        match keys {
+            Some(keys) => RespData::Array(keys.into_iter().map(RespData::from).collect()),
+            None => RespData::Null,
+        },
+    ])
+}
+
+impl TrackingInner {
+    /// Sends an invalidation to the tracker `id`, or to where it redirects.
+    /// `caller` is the client whose command caused it.
+    fn deliver(
+        &mut self,
+        id: u64,
+        keys: Option<Vec<String>>,
+        caller: Option<u64>,
+        subscribed: &dyn Fn(u64) -> bool,
+    ) {
+        let tracker = match self.trackers.get_mut(&id) {
+            Some(tracker) => tracker,
+            None => return,
+        };
+
+        let own = self.connections.get(&id);
+        let target = tracker.options.redirect.unwrap_or(id);
+
+        let connection = match self.connections.get(&target) {
+            Some(connection) => connection,
+            None => {
+                if !tracker.redirect_broken {
+                    tracker.redirect_broken = true;
+
+                    if let Some(own) = own.filter(|own| own.resp3()) {
+                        own.send(&RespData::Push(vec![
+                            RespData::new_bulk("tracking-redir-broken"),
+                            RespData::Integer(target as i64),
+                        ]));
+                    }
+                }
+                return;
+            }
+        };
+
+        let message = match connection.resp3() {
+            true => invalidation(keys),
+            // A RESP2 connection can only take it as a published message.
+            false if subscribed(target) => {
+                let keys = match invalidation(keys) {
+                    RespData::Push(mut items) => items.pop().unwrap(),
+                    _ => unreachable!(),
+                };
+
+                RespData::Array(vec![
+                    RespData::new_bulk("message"),
+                    RespData::new_bulk(INVALIDATE_CHANNEL),
+                    keys,
+                ])
+            }
+            false => return,
+        };
+
+        if target == id && caller == Some(id) {
+            tracker.pending.push(message);
+        } else {
+            connection.send(&message);
+        }
+    }
+}
+
+impl Tracking {
+    pub fn connect(&self, connection: Subscriber) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.connections.insert(connection.id, connection);
+    }
+
+    pub fn disconnect(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.connections.remove(&id);
+        inner.trackers.remove(&id);
+    }
+
+    /// CLIENT TRACKING ON. Switching BCAST or OPTIN/OPTOUT takes turning
+    /// tracking off first, prefixes add up.
+    pub fn enable(&self, id: u64, options: TrackingOptions) -> Result<(), String> {
+        let mut inner = self.inner.lock().unwrap();
+
+        if let Some(redirect) = options.redirect {
+            if !inner.connections.contains_key(&redirect) {
+                return Err("ERR The client ID you want redirect to does not exist".to_string());
+            }
+        }
+
+        let existing = inner.trackers.get(&id).map(|tracker| &tracker.options);
+
+        if let Some(existing) = existing {
+            if existing.bcast != options.bcast {
+                return Err("ERR You can't switch BCAST mode on/off before disabling tracking for this client, and then re-enabling it with a different mode.".to_string());
+            }
+
+            if existing.optin != options.optin || existing.optout != options.optout {
+                return Err("ERR You can't switch OPTIN/OPTOUT mode before disabling tracking for this client, and then re-enabling it with a different mode.".to_string());
+            }
+        }
+
+        let mut prefixes: Vec<String> = existing.map(|e| e.prefixes.clone()).unwrap_or_default();
+
+        for (i, prefix) in options.prefixes.iter().enumerate() {
+            if let Some(other) = prefixes
+                .iter()
+                .chain(&options.prefixes[..i])
+                .find(|other| overlaps(prefix, other))
+            {
+                return Err(format!(
+                    "ERR Prefix '{}' overlaps with an existing prefix '{}'. Prefixes for a single client must not overlap.",
+                    prefix, other
+                ));
+            }
+        }
+
+        prefixes.extend(options.prefixes.iter().cloned());
+
+        // Broadcasting without a prefix covers every key.
+        if options.bcast && prefixes.is_empty() {
+            prefixes.push(String::new());
+        }
+
+        inner.trackers.insert(
+            id,
+            Tracker {
+                options: TrackingOptions {
+                    prefixes,
+                    ..options
+                },
+                redirect_broken: false,
+                pending: vec![],
+            },
+        );
+
+        Ok(())
+    }
+
+    pub fn disable(&self, id: u64) {
+        self.inner.lock().unwrap().trackers.remove(&id);
+    }
+
+    /// Remembers `keys` were read by `id`, if it tracks in default mode and
+    /// OPTIN/OPTOUT with the given CLIENT CACHING let it.
+    pub fn remember(&self, id: u64, keys: &[&str], caching: Option<bool>) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let options = match inner.trackers.get(&id) {
+            Some(tracker) => &tracker.options,
+            None => return,
+        };
+
+        if options.bcast
+            || (options.optin && caching != Some(true))
+            || (options.optout && caching == Some(false))
+        {
+            return;
+        }
+
+        for key in keys {
+            inner.keys.entry(key.to_string()).or_default().insert(id);
+        }
+    }
+
+    /// Tells everyone caching `key` that it changed, `caller` is the client
+    /// that changed it, if any. `subscribed` says whether a connection
+    /// listens on INVALIDATE_CHANNEL.
+    pub fn invalidate(&self, key: &str, caller: Option<u64>, subscribed: &dyn Fn(u64) -> bool) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let mut ids: Vec<u64> = inner.keys.remove(key).into_iter().flatten().collect();
+
+        ids.retain(|id| {
+            inner
+                .trackers
+                .get(id)
+                .is_some_and(|tracker| !tracker.options.bcast)
+        });
+
+        ids.extend(inner.trackers.iter().filter_map(|(&id, tracker)| {
+            let matches = tracker.options.bcast
+                && tracker
+                    .options
+                    .prefixes
+                    .iter()
+                    .any(|prefix| key.starts_with(prefix.as_str()));
+
+            matches.then_some(id)
+        }));
+
+        for id in ids {
+            let tracker = &inner.trackers[&id];
+
+            if tracker.options.noloop && caller == Some(id) {
+                continue;
+            }
+
+            inner.deliver(id, Some(vec![key.to_string()]), caller, subscribed);
+        }
+    }
+
+    /// Tells every tracking client to drop its whole cache, `caller` is the
+    /// client that flushed it, if any.
+    pub fn invalidate_all(&self, caller: Option<u64>, subscribed: &dyn Fn(u64) -> bool) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.keys.clear();
+
+        let ids: Vec<u64> = inner.trackers.keys().copied().collect();
+
+        for id in ids {
+            inner.deliver(id, None, caller, subscribed);
+        }
+    }
+
+    /// Sends what the client's own command invalidated, once its reply is
+    /// written.
+    pub fn flush(&self, id: u64) {
+        let mut inner = self.inner.lock().unwrap();
+
+        let pending = match inner.trackers.get_mut(&id) {
+            Some(tracker) => std::mem::take(&mut tracker.pending),
+            None => return,
+        };
+
+        if let Some(connection) = inner.connections.get(&id) {
+            for message in pending {
+                connection.send(&message);
+            }
+        }
+    }
+
+    /// CLIENT GETREDIR: -1 when not tracking, 0 without redirection.
+    pub fn redirect(&self, id: u64) -> i64 {
+        match self.inner.lock().unwrap().trackers.get(&id) {
+            Some(tracker) => tracker.options.redirect.map_or(0, |id| id as i64),
+            None => -1,
+        }
+    }
+
+    /// Whether `id` tracks with OPTIN and OPTOUT respectively, `None` when
+    /// it doesn't track at all.
+    pub fn opt_mode(&self, id: u64) -> Option<(bool, bool)> {
+        self.inner
+            .lock()
+            .unwrap()
+            .trackers
+            .get(&id)
+            .map(|tracker| (tracker.options.optin, tracker.options.optout))
+    }
+
+    /// CLIENT TRACKINGINFO, as its flags, redirect and prefixes.
+    pub fn info(&self, id: u64, caching: Option<bool>) -> (Vec<&'static str>, i64, Vec<String>) {
+        let inner = self.inner.lock().unwrap();
+
+        let tracker = match inner.trackers.get(&id) {
+            Some(tracker) => tracker,
+            None => return (vec!["off"], -1, vec![]),
+        };
+
+        let options = &tracker.options;
+        let mut flags = vec!["on"];
+
+        for (set, flag) in [
+            (options.bcast, "bcast"),
+            (options.optin, "optin"),
+            (options.optout, "optout"),
+            (options.optin && caching == Some(true), "caching-yes"),
+            (options.optout && caching == Some(false), "caching-no"),
+            (options.noloop, "noloop"),
+            (tracker.redirect_broken, "broken_redirect"),
+        ] {
+            if set {
+                flags.push(flag);
+            }
+        }
+
+        let prefixes = match options.bcast {
+            true => options.prefixes.clone(),
+            false => vec![],
+        };
+
+        (flags, options.redirect.map_or(0, |id| id as i64), prefixes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        net::{TcpListener, TcpStream},
+        sync::{atomic::AtomicBool, Arc},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::redis::client::Connection;
+
+    /// A connection registered with `tracking`, with the end its messages
+    /// arrive at.
+    fn connect(tracking: &Tracking, id: u64, resp3: bool) -> TcpStream {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (peer, _) = listener.accept().unwrap();
+
+        peer.set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        tracking.connect(Subscriber {
+            id,
+            writer: Arc::new(Mutex::new(Connection::Socket(stream))),
+            resp3: Arc::new(AtomicBool::new(resp3)),
+        });
+
+        peer
+    }
+
+    /// Everything sent so far.
+    fn received(peer: &mut TcpStream) -> String {
+        let mut buf = [0; 1024];
+
+        match peer.read(&mut buf) {
+            Ok(len) => String::from_utf8_lossy(&buf[..len]).into_owned(),
+            Err(_) => String::new(),
+        }
+    }
+
+    fn unsubscribed(_: u64) -> bool {
+        false
+    }
+
+    fn bcast(prefixes: &[&str]) -> TrackingOptions {
+        TrackingOptions {
+            bcast: true,
+            prefixes: prefixes.iter().map(|p| p.to_string()).collect(),
+            ..TrackingOptions::default()
+        }
+    }
+
+    #[test]
+    fn read_keys_are_invalidated_once() {
+        let tracking = Tracking::default();
+        let mut peer = connect(&tracking, 1, true);
+
+        tracking.enable(1, TrackingOptions::default()).unwrap();
+        tracking.remember(1, &["k"], None);
+        tracking.invalidate("other", Some(2), &unsubscribed);
+        tracking.invalidate("k", Some(2), &unsubscribed);
+
+        assert_eq!(
+            received(&mut peer),
+            ">2\r\n$10\r\ninvalidate\r\n*1\r\n$1\r\nk\r\n"
+        );
+
+        tracking.invalidate("k", Some(2), &unsubscribed);
+
+        assert_eq!(received(&mut peer), "");
+    }
+
+    #[test]
+    fn own_changes_wait_for_the_reply() {
+        let tracking = Tracking::default();
+        let mut peer = connect(&tracking, 1, true);
+
+        tracking.enable(1, bcast(&[])).unwrap();
+        tracking.invalidate("k", Some(1), &unsubscribed);
+
+        assert_eq!(received(&mut peer), "");
+
+        tracking.flush(1);
+
+        assert!(received(&mut peer).ends_with("$1\r\nk\r\n"));
+    }
+
+    #[test]
+    fn noloop_skips_only_the_callers_own_changes() {
+        let tracking = Tracking::default();
+        let mut peer = connect(&tracking, 1, true);
+
+        let options = TrackingOptions {
+            noloop: true,
+            ..bcast(&["user:"])
+        };
+        tracking.enable(1, options).unwrap();
+
+        tracking.invalidate("user:1", Some(1), &unsubscribed);
+        tracking.flush(1);
+
+        assert_eq!(received(&mut peer), "");
+
+        tracking.invalidate("user:1", Some(2), &unsubscribed);
+        tracking.invalidate("item:1", Some(2), &unsubscribed);
+
+        assert_eq!(
+            received(&mut peer),
+            ">2\r\n$10\r\ninvalidate\r\n*1\r\n$6\r\nuser:1\r\n"
+        );
+    }
+
+    #[test]
+    fn redirects_to_resp2_subscribers() {
+        let tracking = Tracking::default();
+        let _own = connect(&tracking, 1, false);
+        let mut target = connect(&tracking, 2, false);
+
+        let options = TrackingOptions {
+            redirect: Some(2),
+            ..bcast(&[])
+        };
+        tracking.enable(1, options).unwrap();
+
+        assert_eq!(tracking.redirect(1), 2);
+
+        tracking.invalidate("k", None, &unsubscribed);
+
+        assert_eq!(received(&mut target), "");
+
+        tracking.invalidate("k", None, &|id| id == 2);
+
+        assert_eq!(
+            received(&mut target),
+            "*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*1\r\n$1\r\nk\r\n"
+        );
+    }
+
+    #[test]
+    fn broken_redirects_are_reported_once() {
+        let tracking = Tracking::default();
+        let mut own = connect(&tracking, 1, true);
+        let _target = connect(&tracking, 2, true);
+
+        let options = TrackingOptions {
+            redirect: Some(2),
+            ..bcast(&[])
+        };
+        tracking.enable(1, options).unwrap();
+        tracking.disconnect(2);
+        tracking.invalidate("a", None, &unsubscribed);
+        tracking.invalidate("b", None, &unsubscribed);
+
+        assert_eq!(
+            received(&mut own),
+            ">2\r\n$21\r\ntracking-redir-broken\r\n:2\r\n"
+        );
+        assert!(tracking.info(1, None).0.contains(&"broken_redirect"));
+    }
+
+    #[test]
+    fn enabling_checks_the_options() {
+        let tracking = Tracking::default();
+
+        let redirect = TrackingOptions {
+            redirect: Some(9),
+            ..TrackingOptions::default()
+        };
+        assert!(tracking.enable(1, redirect).is_err());
+
+        tracking.enable(1, bcast(&["a:"])).unwrap();
+
+        assert!(tracking.enable(1, bcast(&["a:b"])).is_err());
+        assert!(tracking.enable(1, bcast(&["b:", "b:c"])).is_err());
+        assert!(tracking.enable(1, TrackingOptions::default()).is_err());
+
+        tracking.enable(1, bcast(&["b:"])).unwrap();
+
+        assert_eq!(tracking.info(1, None).2, ["a:", "b:"]);
+
+        tracking.disable(1);
+
+        assert_eq!(tracking.redirect(1), -1);
+        assert_eq!(tracking.info(1, None), (vec!["off"], -1, vec![]));
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+/// Keys clients WATCH. Touching a key flags every client watching it, so
+/// their next EXEC fails.
+#[derive(Default)]
+pub struct WatchedKeys {
+    keys: Mutex<HashMap<String, Vec<Weak<AtomicBool>>>>,
+}
+
+impl WatchedKeys {
+    pub fn watch(&self, key: &str, dirty: &Arc<AtomicBool>) {
+        let mut keys = self.keys.lock().unwrap();
+        let watchers = keys.entry(key.to_string()).or_default();
+
+        // Clients that went away without UNWATCH leave dead entries behind.
+        watchers.retain(|watcher| watcher.strong_count() > 0);
+        watchers.push(Arc::downgrade(dirty));
+    }
+
+    pub fn unwatch(&self, key: &str, dirty: &Arc<AtomicBool>) {
+        let mut keys = self.keys.lock().unwrap();
+
+        if let Some(watchers) = keys.get_mut(key) {
+            watchers.retain(|watcher| {
+                watcher.strong_count() > 0 && !std::ptr::eq(watcher.as_ptr(), Arc::as_ptr(dirty))
+            });
+
+            if watchers.is_empty() {
+                keys.remove(key);
+            }
+        }
+    }
+
+    pub fn touch(&self, key: &str) {
+        let mut keys = self.keys.lock().unwrap();
+
+        if let Some(watchers) = keys.remove(key) {
+            for watcher in watchers.iter().filter_map(Weak::upgrade) {
+                watcher.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Touches every watched key `exists` reports, for commands that drop
+    /// the whole keyspace. `exists` runs without the registry locked, writers
+    /// touch keys while holding their type's lock.
+    pub fn touch_existing(&self, exists: impl Fn(&str) -> bool) {
+        let watched: Vec<String> = self.keys.lock().unwrap().keys().cloned().collect();
+
+        for key in watched.iter().filter(|key| exists(key)) {
+            self.touch(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dirty() -> Arc<AtomicBool> {
+        Arc::new(AtomicBool::new(false))
+    }
+
+    #[test]
+    fn touching_flags_every_watcher() {
+        let watched = WatchedKeys::default();
+        let (a, b, other) = (dirty(), dirty(), dirty());
+
+        watched.watch("k", &a);
+        watched.watch("k", &b);
+        watched.watch("other", &other);
+        watched.touch("k");
+
+        assert!(a.load(Ordering::SeqCst) && b.load(Ordering::SeqCst));
+        assert!(!other.load(Ordering::SeqCst));
+        assert!(!watched.keys.lock().unwrap().contains_key("k"));
+    }
+
+    #[test]
+    fn unwatched_clients_are_left_alone() {
+        let watched = WatchedKeys::default();
+        let (a, b) = (dirty(), dirty());
+
+        watched.watch("k", &a);
+        watched.watch("k", &b);
+        watched.unwatch("k", &a);
+        watched.touch("k");
+
+        assert!(!a.load(Ordering::SeqCst));
+        assert!(b.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn dead_clients_are_pruned() {
+        let watched = WatchedKeys::default();
+        let live = dirty();
+
+        watched.watch("k", &dirty());
+        watched.watch("k", &live);
+
+        assert_eq!(watched.keys.lock().unwrap()["k"].len(), 1);
+
+        watched.unwatch("k", &live);
+
+        assert!(watched.keys.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn touch_existing_skips_missing_keys() {
+        let watched = WatchedKeys::default();
+        let (a, b) = (dirty(), dirty());
+
+        watched.watch("present", &a);
+        watched.watch("missing", &b);
+        watched.touch_existing(|key| key == "present");
+
+        assert!(a.load(Ordering::SeqCst));
+        assert!(!b.load(Ordering::SeqCst));
+    }
+}